@@ -1,5 +1,28 @@
-mod server;
+use std::{env, io::Write, process};
+
+use http_server::server::{self, request::Request, response::Response};
 
 fn main() {
-    server::start_server(8080);
+    // Started per connection by inetd or the like, with the connection as stdin and stdout
+    if env::args().any(|arg| arg == "--inetd") {
+        if let Err(err) = server::inetd::serve_stdio(echo) {
+            eprintln!("Failed to serve stdin: {}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
+    server::start_server(8080, echo);
+}
+
+fn echo(request: &Request) -> Response {
+    let body_text = String::from_utf8_lossy(&request.body);
+
+    let mut response = Response::new();
+    response
+        .body
+        .write_all(format!("You sent me: \"{}\"\n", body_text).as_bytes())
+        .unwrap();
+
+    response
 }
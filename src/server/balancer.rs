@@ -0,0 +1,451 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::{
+    handler::Handler,
+    method::Method,
+    proxy::{upstream_error_status, ReverseProxy},
+    request::Request,
+    response::Response,
+    status_code::StatusCode,
+};
+
+/** How a LoadBalancer chooses which upstream serves a request */
+pub enum Strategy {
+    RoundRobin,
+    LeastConnections,
+    /** Requests with the same key go to the same upstream while it is available */
+    ConsistentHash(HashKey),
+}
+
+pub enum HashKey {
+    Header(String),
+    ClientIp,
+}
+
+/** Points each upstream gets on the hash ring, to even out the distribution */
+const VIRTUAL_NODES: usize = 64;
+
+struct Upstream {
+    proxy: ReverseProxy,
+    /** Cleared while active health checks are failing */
+    healthy: AtomicBool,
+    active: AtomicUsize,
+    failures: AtomicU32,
+    ejected_until: Mutex<Option<Instant>>,
+}
+
+impl Upstream {
+    fn is_available(&self) -> bool {
+        if !self.healthy.load(Ordering::SeqCst) {
+            return false;
+        }
+        match *self.ejected_until.lock().unwrap() {
+            Some(ejected_until) => Instant::now() >= ejected_until,
+            None => true,
+        }
+    }
+
+    fn record_success(&self) {
+        self.failures.store(0, Ordering::SeqCst);
+        *self.ejected_until.lock().unwrap() = None;
+    }
+
+    fn record_failure(&self, max_failures: u32, ejection_time: Duration) {
+        let failures = self.failures.fetch_add(1, Ordering::SeqCst) + 1;
+        if failures >= max_failures {
+            *self.ejected_until.lock().unwrap() = Some(Instant::now() + ejection_time);
+        }
+    }
+}
+
+/** Counts a request against its upstream until the response body is done */
+struct ActiveRequest(Arc<Upstream>);
+
+impl ActiveRequest {
+    fn start(upstream: &Arc<Upstream>) -> Self {
+        upstream.active.fetch_add(1, Ordering::SeqCst);
+        ActiveRequest(Arc::clone(upstream))
+    }
+}
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/**
+ * Spreads requests over a pool of upstreams, each proxied by its own
+ * ReverseProxy. Upstreams that fail `max_failures` requests in a row are
+ * ejected for `ejection_time`, and optional active health checks take
+ * upstreams out of rotation until their health endpoint recovers.
+ */
+pub struct LoadBalancer {
+    upstreams: Vec<Arc<Upstream>>,
+    strategy: Strategy,
+    ring: Vec<(u64, usize)>,
+    next: AtomicUsize,
+    max_failures: u32,
+    ejection_time: Duration,
+}
+
+impl LoadBalancer {
+    pub const DEFAULT_MAX_FAILURES: u32 = 3;
+    pub const DEFAULT_EJECTION_TIME: Duration = Duration::from_secs(30);
+
+    pub fn new(strategy: Strategy, upstreams: Vec<ReverseProxy>) -> Self {
+        let mut ring = vec![];
+        for (index, proxy) in upstreams.iter().enumerate() {
+            for node in 0..VIRTUAL_NODES {
+                ring.push((hash(&format!("{}#{}", proxy.upstream(), node)), index));
+            }
+        }
+        ring.sort();
+
+        LoadBalancer {
+            upstreams: upstreams
+                .into_iter()
+                .map(|proxy| {
+                    Arc::new(Upstream {
+                        proxy,
+                        healthy: AtomicBool::new(true),
+                        active: AtomicUsize::new(0),
+                        failures: AtomicU32::new(0),
+                        ejected_until: Mutex::new(None),
+                    })
+                })
+                .collect(),
+            strategy,
+            ring,
+            next: AtomicUsize::new(0),
+            max_failures: Self::DEFAULT_MAX_FAILURES,
+            ejection_time: Self::DEFAULT_EJECTION_TIME,
+        }
+    }
+
+    /** Consecutive failed requests after which an upstream is ejected */
+    pub fn max_failures(mut self, max_failures: u32) -> Self {
+        self.max_failures = max_failures.max(1);
+        self
+    }
+
+    /** How long an ejected upstream is skipped before it is tried again */
+    pub fn ejection_time(mut self, ejection_time: Duration) -> Self {
+        self.ejection_time = ejection_time;
+        self
+    }
+
+    /**
+     * Every `interval`, sends `GET path` to each upstream. Upstreams that don't
+     * answer with a 2xx or 3xx are taken out of rotation until they do; a
+     * passing check also lifts any passive ejection.
+     */
+    pub fn health_check(self, path: &str, interval: Duration) -> Self {
+        let upstreams = self.upstreams.iter().map(Arc::downgrade).collect();
+        let path = path.to_string();
+        thread::spawn(move || run_health_checks(upstreams, &path, interval));
+        self
+    }
+
+    fn pick(&self, request: &Request) -> Option<&Arc<Upstream>> {
+        if self.upstreams.is_empty() {
+            return None;
+        }
+
+        let start = self.next.fetch_add(1, Ordering::SeqCst) % self.upstreams.len();
+        let rotation = (0..self.upstreams.len())
+            .map(|offset| &self.upstreams[(start + offset) % self.upstreams.len()])
+            .filter(|upstream| upstream.is_available());
+
+        match &self.strategy {
+            Strategy::RoundRobin => rotation.into_iter().next(),
+            Strategy::LeastConnections => {
+                rotation.min_by_key(|upstream| upstream.active.load(Ordering::SeqCst))
+            }
+            Strategy::ConsistentHash(hash_key) => match self.hash_key(hash_key, request) {
+                Some(key) => self.pick_from_ring(hash(&key)),
+                None => rotation.into_iter().next(),
+            },
+        }
+    }
+
+    fn hash_key(&self, hash_key: &HashKey, request: &Request) -> Option<String> {
+        match hash_key {
+            HashKey::Header(name) => request.header(name).map(|value| value.to_string()),
+            HashKey::ClientIp => request.peer_addr.map(|addr| addr.ip().to_string()),
+        }
+    }
+
+    fn pick_from_ring(&self, key_hash: u64) -> Option<&Arc<Upstream>> {
+        let start = self
+            .ring
+            .partition_point(|(node_hash, _)| *node_hash < key_hash);
+        (0..self.ring.len())
+            .map(|offset| &self.upstreams[self.ring[(start + offset) % self.ring.len()].1])
+            .find(|upstream| upstream.is_available())
+    }
+}
+
+impl Handler for LoadBalancer {
+    fn handle(&self, request: &Request) -> Response {
+        let upstream = match self.pick(request) {
+            Some(upstream) => upstream,
+            None => {
+                return Response::error(StatusCode::SERVICE_UNAVAILABLE, "No upstream available")
+            }
+        };
+
+        let active_request = ActiveRequest::start(upstream);
+
+        match upstream.proxy.forward(request) {
+            Ok(mut response) => {
+                upstream.record_success();
+                if let Some(stream_body) = response.stream_body.take() {
+                    response.stream_body = Some(Box::new(move |client| {
+                        let _active_request = active_request;
+                        stream_body(client)
+                    }));
+                }
+                response
+            }
+            Err(err) => {
+                eprintln!("Upstream {} failed: {}", upstream.proxy.upstream(), err);
+                upstream.record_failure(self.max_failures, self.ejection_time);
+                Response::error(upstream_error_status(&err), "Upstream request failed")
+            }
+        }
+    }
+}
+
+/** Runs until the LoadBalancer owning the upstreams is dropped */
+fn run_health_checks(upstreams: Vec<Weak<Upstream>>, path: &str, interval: Duration) {
+    let request = Request::new(Method::GET, path);
+
+    loop {
+        thread::sleep(interval);
+
+        for upstream in &upstreams {
+            let upstream = match upstream.upgrade() {
+                Some(upstream) => upstream,
+                None => return,
+            };
+
+            let passed = match upstream.proxy.forward(&request) {
+                Ok(response) => (200..400).contains(&response.status_code.0),
+                Err(_) => false,
+            };
+
+            upstream.healthy.store(passed, Ordering::SeqCst);
+            if passed {
+                upstream.record_success();
+            }
+        }
+    }
+}
+
+/**
+ * 64-bit FNV-1a, which unlike the std hasher is stable across builds, with a
+ * final mix so short keys that differ in one byte still land far apart
+ */
+pub(super) fn hash(key: &str) -> u64 {
+    let mut hash = key.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::{SocketAddr, TcpListener},
+    };
+
+    use super::*;
+
+    /** An upstream that answers every request with its name, or 503 on /health when sick */
+    fn spawn_upstream(name: &'static str, healthy: Arc<AtomicBool>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                }
+
+                let status = if request_line.starts_with("GET /health ")
+                    && !healthy.load(Ordering::SeqCst)
+                {
+                    "503 Service Unavailable"
+                } else {
+                    "200 OK"
+                };
+                let _ = stream.write_all(
+                    format!(
+                        "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n{}",
+                        status,
+                        name.len(),
+                        name
+                    )
+                    .as_bytes(),
+                );
+            }
+        });
+
+        addr
+    }
+
+    fn closed_port() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    fn request(raw_request: &str) -> Request {
+        let mut request = Request::from_stream(&mut raw_request.as_bytes()).unwrap();
+        request.peer_addr = Some(SocketAddr::from(([10, 0, 0, 1], 40000)));
+        request
+    }
+
+    fn served_by(balancer: &LoadBalancer, request: &Request) -> String {
+        let mut response = balancer.handle(request);
+        let mut output = vec![];
+        response.write_to(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        output.rsplit("\r\n\r\n").next().unwrap().to_string()
+    }
+
+    fn proxies(addrs: &[SocketAddr]) -> Vec<ReverseProxy> {
+        addrs
+            .iter()
+            .map(|addr| ReverseProxy::new(&addr.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn round_robin_cycles_through_upstreams() {
+        let healthy = Arc::new(AtomicBool::new(true));
+        let addrs = [
+            spawn_upstream("a", healthy.clone()),
+            spawn_upstream("b", healthy.clone()),
+            spawn_upstream("c", healthy),
+        ];
+        let balancer = LoadBalancer::new(Strategy::RoundRobin, proxies(&addrs));
+        let request = request("GET / HTTP/1.1\r\n\r\n");
+
+        let served = (0..4)
+            .map(|_| served_by(&balancer, &request))
+            .collect::<Vec<_>>();
+
+        assert_eq!(vec!["a", "b", "c", "a"], served);
+    }
+
+    #[test]
+    fn least_connections_avoids_busy_upstream() {
+        let healthy = Arc::new(AtomicBool::new(true));
+        let addrs = [
+            spawn_upstream("a", healthy.clone()),
+            spawn_upstream("b", healthy),
+        ];
+        let balancer = LoadBalancer::new(Strategy::LeastConnections, proxies(&addrs));
+        let request = request("GET / HTTP/1.1\r\n\r\n");
+
+        // Holding on to a response keeps its upstream busy until the body is written
+        let in_flight = balancer.handle(&request);
+        assert_eq!(1, balancer.upstreams[0].active.load(Ordering::SeqCst));
+
+        assert_eq!("b", served_by(&balancer, &request));
+        assert_eq!("b", served_by(&balancer, &request));
+
+        drop(in_flight);
+        assert_eq!(0, balancer.upstreams[0].active.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn consistent_hash_is_sticky_per_key() {
+        let healthy = Arc::new(AtomicBool::new(true));
+        let addrs = [
+            spawn_upstream("a", healthy.clone()),
+            spawn_upstream("b", healthy.clone()),
+            spawn_upstream("c", healthy),
+        ];
+        let balancer = LoadBalancer::new(
+            Strategy::ConsistentHash(HashKey::Header("X-User".to_string())),
+            proxies(&addrs),
+        );
+
+        let mut seen = vec![];
+        for user in 0..20 {
+            let request = request(&format!("GET / HTTP/1.1\r\nX-User: {}\r\n\r\n", user));
+            let first = served_by(&balancer, &request);
+            assert_eq!(first, served_by(&balancer, &request));
+            assert_eq!(first, served_by(&balancer, &request));
+            seen.push(first);
+        }
+
+        seen.sort();
+        seen.dedup();
+        assert!(seen.len() > 1);
+    }
+
+    #[test]
+    fn failing_upstream_is_ejected_then_reinstated() {
+        let live = spawn_upstream("live", Arc::new(AtomicBool::new(true)));
+        let balancer = LoadBalancer::new(Strategy::RoundRobin, proxies(&[closed_port(), live]))
+            .max_failures(1)
+            .ejection_time(Duration::from_millis(100));
+        let request = request("GET / HTTP/1.1\r\n\r\n");
+
+        let response = balancer.handle(&request);
+        assert_eq!(StatusCode::BAD_GATEWAY, response.status_code);
+        assert!(!balancer.upstreams[0].is_available());
+
+        for _ in 0..3 {
+            assert_eq!("live", served_by(&balancer, &request));
+        }
+
+        thread::sleep(Duration::from_millis(150));
+        assert!(balancer.upstreams[0].is_available());
+    }
+
+    #[test]
+    fn health_checks_take_upstream_out_of_rotation() {
+        let sick = Arc::new(AtomicBool::new(false));
+        let addrs = [
+            spawn_upstream("sick", sick.clone()),
+            spawn_upstream("well", Arc::new(AtomicBool::new(true))),
+        ];
+        let balancer = LoadBalancer::new(Strategy::RoundRobin, proxies(&addrs))
+            .health_check("/health", Duration::from_millis(20));
+        let request = request("GET / HTTP/1.1\r\n\r\n");
+
+        thread::sleep(Duration::from_millis(100));
+        for _ in 0..3 {
+            assert_eq!("well", served_by(&balancer, &request));
+        }
+
+        sick.store(true, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(100));
+        assert!(balancer.upstreams[0].is_available());
+    }
+}
@@ -0,0 +1,279 @@
+use std::{
+    io::{Error, ErrorKind},
+    net::SocketAddr,
+    sync::Arc,
+    thread,
+};
+
+use socket2::{Domain, Socket, Type};
+
+use super::{
+    handler::Handler, listener::Listener, request::Request, shutdown::ServerHandle,
+    socket_options::SocketOptions,
+};
+
+/**
+ * A TCP address to listen on: a specific one like `127.0.0.1:8080`, or a
+ * wildcard like `0.0.0.0:8080` for every IPv4 interface or `[::]:8080` for
+ * every IPv6 one.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BindAddress {
+    addr: SocketAddr,
+    only_v6: Option<bool>,
+    options: SocketOptions,
+    #[cfg(unix)]
+    reuse_port: bool,
+}
+
+impl BindAddress {
+    pub fn new(addr: SocketAddr) -> Self {
+        BindAddress {
+            addr,
+            only_v6: None,
+            options: SocketOptions::default(),
+            #[cfg(unix)]
+            reuse_port: false,
+        }
+    }
+
+    /**
+     * For an IPv6 address, whether it only takes IPv6 connections
+     * (IPV6_V6ONLY), or IPv4 ones too, which show up with IPv4-mapped peer
+     * addresses. Unset, the system decides; on Linux that's
+     * net.ipv6.bindv6only, usually 0. Listening on `[::]` and `0.0.0.0` with
+     * the same port needs it set to true. Ignored for IPv4 addresses.
+     */
+    pub fn only_v6(mut self, only_v6: bool) -> Self {
+        self.only_v6 = Some(only_v6);
+        self
+    }
+
+    /** Tunes the listening socket and the connections accepted from it */
+    pub fn options(mut self, options: SocketOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /** Sets SO_REUSEPORT, so other processes can bind the same address */
+    #[cfg(unix)]
+    pub(super) fn reuse_port(mut self) -> Self {
+        self.reuse_port = true;
+        self
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    #[cfg(feature = "tls")]
+    pub(super) fn socket_options(&self) -> SocketOptions {
+        self.options
+    }
+
+    pub fn bind(&self) -> Result<Listener, Error> {
+        let socket = self.bind_socket()?;
+        self.options.listen(&socket)?;
+        Ok(Listener::Tcp(socket.into()))
+    }
+
+    /** A socket bound to the address, but not yet listening */
+    pub(super) fn bind_socket(&self) -> Result<Socket, Error> {
+        let socket = Socket::new(Domain::for_address(self.addr), Type::STREAM, None)?;
+        // As TcpListener::bind does, so a restart doesn't wait out TIME_WAIT
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        if self.reuse_port {
+            socket.set_reuse_port(true)?;
+        }
+        if let (SocketAddr::V6(_), Some(only_v6)) = (self.addr, self.only_v6) {
+            socket.set_only_v6(only_v6)?;
+        }
+        self.options.before_bind(&socket)?;
+        socket.bind(&self.addr.into())?;
+        Ok(socket)
+    }
+}
+
+impl From<SocketAddr> for BindAddress {
+    fn from(addr: SocketAddr) -> Self {
+        BindAddress::new(addr)
+    }
+}
+
+/**
+ * Serves several addresses from one process, each with the server's
+ * handler or one of its own, e.g. a public port and an admin one that's
+ * only reachable from localhost.
+ */
+#[derive(Default)]
+pub struct Server {
+    addresses: Vec<(BindAddress, Option<Arc<dyn Handler>>)>,
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /** Listens on `address` with the handler the server is served with */
+    pub fn bind(mut self, address: impl Into<BindAddress>) -> Self {
+        self.addresses.push((address.into(), None));
+        self
+    }
+
+    /** Listens on `address` with `handler` rather than the server's */
+    pub fn bind_with<H: Handler>(mut self, address: impl Into<BindAddress>, handler: H) -> Self {
+        self.addresses
+            .push((address.into(), Some(Arc::new(handler))));
+        self
+    }
+
+    /**
+     * Binds every address, failing without serving any if one can't be,
+     * then serves them all until `handle` is shut down.
+     */
+    pub fn serve_until_shutdown<H: Handler>(
+        self,
+        handler: H,
+        handle: &ServerHandle,
+    ) -> Result<(), Error> {
+        if self.addresses.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "No addresses to listen on",
+            ));
+        }
+        let listeners = self
+            .addresses
+            .iter()
+            .map(|(address, _)| {
+                address.bind().map_err(|err| {
+                    Error::new(
+                        err.kind(),
+                        format!("Unable to listen on {}: {}", address.addr, err),
+                    )
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let handler: Arc<dyn Handler> = Arc::new(handler);
+        thread::scope(|scope| {
+            for (listener, (address, own_handler)) in listeners.into_iter().zip(self.addresses) {
+                let handler = own_handler.unwrap_or_else(|| Arc::clone(&handler));
+                scope.spawn(move || {
+                    listener.serve_with_options(
+                        move |request: &Request| handler.handle(request),
+                        handle,
+                        &address.options,
+                    )
+                });
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{IpAddr, Ipv6Addr, TcpListener, TcpStream},
+        time::Duration,
+    };
+
+    use super::{super::response::Response, *};
+
+    /** A port that was free a moment ago */
+    fn free_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    fn get(addr: SocketAddr) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    fn body(body: &'static str) -> impl Handler {
+        move |_: &Request| {
+            let mut response = Response::new();
+            response.body = body.as_bytes().to_vec();
+            response
+        }
+    }
+
+    #[test]
+    fn addresses_are_served_with_their_handlers() {
+        let public = SocketAddr::from(([127, 0, 0, 1], free_port()));
+        let admin = SocketAddr::from((Ipv6Addr::LOCALHOST, free_port()));
+        let handle = ServerHandle::new();
+        let serving = handle.clone();
+        let server = thread::spawn(move || {
+            Server::new()
+                .bind(public)
+                .bind_with(admin, body("admin"))
+                .serve_until_shutdown(body("public"), &serving)
+        });
+
+        // Both are bound before either is served
+        while TcpStream::connect(admin).is_err() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let response = get(public);
+        assert!(response.ends_with("public"), "{}", response);
+        assert!(get(admin).ends_with("admin"));
+
+        handle.shutdown(Duration::from_secs(1));
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn nothing_is_served_if_an_address_is_taken() {
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let free = SocketAddr::from(([127, 0, 0, 1], free_port()));
+
+        let err = Server::new()
+            .bind(free)
+            .bind(taken.local_addr().unwrap())
+            .serve_until_shutdown(body(""), &ServerHandle::new())
+            .unwrap_err();
+        assert_eq!(ErrorKind::AddrInUse, err.kind());
+        assert!(TcpStream::connect(free).is_err());
+    }
+
+    #[test]
+    fn ipv6_wildcards_can_take_ipv4_too() {
+        let port = free_port();
+        let wildcard = SocketAddr::from((Ipv6Addr::UNSPECIFIED, port));
+
+        let dual_stack = BindAddress::new(wildcard).only_v6(false).bind().unwrap();
+        let client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let peer = dual_stack.accept().unwrap().peer_addr().unwrap();
+        assert_eq!(
+            Some(client.local_addr().unwrap().ip()),
+            match peer.ip() {
+                IpAddr::V6(ip) => ip.to_ipv4_mapped().map(Into::into),
+                ip => Some(ip),
+            }
+        );
+        assert!(BindAddress::new(SocketAddr::from(([0, 0, 0, 0], port)))
+            .bind()
+            .is_err());
+        drop(dual_stack);
+
+        let _v6 = BindAddress::new(wildcard).only_v6(true).bind().unwrap();
+        let _v4 = BindAddress::new(SocketAddr::from(([0, 0, 0, 0], port)))
+            .bind()
+            .unwrap();
+    }
+}
@@ -0,0 +1,1245 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{BufRead, BufReader, Error},
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::{
+    balancer::hash, date::parse_http_date, handler::Handler, method::Method, request::Request,
+    response::Response, status_code::StatusCode,
+};
+
+/** Statuses that may be cached without explicit freshness information */
+const HEURISTICALLY_CACHEABLE: [u16; 11] = [200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+
+/** Upper bound on freshness guessed from Last-Modified */
+const MAX_HEURISTIC_FRESHNESS: Duration = Duration::from_secs(24 * 60 * 60);
+
+/** Larger Age values, even ones too big to parse, count as this (RFC 9111 section 1.2.2) */
+const MAX_AGE_VALUE: u64 = 1 << 31;
+
+/** Directives from a request's or a response's Cache-Control header */
+#[derive(Debug, Default, PartialEq)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    public: bool,
+    must_revalidate: bool,
+    only_if_cached: bool,
+    max_age: Option<u64>,
+    s_maxage: Option<u64>,
+    /** `max-stale` without a value accepts any staleness, stored as u64::MAX */
+    max_stale: Option<u64>,
+    min_fresh: Option<u64>,
+    stale_while_revalidate: Option<u64>,
+    stale_if_error: Option<u64>,
+}
+
+impl CacheControl {
+    fn parse(value: &str) -> Self {
+        let mut cache_control = CacheControl::default();
+
+        for directive in value.split(',') {
+            let (name, argument) = match directive.split_once('=') {
+                Some((name, argument)) => (name, Some(argument.trim().trim_matches('"'))),
+                None => (directive, None),
+            };
+            let seconds = argument.and_then(|argument| u64::from_str(argument).ok());
+
+            match name.trim().to_lowercase().as_str() {
+                "no-store" => cache_control.no_store = true,
+                "no-cache" => cache_control.no_cache = true,
+                "private" => cache_control.private = true,
+                "public" => cache_control.public = true,
+                // A shared cache treats proxy-revalidate the same as must-revalidate
+                "must-revalidate" | "proxy-revalidate" => cache_control.must_revalidate = true,
+                "only-if-cached" => cache_control.only_if_cached = true,
+                "max-age" => cache_control.max_age = seconds,
+                "s-maxage" => cache_control.s_maxage = seconds,
+                "max-stale" => cache_control.max_stale = Some(seconds.unwrap_or(u64::MAX)),
+                "min-fresh" => cache_control.min_fresh = seconds,
+                "stale-while-revalidate" => cache_control.stale_while_revalidate = seconds,
+                "stale-if-error" => cache_control.stale_if_error = seconds,
+                _ => {}
+            }
+        }
+
+        cache_control
+    }
+
+    fn of_request(request: &Request) -> Self {
+        let mut cache_control = Self::parse(&header_value(&request.headers, "cache-control"));
+        if !request.headers.contains_key("cache-control")
+            && header_value(&request.headers, "pragma").contains("no-cache")
+        {
+            cache_control.no_cache = true;
+        }
+        cache_control
+    }
+}
+
+/** All values of a header, joined as one comma-separated list */
+fn header_value(headers: &HashMap<String, Vec<String>>, name: &str) -> String {
+    headers
+        .iter()
+        .filter(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+        .flat_map(|(_, values)| values.iter().map(|value| value.as_str()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn seconds_since(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn elapsed(from: SystemTime, to: SystemTime) -> Duration {
+    to.duration_since(from).unwrap_or_default()
+}
+
+/** A stored response, along with what's needed to work out its age and variant */
+#[derive(Clone)]
+struct Entry {
+    status_code: StatusCode,
+    /** Header names are lowercased when stored */
+    headers: HashMap<String, Vec<String>>,
+    body: Vec<u8>,
+    request_time: SystemTime,
+    response_time: SystemTime,
+    /** The request headers named by Vary, as sent with the request that was stored */
+    vary: Vec<(String, Option<String>)>,
+    last_used: u64,
+}
+
+impl Entry {
+    fn new(
+        request: &Request,
+        response: &Response,
+        request_time: SystemTime,
+        response_time: SystemTime,
+    ) -> Self {
+        let mut headers: HashMap<String, Vec<String>> = HashMap::new();
+        for (header_name, values) in &response.headers {
+            let header_name = header_name.to_lowercase();
+            if header_name != "cache-status" {
+                headers
+                    .entry(header_name)
+                    .or_default()
+                    .extend(values.iter().cloned());
+            }
+        }
+        headers.insert(
+            "content-length".to_string(),
+            vec![response.body.len().to_string()],
+        );
+
+        let vary = vary(request, &headers);
+
+        Entry {
+            status_code: response.status_code,
+            headers,
+            body: response.body.clone(),
+            request_time,
+            response_time,
+            vary,
+            last_used: 0,
+        }
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(name)
+            .and_then(|values| values.first())
+            .map(|value| value.as_str())
+    }
+
+    fn cache_control(&self) -> CacheControl {
+        CacheControl::parse(&header_value(&self.headers, "cache-control"))
+    }
+
+    fn date(&self) -> SystemTime {
+        self.header("date")
+            .and_then(parse_http_date)
+            .unwrap_or(self.response_time)
+    }
+
+    fn matches_vary(&self, request: &Request) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| request.header(name) == value.as_deref())
+    }
+
+    fn size(&self) -> usize {
+        let headers_size = self
+            .headers
+            .iter()
+            .map(|(name, values)| name.len() + values.iter().map(String::len).sum::<usize>())
+            .sum::<usize>();
+        self.body.len() + headers_size
+    }
+
+    /** RFC 9111 section 4.2.3 */
+    fn current_age(&self, now: SystemTime) -> Duration {
+        let apparent_age = elapsed(self.date(), self.response_time);
+        let age_value = Duration::from_secs(match self.header("age") {
+            Some(age) if !age.is_empty() && age.bytes().all(|byte| byte.is_ascii_digit()) => {
+                u64::from_str(age).map_or(MAX_AGE_VALUE, |age| age.min(MAX_AGE_VALUE))
+            }
+            _ => 0,
+        });
+        let response_delay = elapsed(self.request_time, self.response_time);
+        let corrected_initial_age = apparent_age.max(age_value + response_delay);
+        corrected_initial_age + elapsed(self.response_time, now)
+    }
+
+    /** RFC 9111 section 4.2.1, as a shared cache */
+    fn freshness_lifetime(&self) -> Duration {
+        let cache_control = self.cache_control();
+
+        if let Some(seconds) = cache_control.s_maxage.or(cache_control.max_age) {
+            return Duration::from_secs(seconds);
+        }
+
+        if let Some(expires) = self.header("expires") {
+            // An Expires that doesn't parse means the response is already stale
+            return parse_http_date(expires)
+                .map(|expires| elapsed(self.date(), expires))
+                .unwrap_or_default();
+        }
+
+        let last_modified = self.header("last-modified").and_then(parse_http_date);
+        match last_modified {
+            Some(last_modified)
+                if cache_control.public
+                    || HEURISTICALLY_CACHEABLE.contains(&self.status_code.0) =>
+            {
+                (elapsed(last_modified, self.date()) / 10).min(MAX_HEURISTIC_FRESHNESS)
+            }
+            _ => Duration::ZERO,
+        }
+    }
+
+    fn to_response(&self, request: &Request, now: SystemTime) -> Response {
+        let mut response = Response::new();
+        response.status_code = self.status_code;
+        response.headers = self.headers.clone();
+        response.headers.insert(
+            "age".to_string(),
+            vec![self.current_age(now).as_secs().to_string()],
+        );
+
+        let etag = self.header("etag");
+        let not_modified = etag.is_some()
+            && header_value(&request.headers, "if-none-match")
+                .split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| {
+                    tag == "*" || Some(tag) == etag.map(|etag| etag.trim_start_matches("W/"))
+                });
+
+        if not_modified {
+            response.status_code = StatusCode::NOT_MODIFIED;
+            response.headers.remove("content-length");
+        } else if request.method != Method::HEAD {
+            response.body = self.body.clone();
+        }
+
+        response
+    }
+
+    /** Takes the headers of a 304 as the new stored headers (RFC 9111 section 4.3.4) */
+    fn freshen(&mut self, not_modified: &Response, request_time: SystemTime, now: SystemTime) {
+        for (header_name, values) in &not_modified.headers {
+            let header_name = header_name.to_lowercase();
+            if header_name != "content-length" && header_name != "cache-status" {
+                self.headers.insert(header_name, values.clone());
+            }
+        }
+        self.request_time = request_time;
+        self.response_time = now;
+    }
+}
+
+enum Lookup {
+    Hit(Entry),
+    UriMiss,
+    VaryMiss,
+}
+
+/**
+ * Stored responses keyed by URI, with one entry per Vary variant. Memory
+ * use is bounded by evicting the least recently used entries; with a
+ * directory set, entries are also written to disk and read back from there
+ * when they aren't in memory.
+ */
+struct Store {
+    entries: HashMap<String, Vec<Entry>>,
+    size: usize,
+    max_size: usize,
+    uses: u64,
+    directory: Option<PathBuf>,
+}
+
+impl Store {
+    fn lookup(&mut self, key: &str, request: &Request) -> Lookup {
+        if !self.entries.contains_key(key) {
+            if let Some(variants) = self.read_from_disk(key) {
+                self.size += variants.iter().map(Entry::size).sum::<usize>();
+                self.entries.insert(key.to_string(), variants);
+                self.evict();
+            }
+        }
+
+        self.uses += 1;
+        let uses = self.uses;
+
+        match self.entries.get_mut(key) {
+            None => Lookup::UriMiss,
+            Some(variants) => match variants
+                .iter_mut()
+                .find(|entry| entry.matches_vary(request))
+            {
+                Some(entry) => {
+                    entry.last_used = uses;
+                    Lookup::Hit(entry.clone())
+                }
+                None => Lookup::VaryMiss,
+            },
+        }
+    }
+
+    fn insert(&mut self, key: &str, mut entry: Entry) {
+        if entry.size() > self.max_size {
+            return;
+        }
+
+        self.uses += 1;
+        entry.last_used = self.uses;
+        self.size += entry.size();
+
+        let variants = self.entries.entry(key.to_string()).or_default();
+        if let Some(index) = variants.iter().position(|stored| stored.vary == entry.vary) {
+            self.size -= variants.remove(index).size();
+        }
+        variants.push(entry);
+
+        self.write_to_disk(key);
+        self.evict();
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(variants) = self.entries.remove(key) {
+            self.size -= variants.iter().map(Entry::size).sum::<usize>();
+        }
+        if let Some(path) = self.disk_path(key) {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.max_size {
+            let least_recently_used = self
+                .entries
+                .iter()
+                .flat_map(|(key, variants)| {
+                    variants
+                        .iter()
+                        .enumerate()
+                        .map(move |(index, entry)| (entry.last_used, key.clone(), index))
+                })
+                .min();
+
+            let (_, key, index) = match least_recently_used {
+                Some(least_recently_used) => least_recently_used,
+                None => return,
+            };
+
+            let variants = self.entries.get_mut(&key).unwrap();
+            self.size -= variants.remove(index).size();
+            if variants.is_empty() {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    /** Named by a hash that stays the same across builds, so entries outlive upgrades */
+    fn disk_path(&self, key: &str) -> Option<PathBuf> {
+        self.directory
+            .as_ref()
+            .map(|directory| directory.join(format!("{:016x}.cache", hash(key))))
+    }
+
+    /**
+     * Each file starts with the URI it holds, followed by every variant as
+     * metadata lines, a blank line, and the response as it would be sent.
+     */
+    fn write_to_disk(&self, key: &str) {
+        let (path, variants) = match (self.disk_path(key), self.entries.get(key)) {
+            (Some(path), Some(variants)) => (path, variants),
+            _ => return,
+        };
+
+        let mut contents = format!("uri: {}\n", key).into_bytes();
+        for entry in variants {
+            let mut metadata = format!(
+                "request-time: {}\nresponse-time: {}\n",
+                seconds_since(entry.request_time),
+                seconds_since(entry.response_time)
+            );
+            for (name, value) in &entry.vary {
+                match value {
+                    Some(value) => metadata.push_str(&format!("vary: {}={}\n", name, value)),
+                    None => metadata.push_str(&format!("vary: {}\n", name)),
+                }
+            }
+            metadata.push('\n');
+            contents.extend_from_slice(metadata.as_bytes());
+
+            let mut response = Response::new();
+            response.status_code = entry.status_code;
+            response.headers = entry.headers.clone();
+            response.body = entry.body.clone();
+            let _ = response.write_to(&mut contents);
+        }
+
+        if let Err(err) = fs::write(&path, contents) {
+            eprintln!("Failed to write cache file {}: {}", path.display(), err);
+        }
+    }
+
+    fn read_from_disk(&self, key: &str) -> Option<Vec<Entry>> {
+        let file = fs::File::open(self.disk_path(key)?).ok()?;
+        let mut reader = BufReader::new(file);
+
+        let mut uri_line = String::new();
+        reader.read_line(&mut uri_line).ok()?;
+        // Different URIs can hash to the same file
+        if uri_line.trim_end().strip_prefix("uri: ") != Some(key) {
+            return None;
+        }
+
+        let mut variants = vec![];
+        loop {
+            let mut entry = Entry {
+                status_code: StatusCode::OK,
+                headers: HashMap::new(),
+                body: vec![],
+                request_time: UNIX_EPOCH,
+                response_time: UNIX_EPOCH,
+                vary: vec![],
+                last_used: 0,
+            };
+
+            let mut metadata_lines = 0;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).ok()? == 0 {
+                    return Some(variants);
+                }
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                metadata_lines += 1;
+
+                let (name, value) = line.split_once(": ")?;
+                match name {
+                    "request-time" => {
+                        entry.request_time = UNIX_EPOCH + Duration::from_secs(value.parse().ok()?)
+                    }
+                    "response-time" => {
+                        entry.response_time = UNIX_EPOCH + Duration::from_secs(value.parse().ok()?)
+                    }
+                    "vary" => entry.vary.push(match value.split_once('=') {
+                        Some((name, value)) => (name.to_string(), Some(value.to_string())),
+                        None => (value.to_string(), None),
+                    }),
+                    _ => {}
+                }
+            }
+            if metadata_lines == 0 {
+                return Some(variants);
+            }
+
+            let response = Response::from_reader(&mut reader).ok()?;
+            entry.status_code = response.status_code;
+            entry.headers = response.headers;
+            entry.body = response.body;
+            variants.push(entry);
+        }
+    }
+}
+
+/**
+ * An HTTP cache (RFC 9111) in front of another handler, acting as a shared
+ * cache. Each response carries a Cache-Status header (RFC 9211) saying
+ * whether it was served from the cache and why the request went forward.
+ */
+pub struct Cache<H: Handler> {
+    handler: Arc<H>,
+    store: Arc<Mutex<Store>>,
+    name: String,
+}
+
+impl<H: Handler> Cache<H> {
+    pub const DEFAULT_MAX_SIZE: usize = 64 * 1024 * 1024;
+    pub const DEFAULT_NAME: &'static str = "http_server";
+
+    pub fn new(handler: H) -> Self {
+        Cache {
+            handler: Arc::new(handler),
+            store: Arc::new(Mutex::new(Store {
+                entries: HashMap::new(),
+                size: 0,
+                max_size: Self::DEFAULT_MAX_SIZE,
+                uses: 0,
+                directory: None,
+            })),
+            name: Self::DEFAULT_NAME.to_string(),
+        }
+    }
+
+    /** Bytes of headers and bodies to keep in memory */
+    pub fn max_size(self, max_size: usize) -> Self {
+        self.store.lock().unwrap().max_size = max_size;
+        self
+    }
+
+    /** Also keeps entries as files in `directory`, so they outlive the process */
+    pub fn on_disk(self, directory: &str) -> Result<Self, Error> {
+        fs::create_dir_all(directory)?;
+        self.store.lock().unwrap().directory = Some(PathBuf::from(directory));
+        Ok(self)
+    }
+
+    /** How this cache identifies itself in Cache-Status */
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    fn with_cache_status(&self, mut response: Response, parameters: &[String]) -> Response {
+        let mut cache_status = vec![self.name.clone()];
+        cache_status.extend(parameters.iter().cloned());
+        response
+            .headers
+            .insert("cache-status".to_string(), cache_status);
+        response
+    }
+
+    /** Sends the request on and stores the response if it may be */
+    fn forward(&self, request: &Request, key: &str, fwd: &str) -> Response {
+        let request_time = SystemTime::now();
+        let mut response = self.handler.handle(request);
+        if let Err(err) = response.buffer_body() {
+            eprintln!("Failed to read response to cache: {}", err);
+            return self.with_cache_status(
+                Response::error(StatusCode::BAD_GATEWAY, "Upstream response failed"),
+                &[format!("fwd={}", fwd)],
+            );
+        }
+
+        let mut parameters = vec![
+            format!("fwd={}", fwd),
+            format!("fwd-status={}", response.status_code.0),
+        ];
+        if request.method == Method::GET && is_storable(request, &response) {
+            let entry = Entry::new(request, &response, request_time, SystemTime::now());
+            self.store.lock().unwrap().insert(key, entry);
+            parameters.push("stored".to_string());
+        }
+
+        self.with_cache_status(response, &parameters)
+    }
+
+    fn hit(&self, entry: &Entry, request: &Request, now: SystemTime) -> Response {
+        let ttl =
+            entry.freshness_lifetime().as_secs() as i64 - entry.current_age(now).as_secs() as i64;
+        self.with_cache_status(
+            entry.to_response(request, now),
+            &["hit".to_string(), format!("ttl={}", ttl)],
+        )
+    }
+}
+
+impl<H: Handler> Handler for Cache<H> {
+    fn handle(&self, request: &Request) -> Response {
+        let key = cache_key(request);
+
+        if request.method != Method::GET && request.method != Method::HEAD {
+            let response = self.handler.handle(request);
+            // A successful unsafe request may have changed what's stored
+            let is_unsafe = !matches!(request.method, Method::OPTIONS | Method::TRACE);
+            if is_unsafe && response.status_code.0 < 400 {
+                self.store.lock().unwrap().remove(&key);
+            }
+            return self.with_cache_status(response, &["fwd=method".to_string()]);
+        }
+
+        let request_cache_control = CacheControl::of_request(request);
+        let now = SystemTime::now();
+
+        let lookup = self.store.lock().unwrap().lookup(&key, request);
+        let entry = match lookup {
+            Lookup::Hit(entry) => entry,
+            Lookup::UriMiss | Lookup::VaryMiss if request_cache_control.only_if_cached => {
+                return self.with_cache_status(
+                    Response::error(StatusCode::GATEWAY_TIMEOUT, "Not in cache"),
+                    &["fwd=miss".to_string()],
+                )
+            }
+            Lookup::UriMiss => return self.forward(request, &key, "uri-miss"),
+            Lookup::VaryMiss => return self.forward(request, &key, "vary-miss"),
+        };
+
+        let response_cache_control = entry.cache_control();
+        let age = entry.current_age(now).as_secs();
+        let lifetime = entry.freshness_lifetime().as_secs();
+        let must_validate = request_cache_control.no_cache || response_cache_control.no_cache;
+
+        if !must_validate
+            && is_fresh_enough(
+                age,
+                lifetime,
+                &request_cache_control,
+                &response_cache_control,
+            )
+        {
+            return self.hit(&entry, request, now);
+        }
+
+        if request_cache_control.only_if_cached {
+            return self.with_cache_status(
+                Response::error(StatusCode::GATEWAY_TIMEOUT, "Cached response is stale"),
+                &["fwd=miss".to_string()],
+            );
+        }
+
+        let staleness = age.saturating_sub(lifetime);
+        let serve_stale = |window: Option<u64>| {
+            !must_validate
+                && !response_cache_control.must_revalidate
+                && window.is_some_and(|window| staleness <= window)
+        };
+
+        if serve_stale(response_cache_control.stale_while_revalidate) {
+            let handler = Arc::clone(&self.handler);
+            let store = Arc::clone(&self.store);
+            let background_request = request.clone();
+            let background_entry = entry.clone();
+            let background_key = key.clone();
+            thread::spawn(move || {
+                let _ = revalidate(
+                    handler.as_ref(),
+                    &store,
+                    &background_key,
+                    &background_request,
+                    background_entry,
+                );
+            });
+            return self.hit(&entry, request, now);
+        }
+
+        let fwd = if request_cache_control.no_cache {
+            "request"
+        } else {
+            "stale"
+        };
+        let stale_if_error = response_cache_control
+            .stale_if_error
+            .or(request_cache_control.stale_if_error);
+
+        match revalidate(
+            self.handler.as_ref(),
+            &self.store,
+            &key,
+            request,
+            entry.clone(),
+        ) {
+            Revalidated::NotModified(entry) => self.with_cache_status(
+                entry.to_response(request, SystemTime::now()),
+                &[format!("fwd={}", fwd), "fwd-status=304".to_string()],
+            ),
+            Revalidated::Failed(status) if serve_stale(stale_if_error) => self.with_cache_status(
+                entry.to_response(request, now),
+                &[
+                    "hit".to_string(),
+                    format!("fwd={}", fwd),
+                    format!("fwd-status={}", status.0),
+                ],
+            ),
+            Revalidated::Replaced(response, stored) => {
+                let mut parameters = vec![
+                    format!("fwd={}", fwd),
+                    format!("fwd-status={}", response.status_code.0),
+                ];
+                if stored {
+                    parameters.push("stored".to_string());
+                }
+                self.with_cache_status(response, &parameters)
+            }
+            Revalidated::Failed(_) => self.forward(request, &key, fwd),
+        }
+    }
+}
+
+enum Revalidated {
+    NotModified(Entry),
+    /** The upstream sent a new response instead, and whether it was stored */
+    Replaced(Response, bool),
+    Failed(StatusCode),
+}
+
+/** Sends a conditional request for `entry`, updating the store with the result */
+fn revalidate<H: Handler>(
+    handler: &H,
+    store: &Mutex<Store>,
+    key: &str,
+    request: &Request,
+    mut entry: Entry,
+) -> Revalidated {
+    let mut conditional_request = request.clone();
+    conditional_request.method = Method::GET;
+    conditional_request.headers.remove("if-none-match");
+    conditional_request.headers.remove("if-modified-since");
+    if let Some(etag) = entry.header("etag") {
+        conditional_request
+            .headers
+            .insert("if-none-match".to_string(), vec![etag.to_string()]);
+    }
+    if let Some(last_modified) = entry.header("last-modified") {
+        conditional_request.headers.insert(
+            "if-modified-since".to_string(),
+            vec![last_modified.to_string()],
+        );
+    }
+
+    let request_time = SystemTime::now();
+    let mut response = handler.handle(&conditional_request);
+    if response.buffer_body().is_err() {
+        return Revalidated::Failed(StatusCode::BAD_GATEWAY);
+    }
+
+    if response.status_code == StatusCode::NOT_MODIFIED {
+        entry.freshen(&response, request_time, SystemTime::now());
+        store.lock().unwrap().insert(key, entry.clone());
+        return Revalidated::NotModified(entry);
+    }
+
+    if response.status_code.0 >= 500 {
+        return Revalidated::Failed(response.status_code);
+    }
+
+    let stored = is_storable(request, &response);
+    if stored {
+        let entry = Entry::new(request, &response, request_time, SystemTime::now());
+        store.lock().unwrap().insert(key, entry);
+    }
+    if request.method == Method::HEAD {
+        response.body.clear();
+    }
+    Revalidated::Replaced(response, stored)
+}
+
+/** The request headers a response's Vary names, with the values `request` sent */
+pub(super) fn vary(
+    request: &Request,
+    response_headers: &HashMap<String, Vec<String>>,
+) -> Vec<(String, Option<String>)> {
+    header_value(response_headers, "vary")
+        .split(',')
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            let value = request.header(&name).map(|value| value.to_string());
+            (name, value)
+        })
+        .collect()
+}
+
+/**
+ * Whether a response is only for the client it was made for: marked
+ * private or no-store, or setting cookies
+ */
+pub(super) fn is_private(response: &Response) -> bool {
+    let cache_control = CacheControl::parse(&header_value(&response.headers, "cache-control"));
+    cache_control.private
+        || cache_control.no_store
+        || response
+            .headers
+            .keys()
+            .any(|name| name.eq_ignore_ascii_case("set-cookie"))
+}
+
+/** Identifies the resource a request is for: its Host and target */
+pub fn cache_key(request: &Request) -> String {
+    format!(
+        "{}{}",
+        request.header("host").unwrap_or_default(),
+        request.raw_target
+    )
+}
+
+fn is_fresh_enough(
+    age: u64,
+    lifetime: u64,
+    request_cache_control: &CacheControl,
+    response_cache_control: &CacheControl,
+) -> bool {
+    if request_cache_control
+        .max_age
+        .is_some_and(|max_age| age > max_age)
+    {
+        return false;
+    }
+
+    let min_fresh = request_cache_control.min_fresh.unwrap_or(0);
+    if age.saturating_add(min_fresh) < lifetime {
+        return true;
+    }
+
+    // max-stale can't stretch a response that must be revalidated once stale
+    !response_cache_control.must_revalidate
+        && request_cache_control
+            .max_stale
+            .is_some_and(|max_stale| age - lifetime.min(age) <= max_stale)
+}
+
+/** RFC 9111 section 3, for a shared cache */
+fn is_storable(request: &Request, response: &Response) -> bool {
+    let request_cache_control = CacheControl::of_request(request);
+    let cache_control = CacheControl::parse(&header_value(&response.headers, "cache-control"));
+    let status_code = response.status_code.0;
+
+    // Private covers Set-Cookie too, so one client's cookies aren't handed to the next
+    if request_cache_control.no_store
+        || is_private(response)
+        || status_code < 200
+        || status_code == 206
+        || status_code == 304
+        || header_value(&response.headers, "vary").contains('*')
+    {
+        return false;
+    }
+
+    if request.headers.contains_key("authorization")
+        && !cache_control.public
+        && !cache_control.must_revalidate
+        && cache_control.s_maxage.is_none()
+    {
+        return false;
+    }
+
+    cache_control.public
+        || cache_control.max_age.is_some()
+        || cache_control.s_maxage.is_some()
+        || response.header("Expires").is_some()
+        || HEURISTICALLY_CACHEABLE.contains(&status_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::server::date::format_http_date;
+
+    type Origin = Box<dyn Fn(&Request) -> Response + Send + Sync>;
+
+    /** A cache in front of `origin`, with a count of requests that reached it */
+    fn cache<F>(origin: F) -> (Cache<Origin>, Arc<AtomicUsize>)
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let origin: Origin = Box::new(move |request| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            origin(request)
+        });
+        (Cache::new(origin), calls)
+    }
+
+    fn response(headers: &[(&str, &str)], body: &str) -> Response {
+        let mut response = Response::new();
+        for (name, value) in headers {
+            response
+                .headers
+                .insert(name.to_string(), vec![value.to_string()]);
+        }
+        response.body = body.as_bytes().to_vec();
+        response
+    }
+
+    fn get(headers: &[(&str, &str)]) -> Request {
+        let mut request = Request::new(Method::GET, "/data");
+        request
+            .headers
+            .insert("host".to_string(), vec!["localhost".to_string()]);
+        for (name, value) in headers {
+            request
+                .headers
+                .insert(name.to_lowercase(), vec![value.to_string()]);
+        }
+        request
+    }
+
+    fn cache_status(response: &Response) -> String {
+        response.headers.get("cache-status").unwrap().join(";")
+    }
+
+    /** Makes everything stored look `by` older */
+    fn age<H: Handler>(cache: &Cache<H>, by: Duration) {
+        let mut store = cache.store.lock().unwrap();
+        for entry in store.entries.values_mut().flatten() {
+            entry.request_time -= by;
+            entry.response_time -= by;
+        }
+    }
+
+    #[test]
+    fn cache_control_directives() {
+        assert_eq!(
+            CacheControl {
+                public: true,
+                max_age: Some(60),
+                s_maxage: Some(120),
+                stale_while_revalidate: Some(30),
+                ..Default::default()
+            },
+            CacheControl::parse("public, max-age=60, S-MAXAGE=\"120\", stale-while-revalidate=30")
+        );
+        assert_eq!(Some(u64::MAX), CacheControl::parse("max-stale").max_stale);
+    }
+
+    #[test]
+    fn fresh_responses_are_served_from_cache() {
+        let (cache, calls) = cache(|_| response(&[("Cache-Control", "max-age=60")], "v1"));
+
+        let first = cache.handle(&get(&[]));
+        let second = cache.handle(&get(&[]));
+
+        assert_eq!(
+            "http_server;fwd=uri-miss;fwd-status=200;stored",
+            cache_status(&first)
+        );
+        assert_eq!("http_server;hit;ttl=60", cache_status(&second));
+        assert_eq!(b"v1".to_vec(), second.body);
+        assert_eq!(Some("0"), second.header("Age"));
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+
+        age(&cache, Duration::from_secs(30));
+        let third = cache.handle(&get(&[]));
+        assert_eq!(Some("30"), third.header("Age"));
+        assert_eq!("http_server;hit;ttl=30", cache_status(&third));
+
+        let head = cache.handle(&Request {
+            method: Method::HEAD,
+            ..get(&[])
+        });
+        assert!(head.body.is_empty());
+        assert_eq!(Some("2"), head.header("Content-Length"));
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn huge_age_values_are_capped() {
+        let (cache, calls) = cache(|_| {
+            response(
+                &[
+                    ("Cache-Control", "max-age=60"),
+                    ("Age", "18446744073709551615"),
+                ],
+                "v1",
+            )
+        });
+        cache.handle(&get(&[]));
+        age(&cache, Duration::from_secs(2));
+
+        // Long stale, rather than overflowing when the age is worked out
+        let second = cache.handle(&get(&[]));
+        assert_eq!(
+            "http_server;fwd=stale;fwd-status=200;stored",
+            cache_status(&second)
+        );
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn request_directives_limit_what_is_served() {
+        let (cache, calls) = cache(|_| response(&[("Cache-Control", "max-age=60")], "v1"));
+        cache.handle(&get(&[]));
+        age(&cache, Duration::from_secs(30));
+
+        cache.handle(&get(&[("Cache-Control", "max-age=10")]));
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+
+        cache.handle(&get(&[("Pragma", "no-cache")]));
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+
+        age(&cache, Duration::from_secs(90));
+        let stale_ok = cache.handle(&get(&[("Cache-Control", "max-stale=60")]));
+        assert_eq!("http_server;hit;ttl=-30", cache_status(&stale_ok));
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn uncacheable_responses_are_not_stored() {
+        let (cache, calls) = cache(|request| match request.header("x-kind") {
+            Some("no-store") => response(&[("Cache-Control", "no-store")], ""),
+            Some("private") => response(&[("Cache-Control", "private, max-age=60")], ""),
+            Some("vary-star") => response(&[("Cache-Control", "max-age=60"), ("Vary", "*")], ""),
+            Some("set-cookie") => response(
+                &[
+                    ("Cache-Control", "max-age=60"),
+                    ("Set-Cookie", "session=abc"),
+                ],
+                "",
+            ),
+            _ => {
+                let mut response = response(&[("Cache-Control", "max-age=60")], "");
+                response.status_code = StatusCode::PARTIAL_CONTENT;
+                response
+            }
+        });
+
+        for kind in ["no-store", "private", "vary-star", "set-cookie", "partial"] {
+            cache.handle(&get(&[("X-Kind", kind)]));
+            cache.handle(&get(&[("X-Kind", kind)]));
+        }
+
+        assert_eq!(10, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn authorized_requests_need_explicit_permission_to_store() {
+        let (cache, calls) = cache(|_| response(&[("Cache-Control", "max-age=60")], ""));
+
+        cache.handle(&get(&[("Authorization", "Bearer abc")]));
+        cache.handle(&get(&[("Authorization", "Bearer abc")]));
+
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn expires_and_heuristic_freshness() {
+        let date = SystemTime::now();
+        let expires = response(
+            &[
+                ("Date", &format_http_date(date)),
+                (
+                    "Expires",
+                    &format_http_date(date + Duration::from_secs(3600)),
+                ),
+            ],
+            "",
+        );
+        let entry = Entry::new(&get(&[]), &expires, date, date);
+        assert_eq!(3600, entry.freshness_lifetime().as_secs());
+
+        let last_modified = response(
+            &[
+                ("Date", &format_http_date(date)),
+                (
+                    "Last-Modified",
+                    &format_http_date(date - Duration::from_secs(1000)),
+                ),
+            ],
+            "",
+        );
+        let entry = Entry::new(&get(&[]), &last_modified, date, date);
+        assert_eq!(100, entry.freshness_lifetime().as_secs());
+
+        let invalid_expires = response(&[("Expires", "0"), ("Date", &format_http_date(date))], "");
+        let entry = Entry::new(&get(&[]), &invalid_expires, date, date);
+        assert_eq!(0, entry.freshness_lifetime().as_secs());
+    }
+
+    #[test]
+    fn stale_responses_are_revalidated() {
+        let (cache, calls) = cache(|request| match request.header("if-none-match") {
+            Some("\"v1\"") => {
+                let mut response = response(&[("Cache-Control", "max-age=60")], "");
+                response.status_code = StatusCode::NOT_MODIFIED;
+                response
+            }
+            _ => response(&[("Cache-Control", "no-cache"), ("ETag", "\"v1\"")], "body"),
+        });
+
+        cache.handle(&get(&[]));
+        let revalidated = cache.handle(&get(&[]));
+
+        assert_eq!(
+            "http_server;fwd=stale;fwd-status=304",
+            cache_status(&revalidated)
+        );
+        assert_eq!(StatusCode::OK, revalidated.status_code);
+        assert_eq!(b"body".to_vec(), revalidated.body);
+        assert_eq!(Some("max-age=60"), revalidated.header("Cache-Control"));
+
+        let fresh_again = cache.handle(&get(&[]));
+        assert_eq!("http_server;hit;ttl=60", cache_status(&fresh_again));
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+
+        let conditional = cache.handle(&get(&[("If-None-Match", "\"v1\"")]));
+        assert_eq!(StatusCode::NOT_MODIFIED, conditional.status_code);
+        assert!(conditional.body.is_empty());
+    }
+
+    #[test]
+    fn vary_keeps_a_variant_per_request_header() {
+        let (cache, calls) = cache(|request| {
+            response(
+                &[("Cache-Control", "max-age=60"), ("Vary", "Accept-Language")],
+                request.header("accept-language").unwrap_or("none"),
+            )
+        });
+
+        let english = cache.handle(&get(&[("Accept-Language", "en")]));
+        let french = cache.handle(&get(&[("Accept-Language", "fr")]));
+        let english_again = cache.handle(&get(&[("Accept-Language", "en")]));
+
+        assert_eq!(
+            "http_server;fwd=uri-miss;fwd-status=200;stored",
+            cache_status(&english)
+        );
+        assert_eq!(
+            "http_server;fwd=vary-miss;fwd-status=200;stored",
+            cache_status(&french)
+        );
+        assert_eq!(b"en".to_vec(), english_again.body);
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn stale_while_revalidate_serves_stale_and_refreshes() {
+        let version = Arc::new(AtomicUsize::new(1));
+        let origin_version = version.clone();
+        let (cache, calls) = cache(move |_| {
+            response(
+                &[("Cache-Control", "max-age=10, stale-while-revalidate=60")],
+                &format!("v{}", origin_version.load(Ordering::SeqCst)),
+            )
+        });
+
+        cache.handle(&get(&[]));
+        version.store(2, Ordering::SeqCst);
+        age(&cache, Duration::from_secs(20));
+
+        let stale = cache.handle(&get(&[]));
+        assert_eq!(b"v1".to_vec(), stale.body);
+        assert_eq!("http_server;hit;ttl=-10", cache_status(&stale));
+
+        for _ in 0..100 {
+            if calls.load(Ordering::SeqCst) == 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        thread::sleep(Duration::from_millis(20));
+
+        let refreshed = cache.handle(&get(&[]));
+        assert_eq!(b"v2".to_vec(), refreshed.body);
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn stale_if_error_hides_upstream_failures() {
+        let failing = Arc::new(AtomicUsize::new(0));
+        let origin_failing = failing.clone();
+        let (cache, _) = cache(move |_| {
+            if origin_failing.load(Ordering::SeqCst) == 1 {
+                Response::error(StatusCode::BAD_GATEWAY, "down")
+            } else {
+                response(&[("Cache-Control", "max-age=10, stale-if-error=60")], "ok")
+            }
+        });
+
+        cache.handle(&get(&[]));
+        failing.store(1, Ordering::SeqCst);
+        age(&cache, Duration::from_secs(20));
+
+        let stale = cache.handle(&get(&[]));
+        assert_eq!(StatusCode::OK, stale.status_code);
+        assert_eq!(b"ok".to_vec(), stale.body);
+        assert_eq!(
+            "http_server;hit;fwd=stale;fwd-status=502",
+            cache_status(&stale)
+        );
+
+        age(&cache, Duration::from_secs(120));
+        let too_stale = cache.handle(&get(&[]));
+        assert_eq!(StatusCode::BAD_GATEWAY, too_stale.status_code);
+    }
+
+    #[test]
+    fn unsafe_requests_invalidate() {
+        let (cache, calls) = cache(|_| response(&[("Cache-Control", "max-age=60")], ""));
+
+        cache.handle(&get(&[]));
+        let post = cache.handle(&Request {
+            method: Method::POST,
+            ..get(&[])
+        });
+        cache.handle(&get(&[]));
+
+        assert_eq!("http_server;fwd=method", cache_status(&post));
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn least_recently_used_entries_are_evicted() {
+        let (cache, calls) =
+            cache(|request| response(&[("Cache-Control", "max-age=60")], &request.raw_target));
+        let cache = cache.max_size(100);
+        let get_path = |path: &str| Request {
+            raw_target: path.to_string(),
+            ..get(&[])
+        };
+
+        cache.handle(&get_path("/a"));
+        cache.handle(&get_path("/b"));
+        cache.handle(&get_path("/a"));
+        cache.handle(&get_path("/c"));
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+
+        cache.handle(&get_path("/a"));
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+        cache.handle(&get_path("/b"));
+        assert_eq!(4, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn disk_entries_outlive_the_cache() {
+        let directory =
+            std::env::temp_dir().join(format!("http_server_cache_{}", std::process::id()));
+        let directory = directory.to_str().unwrap();
+
+        let (first, _) = cache(|_| {
+            response(
+                &[("Cache-Control", "max-age=60"), ("Vary", "Accept")],
+                "from disk",
+            )
+        });
+        let first = first.on_disk(directory).unwrap();
+        first.handle(&get(&[("Accept", "text/plain")]));
+        drop(first);
+
+        let (second, calls) = cache(|_| response(&[], "from origin"));
+        let second = second.on_disk(directory).unwrap();
+        let response = second.handle(&get(&[("Accept", "text/plain")]));
+
+        fs::remove_dir_all(directory).unwrap();
+
+        assert_eq!(0, calls.load(Ordering::SeqCst));
+        assert_eq!(b"from disk".to_vec(), response.body);
+        assert_eq!(Some("Accept"), response.header("Vary"));
+    }
+}
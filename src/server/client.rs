@@ -0,0 +1,567 @@
+use std::{
+    collections::HashMap,
+    io::{BufReader, BufWriter, Error, ErrorKind, Write},
+    net::{TcpStream, ToSocketAddrs},
+    str::FromStr,
+    sync::Mutex,
+    time::Duration,
+};
+
+use super::{
+    http_version::HttpVersion, method::Method, request::Request, response::Response,
+    status_code::StatusCode, tunnel::parse_authority,
+};
+
+/** An absolute `http://` URL, split into what the client needs to send a request */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Url {
+    pub host: String,
+    pub port: u16,
+    /** Path and query, always starting with '/' */
+    pub path: String,
+}
+
+impl Url {
+    /** `host:port`, for connecting and for keying pooled connections */
+    pub fn authority(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+
+    /** The Host header value, which leaves out the default port */
+    pub fn host_header(&self) -> String {
+        match self.port {
+            80 => self.authority().trim_end_matches(":80").to_string(),
+            _ => self.authority(),
+        }
+    }
+
+    /** Resolves a Location header value against this URL */
+    pub fn join(&self, location: &str) -> Option<Url> {
+        if location.starts_with("//") {
+            return Url::from_str(&format!("http:{}", location)).ok();
+        }
+        if location.starts_with('/') {
+            return Some(Url {
+                path: location.split('#').next().unwrap_or_default().to_string(),
+                ..self.clone()
+            });
+        }
+        if location.contains("://") {
+            return Url::from_str(location).ok();
+        }
+
+        let path_only = self.path.split('?').next().unwrap_or_default();
+        let directory = &path_only[..=path_only.rfind('/').unwrap_or(0)];
+        Some(Url {
+            path: format!(
+                "{}{}",
+                directory,
+                location.split('#').next().unwrap_or_default()
+            ),
+            ..self.clone()
+        })
+    }
+}
+
+impl FromStr for Url {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let scheme_end = s.find("://").ok_or(())?;
+        if !s[..scheme_end].eq_ignore_ascii_case("http") {
+            return Err(());
+        }
+        let rest = &s[scheme_end + 3..];
+
+        let authority_end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+        let (authority, path) = rest.split_at(authority_end);
+        let path = path.split('#').next().unwrap_or_default();
+        let path = match path.chars().next() {
+            None => "/".to_string(),
+            Some('?') => format!("/{}", path),
+            Some(_) => path.to_string(),
+        };
+
+        let (host, port) = parse_authority(authority)
+            .or_else(|| parse_authority(&format!("{}:80", authority)))
+            .ok_or(())?;
+
+        Ok(Url { host, port, path })
+    }
+}
+
+/**
+ * A blocking HTTP/1.1 client. Requests are addressed by an absolute
+ * `http://` URL in `raw_target`. Connections are kept alive and pooled per
+ * host:port, and redirects are followed up to `max_redirects`.
+ */
+pub struct Client {
+    idle: Mutex<HashMap<String, Vec<TcpStream>>>,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    follow_redirects: bool,
+    max_redirects: usize,
+    max_idle_per_host: usize,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Client {
+    pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+    pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+    pub const DEFAULT_MAX_REDIRECTS: usize = 10;
+    pub const DEFAULT_MAX_IDLE_PER_HOST: usize = 8;
+
+    pub fn new() -> Self {
+        Client {
+            idle: Mutex::new(HashMap::new()),
+            connect_timeout: Self::DEFAULT_CONNECT_TIMEOUT,
+            read_timeout: Self::DEFAULT_READ_TIMEOUT,
+            follow_redirects: true,
+            max_redirects: Self::DEFAULT_MAX_REDIRECTS,
+            max_idle_per_host: Self::DEFAULT_MAX_IDLE_PER_HOST,
+        }
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /** How long to wait on the server for any read or write */
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    /** Redirects to follow before giving up with an error */
+    pub fn max_redirects(mut self, max_redirects: usize) -> Self {
+        self.max_redirects = max_redirects;
+        self
+    }
+
+    /** When off, redirect responses are returned instead of followed */
+    pub fn follow_redirects(mut self, follow_redirects: bool) -> Self {
+        self.follow_redirects = follow_redirects;
+        self
+    }
+
+    pub fn max_idle_per_host(mut self, max_idle_per_host: usize) -> Self {
+        self.max_idle_per_host = max_idle_per_host;
+        self
+    }
+
+    pub fn get(&self, url: &str) -> Result<Response, Error> {
+        self.send(&Request::new(Method::GET, url))
+    }
+
+    pub fn send(&self, request: &Request) -> Result<Response, Error> {
+        let mut url = Url::from_str(&request.raw_target).map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Not an absolute http:// URL: {}", request.raw_target),
+            )
+        })?;
+        let mut request = request.clone();
+
+        for _ in 0..=self.max_redirects {
+            let response = self.send_to(&url, &request)?;
+
+            let location = match response.header("Location") {
+                Some(location) if self.follow_redirects && is_redirect(&response.status_code) => {
+                    location
+                }
+                _ => return Ok(response),
+            };
+            let next_url = url.join(location).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unsupported redirect location: {}", location),
+                )
+            })?;
+
+            // 301 and 302 historically turn POST into GET; 303 always means GET.
+            // 307 and 308 repeat the request exactly.
+            let becomes_get = match response.status_code.0 {
+                301 | 302 => request.method == Method::POST,
+                303 => request.method != Method::HEAD,
+                _ => false,
+            };
+            if becomes_get {
+                request.method = Method::GET;
+                request.body.clear();
+                request.headers.remove("content-length");
+                request.headers.remove("content-type");
+            }
+
+            if next_url.authority() != url.authority() {
+                request.headers.remove("authorization");
+                request.headers.remove("cookie");
+            }
+
+            url = next_url;
+        }
+
+        Err(Error::other(format!(
+            "Gave up after {} redirects",
+            self.max_redirects
+        )))
+    }
+
+    /** Sends a single request to `url` without following redirects */
+    fn send_to(&self, url: &Url, request: &Request) -> Result<Response, Error> {
+        let mut wire_request = request.clone();
+        wire_request.raw_target = url.path.clone();
+        wire_request.http_version = HttpVersion::Http1_1;
+        wire_request
+            .headers
+            .insert("host".to_string(), vec![url.host_header()]);
+        wire_request.headers.remove("transfer-encoding");
+        if request.body.is_empty()
+            && !matches!(request.method, Method::POST | Method::PUT | Method::PATCH)
+        {
+            wire_request.headers.remove("content-length");
+        } else {
+            wire_request.headers.insert(
+                "content-length".to_string(),
+                vec![request.body.len().to_string()],
+            );
+        }
+
+        let authority = url.authority();
+
+        if let Some(stream) = self.take_idle(&authority) {
+            match self.exchange(&authority, stream, &wire_request) {
+                Ok(response) => return Ok(response),
+                // The server may have closed the idle connection in the meantime
+                Err(err) if is_stale_connection(&err) && is_idempotent(&request.method) => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        let stream = self.connect(&authority)?;
+        self.exchange(&authority, stream, &wire_request)
+    }
+
+    fn exchange(
+        &self,
+        authority: &str,
+        stream: TcpStream,
+        request: &Request,
+    ) -> Result<Response, Error> {
+        stream.set_read_timeout(Some(self.read_timeout))?;
+        stream.set_write_timeout(Some(self.read_timeout))?;
+
+        // One write for the whole request, so Nagle doesn't hold back the body
+        let mut writer = BufWriter::new(&stream);
+        request.write_to(&mut writer)?;
+        writer.flush()?;
+        drop(writer);
+
+        let mut reader = BufReader::new(stream);
+        let response = loop {
+            let response = if request.method == Method::HEAD {
+                Response::head_from_reader(&mut reader)?
+            } else {
+                Response::from_reader(&mut reader)?
+            };
+
+            // Interim responses like 100 Continue precede the real one
+            let status_code = response.status_code.0;
+            if !(100..200).contains(&status_code) || status_code == 101 {
+                break response;
+            }
+        };
+
+        if is_reusable(&response, request) && reader.buffer().is_empty() {
+            self.put_idle(authority, reader.into_inner());
+        }
+
+        Ok(response)
+    }
+
+    fn connect(&self, authority: &str) -> Result<TcpStream, Error> {
+        let mut last_err = Error::new(
+            ErrorKind::NotFound,
+            format!("Host did not resolve: {}", authority),
+        );
+
+        for addr in authority.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, self.connect_timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = err,
+            }
+        }
+
+        Err(last_err)
+    }
+
+    fn take_idle(&self, authority: &str) -> Option<TcpStream> {
+        self.idle
+            .lock()
+            .unwrap()
+            .get_mut(authority)
+            .and_then(|streams| streams.pop())
+    }
+
+    fn put_idle(&self, authority: &str, stream: TcpStream) {
+        let mut idle = self.idle.lock().unwrap();
+        let streams = idle.entry(authority.to_string()).or_default();
+        if streams.len() < self.max_idle_per_host {
+            streams.push(stream);
+        }
+    }
+}
+
+fn is_redirect(status_code: &StatusCode) -> bool {
+    matches!(status_code.0, 301 | 302 | 303 | 307 | 308)
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    !matches!(method, Method::POST | Method::PATCH | Method::CONNECT)
+}
+
+fn is_stale_connection(err: &Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::UnexpectedEof
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+    )
+}
+
+/** Whether the connection is left at a clean boundary for another request */
+fn is_reusable(response: &Response, request: &Request) -> bool {
+    let closing = response
+        .header("Connection")
+        .map(|connection| connection.eq_ignore_ascii_case("close"))
+        .unwrap_or(false);
+    let length_delimited = request.method == Method::HEAD
+        || response.status_code == StatusCode::NO_CONTENT
+        || response.status_code == StatusCode::NOT_MODIFIED
+        || response.is_chunked()
+        || response.header("Content-Length").is_some();
+
+    response.http_version == HttpVersion::Http1_1 && !closing && length_delimited
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Shutdown, SocketAddr, TcpListener},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    use super::*;
+
+    /**
+     * Serves keep-alive connections with `respond`, closing each connection
+     * after `requests_per_connection` requests. Returns the address and a
+     * count of accepted connections.
+     */
+    fn serve<F>(requests_per_connection: usize, respond: F) -> (SocketAddr, Arc<AtomicUsize>)
+    where
+        F: Fn(&Request) -> String + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let respond = Arc::new(respond);
+
+        let accepted = connections.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                let respond = respond.clone();
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.unwrap());
+                    for _ in 0..requests_per_connection {
+                        let request = match Request::from_reader(&mut reader) {
+                            Ok(request) => request,
+                            Err(_) => return,
+                        };
+                        let response = respond(&request);
+                        reader.get_mut().write_all(response.as_bytes()).unwrap();
+                    }
+                    let _ = reader.get_ref().shutdown(Shutdown::Both);
+                });
+            }
+        });
+
+        (addr, connections)
+    }
+
+    fn ok(body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+
+    fn redirect(status: &str, location: &str) -> String {
+        format!(
+            "HTTP/1.1 {}\r\nLocation: {}\r\nContent-Length: 0\r\n\r\n",
+            status, location
+        )
+    }
+
+    #[test]
+    fn url_parsing() {
+        assert_eq!(
+            Ok(Url {
+                host: "example.com".to_string(),
+                port: 80,
+                path: "/".to_string()
+            }),
+            Url::from_str("HTTP://Example.com")
+        );
+        assert_eq!(
+            Ok(Url {
+                host: "::1".to_string(),
+                port: 8080,
+                path: "/a/b?c=d".to_string()
+            }),
+            Url::from_str("http://[::1]:8080/a/b?c=d#frag")
+        );
+        assert_eq!("/?q=1", Url::from_str("http://localhost?q=1").unwrap().path);
+        assert_eq!(Err(()), Url::from_str("https://example.com/"));
+        assert_eq!(Err(()), Url::from_str("/relative"));
+        assert_eq!(Err(()), Url::from_str("http://example.com:port/"));
+    }
+
+    #[test]
+    fn url_join() {
+        let base = Url::from_str("http://localhost:8080/docs/guide?page=2").unwrap();
+
+        assert_eq!("/docs/intro", base.join("intro").unwrap().path);
+        assert_eq!("/about", base.join("/about").unwrap().path);
+        assert_eq!("other:80", base.join("//other/x").unwrap().authority());
+        assert_eq!(
+            "example.com",
+            base.join("http://example.com/").unwrap().host_header()
+        );
+        assert_eq!(None, base.join("https://example.com/"));
+    }
+
+    #[test]
+    fn keep_alive_reuses_pooled_connection() {
+        let (addr, connections) = serve(usize::MAX, |request| ok(&request.raw_target));
+        let client = Client::new();
+
+        for path in ["/one", "/two", "/three"] {
+            let response = client.get(&format!("http://{}{}", addr, path)).unwrap();
+            assert_eq!(path.as_bytes(), response.body.as_slice());
+        }
+
+        assert_eq!(1, connections.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn closed_idle_connection_is_replaced() {
+        let (addr, connections) = serve(1, |_| ok("fresh"));
+        let client = Client::new();
+
+        client.get(&format!("http://{}/", addr)).unwrap();
+        let response = client.get(&format!("http://{}/", addr)).unwrap();
+
+        assert_eq!(b"fresh", response.body.as_slice());
+        assert_eq!(2, connections.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn see_other_redirect_becomes_get_and_body_is_chunked() {
+        let (addr, _) = serve(usize::MAX, |request| match request.raw_target.as_str() {
+            "/submit" => redirect("303 See Other", "done"),
+            _ => {
+                let body = format!(
+                    "{} {} {}",
+                    request.method.as_str(),
+                    request.raw_target,
+                    request.body.len()
+                );
+                format!(
+                    "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n{}\r\n0\r\n\r\n",
+                    body.len(),
+                    body
+                )
+            }
+        });
+        let client = Client::new();
+
+        let mut request = Request::new(Method::POST, &format!("http://{}/submit", addr));
+        request.body = b"name=value".to_vec();
+        let response = client.send(&request).unwrap();
+
+        assert_eq!(StatusCode::OK, response.status_code);
+        assert_eq!(b"GET /done 0", response.body.as_slice());
+    }
+
+    #[test]
+    fn temporary_redirect_repeats_method_and_body() {
+        let (addr, _) = serve(usize::MAX, |request| match request.raw_target.as_str() {
+            "/old" => redirect("307 Temporary Redirect", "/new"),
+            _ => ok(&format!(
+                "{} {}",
+                request.method.as_str(),
+                String::from_utf8_lossy(&request.body)
+            )),
+        });
+        let client = Client::new();
+
+        let mut request = Request::new(Method::PUT, &format!("http://{}/old", addr));
+        request.body = b"payload".to_vec();
+        let response = client.send(&request).unwrap();
+
+        assert_eq!(b"PUT payload", response.body.as_slice());
+    }
+
+    #[test]
+    fn redirect_loops_give_up() {
+        let (addr, _) = serve(usize::MAX, |_| redirect("302 Found", "/loop"));
+        let client = Client::new().max_redirects(3);
+
+        let err = client.get(&format!("http://{}/loop", addr)).unwrap_err();
+
+        assert_eq!(ErrorKind::Other, err.kind());
+
+        let response = Client::new()
+            .follow_redirects(false)
+            .get(&format!("http://{}/loop", addr))
+            .unwrap();
+        assert_eq!(StatusCode::FOUND, response.status_code);
+    }
+
+    #[test]
+    fn slow_server_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            thread::sleep(Duration::from_millis(200));
+            drop(stream);
+        });
+
+        let client = Client::new().read_timeout(Duration::from_millis(20));
+        let err = client.get(&format!("http://{}/", addr)).unwrap_err();
+
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::WouldBlock | ErrorKind::TimedOut
+        ));
+        server.join().unwrap();
+    }
+}
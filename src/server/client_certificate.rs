@@ -0,0 +1,623 @@
+use std::{
+    io::{Error, ErrorKind},
+    net::IpAddr,
+};
+
+use super::{handler::Handler, request::Request, response::Response, status_code::StatusCode};
+
+const BOOLEAN: u8 = 0x01;
+const INTEGER: u8 = 0x02;
+const OCTET_STRING: u8 = 0x04;
+const OBJECT_IDENTIFIER: u8 = 0x06;
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+/** The optional [0] version and [3] extensions of a TBSCertificate */
+const VERSION: u8 = 0xa0;
+const EXTENSIONS: u8 = 0xa3;
+
+/** id-ce-subjectAltName, 2.5.29.17 */
+const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+/** Short names for attribute types, as RFC 4514 section 3 lists them */
+const ATTRIBUTE_NAMES: [(&[u8], &str); 8] = [
+    (&[0x55, 0x04, 0x03], "CN"),
+    (&[0x55, 0x04, 0x07], "L"),
+    (&[0x55, 0x04, 0x08], "ST"),
+    (&[0x55, 0x04, 0x0a], "O"),
+    (&[0x55, 0x04, 0x0b], "OU"),
+    (&[0x55, 0x04, 0x06], "C"),
+    (&[0x55, 0x04, 0x09], "STREET"),
+    (
+        &[0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x19],
+        "DC",
+    ),
+];
+
+/** One entry of a certificate's subjectAltName extension */
+#[derive(Clone, Debug, PartialEq)]
+pub enum SubjectAltName {
+    Email(String),
+    Dns(String),
+    Uri(String),
+    Ip(IpAddr),
+}
+
+/**
+ * The certificate a client authenticated with during the TLS handshake. It
+ * has already been verified against the listener's CA bundle by the time
+ * it's on a Request; this only pulls out the parts that identify the client.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientCertificate {
+    /** The subject's distinguished name as an RFC 4514 string, e.g. `CN=billing,O=Example` */
+    pub subject: String,
+    pub subject_alt_names: Vec<SubjectAltName>,
+    /** The whole certificate, DER encoded */
+    pub der: Vec<u8>,
+}
+
+impl ClientCertificate {
+    pub fn from_der(der: &[u8]) -> Result<Self, Error> {
+        let mut certificate = Der(Der(der).expect(SEQUENCE)?);
+        let mut tbs = Der(certificate.expect(SEQUENCE)?);
+
+        if tbs.peek() == Some(VERSION) {
+            tbs.read()?;
+        }
+        tbs.expect(INTEGER)?; // serialNumber
+        tbs.expect(SEQUENCE)?; // signature
+        tbs.expect(SEQUENCE)?; // issuer
+        tbs.expect(SEQUENCE)?; // validity
+        let subject = distinguished_name(tbs.expect(SEQUENCE)?)?;
+        tbs.expect(SEQUENCE)?; // subjectPublicKeyInfo
+
+        let mut subject_alt_names = vec![];
+        // Skips the issuerUniqueID and subjectUniqueID, if there are any
+        while !tbs.is_empty() {
+            let (tag, contents) = tbs.read()?;
+            if tag != EXTENSIONS {
+                continue;
+            }
+            let mut extensions = Der(Der(contents).expect(SEQUENCE)?);
+            while !extensions.is_empty() {
+                let mut extension = Der(extensions.expect(SEQUENCE)?);
+                let id = extension.expect(OBJECT_IDENTIFIER)?;
+                if extension.peek() == Some(BOOLEAN) {
+                    extension.read()?; // critical
+                }
+                let value = extension.expect(OCTET_STRING)?;
+                if id == SUBJECT_ALT_NAME {
+                    subject_alt_names = general_names(value)?;
+                }
+            }
+        }
+
+        Ok(ClientCertificate {
+            subject,
+            subject_alt_names,
+            der: der.to_vec(),
+        })
+    }
+
+    pub fn has_identity(&self, identity: &Identity) -> bool {
+        match identity {
+            Identity::Subject(subject) => &self.subject == subject,
+            Identity::SubjectAltName(SubjectAltName::Dns(dns)) => {
+                self.subject_alt_names.iter().any(|name| {
+                    matches!(name, SubjectAltName::Dns(name) if name.eq_ignore_ascii_case(dns))
+                })
+            }
+            Identity::SubjectAltName(name) => self.subject_alt_names.contains(name),
+        }
+    }
+}
+
+/** Something a client certificate has to name for a route to accept it */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Identity {
+    /** The whole subject, compared with ClientCertificate::subject */
+    Subject(String),
+    /** Any one of the subject alternative names; DNS names ignore case */
+    SubjectAltName(SubjectAltName),
+}
+
+/**
+ * Limits routes to clients that authenticated with a certificate naming one
+ * of the given identities. A route is a path prefix matched on whole
+ * segments, so `/admin` covers `/admin/users` but not `/administrators`;
+ * the longest matching route applies. Requests no route matches are passed
+ * through whether or not there's a certificate. Paths are matched as
+ * handlers are likely to see them: percent-decoded, with dot segments and
+ * empty segments removed, and taken out of absolute-form targets.
+ */
+pub struct RequireClientCertificate<H> {
+    handler: H,
+    routes: Vec<(String, Vec<Identity>)>,
+}
+
+impl<H: Handler> RequireClientCertificate<H> {
+    pub fn new(handler: H) -> Self {
+        RequireClientCertificate {
+            handler,
+            routes: vec![],
+        }
+    }
+
+    /**
+     * Requires one of `identities` under `path_prefix`, or any verified
+     * certificate if there are none
+     */
+    pub fn route(
+        mut self,
+        path_prefix: &str,
+        identities: impl IntoIterator<Item = Identity>,
+    ) -> Self {
+        let path_prefix = path_prefix.trim_end_matches('/').to_string();
+        self.routes
+            .push((path_prefix, identities.into_iter().collect()));
+        self
+    }
+
+    fn identities_for(&self, path: &str) -> Option<&[Identity]> {
+        self.routes
+            .iter()
+            .filter(|(prefix, _)| {
+                path.strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, identities)| identities.as_slice())
+    }
+}
+
+impl<H: Handler> Handler for RequireClientCertificate<H> {
+    fn handle(&self, request: &Request) -> Response {
+        let path = normalized_path(&request.raw_target);
+        let Some(identities) = self.identities_for(&path) else {
+            return self.handler.handle(request);
+        };
+
+        let Some(certificate) = &request.client_certificate else {
+            return Response::error(StatusCode::FORBIDDEN, "A client certificate is required");
+        };
+        if !identities.is_empty()
+            && !identities
+                .iter()
+                .any(|identity| certificate.has_identity(identity))
+        {
+            return Response::error(
+                StatusCode::FORBIDDEN,
+                "Client certificate is not allowed here",
+            );
+        }
+
+        self.handler.handle(request)
+    }
+}
+
+/**
+ * The path of a request target, percent-decoded and with `.`, `..` and
+ * empty segments resolved, so `/a/../b`, `//b` and `/%62` all become `/b`.
+ * Targets that aren't a path or an absolute URL, like `*` or CONNECT's
+ * `host:port`, are treated as `/`.
+ */
+pub(super) fn normalized_path(raw_target: &str) -> String {
+    let path = raw_target.split(['?', '#']).next().unwrap_or_default();
+    let path = match path.split_once("://") {
+        // Absolute form: the path starts after the authority
+        Some((scheme, rest)) if !scheme.contains('/') => {
+            rest.find('/').map_or("", |start| &rest[start..])
+        }
+        _ if path.starts_with('/') => path,
+        _ => "",
+    };
+
+    let decoded = percent_decode(path);
+    let mut segments = vec![];
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    format!("/{}", segments.join("/"))
+}
+
+/** Decodes %XX escapes, leaving malformed ones as they are */
+fn percent_decode(encoded: &str) -> String {
+    let mut decoded = vec![];
+    let mut bytes = encoded.as_bytes();
+    while let [first, rest @ ..] = bytes {
+        let escaped = match rest {
+            [high, low, ..] if *first == b'%' => std::str::from_utf8(&[*high, *low])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                bytes = &rest[2..];
+            }
+            None => {
+                decoded.push(*first);
+                bytes = rest;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn malformed(message: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Malformed certificate: {}", message),
+    )
+}
+
+/** Reads DER elements (X.690) one after another out of a constructed element's contents */
+struct Der<'a>(&'a [u8]);
+
+impl<'a> Der<'a> {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.0.first().copied()
+    }
+
+    /** Returns the next element's tag and contents */
+    fn read(&mut self) -> Result<(u8, &'a [u8]), Error> {
+        let [tag, first, rest @ ..] = self.0 else {
+            return Err(malformed("truncated element"));
+        };
+
+        let (length, rest) = if first & 0x80 == 0 {
+            (*first as usize, rest)
+        } else {
+            let count = (first & 0x7f) as usize;
+            if count == 0 || count > 4 || rest.len() < count {
+                return Err(malformed("bad length"));
+            }
+            let length = rest[..count]
+                .iter()
+                .fold(0, |length, &byte| length << 8 | byte as usize);
+            (length, &rest[count..])
+        };
+
+        if rest.len() < length {
+            return Err(malformed("truncated element"));
+        }
+        self.0 = &rest[length..];
+        Ok((*tag, &rest[..length]))
+    }
+
+    /** Like read(), including the tag and length in what's returned */
+    fn read_encoded(&mut self) -> Result<(u8, &'a [u8], &'a [u8]), Error> {
+        let before = self.0;
+        let (tag, contents) = self.read()?;
+        Ok((tag, contents, &before[..before.len() - self.0.len()]))
+    }
+
+    fn expect(&mut self, tag: u8) -> Result<&'a [u8], Error> {
+        match self.read()? {
+            (found, contents) if found == tag => Ok(contents),
+            (found, _) => Err(malformed(&format!(
+                "expected tag {:#04x}, found {:#04x}",
+                tag, found
+            ))),
+        }
+    }
+}
+
+/** Renders a Name as RFC 4514 does: last RDN first, multi-valued RDNs joined by `+` */
+fn distinguished_name(name: &[u8]) -> Result<String, Error> {
+    let mut name = Der(name);
+    let mut rdns = vec![];
+
+    while !name.is_empty() {
+        let mut rdn = Der(name.expect(SET)?);
+        let mut attributes = vec![];
+        while !rdn.is_empty() {
+            let mut attribute = Der(rdn.expect(SEQUENCE)?);
+            let id = attribute.expect(OBJECT_IDENTIFIER)?;
+            let (tag, contents, encoded) = attribute.read_encoded()?;
+
+            let value = match attribute_string(tag, contents) {
+                Some(value) => escape(&value),
+                None => format!("#{}", hex(encoded)),
+            };
+            let attribute_type = match ATTRIBUTE_NAMES.iter().find(|(oid, _)| *oid == id) {
+                Some((_, short_name)) => short_name.to_string(),
+                None => object_identifier(id)?,
+            };
+            attributes.push(format!("{}={}", attribute_type, value));
+        }
+        rdns.push(attributes.join("+"));
+    }
+
+    rdns.reverse();
+    Ok(rdns.join(","))
+}
+
+/** Decodes the string types directory names use; anything else is None */
+fn attribute_string(tag: u8, contents: &[u8]) -> Option<String> {
+    match tag {
+        // UTF8String, PrintableString, TeletexString and IA5String
+        0x0c | 0x13 | 0x14 | 0x16 => String::from_utf8(contents.to_vec()).ok(),
+        // BMPString is UTF-16
+        0x1e if contents.len().is_multiple_of(2) => String::from_utf16(
+            &contents
+                .chunks(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                .collect::<Vec<_>>(),
+        )
+        .ok(),
+        _ => None,
+    }
+}
+
+/** Escapes an attribute value as RFC 4514 section 2.4 requires */
+fn escape(value: &str) -> String {
+    let last = value.chars().count().saturating_sub(1);
+    let mut escaped = String::new();
+
+    for (index, c) in value.chars().enumerate() {
+        if c == '\0' {
+            escaped.push_str("\\00");
+            continue;
+        }
+        if matches!(c, '"' | '+' | ',' | ';' | '<' | '>' | '\\')
+            || (index == 0 && matches!(c, ' ' | '#'))
+            || (index == last && c == ' ')
+        {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/** Formats an OBJECT IDENTIFIER's contents in dotted decimal */
+fn object_identifier(contents: &[u8]) -> Result<String, Error> {
+    let mut arcs = vec![];
+    let mut arc: u64 = 0;
+    for &byte in contents {
+        arc = arc
+            .checked_mul(128)
+            .ok_or_else(|| malformed("object identifier arc overflows"))?
+            | (byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            arcs.push(arc);
+            arc = 0;
+        }
+    }
+    if arc != 0 || arcs.is_empty() {
+        return Err(malformed("truncated object identifier"));
+    }
+
+    // The first subidentifier packs the first two arcs together
+    let first = arcs[0];
+    let (top, second) = match first {
+        0..=39 => (0, first),
+        40..=79 => (1, first - 40),
+        _ => (2, first - 80),
+    };
+    let mut dotted = format!("{}.{}", top, second);
+    for arc in &arcs[1..] {
+        dotted.push_str(&format!(".{}", arc));
+    }
+    Ok(dotted)
+}
+
+/** Reads GeneralNames, skipping the kinds SubjectAltName has no variant for */
+fn general_names(value: &[u8]) -> Result<Vec<SubjectAltName>, Error> {
+    let mut names = Der(Der(value).expect(SEQUENCE)?);
+    let mut subject_alt_names = vec![];
+
+    while !names.is_empty() {
+        let (tag, contents) = names.read()?;
+        let text =
+            || String::from_utf8(contents.to_vec()).map_err(|_| malformed("name isn't IA5String"));
+        let name = match tag {
+            0x81 => SubjectAltName::Email(text()?),
+            0x82 => SubjectAltName::Dns(text()?),
+            0x86 => SubjectAltName::Uri(text()?),
+            0x87 => match contents.len() {
+                4 => SubjectAltName::Ip(IpAddr::from(<[u8; 4]>::try_from(contents).unwrap())),
+                16 => SubjectAltName::Ip(IpAddr::from(<[u8; 16]>::try_from(contents).unwrap())),
+                _ => return Err(malformed("bad IP address length")),
+            },
+            _ => continue,
+        };
+        subject_alt_names.push(name);
+    }
+
+    Ok(subject_alt_names)
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair, SanType};
+
+    use super::{super::method::Method, *};
+
+    fn certificate(subject: &[(DnType, &str)], sans: Vec<SanType>) -> Vec<u8> {
+        let mut params = CertificateParams::default();
+        params.distinguished_name = DistinguishedName::new();
+        for (dn_type, value) in subject {
+            params.distinguished_name.push(dn_type.clone(), *value);
+        }
+        params.subject_alt_names = sans;
+        let key = KeyPair::generate().unwrap();
+        params.self_signed(&key).unwrap().der().to_vec()
+    }
+
+    fn client_certificate(subject: &str, sans: &[SubjectAltName]) -> ClientCertificate {
+        ClientCertificate {
+            subject: subject.to_string(),
+            subject_alt_names: sans.to_vec(),
+            der: vec![],
+        }
+    }
+
+    #[test]
+    fn subject_and_alt_names_are_parsed() {
+        let der = certificate(
+            &[
+                (DnType::CountryName, "GB"),
+                (DnType::OrganizationName, "Example, Inc."),
+                (DnType::CommonName, "billing"),
+                (DnType::CustomDnType(vec![1, 2, 3, 4]), "other"),
+            ],
+            vec![
+                SanType::DnsName("billing.internal".try_into().unwrap()),
+                SanType::URI("spiffe://example.org/billing".try_into().unwrap()),
+                SanType::IpAddress("10.0.0.7".parse().unwrap()),
+                SanType::IpAddress("::1".parse().unwrap()),
+                SanType::Rfc822Name("ops@example.org".try_into().unwrap()),
+            ],
+        );
+
+        let certificate = ClientCertificate::from_der(&der).unwrap();
+        assert_eq!(
+            "1.2.3.4=other,CN=billing,O=Example\\, Inc.,C=GB",
+            certificate.subject
+        );
+        assert_eq!(
+            vec![
+                SubjectAltName::Dns("billing.internal".to_string()),
+                SubjectAltName::Uri("spiffe://example.org/billing".to_string()),
+                SubjectAltName::Ip("10.0.0.7".parse().unwrap()),
+                SubjectAltName::Ip("::1".parse().unwrap()),
+                SubjectAltName::Email("ops@example.org".to_string()),
+            ],
+            certificate.subject_alt_names
+        );
+        assert_eq!(der, certificate.der);
+
+        assert!(ClientCertificate::from_der(&der[..der.len() - 1]).is_err());
+        assert!(ClientCertificate::from_der(&[0x30, 0x82, 0x01]).is_err());
+    }
+
+    #[test]
+    fn values_are_escaped() {
+        assert_eq!("a\\+b\\,c\\;d", escape("a+b,c;d"));
+        assert_eq!("\\#1 \\<x\\> \\\"y\\\"\\ ", escape("#1 <x> \"y\" "));
+        assert_eq!("\\ lead", escape(" lead"));
+        assert_eq!("2.999.3", object_identifier(&[0x88, 0x37, 0x03]).unwrap());
+    }
+
+    #[test]
+    fn identities_are_matched() {
+        let certificate = client_certificate(
+            "CN=billing,O=Example",
+            &[
+                SubjectAltName::Dns("Billing.Internal".to_string()),
+                SubjectAltName::Uri("spiffe://example.org/billing".to_string()),
+            ],
+        );
+
+        for identity in [
+            Identity::Subject("CN=billing,O=Example".to_string()),
+            Identity::SubjectAltName(SubjectAltName::Dns("billing.internal".to_string())),
+            Identity::SubjectAltName(SubjectAltName::Uri(
+                "spiffe://example.org/billing".to_string(),
+            )),
+        ] {
+            assert!(certificate.has_identity(&identity), "{:?}", identity);
+        }
+        for identity in [
+            Identity::Subject("CN=billing".to_string()),
+            Identity::SubjectAltName(SubjectAltName::Uri(
+                "spiffe://example.org/Billing".to_string(),
+            )),
+            Identity::SubjectAltName(SubjectAltName::Ip("10.0.0.7".parse().unwrap())),
+        ] {
+            assert!(!certificate.has_identity(&identity), "{:?}", identity);
+        }
+    }
+
+    #[test]
+    fn routes_require_identities() {
+        let admin = Identity::Subject("CN=admin".to_string());
+        let handler = RequireClientCertificate::new(|_: &Request| Response::new())
+            .route("/internal", [])
+            .route("/internal/admin/", [admin]);
+
+        let request = |target: &str, subject: Option<&str>| {
+            let mut request = Request::new(Method::GET, target);
+            request.client_certificate = subject.map(|subject| client_certificate(subject, &[]));
+            handler.handle(&request).status_code
+        };
+
+        assert_eq!(StatusCode::OK, request("/public", None));
+        assert_eq!(StatusCode::OK, request("/internalish", None));
+        assert_eq!(StatusCode::FORBIDDEN, request("/internal", None));
+        assert_eq!(StatusCode::FORBIDDEN, request("/internal/jobs?x=1", None));
+        assert_eq!(
+            StatusCode::OK,
+            request("/internal/jobs", Some("CN=billing"))
+        );
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            request("/internal/admin?user=1", Some("CN=billing"))
+        );
+        assert_eq!(
+            StatusCode::OK,
+            request("/internal/admin/users", Some("CN=admin"))
+        );
+    }
+
+    #[test]
+    fn paths_are_normalized_before_matching() {
+        let handler =
+            RequireClientCertificate::new(|_: &Request| Response::new()).route("/admin", []);
+        let status = |target: &str| {
+            handler
+                .handle(&Request::new(Method::GET, target))
+                .status_code
+        };
+
+        for target in [
+            "/admin/../admin/x",
+            "/public/../admin",
+            "//admin",
+            "/./admin/",
+            "/%61dmin",
+            "/%2e%2e/admin",
+            "/admin%2Fusers",
+            "http://example.com/admin",
+            "https://example.com//admin?x=1",
+        ] {
+            assert_eq!(StatusCode::FORBIDDEN, status(target), "{}", target);
+        }
+        for target in ["/administrators", "/admin/../public", "http://example.com/"] {
+            assert_eq!(StatusCode::OK, status(target), "{}", target);
+        }
+    }
+
+    #[test]
+    fn targets_that_are_not_paths_match_the_root() {
+        let handler = RequireClientCertificate::new(|_: &Request| Response::new()).route("/", []);
+        let status =
+            |method, target: &str| handler.handle(&Request::new(method, target)).status_code;
+
+        assert_eq!(StatusCode::FORBIDDEN, status(Method::OPTIONS, "*"));
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            status(Method::CONNECT, "example.com:443")
+        );
+        assert_eq!("/", normalized_path("http://example.com"));
+        assert_eq!("/a/%zz", normalized_path("/a/b/..//%zz#top"));
+    }
+}
@@ -0,0 +1,392 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::{
+    cache::{cache_key, is_private, vary},
+    handler::Handler,
+    http_version::HttpVersion,
+    method::Method,
+    request::Request,
+    response::Response,
+    status_code::StatusCode,
+};
+
+/** A buffered response that every request waiting on a fetch gets a copy of */
+#[derive(Clone)]
+struct Shared {
+    http_version: HttpVersion,
+    status_code: StatusCode,
+    headers: HashMap<String, Vec<String>>,
+    body: Vec<u8>,
+    /** The request headers named by Vary, as the fetching request sent them */
+    vary: Vec<(String, Option<String>)>,
+}
+
+impl Shared {
+    /** Whether the response suits `request` as well as the one it was fetched for */
+    fn matches_vary(&self, request: &Request) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| name != "*" && request.header(name) == value.as_deref())
+    }
+
+    fn to_response(&self) -> Response {
+        let mut response = Response::new();
+        response.http_version = self.http_version;
+        response.status_code = self.status_code;
+        response.headers = self.headers.clone();
+        response.body = self.body.clone();
+        response
+    }
+}
+
+/**
+ * A fetch in progress. `result` stays None until the fetch finishes, and is
+ * still None afterwards if it failed, in which case waiters fetch for
+ * themselves.
+ */
+#[derive(Default)]
+struct Flight {
+    result: Mutex<Option<Option<Shared>>>,
+    done: Condvar,
+}
+
+/** Ends a flight when the fetching request is done with it, even by panicking */
+struct Landing<'a> {
+    in_flight: &'a Mutex<HashMap<String, Arc<Flight>>>,
+    key: &'a str,
+    flight: &'a Flight,
+    shared: Option<Shared>,
+}
+
+impl Drop for Landing<'_> {
+    fn drop(&mut self) {
+        self.in_flight.lock().unwrap().remove(self.key);
+        *self.flight.result.lock().unwrap() = Some(self.shared.take());
+        self.flight.done.notify_all();
+    }
+}
+
+/**
+ * Collapses concurrent identical requests into one. The first GET or HEAD
+ * for a cache key is passed on to the handler; requests for the same key
+ * that arrive while it's in flight wait for its response and get a copy
+ * instead. Waiters that wait longer than the timeout go to the handler
+ * themselves, as do those the response doesn't suit: it's private,
+ * no-store or sets cookies, or its Vary names a header the waiter sent
+ * differently. Requests with credentials (Authorization or Cookie) are
+ * never coalesced, since their responses are likely meant for them alone.
+ * Meant to sit in front of a Cache or ReverseProxy, so a stale entry or
+ * cold upstream is only fetched once.
+ */
+pub struct Coalesce<H: Handler> {
+    handler: H,
+    in_flight: Mutex<HashMap<String, Arc<Flight>>>,
+    timeout: Duration,
+}
+
+impl<H: Handler> Coalesce<H> {
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+    pub fn new(handler: H) -> Self {
+        Coalesce {
+            handler,
+            in_flight: Mutex::new(HashMap::new()),
+            timeout: Self::DEFAULT_TIMEOUT,
+        }
+    }
+
+    /** How long a request waits on another's fetch before making its own */
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn fetch(&self, request: &Request, key: &str, flight: &Flight) -> Response {
+        let mut landing = Landing {
+            in_flight: &self.in_flight,
+            key,
+            flight,
+            shared: None,
+        };
+
+        let mut response = self.handler.handle(request);
+        // Upgrades and unreadable bodies can't be shared, nor one client's
+        // private response, so waiters fetch their own
+        if response.on_upgrade.is_some() || is_private(&response) {
+            return response;
+        }
+        if let Err(err) = response.buffer_body() {
+            eprintln!("Failed to read response to share: {}", err);
+            return Response::error(StatusCode::BAD_GATEWAY, "Upstream response failed");
+        }
+
+        landing.shared = Some(Shared {
+            http_version: response.http_version,
+            status_code: response.status_code,
+            headers: response.headers.clone(),
+            body: response.body.clone(),
+            vary: vary(request, &response.headers),
+        });
+        response
+    }
+
+    fn wait(&self, flight: &Flight) -> Option<Shared> {
+        let deadline = Instant::now() + self.timeout;
+        let mut result = flight.result.lock().unwrap();
+
+        while result.is_none() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return None;
+            }
+            result = flight.done.wait_timeout(result, remaining).unwrap().0;
+        }
+
+        result.clone().flatten()
+    }
+}
+
+impl<H: Handler> Handler for Coalesce<H> {
+    fn handle(&self, request: &Request) -> Response {
+        if request.method != Method::GET && request.method != Method::HEAD
+            || request.header("authorization").is_some()
+            || request.header("cookie").is_some()
+        {
+            return self.handler.handle(request);
+        }
+
+        let key = format!("{} {}", request.method.as_str(), cache_key(request));
+
+        let (flight, leading) = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(&key) {
+                Some(flight) => (Arc::clone(flight), false),
+                None => {
+                    let flight = Arc::new(Flight::default());
+                    in_flight.insert(key.clone(), Arc::clone(&flight));
+                    (flight, true)
+                }
+            }
+        };
+
+        if leading {
+            return self.fetch(request, &key, &flight);
+        }
+
+        match self.wait(&flight) {
+            Some(shared) if shared.matches_vary(request) => shared.to_response(),
+            _ => self.handler.handle(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+    };
+
+    use super::*;
+
+    /** A handler that takes `delay` to answer, with a count of calls */
+    fn slow_origin(
+        delay: Duration,
+    ) -> (
+        impl Fn(&Request) -> Response + Send + Sync + 'static,
+        Arc<AtomicUsize>,
+    ) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let origin = move |request: &Request| {
+            let call = counted.fetch_add(1, Ordering::SeqCst) + 1;
+            thread::sleep(delay);
+            let mut response = Response::new();
+            response.body = format!("{} #{}", request.raw_target, call).into_bytes();
+            response
+        };
+        (origin, calls)
+    }
+
+    /** Sends `count` copies of a request at once, returning the bodies */
+    fn concurrently<H: Handler>(
+        coalesce: &Arc<Coalesce<H>>,
+        request: Request,
+        count: usize,
+    ) -> Vec<String> {
+        let threads = (0..count)
+            .map(|_| {
+                let coalesce = Arc::clone(coalesce);
+                let request = request.clone();
+                thread::spawn(move || coalesce.handle(&request).body)
+            })
+            .collect::<Vec<_>>();
+
+        threads
+            .into_iter()
+            .map(|thread| String::from_utf8(thread.join().unwrap()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn identical_requests_share_one_fetch() {
+        let (origin, calls) = slow_origin(Duration::from_millis(200));
+        let coalesce = Arc::new(Coalesce::new(origin));
+
+        let bodies = concurrently(&coalesce, Request::new(Method::GET, "/popular"), 8);
+
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+        assert!(bodies.iter().all(|body| body == "/popular #1"));
+
+        coalesce.handle(&Request::new(Method::GET, "/popular"));
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn different_requests_are_not_shared() {
+        let (origin, calls) = slow_origin(Duration::from_millis(100));
+        let coalesce = Arc::new(Coalesce::new(origin));
+
+        let first = Arc::clone(&coalesce);
+        let other = thread::spawn(move || first.handle(&Request::new(Method::GET, "/a")));
+        coalesce.handle(&Request::new(Method::GET, "/b"));
+        concurrently(&coalesce, Request::new(Method::POST, "/b"), 2);
+        other.join().unwrap();
+
+        assert_eq!(4, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn waiters_fall_through_after_timeout() {
+        let (origin, calls) = slow_origin(Duration::from_millis(200));
+        let coalesce = Arc::new(Coalesce::new(origin).timeout(Duration::from_millis(20)));
+
+        let bodies = concurrently(&coalesce, Request::new(Method::GET, "/slow"), 3);
+
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+        assert_eq!(3, bodies.len());
+    }
+
+    /** A handler that takes a while to answer, echoing `header`, with a count of calls */
+    fn echoing_origin(
+        header: &'static str,
+        response_headers: &'static [(&'static str, &'static str)],
+    ) -> (
+        impl Fn(&Request) -> Response + Send + Sync + 'static,
+        Arc<AtomicUsize>,
+    ) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let origin = move |request: &Request| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(100));
+            let mut response = Response::new();
+            for (name, value) in response_headers {
+                response
+                    .headers
+                    .insert(name.to_string(), vec![value.to_string()]);
+            }
+            response.body = request
+                .header(header)
+                .unwrap_or_default()
+                .as_bytes()
+                .to_vec();
+            response
+        };
+        (origin, calls)
+    }
+
+    /** Handles `first`, then `second` while the first is still in flight */
+    fn overlapping<H: Handler>(
+        coalesce: &Arc<Coalesce<H>>,
+        first: Request,
+        second: Request,
+    ) -> (String, String) {
+        let leader = Arc::clone(coalesce);
+        let leader = thread::spawn(move || leader.handle(&first).body);
+        thread::sleep(Duration::from_millis(20));
+        let second = coalesce.handle(&second).body;
+        (
+            String::from_utf8(leader.join().unwrap()).unwrap(),
+            String::from_utf8(second).unwrap(),
+        )
+    }
+
+    fn with_header(name: &str, value: &str) -> Request {
+        let mut request = Request::new(Method::GET, "/account");
+        request
+            .headers
+            .insert(name.to_string(), vec![value.to_string()]);
+        request
+    }
+
+    #[test]
+    fn requests_with_credentials_are_not_coalesced() {
+        let (origin, calls) = echoing_origin("authorization", &[]);
+        let coalesce = Arc::new(Coalesce::new(origin));
+
+        let (alice, bob) = overlapping(
+            &coalesce,
+            with_header("authorization", "Bearer alice"),
+            with_header("authorization", "Bearer bob"),
+        );
+
+        assert_eq!("Bearer alice", alice);
+        assert_eq!("Bearer bob", bob);
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+
+        overlapping(
+            &coalesce,
+            with_header("cookie", "session=alice"),
+            with_header("cookie", "session=bob"),
+        );
+        assert_eq!(4, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn private_responses_are_not_shared() {
+        for headers in [
+            &[("Cache-Control", "private, max-age=60")][..],
+            &[("Cache-Control", "no-store")],
+            &[("Set-Cookie", "session=abc")],
+        ] {
+            let (origin, calls) = echoing_origin("x-client", headers);
+            let coalesce = Arc::new(Coalesce::new(origin));
+
+            let (first, second) = overlapping(
+                &coalesce,
+                with_header("x-client", "first"),
+                with_header("x-client", "second"),
+            );
+
+            assert_eq!(("first", "second"), (first.as_str(), second.as_str()));
+            assert_eq!(2, calls.load(Ordering::SeqCst), "{:?}", headers);
+        }
+    }
+
+    #[test]
+    fn responses_are_only_shared_with_matching_vary() {
+        let (origin, calls) = echoing_origin("accept-language", &[("Vary", "Accept-Language")]);
+        let coalesce = Arc::new(Coalesce::new(origin));
+
+        let (en, fr) = overlapping(
+            &coalesce,
+            with_header("accept-language", "en"),
+            with_header("accept-language", "fr"),
+        );
+        assert_eq!(("en", "fr"), (en.as_str(), fr.as_str()));
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+
+        let (_, en) = overlapping(
+            &coalesce,
+            with_header("accept-language", "en"),
+            with_header("accept-language", "en"),
+        );
+        assert_eq!("en", en);
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,138 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/** Formats a time as an IMF-fixdate, e.g. "Sun, 06 Nov 1994 08:49:37 GMT" */
+pub fn format_http_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let days = secs.div_euclid(86400);
+    let secs_of_day = secs.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days + 4).rem_euclid(7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+/**
+ * Parses an HTTP-date in any of the three formats recipients must accept:
+ * IMF-fixdate, the obsolete RFC 850 format, and asctime()
+ */
+pub fn parse_http_date(s: &str) -> Option<SystemTime> {
+    let parts = s.split_whitespace().collect::<Vec<_>>();
+
+    let (day, month, year, time) = match parts.as_slice() {
+        // Sun, 06 Nov 1994 08:49:37 GMT
+        [_, day, month, year, time, "GMT"] => (*day, *month, year.parse().ok()?, *time),
+        // Sunday, 06-Nov-94 08:49:37 GMT
+        [_, date, time, "GMT"] => {
+            let mut date_parts = date.split('-');
+            let day = date_parts.next()?;
+            let month = date_parts.next()?;
+            let year: i64 = date_parts.next()?.parse().ok()?;
+            // Two-digit years are read as the nearest matching year in the past century
+            let year = if year < 70 { 2000 + year } else { 1900 + year };
+            (day, month, year, *time)
+        }
+        // Sun Nov  6 08:49:37 1994
+        [_, month, day, time, year] => (*day, *month, year.parse().ok()?, *time),
+        _ => return None,
+    };
+
+    let day: i64 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|name| *name == month)? as i64 + 1;
+
+    let mut time_parts = time.split(':').map(|part| part.parse::<i64>().ok());
+    let hours = time_parts.next()??;
+    let minutes = time_parts.next()??;
+    let seconds = time_parts.next()??;
+
+    if !(1..=31).contains(&day) || hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+
+    let secs = days_from_civil(year, month, day) * 86400 + hours * 3600 + minutes * 60 + seconds;
+    if secs < 0 {
+        return None;
+    }
+
+    Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
+}
+
+/** Days since 1970-01-01 for a proleptic Gregorian date */
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn all_three_formats() {
+        let expected = UNIX_EPOCH + Duration::from_secs(784111777);
+
+        assert_eq!(
+            Some(expected),
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT")
+        );
+        assert_eq!(
+            Some(expected),
+            parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT")
+        );
+        assert_eq!(Some(expected), parse_http_date("Sun Nov  6 08:49:37 1994"));
+        assert_eq!("Sun, 06 Nov 1994 08:49:37 GMT", format_http_date(expected));
+    }
+
+    #[test]
+    fn round_trip() {
+        for secs in [0, 951782400, 1709164799, 4102444800] {
+            let time = UNIX_EPOCH + Duration::from_secs(secs);
+            assert_eq!(Some(time), parse_http_date(&format_http_date(time)));
+        }
+    }
+
+    #[test]
+    fn invalid_dates() {
+        assert_eq!(None, parse_http_date("0"));
+        assert_eq!(None, parse_http_date("Sun, 06 Nov 1994 08:49:37 PST"));
+        assert_eq!(None, parse_http_date("Sun, 06 Foo 1994 08:49:37 GMT"));
+        assert_eq!(None, parse_http_date("Sun, 06 Nov 1994 25:49:37 GMT"));
+    }
+}
@@ -0,0 +1,301 @@
+use std::{collections::HashMap, str::FromStr};
+
+use super::{
+    client::{Client, Url},
+    handler::Handler,
+    method::Method,
+    proxy::{strip_hop_by_hop, upstream_error_status},
+    request::Request,
+    response::Response,
+    status_code::StatusCode,
+    tunnel::{parse_authority, Tunnel},
+};
+
+/**
+ * A destination pattern: `*`, an exact host, or `*.example.com` for any
+ * subdomain, optionally followed by `:port`
+ */
+struct AccessRule {
+    allow: bool,
+    host: String,
+    port: Option<u16>,
+}
+
+impl AccessRule {
+    fn new(allow: bool, pattern: &str) -> Self {
+        let (host, port) = match parse_authority(pattern) {
+            Some((host, port)) => (host, Some(port)),
+            None => (pattern.to_lowercase(), None),
+        };
+        AccessRule { allow, host, port }
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        let host_matches = self.host == "*"
+            || self.host == url.host
+            || self
+                .host
+                .strip_prefix("*.")
+                .is_some_and(|domain| url.host.ends_with(&format!(".{}", domain)));
+
+        host_matches && self.port.is_none_or(|port| port == url.port)
+    }
+}
+
+/**
+ * Serves requests from clients configured to use this server as their HTTP
+ * proxy. Absolute-form requests (`GET http://host/path HTTP/1.1`) are checked
+ * against the access rules and forwarded with a Client; CONNECT requests go
+ * to the Tunnel, if one is set. Rules are checked in order and the first
+ * match wins; destinations no rule matches are denied.
+ */
+pub struct ForwardProxy {
+    client: Client,
+    rules: Vec<AccessRule>,
+    tunnel: Option<Tunnel>,
+    pseudonym: String,
+}
+
+impl Default for ForwardProxy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ForwardProxy {
+    pub const DEFAULT_PSEUDONYM: &'static str = "http_server";
+
+    pub fn new() -> Self {
+        ForwardProxy {
+            client: Client::new().follow_redirects(false),
+            rules: vec![],
+            tunnel: None,
+            pseudonym: Self::DEFAULT_PSEUDONYM.to_string(),
+        }
+    }
+
+    /** Uses `client` for upstream requests; redirects are always passed back, not followed */
+    pub fn client(mut self, client: Client) -> Self {
+        self.client = client.follow_redirects(false);
+        self
+    }
+
+    pub fn allow(mut self, pattern: &str) -> Self {
+        self.rules.push(AccessRule::new(true, pattern));
+        self
+    }
+
+    pub fn deny(mut self, pattern: &str) -> Self {
+        self.rules.push(AccessRule::new(false, pattern));
+        self
+    }
+
+    pub fn tunnel(mut self, tunnel: Tunnel) -> Self {
+        self.tunnel = Some(tunnel);
+        self
+    }
+
+    /** How this proxy names itself in Via headers */
+    pub fn pseudonym(mut self, pseudonym: &str) -> Self {
+        self.pseudonym = pseudonym.to_string();
+        self
+    }
+
+    fn is_allowed(&self, url: &Url) -> bool {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(url))
+            .is_some_and(|rule| rule.allow)
+    }
+
+    fn add_via(&self, headers: &mut HashMap<String, Vec<String>>, via: &str) {
+        let mut value = headers
+            .get("via")
+            .map(|values| values.join(";"))
+            .unwrap_or_default();
+        if !value.is_empty() {
+            value.push_str(", ");
+        }
+        value.push_str(&format!("{} {}", via, self.pseudonym));
+        headers.insert("via".to_string(), vec![value]);
+    }
+}
+
+impl Handler for ForwardProxy {
+    fn handle(&self, request: &Request) -> Response {
+        if request.method == Method::CONNECT {
+            return match &self.tunnel {
+                Some(tunnel) => tunnel.handle(request),
+                None => Response::error(StatusCode::METHOD_NOT_ALLOWED, "CONNECT is not enabled"),
+            };
+        }
+
+        let url = match Url::from_str(&request.raw_target) {
+            Ok(url) => url,
+            Err(_) => {
+                return Response::error(
+                    StatusCode::BAD_REQUEST,
+                    "Proxy requests need an absolute http:// target",
+                )
+            }
+        };
+
+        if !self.is_allowed(&url) {
+            return Response::error(StatusCode::FORBIDDEN, "Destination is not allowed");
+        }
+
+        let mut upstream_request = request.clone();
+        strip_hop_by_hop(&mut upstream_request.headers);
+        // The client sets Host from the target, which takes precedence over any sent
+        upstream_request.headers.remove("host");
+        self.add_via(
+            &mut upstream_request.headers,
+            via_protocol(request.http_version.as_str()),
+        );
+
+        let mut response = match self.client.send(&upstream_request) {
+            Ok(response) => response,
+            Err(err) => {
+                eprintln!("Forwarding to {} failed: {}", url.authority(), err);
+                return Response::error(upstream_error_status(&err), "Upstream request failed");
+            }
+        };
+
+        let upstream_version = via_protocol(response.http_version.as_str());
+        strip_hop_by_hop(&mut response.headers);
+        self.add_via(&mut response.headers, upstream_version);
+
+        // The Client already took the body off any chunked framing
+        if request.method != Method::HEAD && !response.headers.contains_key("content-length") {
+            response.headers.insert(
+                "content-length".to_string(),
+                vec![response.body.len().to_string()],
+            );
+        }
+
+        response
+    }
+}
+
+/** Via names the protocol version without the "HTTP/" prefix */
+fn via_protocol(http_version: &str) -> &str {
+    http_version.trim_start_matches("HTTP/")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufReader, Write},
+        net::{SocketAddr, TcpListener},
+        sync::mpsc,
+        thread,
+    };
+
+    use super::*;
+
+    /** Answers one request with `response`, sending back what it received */
+    fn upstream(response: &'static str) -> (SocketAddr, mpsc::Receiver<Request>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let request = Request::from_reader(&mut reader).unwrap();
+            reader.get_mut().write_all(response.as_bytes()).unwrap();
+            sender.send(request).unwrap();
+        });
+
+        (addr, receiver)
+    }
+
+    fn proxy_request(raw_request: &str) -> Request {
+        Request::from_stream(&mut raw_request.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn forwards_absolute_form_requests() {
+        let (addr, received) = upstream(
+            "HTTP/1.1 200 OK\r\n\
+            Keep-Alive: timeout=5\r\n\
+            Via: 1.1 origin-cache\r\n\
+            Transfer-Encoding: chunked\r\n\
+            \r\n\
+            2\r\nhi\r\n0\r\n\r\n",
+        );
+        let proxy = ForwardProxy::new().allow("127.0.0.1");
+
+        let response = proxy.handle(&proxy_request(&format!(
+            "GET http://{}/status?verbose=1 HTTP/1.1\r\n\
+            Host: {}\r\n\
+            Proxy-Connection: keep-alive\r\n\
+            Proxy-Authorization: Basic dXNlcjpwYXNz\r\n\
+            X-Request-Id: 17\r\n\
+            \r\n",
+            addr, addr
+        )));
+
+        let upstream_request = received.recv().unwrap();
+        assert_eq!("/status?verbose=1", upstream_request.raw_target);
+        assert_eq!(Some("1.1 http_server"), upstream_request.header("Via"));
+        assert_eq!(Some("17"), upstream_request.header("X-Request-Id"));
+        assert_eq!(None, upstream_request.header("Proxy-Connection"));
+        assert_eq!(None, upstream_request.header("Proxy-Authorization"));
+
+        assert_eq!(StatusCode::OK, response.status_code);
+        assert_eq!(b"hi".to_vec(), response.body);
+        assert_eq!(
+            Some("1.1 origin-cache, 1.1 http_server"),
+            response.header("Via")
+        );
+        assert_eq!(Some("2"), response.header("Content-Length"));
+        assert_eq!(None, response.header("Keep-Alive"));
+        assert_eq!(None, response.header("Transfer-Encoding"));
+    }
+
+    #[test]
+    fn redirects_are_passed_back() {
+        let (addr, _) =
+            upstream("HTTP/1.1 302 Found\r\nLocation: /elsewhere\r\nContent-Length: 0\r\n\r\n");
+        let proxy = ForwardProxy::new().allow("*");
+
+        let response = proxy.handle(&proxy_request(&format!(
+            "GET http://{}/ HTTP/1.1\r\n\r\n",
+            addr
+        )));
+
+        assert_eq!(StatusCode::FOUND, response.status_code);
+        assert_eq!(Some("/elsewhere"), response.header("Location"));
+    }
+
+    #[test]
+    fn access_rules_first_match_wins() {
+        let proxy = ForwardProxy::new()
+            .deny("admin.example.com")
+            .allow("*.example.com")
+            .allow("localhost:8080");
+
+        let is_allowed = |url: &str| proxy.is_allowed(&Url::from_str(url).unwrap());
+
+        assert!(is_allowed("http://api.example.com/"));
+        assert!(!is_allowed("http://admin.example.com/"));
+        assert!(!is_allowed("http://example.com/"));
+        assert!(is_allowed("http://localhost:8080/"));
+        assert!(!is_allowed("http://localhost:9090/"));
+        assert!(!is_allowed("http://elsewhere.org/"));
+    }
+
+    #[test]
+    fn rejected_requests() {
+        let proxy = ForwardProxy::new().allow("localhost");
+
+        let denied = proxy.handle(&proxy_request("GET http://example.org/ HTTP/1.1\r\n\r\n"));
+        let origin_form = proxy.handle(&proxy_request("GET /index.html HTTP/1.1\r\n\r\n"));
+        let connect = proxy.handle(&proxy_request("CONNECT localhost:443 HTTP/1.1\r\n\r\n"));
+
+        assert_eq!(StatusCode::FORBIDDEN, denied.status_code);
+        assert_eq!(StatusCode::BAD_REQUEST, origin_form.status_code);
+        assert_eq!(StatusCode::METHOD_NOT_ALLOWED, connect.status_code);
+    }
+}
@@ -0,0 +1,15 @@
+use super::{request::Request, response::Response};
+
+/** Produces a Response for each Request the server reads off a connection */
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, request: &Request) -> Response;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&Request) -> Response + Send + Sync + 'static,
+{
+    fn handle(&self, request: &Request) -> Response {
+        self(request)
+    }
+}
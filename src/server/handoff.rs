@@ -0,0 +1,412 @@
+use std::{
+    env,
+    ffi::OsString,
+    fs,
+    io::{Error, ErrorKind},
+    net::{SocketAddr, TcpListener},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::{net::UnixDatagram, process::CommandExt},
+    },
+    path::{Path, PathBuf},
+    process::{self, Child, Command},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::{
+    handler::Handler,
+    listener::Listener,
+    shutdown::{ServerHandle, DEFAULT_GRACE_PERIOD},
+    signal::Signals,
+    systemd::{listener_from_fd, Notifier},
+};
+
+/** The descriptors of the listeners a new process is handed, comma-separated */
+const LISTEN_FDS: &str = "UPGRADE_LISTEN_FDS";
+/** The datagram socket a new process sends READY=1 to once it's serving */
+const NOTIFY_SOCKET: &str = "UPGRADE_NOTIFY_SOCKET";
+
+/** How long a new process gets to become ready before the upgrade is abandoned */
+pub const DEFAULT_READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/**
+ * Takes the listeners the process that started this one handed over in an
+ * upgrade, or none if it wasn't started that way. With `unset_environment`
+ * the variable naming them is removed, so child processes don't take them
+ * too; that's only sound before any other threads start, as they may be
+ * reading the environment.
+ */
+pub fn inherited_listeners(unset_environment: bool) -> Result<Vec<Listener>, Error> {
+    let fds = env::var(LISTEN_FDS).ok();
+    if unset_environment {
+        env::remove_var(LISTEN_FDS);
+    }
+    let Some(fds) = fds else {
+        return Ok(vec![]);
+    };
+
+    parse_fds(&fds)?
+        .into_iter()
+        // SAFETY: the previous process left these open for us, and nothing else uses them
+        .map(|fd| unsafe { listener_from_fd(fd) })
+        .collect()
+}
+
+fn parse_fds(fds: &str) -> Result<Vec<RawFd>, Error> {
+    fds.split(',')
+        .map(|fd| {
+            fd.parse::<RawFd>().map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{} isn't a list of descriptors: {}", LISTEN_FDS, fds),
+                )
+            })
+        })
+        .collect()
+}
+
+/**
+ * Tells the process that started this one in an upgrade that it's serving,
+ * so that one can shut down. Does nothing if it wasn't started that way.
+ * `unset_environment` is as for inherited_listeners().
+ */
+pub fn notify_parent(unset_environment: bool) -> Result<(), Error> {
+    let socket = env::var(NOTIFY_SOCKET).ok();
+    if unset_environment {
+        env::remove_var(NOTIFY_SOCKET);
+    }
+    match socket {
+        Some(socket) => Notifier::new(&socket)?.ready(),
+        None => Ok(()),
+    }
+}
+
+/**
+ * Replaces the running process with a new one without dropping
+ * connections: the new process inherits the listening sockets, so
+ * connections queue up rather than being refused while it starts, and
+ * this one only stops accepting once the new one says it's ready.
+ */
+pub struct Upgrader {
+    program: PathBuf,
+    args: Vec<OsString>,
+    listeners: Vec<Listener>,
+    ready_timeout: Duration,
+}
+
+impl Upgrader {
+    /**
+     * Upgrades by starting the binary at the path this process was started
+     * from, with the same arguments. Create it at startup: on Linux, the
+     * path of a binary that's since been replaced ends in " (deleted)".
+     */
+    pub fn new(listeners: &[&Listener]) -> Result<Self, Error> {
+        Ok(Upgrader {
+            program: env::current_exe()?,
+            args: env::args_os().skip(1).collect(),
+            listeners: listeners
+                .iter()
+                .map(|listener| listener.try_clone())
+                .collect::<Result<_, _>>()?,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+        })
+    }
+
+    /** Upgrades by starting `program` with `args` instead */
+    pub fn program(
+        mut self,
+        program: impl Into<PathBuf>,
+        args: impl IntoIterator<Item = impl Into<OsString>>,
+    ) -> Self {
+        self.program = program.into();
+        self.args = args.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn ready_timeout(mut self, ready_timeout: Duration) -> Self {
+        self.ready_timeout = ready_timeout;
+        self
+    }
+
+    /**
+     * Starts the new process, returning once it's ready. If it exits or
+     * isn't ready in time it's killed, and this process should carry on.
+     */
+    pub fn spawn(&self) -> Result<Child, Error> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!(
+            "http_server_upgrade_{}_{}.sock",
+            process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        // Left over from an upgrade that was interrupted
+        let _ = fs::remove_file(&path);
+
+        let ready = UnixDatagram::bind(&path)?;
+        let child = self.spawn_notifying(&ready, &path);
+        let _ = fs::remove_file(&path);
+        child
+    }
+
+    fn spawn_notifying(&self, ready: &UnixDatagram, path: &Path) -> Result<Child, Error> {
+        let fds = self
+            .listeners
+            .iter()
+            .map(AsRawFd::as_raw_fd)
+            .collect::<Vec<_>>();
+        let mut command = Command::new(&self.program);
+        command
+            .args(&self.args)
+            .env(
+                LISTEN_FDS,
+                fds.iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(","),
+            )
+            .env(NOTIFY_SOCKET, path);
+        // SAFETY: fcntl is async-signal-safe, and nothing here allocates
+        unsafe {
+            command.pre_exec(move || {
+                // Everything else we open is close-on-exec, so only these are inherited
+                for &fd in &fds {
+                    if libc::fcntl(fd, libc::F_SETFD, 0) != 0 {
+                        return Err(Error::last_os_error());
+                    }
+                }
+                Ok(())
+            })
+        };
+
+        let mut child = command.spawn()?;
+        match wait_until_ready(&mut child, ready, self.ready_timeout) {
+            Ok(()) => Ok(child),
+            Err(err) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                Err(err)
+            }
+        }
+    }
+
+    /**
+     * Upgrades on SIGUSR2. Once the new process is ready, this one shuts
+     * `handle` down, waiting up to `grace_period` for its connections, and
+     * exits. Under systemd, the new process becomes the service's main one.
+     */
+    pub fn upgrade_on_signal(
+        self,
+        handle: ServerHandle,
+        grace_period: Duration,
+    ) -> Result<(), Error> {
+        let mut signals = Signals::new(&[libc::SIGUSR2])?;
+
+        thread::spawn(move || loop {
+            if let Err(err) = signals.wait() {
+                eprintln!("Failed to wait for signals: {}", err);
+                return;
+            }
+            if handle.is_shutting_down() {
+                continue;
+            }
+
+            eprintln!("Starting {} to take over", self.program.display());
+            let child = match self.spawn() {
+                Ok(child) => child,
+                Err(err) => {
+                    eprintln!("Upgrade failed, carrying on serving: {}", err);
+                    continue;
+                }
+            };
+
+            eprintln!("Process {} is serving, shutting down", child.id());
+            match Notifier::from_env() {
+                Ok(Some(notifier)) => {
+                    if let Err(err) = notifier.main_pid(child.id()) {
+                        eprintln!("Failed to notify systemd: {}", err);
+                    }
+                }
+                Ok(None) => {}
+                Err(err) => eprintln!("Unable to use NOTIFY_SOCKET: {}", err),
+            }
+            handle.shutdown(grace_period);
+            // Whatever else the process would do, the new one does now
+            process::exit(0);
+        });
+        Ok(())
+    }
+}
+
+fn wait_until_ready(
+    child: &mut Child,
+    ready: &UnixDatagram,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0; 256];
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Err(Error::other(format!(
+                "New process exited before it was ready: {}",
+                status
+            )));
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                "New process wasn't ready in time",
+            ));
+        }
+
+        // Wakes up now and then to notice if the process has exited
+        ready.set_read_timeout(Some((deadline - now).min(Duration::from_millis(100))))?;
+        match ready.recv(&mut buf) {
+            Ok(length) => {
+                if String::from_utf8_lossy(&buf[..length])
+                    .lines()
+                    .any(|line| line == "READY=1")
+                {
+                    return Ok(());
+                }
+            }
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/**
+ * Serves on localhost:`port`, or on the listener handed over by the
+ * process this one is upgrading, until SIGTERM or SIGINT. On SIGUSR2 it
+ * starts its binary again to take over, then shuts down gracefully.
+ */
+pub fn start_upgradable_server<H: Handler>(port: u16, handler: H) {
+    // Left set, as other threads may be running, but the processes an
+    // Upgrader starts are given their own
+    let listener = match inherited_listeners(false) {
+        Ok(mut listeners) if !listeners.is_empty() => listeners.swap_remove(0),
+        Ok(_) => TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], port)))
+            .map(Listener::from)
+            .unwrap_or_else(|_| panic!("Unable to listen on localhost:{port}")),
+        Err(err) => panic!("Unable to use the listener handed over: {err}"),
+    };
+
+    let handle = ServerHandle::new();
+    if let Err(err) = handle.shutdown_on_signals(DEFAULT_GRACE_PERIOD) {
+        eprintln!("Failed to handle shutdown signals: {}", err);
+    }
+    let upgrading = Upgrader::new(&[&listener])
+        .and_then(|upgrader| upgrader.upgrade_on_signal(handle.clone(), DEFAULT_GRACE_PERIOD));
+    if let Err(err) = upgrading {
+        eprintln!("Unable to upgrade on SIGUSR2: {}", err);
+    }
+    if let Err(err) = notify_parent(false) {
+        eprintln!("Failed to tell the previous process we're ready: {}", err);
+    }
+
+    listener.serve_until_shutdown(handler, &handle);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+    };
+
+    use super::{
+        super::{handle_connection, request::Request, response::Response},
+        *,
+    };
+
+    /** Run by upgrades_hand_over_listeners as the new process; does nothing otherwise */
+    #[test]
+    fn serves_when_handed_a_listener() {
+        if env::var_os(LISTEN_FDS).is_none() {
+            return;
+        }
+
+        let listeners = inherited_listeners(false).unwrap();
+        assert_eq!(1, listeners.len());
+        notify_parent(false).unwrap();
+
+        let stream = listeners[0].accept().unwrap();
+        handle_connection(
+            stream,
+            &|_: &Request| {
+                let mut response = Response::new();
+                response.body = process::id().to_string().into_bytes();
+                response
+            },
+            None,
+        );
+    }
+
+    #[test]
+    fn upgrades_hand_over_listeners() {
+        let listener = Listener::from(TcpListener::bind("127.0.0.1:0").unwrap());
+        let Listener::Tcp(tcp) = &listener else {
+            unreachable!()
+        };
+        let addr = tcp.local_addr().unwrap();
+
+        let upgrader = Upgrader::new(&[&listener]).unwrap().program(
+            env::current_exe().unwrap(),
+            [
+                "--exact",
+                "--quiet",
+                "server::handoff::tests::serves_when_handed_a_listener",
+            ],
+        );
+        let mut child = upgrader.spawn().unwrap();
+        // Only the new process has the socket now
+        drop(upgrader);
+        drop(listener);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(
+            response.ends_with(&format!("\r\n\r\n{}", child.id())),
+            "{}",
+            response
+        );
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn failed_upgrades_are_abandoned() {
+        let listener = Listener::from(TcpListener::bind("127.0.0.1:0").unwrap());
+
+        let err = Upgrader::new(&[&listener])
+            .unwrap()
+            .program("sh", ["-c", "exit 3"])
+            .spawn()
+            .unwrap_err();
+        assert!(err.to_string().contains("exited"), "{}", err);
+
+        let started = Instant::now();
+        let err = Upgrader::new(&[&listener])
+            .unwrap()
+            .program("sleep", ["10"])
+            .ready_timeout(Duration::from_millis(200))
+            .spawn()
+            .unwrap_err();
+        assert_eq!(ErrorKind::TimedOut, err.kind());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn fds_are_parsed() {
+        assert_eq!(vec![3, 4], parse_fds("3,4").unwrap());
+        assert!(parse_fds("3,").is_err());
+        assert!(parse_fds("").is_err());
+    }
+}
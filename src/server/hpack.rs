@@ -0,0 +1,1209 @@
+use std::{
+    collections::VecDeque,
+    io::{Error, ErrorKind},
+    sync::OnceLock,
+};
+
+/** The static table from RFC 7541 Appendix A; index 1 is the first entry */
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/** Huffman codes from RFC 7541 Appendix B as (code, length in bits), by symbol; 256 is EOS */
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13),
+    (0x7fffd8, 23),
+    (0xfffffe2, 28),
+    (0xfffffe3, 28),
+    (0xfffffe4, 28),
+    (0xfffffe5, 28),
+    (0xfffffe6, 28),
+    (0xfffffe7, 28),
+    (0xfffffe8, 28),
+    (0xffffea, 24),
+    (0x3ffffffc, 30),
+    (0xfffffe9, 28),
+    (0xfffffea, 28),
+    (0x3ffffffd, 30),
+    (0xfffffeb, 28),
+    (0xfffffec, 28),
+    (0xfffffed, 28),
+    (0xfffffee, 28),
+    (0xfffffef, 28),
+    (0xffffff0, 28),
+    (0xffffff1, 28),
+    (0xffffff2, 28),
+    (0x3ffffffe, 30),
+    (0xffffff3, 28),
+    (0xffffff4, 28),
+    (0xffffff5, 28),
+    (0xffffff6, 28),
+    (0xffffff7, 28),
+    (0xffffff8, 28),
+    (0xffffff9, 28),
+    (0xffffffa, 28),
+    (0xffffffb, 28),
+    (0x14, 6),
+    (0x3f8, 10),
+    (0x3f9, 10),
+    (0xffa, 12),
+    (0x1ff9, 13),
+    (0x15, 6),
+    (0xf8, 8),
+    (0x7fa, 11),
+    (0x3fa, 10),
+    (0x3fb, 10),
+    (0xf9, 8),
+    (0x7fb, 11),
+    (0xfa, 8),
+    (0x16, 6),
+    (0x17, 6),
+    (0x18, 6),
+    (0x0, 5),
+    (0x1, 5),
+    (0x2, 5),
+    (0x19, 6),
+    (0x1a, 6),
+    (0x1b, 6),
+    (0x1c, 6),
+    (0x1d, 6),
+    (0x1e, 6),
+    (0x1f, 6),
+    (0x5c, 7),
+    (0xfb, 8),
+    (0x7ffc, 15),
+    (0x20, 6),
+    (0xffb, 12),
+    (0x3fc, 10),
+    (0x1ffa, 13),
+    (0x21, 6),
+    (0x5d, 7),
+    (0x5e, 7),
+    (0x5f, 7),
+    (0x60, 7),
+    (0x61, 7),
+    (0x62, 7),
+    (0x63, 7),
+    (0x64, 7),
+    (0x65, 7),
+    (0x66, 7),
+    (0x67, 7),
+    (0x68, 7),
+    (0x69, 7),
+    (0x6a, 7),
+    (0x6b, 7),
+    (0x6c, 7),
+    (0x6d, 7),
+    (0x6e, 7),
+    (0x6f, 7),
+    (0x70, 7),
+    (0x71, 7),
+    (0x72, 7),
+    (0xfc, 8),
+    (0x73, 7),
+    (0xfd, 8),
+    (0x1ffb, 13),
+    (0x7fff0, 19),
+    (0x1ffc, 13),
+    (0x3ffc, 14),
+    (0x22, 6),
+    (0x7ffd, 15),
+    (0x3, 5),
+    (0x23, 6),
+    (0x4, 5),
+    (0x24, 6),
+    (0x5, 5),
+    (0x25, 6),
+    (0x26, 6),
+    (0x27, 6),
+    (0x6, 5),
+    (0x74, 7),
+    (0x75, 7),
+    (0x28, 6),
+    (0x29, 6),
+    (0x2a, 6),
+    (0x7, 5),
+    (0x2b, 6),
+    (0x76, 7),
+    (0x2c, 6),
+    (0x8, 5),
+    (0x9, 5),
+    (0x2d, 6),
+    (0x77, 7),
+    (0x78, 7),
+    (0x79, 7),
+    (0x7a, 7),
+    (0x7b, 7),
+    (0x7ffe, 15),
+    (0x7fc, 11),
+    (0x3ffd, 14),
+    (0x1ffd, 13),
+    (0xffffffc, 28),
+    (0xfffe6, 20),
+    (0x3fffd2, 22),
+    (0xfffe7, 20),
+    (0xfffe8, 20),
+    (0x3fffd3, 22),
+    (0x3fffd4, 22),
+    (0x3fffd5, 22),
+    (0x7fffd9, 23),
+    (0x3fffd6, 22),
+    (0x7fffda, 23),
+    (0x7fffdb, 23),
+    (0x7fffdc, 23),
+    (0x7fffdd, 23),
+    (0x7fffde, 23),
+    (0xffffeb, 24),
+    (0x7fffdf, 23),
+    (0xffffec, 24),
+    (0xffffed, 24),
+    (0x3fffd7, 22),
+    (0x7fffe0, 23),
+    (0xffffee, 24),
+    (0x7fffe1, 23),
+    (0x7fffe2, 23),
+    (0x7fffe3, 23),
+    (0x7fffe4, 23),
+    (0x1fffdc, 21),
+    (0x3fffd8, 22),
+    (0x7fffe5, 23),
+    (0x3fffd9, 22),
+    (0x7fffe6, 23),
+    (0x7fffe7, 23),
+    (0xffffef, 24),
+    (0x3fffda, 22),
+    (0x1fffdd, 21),
+    (0xfffe9, 20),
+    (0x3fffdb, 22),
+    (0x3fffdc, 22),
+    (0x7fffe8, 23),
+    (0x7fffe9, 23),
+    (0x1fffde, 21),
+    (0x7fffea, 23),
+    (0x3fffdd, 22),
+    (0x3fffde, 22),
+    (0xfffff0, 24),
+    (0x1fffdf, 21),
+    (0x3fffdf, 22),
+    (0x7fffeb, 23),
+    (0x7fffec, 23),
+    (0x1fffe0, 21),
+    (0x1fffe1, 21),
+    (0x3fffe0, 22),
+    (0x1fffe2, 21),
+    (0x7fffed, 23),
+    (0x3fffe1, 22),
+    (0x7fffee, 23),
+    (0x7fffef, 23),
+    (0xfffea, 20),
+    (0x3fffe2, 22),
+    (0x3fffe3, 22),
+    (0x3fffe4, 22),
+    (0x7ffff0, 23),
+    (0x3fffe5, 22),
+    (0x3fffe6, 22),
+    (0x7ffff1, 23),
+    (0x3ffffe0, 26),
+    (0x3ffffe1, 26),
+    (0xfffeb, 20),
+    (0x7fff1, 19),
+    (0x3fffe7, 22),
+    (0x7ffff2, 23),
+    (0x3fffe8, 22),
+    (0x1ffffec, 25),
+    (0x3ffffe2, 26),
+    (0x3ffffe3, 26),
+    (0x3ffffe4, 26),
+    (0x7ffffde, 27),
+    (0x7ffffdf, 27),
+    (0x3ffffe5, 26),
+    (0xfffff1, 24),
+    (0x1ffffed, 25),
+    (0x7fff2, 19),
+    (0x1fffe3, 21),
+    (0x3ffffe6, 26),
+    (0x7ffffe0, 27),
+    (0x7ffffe1, 27),
+    (0x3ffffe7, 26),
+    (0x7ffffe2, 27),
+    (0xfffff2, 24),
+    (0x1fffe4, 21),
+    (0x1fffe5, 21),
+    (0x3ffffe8, 26),
+    (0x3ffffe9, 26),
+    (0xffffffd, 28),
+    (0x7ffffe3, 27),
+    (0x7ffffe4, 27),
+    (0x7ffffe5, 27),
+    (0xfffec, 20),
+    (0xfffff3, 24),
+    (0xfffed, 20),
+    (0x1fffe6, 21),
+    (0x3fffe9, 22),
+    (0x1fffe7, 21),
+    (0x1fffe8, 21),
+    (0x7ffff3, 23),
+    (0x3fffea, 22),
+    (0x3fffeb, 22),
+    (0x1ffffee, 25),
+    (0x1ffffef, 25),
+    (0xfffff4, 24),
+    (0xfffff5, 24),
+    (0x3ffffea, 26),
+    (0x7ffff4, 23),
+    (0x3ffffeb, 26),
+    (0x7ffffe6, 27),
+    (0x3ffffec, 26),
+    (0x3ffffed, 26),
+    (0x7ffffe7, 27),
+    (0x7ffffe8, 27),
+    (0x7ffffe9, 27),
+    (0x7ffffea, 27),
+    (0x7ffffeb, 27),
+    (0xffffffe, 28),
+    (0x7ffffec, 27),
+    (0x7ffffed, 27),
+    (0x7ffffee, 27),
+    (0x7ffffef, 27),
+    (0x7fffff0, 27),
+    (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+
+/** The default and initial SETTINGS_HEADER_TABLE_SIZE */
+pub const DEFAULT_TABLE_SIZE: usize = 4096;
+
+/** Each entry's size counts its name and value plus this much overhead */
+const ENTRY_OVERHEAD: usize = 32;
+
+fn compression_error(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+/** The entries added by header blocks, newest first, evicted oldest first */
+struct DynamicTable {
+    entries: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+}
+
+impl DynamicTable {
+    fn new(max_size: usize) -> Self {
+        DynamicTable {
+            entries: VecDeque::new(),
+            size: 0,
+            max_size,
+        }
+    }
+
+    fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+        self.evict(0);
+    }
+
+    /** Makes room for `needed` more bytes */
+    fn evict(&mut self, needed: usize) {
+        while self.size + needed > self.max_size {
+            match self.entries.pop_back() {
+                Some((name, value)) => self.size -= name.len() + value.len() + ENTRY_OVERHEAD,
+                None => return,
+            }
+        }
+    }
+
+    /** An entry too big for the table empties it and isn't added (RFC 7541 section 4.4) */
+    fn insert(&mut self, name: String, value: String) {
+        let entry_size = name.len() + value.len() + ENTRY_OVERHEAD;
+        self.evict(entry_size);
+        if entry_size <= self.max_size {
+            self.size += entry_size;
+            self.entries.push_front((name, value));
+        }
+    }
+
+    /** Looks up an index into the combined static and dynamic tables */
+    fn get(&self, index: usize) -> Option<(&str, &str)> {
+        match index {
+            0 => None,
+            1..=61 => Some(STATIC_TABLE[index - 1]),
+            _ => self
+                .entries
+                .get(index - STATIC_TABLE.len() - 1)
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        }
+    }
+}
+
+/**
+ * Decodes HPACK header blocks (RFC 7541). A connection needs one Decoder for
+ * everything its peer sends, since header blocks build on the dynamic table
+ * left by the ones before.
+ */
+pub struct Decoder {
+    table: DynamicTable,
+    /** The largest table size the peer may switch to, from our SETTINGS */
+    max_table_size: usize,
+    /** The largest header list a block may decode to, sized as the dynamic table counts it */
+    max_header_list_size: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder {
+            table: DynamicTable::new(DEFAULT_TABLE_SIZE),
+            max_table_size: DEFAULT_TABLE_SIZE,
+            max_header_list_size: usize::MAX,
+        }
+    }
+
+    /**
+     * Fails blocks that decode to more than `max_header_list_size`, the
+     * SETTINGS_MAX_HEADER_LIST_SIZE that the peer has been sent. Capping the
+     * encoded block isn't enough, since a one-byte index can stand for a
+     * field as large as the whole dynamic table.
+     */
+    pub fn max_header_list_size(mut self, max_header_list_size: usize) -> Self {
+        self.max_header_list_size = max_header_list_size;
+        self
+    }
+
+    /** Sets the SETTINGS_HEADER_TABLE_SIZE that the peer has been sent */
+    pub fn set_max_table_size(&mut self, max_table_size: usize) {
+        self.max_table_size = max_table_size;
+        if self.table.max_size > max_table_size {
+            self.table.set_max_size(max_table_size);
+        }
+    }
+
+    /** Decodes a complete header block into (name, value) pairs, in order */
+    pub fn decode(&mut self, mut block: &[u8]) -> Result<Vec<(String, String)>, Error> {
+        let mut headers = vec![];
+        let mut header_list_size = 0;
+
+        while let Some(&first) = block.first() {
+            let (name, value) = if first & 0x80 != 0 {
+                // Indexed header field
+                let index = decode_integer(&mut block, 7)?;
+                let (name, value) = self
+                    .table
+                    .get(index)
+                    .ok_or_else(|| compression_error("Header index out of range"))?;
+                (name.to_string(), value.to_string())
+            } else if first & 0xc0 == 0x40 {
+                // Literal with incremental indexing
+                let (name, value) = self.decode_literal(&mut block, 6)?;
+                self.table.insert(name.clone(), value.clone());
+                (name, value)
+            } else if first & 0xe0 == 0x20 {
+                // Dynamic table size update, only allowed before any fields
+                if !headers.is_empty() {
+                    return Err(compression_error(
+                        "Table size update after the start of a header block",
+                    ));
+                }
+                let max_size = decode_integer(&mut block, 5)?;
+                if max_size > self.max_table_size {
+                    return Err(compression_error("Table size update above the limit"));
+                }
+                self.table.set_max_size(max_size);
+                continue;
+            } else {
+                // Literal without indexing (0000) or never indexed (0001)
+                self.decode_literal(&mut block, 4)?
+            };
+
+            // RFC 9113 section 6.5.2
+            header_list_size += name.len() + value.len() + ENTRY_OVERHEAD;
+            if header_list_size > self.max_header_list_size {
+                return Err(compression_error("Header list is too large"));
+            }
+            headers.push((name, value));
+        }
+
+        Ok(headers)
+    }
+
+    /** A literal field's name is either an index (when nonzero) or a string */
+    fn decode_literal(
+        &self,
+        block: &mut &[u8],
+        prefix_bits: u8,
+    ) -> Result<(String, String), Error> {
+        let name = match decode_integer(block, prefix_bits)? {
+            0 => decode_string(block, 7)?,
+            index => self
+                .table
+                .get(index)
+                .ok_or_else(|| compression_error("Header index out of range"))?
+                .0
+                .to_string(),
+        };
+        let value = decode_string(block, 7)?;
+        Ok((name, value))
+    }
+}
+
+/** Fields whose values are never added to a dynamic table unless configured otherwise */
+const DEFAULT_NEVER_INDEXED: [&str; 2] = ["authorization", "proxy-authorization"];
+
+/**
+ * Encodes HPACK header blocks. Fields already in the static or dynamic table
+ * are sent as an index; others are sent as literals and added to the
+ * dynamic table, except sensitive ones, which are sent as never-indexed
+ * literals so no intermediary stores them either. Strings are Huffman coded
+ * unless that would make them longer.
+ */
+pub struct Encoder {
+    table: DynamicTable,
+    huffman: bool,
+    never_indexed: Vec<String>,
+    /** Table size changes not yet announced: the smallest, then the latest */
+    size_updates: Vec<usize>,
+}
+
+impl Default for Encoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Encoder {
+            table: DynamicTable::new(DEFAULT_TABLE_SIZE),
+            huffman: true,
+            never_indexed: DEFAULT_NEVER_INDEXED
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            size_updates: vec![],
+        }
+    }
+
+    /** The dynamic table size both sides start with, without announcing it */
+    pub fn table_size(mut self, table_size: usize) -> Self {
+        self.table.set_max_size(table_size);
+        self
+    }
+
+    pub fn huffman(mut self, huffman: bool) -> Self {
+        self.huffman = huffman;
+        self
+    }
+
+    /** Sends `name` as a never-indexed literal */
+    pub fn never_index(mut self, name: &str) -> Self {
+        self.never_indexed.push(name.to_lowercase());
+        self
+    }
+
+    /**
+     * Changes the dynamic table size, e.g. after the peer's
+     * SETTINGS_HEADER_TABLE_SIZE. The change is announced at the start of
+     * the next header block (RFC 7541 section 4.2).
+     */
+    pub fn set_max_table_size(&mut self, max_table_size: usize) {
+        if max_table_size == self.table.max_size && self.size_updates.is_empty() {
+            return;
+        }
+
+        let smallest = self
+            .size_updates
+            .first()
+            .map_or(max_table_size, |&smallest| smallest.min(max_table_size));
+        self.size_updates = if smallest == max_table_size {
+            vec![max_table_size]
+        } else {
+            vec![smallest, max_table_size]
+        };
+        self.table.set_max_size(smallest);
+    }
+
+    pub fn encode(&mut self, headers: &[(String, String)]) -> Vec<u8> {
+        let mut block = vec![];
+
+        for max_size in std::mem::take(&mut self.size_updates) {
+            encode_integer(&mut block, 0x20, 5, max_size);
+            self.table.set_max_size(max_size);
+        }
+
+        for (name, value) in headers {
+            let (full_index, name_index) = self.find(name, value);
+
+            if self.never_indexed.contains(&name.to_lowercase()) {
+                self.encode_literal(&mut block, 0x10, 4, name_index, name, value);
+                continue;
+            }
+
+            if let Some(index) = full_index {
+                encode_integer(&mut block, 0x80, 7, index);
+                continue;
+            }
+
+            self.encode_literal(&mut block, 0x40, 6, name_index, name, value);
+            self.table.insert(name.clone(), value.clone());
+        }
+
+        block
+    }
+
+    /** The index of an entry matching the whole field, and of one matching its name */
+    fn find(&self, name: &str, value: &str) -> (Option<usize>, Option<usize>) {
+        let dynamic_entries = self
+            .table
+            .entries
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()));
+        let mut entries = STATIC_TABLE.iter().copied().chain(dynamic_entries);
+
+        let mut name_index = None;
+        for index in 1.. {
+            match entries.next() {
+                Some(entry) if entry == (name, value) => return (Some(index), Some(index)),
+                Some((entry_name, _)) if entry_name == name && name_index.is_none() => {
+                    name_index = Some(index)
+                }
+                Some(_) => {}
+                None => break,
+            }
+        }
+        (None, name_index)
+    }
+
+    fn encode_literal(
+        &self,
+        block: &mut Vec<u8>,
+        flags: u8,
+        prefix_bits: u8,
+        name_index: Option<usize>,
+        name: &str,
+        value: &str,
+    ) {
+        match name_index {
+            Some(index) => encode_integer(block, flags, prefix_bits, index),
+            None => {
+                block.push(flags);
+                encode_string(block, 0x00, 7, name, self.huffman);
+            }
+        }
+        encode_string(block, 0x00, 7, value, self.huffman);
+    }
+}
+
+/** RFC 7541 section 5.1: an integer in the low `prefix_bits` of the first byte */
+pub(super) fn decode_integer(block: &mut &[u8], prefix_bits: u8) -> Result<usize, Error> {
+    let (&first, mut rest) = block
+        .split_first()
+        .ok_or_else(|| compression_error("Header block ends inside an integer"))?;
+    let max_prefix = (1usize << prefix_bits) - 1;
+
+    let mut value = first as usize & max_prefix;
+    if value == max_prefix {
+        let mut shift = 0;
+        loop {
+            let (&byte, remaining) = rest
+                .split_first()
+                .ok_or_else(|| compression_error("Header block ends inside an integer"))?;
+            rest = remaining;
+            if shift > 28 {
+                return Err(compression_error("Integer is too large"));
+            }
+            value += (byte as usize & 0x7f) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+    }
+
+    *block = rest;
+    Ok(value)
+}
+
+pub(super) fn encode_integer(block: &mut Vec<u8>, flags: u8, prefix_bits: u8, value: usize) {
+    let max_prefix = (1usize << prefix_bits) - 1;
+    if value < max_prefix {
+        block.push(flags | value as u8);
+        return;
+    }
+
+    block.push(flags | max_prefix as u8);
+    let mut value = value - max_prefix;
+    while value >= 0x80 {
+        block.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+/**
+ * RFC 7541 section 5.2: a length-prefixed string, Huffman coded if the bit
+ * above the prefix is set. HPACK always uses a 7-bit prefix; QPACK also
+ * packs strings after other flags.
+ */
+pub(super) fn decode_string(block: &mut &[u8], prefix_bits: u8) -> Result<String, Error> {
+    let huffman = block
+        .first()
+        .is_some_and(|first| first & (1 << prefix_bits) != 0);
+    let length = decode_integer(block, prefix_bits)?;
+    if block.len() < length {
+        return Err(compression_error("Header block ends inside a string"));
+    }
+
+    let (bytes, rest) = block.split_at(length);
+    *block = rest;
+
+    let bytes = if huffman {
+        huffman_decode(bytes)?
+    } else {
+        bytes.to_vec()
+    };
+    String::from_utf8(bytes).map_err(|_| compression_error("Header is not valid UTF-8"))
+}
+
+/** Huffman codes `value` if asked to, unless that would make it longer */
+pub(super) fn encode_string(
+    block: &mut Vec<u8>,
+    flags: u8,
+    prefix_bits: u8,
+    value: &str,
+    huffman: bool,
+) {
+    let bytes = value.as_bytes();
+    if huffman && huffman_encoded_length(bytes) <= bytes.len() {
+        let encoded = huffman_encode(bytes);
+        encode_integer(block, flags | 1 << prefix_bits, prefix_bits, encoded.len());
+        block.extend_from_slice(&encoded);
+    } else {
+        encode_integer(block, flags, prefix_bits, bytes.len());
+        block.extend_from_slice(bytes);
+    }
+}
+
+fn huffman_encoded_length(bytes: &[u8]) -> usize {
+    let bits = bytes
+        .iter()
+        .map(|&byte| HUFFMAN_CODES[byte as usize].1 as usize)
+        .sum::<usize>();
+    bits.div_ceil(8)
+}
+
+fn huffman_encode(bytes: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(huffman_encoded_length(bytes));
+    // Bits not yet written out, aligned to the right
+    let mut pending: u64 = 0;
+    let mut pending_bits = 0;
+
+    for &byte in bytes {
+        let (code, length) = HUFFMAN_CODES[byte as usize];
+        pending = (pending << length) | code as u64;
+        pending_bits += length;
+        while pending_bits >= 8 {
+            pending_bits -= 8;
+            encoded.push((pending >> pending_bits) as u8);
+        }
+    }
+
+    // Pad with the most significant bits of EOS, which are all ones
+    if pending_bits > 0 {
+        let padding = 8 - pending_bits;
+        encoded.push(((pending << padding) | ((1 << padding) - 1)) as u8);
+    }
+
+    encoded
+}
+
+/** A node in the Huffman decoding tree: children for a 0 and a 1 bit, or a symbol at a leaf */
+#[derive(Clone, Copy, Default)]
+struct HuffmanNode {
+    children: [Option<u16>; 2],
+    symbol: Option<u16>,
+}
+
+fn huffman_tree() -> &'static [HuffmanNode] {
+    static TREE: OnceLock<Vec<HuffmanNode>> = OnceLock::new();
+
+    TREE.get_or_init(|| {
+        let mut tree = vec![HuffmanNode::default()];
+        for (symbol, &(code, length)) in HUFFMAN_CODES.iter().enumerate() {
+            let mut node = 0;
+            for bit_index in (0..length).rev() {
+                let bit = (code >> bit_index) as usize & 1;
+                node = match tree[node].children[bit] {
+                    Some(child) => child as usize,
+                    None => {
+                        tree.push(HuffmanNode::default());
+                        let child = tree.len() - 1;
+                        tree[node].children[bit] = Some(child as u16);
+                        child
+                    }
+                };
+            }
+            tree[node].symbol = Some(symbol as u16);
+        }
+        tree
+    })
+}
+
+fn huffman_decode(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    let tree = huffman_tree();
+    let mut decoded = vec![];
+    let mut node = 0;
+    // Bits read since the last symbol, and whether they've all been ones
+    let mut pending_bits = 0;
+    let mut all_ones = true;
+
+    for byte in bytes {
+        for bit_index in (0..8).rev() {
+            let bit = (byte >> bit_index) as usize & 1;
+            node = tree[node].children[bit]
+                .ok_or_else(|| compression_error("Invalid Huffman code"))?
+                as usize;
+            pending_bits += 1;
+            all_ones &= bit == 1;
+
+            if let Some(symbol) = tree[node].symbol {
+                if symbol == 256 {
+                    return Err(compression_error("Huffman string contains EOS"));
+                }
+                decoded.push(symbol as u8);
+                node = 0;
+                pending_bits = 0;
+                all_ones = true;
+            }
+        }
+    }
+
+    // Padding is the most significant bits of EOS, so up to 7 one bits
+    if pending_bits > 7 || !all_ones {
+        return Err(compression_error("Invalid Huffman padding"));
+    }
+
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(fields: &[(&str, &str)]) -> Vec<(String, String)> {
+        fields
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /** A header block in hex, the header list it encodes, and the table size after */
+    type Example<'a> = (&'a str, &'a [(&'a str, &'a str)], usize);
+
+    fn hex(hex: &str) -> Vec<u8> {
+        let digits = hex.split_whitespace().collect::<String>();
+        (0..digits.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /**
+     * Encodes and decodes each header list in turn with the same encoder and
+     * decoder, checking the block and both dynamic tables' size after each
+     */
+    fn check_sequence(mut encoder: Encoder, mut decoder: Decoder, examples: &[Example]) {
+        for (block, headers, table_size) in examples {
+            let headers = fields(headers);
+            assert_eq!(hex(block), encoder.encode(&headers));
+            assert_eq!(headers, decoder.decode(&hex(block)).unwrap());
+            assert_eq!(*table_size, encoder.table.size);
+            assert_eq!(*table_size, decoder.table.size);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let headers = fields(&[
+            (":status", "200"),
+            (":status", "201"),
+            ("content-type", "text/plain"),
+            ("x-request-id", "17"),
+        ]);
+
+        let block = Encoder::new().encode(&headers);
+
+        assert_eq!(0x88, block[0]);
+        assert_eq!(headers, Decoder::new().decode(&block).unwrap());
+    }
+
+    #[test]
+    fn huffman_strings_and_dynamic_table() {
+        let mut decoder = Decoder::new();
+        // A literal with incremental indexing, then the same field by its new index
+        let block = [
+            0x41, 0x8c, 0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0, 0xab, 0x90, 0xf4, 0xff,
+            0xbe,
+        ];
+
+        assert_eq!(
+            fields(&[
+                (":authority", "www.example.com"),
+                (":authority", "www.example.com")
+            ]),
+            decoder.decode(&block).unwrap()
+        );
+    }
+
+    #[test]
+    fn header_list_size_is_capped_after_decoding() {
+        let mut encoder = Encoder::new().huffman(false);
+        let big = fields(&[("x-big", &"a".repeat(4000))]);
+        let mut block = encoder.encode(&big);
+        // Each byte refers back to the 4 KiB field just added
+        block.extend_from_slice(&[0xbe; 16]);
+
+        let mut decoder = Decoder::new().max_header_list_size(64 * 1024);
+        assert_eq!(
+            ErrorKind::InvalidData,
+            decoder.decode(&block).unwrap_err().kind()
+        );
+
+        let mut decoder = Decoder::new().max_header_list_size(64 * 1024);
+        let headers = decoder.decode(&block[..block.len() - 1]).unwrap();
+        assert_eq!(16, headers.len());
+    }
+
+    #[test]
+    fn invalid_blocks() {
+        let mut decoder = Decoder::new();
+
+        assert!(decoder.decode(&[0x80]).is_err());
+        assert!(decoder.decode(&[0xff, 0x00]).is_err());
+        assert!(decoder.decode(&[0x3f, 0xe2, 0x1f]).is_err());
+        assert!(decoder.decode(&[0x04, 0x85, b'/']).is_err());
+    }
+
+    /** RFC 7541 Appendix C.1 */
+    #[test]
+    fn integer_representation() {
+        let examples = [(10, 5, "0a"), (1337, 5, "1f9a0a"), (42, 8, "2a")];
+
+        for (value, prefix_bits, encoded) in examples {
+            let mut block = vec![];
+            encode_integer(&mut block, 0x00, prefix_bits, value);
+            assert_eq!(hex(encoded), block);
+            assert_eq!(value, decode_integer(&mut &block[..], prefix_bits).unwrap());
+        }
+    }
+
+    /** RFC 7541 Appendix C.2 */
+    #[test]
+    fn header_field_representation() {
+        let mut decoder = Decoder::new();
+        assert_eq!(
+            fields(&[("custom-key", "custom-header")]),
+            decoder
+                .decode(&hex(
+                    "400a 6375 7374 6f6d 2d6b 6579 0d63 7573 746f 6d2d 6865 6164 6572"
+                ))
+                .unwrap()
+        );
+        assert_eq!(55, decoder.table.size);
+
+        let mut decoder = Decoder::new();
+        assert_eq!(
+            fields(&[(":path", "/sample/path")]),
+            decoder
+                .decode(&hex("040c 2f73 616d 706c 652f 7061 7468"))
+                .unwrap()
+        );
+        assert_eq!(0, decoder.table.size);
+
+        let password = fields(&[("password", "secret")]);
+        let block = hex("1008 7061 7373 776f 7264 0673 6563 7265 74");
+        let mut encoder = Encoder::new().huffman(false).never_index("Password");
+        assert_eq!(block, encoder.encode(&password));
+        assert_eq!(0, encoder.table.size);
+        assert_eq!(password, Decoder::new().decode(&block).unwrap());
+
+        assert_eq!(
+            fields(&[(":method", "GET")]),
+            Decoder::new().decode(&hex("82")).unwrap()
+        );
+    }
+
+    /** RFC 7541 Appendix C.3 */
+    #[test]
+    fn requests_without_huffman() {
+        check_sequence(
+            Encoder::new().huffman(false),
+            Decoder::new(),
+            &[
+                (
+                    "8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+                    &[
+                        (":method", "GET"),
+                        (":scheme", "http"),
+                        (":path", "/"),
+                        (":authority", "www.example.com"),
+                    ],
+                    57,
+                ),
+                (
+                    "8286 84be 5808 6e6f 2d63 6163 6865",
+                    &[
+                        (":method", "GET"),
+                        (":scheme", "http"),
+                        (":path", "/"),
+                        (":authority", "www.example.com"),
+                        ("cache-control", "no-cache"),
+                    ],
+                    110,
+                ),
+                (
+                    "8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65",
+                    &[
+                        (":method", "GET"),
+                        (":scheme", "https"),
+                        (":path", "/index.html"),
+                        (":authority", "www.example.com"),
+                        ("custom-key", "custom-value"),
+                    ],
+                    164,
+                ),
+            ],
+        );
+    }
+
+    /** RFC 7541 Appendix C.4 */
+    #[test]
+    fn requests_with_huffman() {
+        check_sequence(
+            Encoder::new(),
+            Decoder::new(),
+            &[
+                (
+                    "8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff",
+                    &[
+                        (":method", "GET"),
+                        (":scheme", "http"),
+                        (":path", "/"),
+                        (":authority", "www.example.com"),
+                    ],
+                    57,
+                ),
+                (
+                    "8286 84be 5886 a8eb 1064 9cbf",
+                    &[
+                        (":method", "GET"),
+                        (":scheme", "http"),
+                        (":path", "/"),
+                        (":authority", "www.example.com"),
+                        ("cache-control", "no-cache"),
+                    ],
+                    110,
+                ),
+                (
+                    "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+                    &[
+                        (":method", "GET"),
+                        (":scheme", "https"),
+                        (":path", "/index.html"),
+                        (":authority", "www.example.com"),
+                        ("custom-key", "custom-value"),
+                    ],
+                    164,
+                ),
+            ],
+        );
+    }
+
+    const RESPONSE_1: [(&str, &str); 4] = [
+        (":status", "302"),
+        ("cache-control", "private"),
+        ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+        ("location", "https://www.example.com"),
+    ];
+    const RESPONSE_2: [(&str, &str); 4] = [
+        (":status", "307"),
+        ("cache-control", "private"),
+        ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+        ("location", "https://www.example.com"),
+    ];
+    const RESPONSE_3: [(&str, &str); 6] = [
+        (":status", "200"),
+        ("cache-control", "private"),
+        ("date", "Mon, 21 Oct 2013 20:13:22 GMT"),
+        ("location", "https://www.example.com"),
+        ("content-encoding", "gzip"),
+        (
+            "set-cookie",
+            "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1",
+        ),
+    ];
+
+    fn small_table_decoder() -> Decoder {
+        let mut decoder = Decoder::new();
+        decoder.set_max_table_size(256);
+        decoder
+    }
+
+    /** RFC 7541 Appendix C.5, which evicts from a 256 byte table */
+    #[test]
+    fn responses_without_huffman() {
+        check_sequence(
+            Encoder::new().huffman(false).table_size(256),
+            small_table_decoder(),
+            &[
+                (
+                    "4803 3330 3258 0770 7269 7661 7465 611d
+                    4d6f 6e2c 2032 3120 4f63 7420 3230 3133
+                    2032 303a 3133 3a32 3120 474d 546e 1768
+                    7474 7073 3a2f 2f77 7777 2e65 7861 6d70
+                    6c65 2e63 6f6d",
+                    &RESPONSE_1,
+                    222,
+                ),
+                ("4803 3330 37c1 c0bf", &RESPONSE_2, 222),
+                (
+                    "88c1 611d 4d6f 6e2c 2032 3120 4f63 7420
+                    3230 3133 2032 303a 3133 3a32 3220 474d
+                    54c0 5a04 677a 6970 7738 666f 6f3d 4153
+                    444a 4b48 514b 425a 584f 5157 454f 5049
+                    5541 5851 5745 4f49 553b 206d 6178 2d61
+                    6765 3d33 3630 303b 2076 6572 7369 6f6e
+                    3d31",
+                    &RESPONSE_3,
+                    215,
+                ),
+            ],
+        );
+    }
+
+    /** RFC 7541 Appendix C.6 */
+    #[test]
+    fn responses_with_huffman() {
+        check_sequence(
+            Encoder::new().table_size(256),
+            small_table_decoder(),
+            &[
+                (
+                    "4882 6402 5885 aec3 771a 4b61 96d0 7abe
+                    9410 54d4 44a8 2005 9504 0b81 66e0 82a6
+                    2d1b ff6e 919d 29ad 1718 63c7 8f0b 97c8
+                    e9ae 82ae 43d3",
+                    &RESPONSE_1,
+                    222,
+                ),
+                ("4883 640e ffc1 c0bf", &RESPONSE_2, 222),
+                (
+                    "88c1 6196 d07a be94 1054 d444 a820 0595
+                    040b 8166 e084 a62d 1bff c05a 839b d9ab
+                    77ad 94e7 821d d7f2 e6c7 b335 dfdf cd5b
+                    3960 d5af 2708 7f36 72c1 ab27 0fb5 291f
+                    9587 3160 65c0 03ed 4ee5 b106 3d50 07",
+                    &RESPONSE_3,
+                    215,
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn table_size_updates() {
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+        let headers = fields(&[("x-trace", "abc")]);
+        decoder.decode(&encoder.encode(&headers)).unwrap();
+        assert_eq!(42, decoder.table.size);
+
+        // Shrinking to 0 and back evicts everything, announced as two updates
+        encoder.set_max_table_size(0);
+        encoder.set_max_table_size(100);
+        let block = encoder.encode(&headers);
+        assert_eq!(hex("20 3f45"), block[..3]);
+        assert_eq!(headers, decoder.decode(&block).unwrap());
+        assert_eq!(42, decoder.table.size);
+        assert_eq!(100, decoder.table.max_size);
+
+        let mut limited = Decoder::new();
+        limited.set_max_table_size(64);
+        assert!(limited.decode(&hex("3f45")).is_err());
+        assert!(limited.decode(&hex("82 20")).is_err());
+    }
+
+    #[test]
+    fn huffman_round_trip() {
+        let all_bytes = (0..=255u8).collect::<Vec<_>>();
+        let encoded = huffman_encode(&all_bytes);
+        assert_eq!(huffman_encoded_length(&all_bytes), encoded.len());
+        assert_eq!(all_bytes, huffman_decode(&encoded).unwrap());
+
+        // Padding longer than 7 bits, or not all ones, is an error
+        assert!(huffman_decode(&[0xff, 0xff]).is_err());
+        assert!(huffman_decode(&hex("f1e3 c2e5 f23a 6ba0 ab90 f4fe")).is_err());
+    }
+}
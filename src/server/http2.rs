@@ -0,0 +1,1752 @@
+use std::{
+    collections::HashMap,
+    io::{BufReader, BufWriter, Error, ErrorKind, Read, Write},
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, Scope},
+};
+
+use super::{
+    client_certificate::ClientCertificate,
+    handler::Handler,
+    hpack::{Decoder, Encoder, DEFAULT_TABLE_SIZE},
+    http_version::HttpVersion,
+    listener::{PeerCredentials, Stream},
+    method::Method,
+    request::Request,
+    response::Response,
+    shutdown::Tracked,
+    status_code::StatusCode,
+};
+
+/** What a client with prior knowledge sends before its first frame */
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+/** Enough of the preface to tell it from an HTTP/1.1 request */
+const PREFACE_METHOD: &[u8] = b"PRI ";
+
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x1;
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+/** Error codes sent in RST_STREAM and GOAWAY (RFC 9113 section 7) */
+pub const NO_ERROR: u32 = 0x0;
+pub const PROTOCOL_ERROR: u32 = 0x1;
+pub const INTERNAL_ERROR: u32 = 0x2;
+pub const FLOW_CONTROL_ERROR: u32 = 0x3;
+pub const STREAM_CLOSED: u32 = 0x5;
+pub const FRAME_SIZE_ERROR: u32 = 0x6;
+pub const REFUSED_STREAM: u32 = 0x7;
+pub const CANCEL: u32 = 0x8;
+pub const COMPRESSION_ERROR: u32 = 0x9;
+pub const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+const DEFAULT_WINDOW_SIZE: i64 = 65_535;
+const MAX_WINDOW_SIZE: i64 = (1 << 31) - 1;
+const DEFAULT_MAX_FRAME_SIZE: usize = 16_384;
+const MAX_FRAME_SIZE_LIMIT: usize = (1 << 24) - 1;
+const MAX_CONCURRENT_STREAMS: usize = 100;
+const MAX_STREAM_ID: u32 = (1 << 31) - 1;
+/**
+ * The largest header list we accept, as SETTINGS_MAX_HEADER_LIST_SIZE counts
+ * it once decoded, and the largest block across HEADERS and CONTINUATION
+ */
+const MAX_HEADER_LIST_SIZE: usize = 64 * 1024;
+
+/** The largest request body buffered for a handler; streams sending more are cancelled */
+const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+/**
+ * The receive window for the whole connection. Bodies only give it back once
+ * they're handed to the handler, so it bounds the memory taken by those still
+ * arriving. It's larger than MAX_BODY_SIZE so a body that's too large is
+ * cancelled rather than left waiting for window that never comes.
+ */
+const CONNECTION_WINDOW_SIZE: usize = 2 * MAX_BODY_SIZE;
+
+/** Headers that only mean something on an HTTP/1.1 connection */
+pub(super) const CONNECTION_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/** Why a connection ended early */
+enum Closed {
+    Io(Error),
+    /** A connection error, with the code and message to send in GOAWAY */
+    GoAway(u32, &'static str),
+}
+
+impl From<Error> for Closed {
+    fn from(err: Error) -> Self {
+        Closed::Io(err)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    fn new(kind: u8, flags: u8, stream_id: u32, payload: Vec<u8>) -> Self {
+        Frame {
+            kind,
+            flags,
+            stream_id,
+            payload,
+        }
+    }
+
+    fn read_from(reader: &mut dyn Read, max_size: usize) -> Result<Self, Closed> {
+        let mut header = [0; 9];
+        reader.read_exact(&mut header)?;
+
+        let length = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        if length > max_size {
+            return Err(Closed::GoAway(FRAME_SIZE_ERROR, "Frame is too large"));
+        }
+
+        let mut payload = vec![0; length];
+        reader.read_exact(&mut payload)?;
+
+        Ok(Frame {
+            kind: header[3],
+            flags: header[4],
+            // The high bit is reserved and ignored
+            stream_id: u32::from_be_bytes([header[5], header[6], header[7], header[8]])
+                & 0x7fff_ffff,
+            payload,
+        })
+    }
+
+    fn write_to(&self, stream: &mut dyn Write) -> Result<(), Error> {
+        let length = (self.payload.len() as u32).to_be_bytes();
+        stream.write_all(&length[1..])?;
+        stream.write_all(&[self.kind, self.flags])?;
+        stream.write_all(&self.stream_id.to_be_bytes())?;
+        stream.write_all(&self.payload)
+    }
+
+    fn has_flag(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    /** The payload without any padding (RFC 9113 section 6.1) */
+    fn unpadded(&self) -> Result<&[u8], Closed> {
+        if !self.has_flag(PADDED) {
+            return Ok(&self.payload);
+        }
+
+        let (&pad_length, rest) = self
+            .payload
+            .split_first()
+            .ok_or(Closed::GoAway(FRAME_SIZE_ERROR, "Padded frame is empty"))?;
+        if pad_length as usize > rest.len() {
+            return Err(Closed::GoAway(
+                PROTOCOL_ERROR,
+                "Padding is longer than the frame",
+            ));
+        }
+        Ok(&rest[..rest.len() - pad_length as usize])
+    }
+}
+
+fn window_update(stream_id: u32, increment: usize) -> Frame {
+    Frame::new(
+        WINDOW_UPDATE,
+        0,
+        stream_id,
+        (increment as u32).to_be_bytes().to_vec(),
+    )
+}
+
+struct Writer {
+    stream: BufWriter<Box<dyn Write + Send>>,
+    encoder: Encoder,
+}
+
+impl Writer {
+    fn write_frame(&mut self, frame: &Frame) -> Result<(), Error> {
+        frame.write_to(&mut self.stream)?;
+        self.stream.flush()
+    }
+}
+
+struct StreamState {
+    send_window: i64,
+    /** Set when either side resets the stream while its response is being sent */
+    reset: bool,
+}
+
+/** A request whose body is still arriving */
+struct Receiving {
+    request: Request,
+    /** How much more DATA the peer may send on the stream */
+    window: i64,
+}
+
+struct State {
+    /** The connection-level window for DATA we send */
+    send_window: i64,
+    /** The connection-level window for DATA the peer sends */
+    receive_window: i64,
+    /** Open streams, from when their HEADERS arrive until the response is sent */
+    streams: HashMap<u32, StreamState>,
+    initial_window_size: i64,
+    max_frame_size: usize,
+    last_stream_id: u32,
+    closed: bool,
+}
+
+/**
+ * A server-side HTTP/2 connection (RFC 9113). Frames are read on the
+ * connection's own thread; each request is handled on a thread of its own
+ * so slow responses don't hold up the rest, and writes go through a shared
+ * Writer. DATA waits on a Condvar while the peer's flow-control windows are
+ * used up.
+ */
+struct Connection<'a> {
+    handler: &'a dyn Handler,
+    peer: Peer,
+    /** Shared with the GOAWAY sent when the server starts shutting down */
+    writer: Arc<Mutex<Writer>>,
+    state: Mutex<State>,
+    tracked: Option<&'a Tracked>,
+    window_update: Condvar,
+}
+
+/** What's known about the other end of a connection, copied onto each request it carries */
+struct Peer {
+    addr: Option<SocketAddr>,
+    client_certificate: Option<ClientCertificate>,
+    credentials: Option<PeerCredentials>,
+    tls: bool,
+}
+
+impl Peer {
+    fn apply(&self, request: &mut Request) {
+        request.peer_addr = self.addr;
+        request.client_certificate = self.client_certificate.clone();
+        request.peer_credentials = self.credentials;
+        request.tls = self.tls;
+    }
+}
+
+/**
+ * Serves an HTTP/2 connection whose client sent the connection preface, which
+ * is expected to be the next thing in `reader`. Returns once the client
+ * closes the connection or after a connection error. The client is sent a
+ * GOAWAY once `tracked`'s server starts shutting down.
+ */
+pub fn serve(reader: BufReader<Stream>, handler: &dyn Handler, tracked: Option<&Tracked>) {
+    serve_socket(reader, handler, None, &[], tracked);
+}
+
+/**
+ * Serves HTTP/2 over TLS once ALPN has picked h2. `reader` and `writer` are the two directions of
+ * the same connection; the writer is shared by every stream's response.
+ * `client_certificate` is put on every request the connection carries.
+ * The client is sent a GOAWAY once `tracked`'s server starts shutting down.
+ */
+pub fn serve_stream(
+    reader: &mut dyn Read,
+    writer: Box<dyn Write + Send>,
+    peer_addr: Option<SocketAddr>,
+    client_certificate: Option<ClientCertificate>,
+    handler: &dyn Handler,
+    tracked: Option<&Tracked>,
+) {
+    let peer = Peer {
+        addr: peer_addr,
+        client_certificate,
+        credentials: None,
+        tls: true,
+    };
+    serve_connection(reader, writer, peer, handler, None, &[], tracked);
+}
+
+/**
+ * Switches an HTTP/1.1 connection to HTTP/2 after an `Upgrade: h2c` request
+ * (RFC 7540 section 3.2): replies 101, applies `settings` from the
+ * request's HTTP2-Settings header, and answers the request on stream 1.
+ */
+pub fn serve_h2c_upgrade(
+    mut reader: BufReader<Stream>,
+    handler: &dyn Handler,
+    mut request: Request,
+    settings: &[u8],
+    tracked: Option<&Tracked>,
+) {
+    let mut response = Response::new();
+    response.status_code = StatusCode::SWITCHING_PROTOCOLS;
+    response
+        .headers
+        .insert("Connection".to_string(), vec!["Upgrade".to_string()]);
+    response
+        .headers
+        .insert("Upgrade".to_string(), vec!["h2c".to_string()]);
+    if let Err(err) = response.write_to(reader.get_mut()) {
+        eprintln!("Failed to write response: {}", err);
+        return;
+    }
+
+    request.http_version = HttpVersion::Http2_0;
+    for header_name in ["connection", "upgrade", "http2-settings"] {
+        request.headers.remove(header_name);
+    }
+
+    serve_socket(reader, handler, Some(request), settings, tracked);
+}
+
+/**
+ * The HTTP2-Settings payload of a request asking to upgrade to h2c, or None
+ * if it isn't one or the header is malformed, in which case the request is
+ * served as HTTP/1.1
+ */
+pub fn h2c_upgrade_settings(request: &Request) -> Option<Vec<u8>> {
+    let has_token = |header_name: &str, token: &str| {
+        request
+            .headers
+            .get(header_name)
+            .into_iter()
+            .flatten()
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    };
+
+    if request.http_version != HttpVersion::Http1_1
+        || !has_token("upgrade", "h2c")
+        || !has_token("connection", "upgrade")
+    {
+        return None;
+    }
+
+    match request.headers.get("http2-settings")?.as_slice() {
+        [settings] => {
+            decode_base64url(settings).filter(|settings| settings.len().is_multiple_of(6))
+        }
+        _ => None,
+    }
+}
+
+/** Unpadded base64url, as used by HTTP2-Settings */
+fn decode_base64url(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = vec![];
+    let mut bits: u32 = 0;
+    let mut bit_count = 0;
+
+    for c in encoded.trim_end_matches('=').bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        bits = (bits << 6) | value as u32;
+        bit_count += 6;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            decoded.push((bits >> bit_count) as u8);
+        }
+    }
+
+    Some(decoded)
+}
+
+fn serve_socket(
+    mut reader: BufReader<Stream>,
+    handler: &dyn Handler,
+    upgraded_request: Option<Request>,
+    settings: &[u8],
+    tracked: Option<&Tracked>,
+) {
+    let stream = match reader.get_ref().try_clone() {
+        Ok(stream) => stream,
+        Err(err) => {
+            eprintln!("Failed to clone HTTP/2 stream: {}", err);
+            return;
+        }
+    };
+    let peer = Peer {
+        addr: reader.get_ref().peer_addr(),
+        client_certificate: None,
+        credentials: reader.get_ref().peer_credentials(),
+        tls: false,
+    };
+
+    serve_connection(
+        &mut reader,
+        Box::new(stream),
+        peer,
+        handler,
+        upgraded_request,
+        settings,
+        tracked,
+    );
+}
+
+fn serve_connection(
+    reader: &mut dyn Read,
+    writer: Box<dyn Write + Send>,
+    peer: Peer,
+    handler: &dyn Handler,
+    upgraded_request: Option<Request>,
+    settings: &[u8],
+    tracked: Option<&Tracked>,
+) {
+    let connection = Connection {
+        handler,
+        peer,
+        writer: Arc::new(Mutex::new(Writer {
+            stream: BufWriter::new(writer),
+            encoder: Encoder::new(),
+        })),
+        state: Mutex::new(State {
+            send_window: DEFAULT_WINDOW_SIZE,
+            receive_window: CONNECTION_WINDOW_SIZE as i64,
+            streams: HashMap::new(),
+            initial_window_size: DEFAULT_WINDOW_SIZE,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            last_stream_id: 0,
+            closed: false,
+        }),
+        tracked,
+        window_update: Condvar::new(),
+    };
+
+    thread::scope(|scope| {
+        let result = connection
+            .apply_settings(settings)
+            .and_then(|()| connection.run(reader, upgraded_request, scope));
+        match result {
+            Ok(()) => {}
+            Err(Closed::Io(err)) if err.kind() == ErrorKind::UnexpectedEof => {}
+            Err(Closed::Io(err)) => eprintln!("HTTP/2 connection failed: {}", err),
+            Err(Closed::GoAway(code, message)) => {
+                eprintln!("Closing HTTP/2 connection: {}", message);
+                let _ = connection.go_away(code, message);
+            }
+        }
+        connection.close();
+    });
+}
+
+/**
+ * Whether the client opened the connection with the HTTP/2 preface. Looks
+ * without consuming anything, so an HTTP/1.1 request can still be read.
+ * Only the preface's "PRI " is waited for, which no HTTP/1.1 request line
+ * is shorter than; the rest is checked once the connection is served.
+ */
+pub fn has_preface(stream: &Stream) -> bool {
+    let mut peeked = [0; PREFACE_METHOD.len()];
+    stream.peek_exact(&mut peeked).is_ok() && peeked == PREFACE_METHOD
+}
+
+impl<'a> Connection<'a> {
+    /** Serves the connection, starting with `upgraded_request` on stream 1 if there is one */
+    fn run<'scope>(
+        &'scope self,
+        reader: &mut dyn Read,
+        upgraded_request: Option<Request>,
+        scope: &'scope Scope<'scope, '_>,
+    ) -> Result<(), Closed> {
+        let mut settings = vec![];
+        settings.extend_from_slice(&SETTINGS_MAX_CONCURRENT_STREAMS.to_be_bytes());
+        settings.extend_from_slice(&(MAX_CONCURRENT_STREAMS as u32).to_be_bytes());
+        settings.extend_from_slice(&SETTINGS_MAX_HEADER_LIST_SIZE.to_be_bytes());
+        settings.extend_from_slice(&(MAX_HEADER_LIST_SIZE as u32).to_be_bytes());
+        self.send(&Frame::new(SETTINGS, 0, 0, settings))?;
+        self.send(&window_update(
+            0,
+            CONNECTION_WINDOW_SIZE - DEFAULT_WINDOW_SIZE as usize,
+        ))?;
+
+        // Registered after SETTINGS, which has to be the first frame sent
+        if let Some(tracked) = self.tracked {
+            let writer = Arc::clone(&self.writer);
+            tracked.on_drain(Box::new(move || {
+                // The highest possible stream ID, so streams the client has
+                // already sent still get answered (RFC 9113 section 6.8)
+                let mut payload = MAX_STREAM_ID.to_be_bytes().to_vec();
+                payload.extend_from_slice(&NO_ERROR.to_be_bytes());
+                let frame = Frame::new(GOAWAY, 0, 0, payload);
+                if let Err(err) = writer.lock().unwrap().write_frame(&frame) {
+                    eprintln!("Failed to send GOAWAY: {}", err);
+                }
+            }));
+        }
+
+        // The upgrade request is already complete, so its stream is half-closed
+        if let Some(mut request) = upgraded_request {
+            {
+                let mut state = self.state.lock().unwrap();
+                state.last_stream_id = 1;
+                let send_window = state.initial_window_size;
+                state.streams.insert(
+                    1,
+                    StreamState {
+                        send_window,
+                        reset: false,
+                    },
+                );
+            }
+            self.peer.apply(&mut request);
+            self.dispatch(1, request, scope)?;
+        }
+
+        let mut preface = [0; PREFACE.len()];
+        reader.read_exact(&mut preface)?;
+        if preface != PREFACE {
+            return Err(Closed::GoAway(PROTOCOL_ERROR, "Missing connection preface"));
+        }
+
+        let mut decoder = Decoder::new().max_header_list_size(MAX_HEADER_LIST_SIZE);
+        // Requests whose body is still arriving
+        let mut receiving: HashMap<u32, Receiving> = HashMap::new();
+        let mut first_frame = true;
+
+        loop {
+            let frame = Frame::read_from(reader, DEFAULT_MAX_FRAME_SIZE)?;
+
+            if first_frame && frame.kind != SETTINGS {
+                return Err(Closed::GoAway(
+                    PROTOCOL_ERROR,
+                    "Expected SETTINGS after the preface",
+                ));
+            }
+            first_frame = false;
+
+            match frame.kind {
+                DATA => self.on_data(&frame, &mut receiving, scope)?,
+                HEADERS => {
+                    let block = read_header_block(reader, &frame)?;
+                    let headers = decoder
+                        .decode(&block)
+                        .map_err(|_| Closed::GoAway(COMPRESSION_ERROR, "Invalid header block"))?;
+                    self.on_headers(&frame, headers, &mut receiving, scope)?;
+                }
+                PRIORITY => {
+                    if frame.stream_id == 0 {
+                        return Err(Closed::GoAway(PROTOCOL_ERROR, "PRIORITY on stream 0"));
+                    }
+                    if frame.payload.len() != 5 {
+                        self.reset(frame.stream_id, FRAME_SIZE_ERROR, &mut receiving)?;
+                    }
+                }
+                RST_STREAM => {
+                    if frame.stream_id == 0 || frame.stream_id > self.last_stream_id() {
+                        return Err(Closed::GoAway(
+                            PROTOCOL_ERROR,
+                            "RST_STREAM on an idle stream",
+                        ));
+                    }
+                    if frame.payload.len() != 4 {
+                        return Err(Closed::GoAway(FRAME_SIZE_ERROR, "RST_STREAM size"));
+                    }
+                    if let Some(received) = receiving.remove(&frame.stream_id) {
+                        self.release(received.request.body.len())?;
+                    }
+                    self.end_stream(frame.stream_id);
+                }
+                SETTINGS => self.on_settings(&frame)?,
+                PUSH_PROMISE => {
+                    return Err(Closed::GoAway(PROTOCOL_ERROR, "Clients can't push"));
+                }
+                PING => {
+                    if frame.stream_id != 0 {
+                        return Err(Closed::GoAway(PROTOCOL_ERROR, "PING on a stream"));
+                    }
+                    if frame.payload.len() != 8 {
+                        return Err(Closed::GoAway(FRAME_SIZE_ERROR, "PING size"));
+                    }
+                    if !frame.has_flag(ACK) {
+                        self.send(&Frame::new(PING, ACK, 0, frame.payload))?;
+                    }
+                }
+                GOAWAY if frame.stream_id != 0 => {
+                    return Err(Closed::GoAway(PROTOCOL_ERROR, "GOAWAY on a stream"));
+                }
+                // Responses already underway are still sent; the client closes when done
+                GOAWAY => {}
+                WINDOW_UPDATE => self.on_window_update(&frame, &mut receiving)?,
+                CONTINUATION => {
+                    return Err(Closed::GoAway(
+                        PROTOCOL_ERROR,
+                        "CONTINUATION without HEADERS",
+                    ));
+                }
+                // Unknown frame types are ignored (RFC 9113 section 5.5)
+                _ => {}
+            }
+        }
+    }
+
+    fn on_headers<'scope>(
+        &'scope self,
+        frame: &Frame,
+        headers: Vec<(String, String)>,
+        receiving: &mut HashMap<u32, Receiving>,
+        scope: &'scope Scope<'scope, '_>,
+    ) -> Result<(), Closed> {
+        let stream_id = frame.stream_id;
+        let end_stream = frame.has_flag(END_STREAM);
+
+        // A second HEADERS on a stream carries trailers, which end the request
+        if let Some(received) = receiving.get_mut(&stream_id) {
+            if !end_stream || headers.iter().any(|(name, _)| name.starts_with(':')) {
+                return self.reset(stream_id, PROTOCOL_ERROR, receiving);
+            }
+            for (name, value) in headers {
+                received
+                    .request
+                    .headers
+                    .entry(name)
+                    .or_default()
+                    .push(value);
+            }
+            let request = receiving.remove(&stream_id).unwrap().request;
+            self.release(request.body.len())?;
+            return self.dispatch(stream_id, request, scope);
+        }
+
+        if stream_id.is_multiple_of(2) || stream_id <= self.last_stream_id() {
+            return Err(Closed::GoAway(
+                PROTOCOL_ERROR,
+                "HEADERS on a stream that isn't new",
+            ));
+        }
+
+        {
+            let mut state = self.state.lock().unwrap();
+            state.last_stream_id = stream_id;
+            if state.streams.len() >= MAX_CONCURRENT_STREAMS {
+                drop(state);
+                return self.reset(stream_id, REFUSED_STREAM, receiving);
+            }
+            let send_window = state.initial_window_size;
+            state.streams.insert(
+                stream_id,
+                StreamState {
+                    send_window,
+                    reset: false,
+                },
+            );
+        }
+
+        let mut request = match request_from_headers(headers) {
+            Some(request) => request,
+            None => return self.reset(stream_id, PROTOCOL_ERROR, receiving),
+        };
+        self.peer.apply(&mut request);
+
+        if end_stream {
+            self.dispatch(stream_id, request, scope)
+        } else {
+            receiving.insert(
+                stream_id,
+                Receiving {
+                    request,
+                    window: DEFAULT_WINDOW_SIZE,
+                },
+            );
+            Ok(())
+        }
+    }
+
+    fn on_data<'scope>(
+        &'scope self,
+        frame: &Frame,
+        receiving: &mut HashMap<u32, Receiving>,
+        scope: &'scope Scope<'scope, '_>,
+    ) -> Result<(), Closed> {
+        let stream_id = frame.stream_id;
+        if stream_id == 0 {
+            return Err(Closed::GoAway(PROTOCOL_ERROR, "DATA on stream 0"));
+        }
+        if stream_id > self.last_stream_id() {
+            return Err(Closed::GoAway(PROTOCOL_ERROR, "DATA on an idle stream"));
+        }
+
+        // The whole frame counts against flow control, padding included
+        let length = frame.payload.len();
+        {
+            let mut state = self.state.lock().unwrap();
+            state.receive_window -= length as i64;
+            if state.receive_window < 0 {
+                return Err(Closed::GoAway(
+                    FLOW_CONTROL_ERROR,
+                    "DATA beyond the connection window",
+                ));
+            }
+        }
+        let data = frame.unpadded()?;
+
+        let error_code = match receiving.get_mut(&stream_id) {
+            Some(received) => {
+                received.window -= length as i64;
+                if received.window < 0 {
+                    Some(FLOW_CONTROL_ERROR)
+                } else if received.request.body.len() + data.len() > MAX_BODY_SIZE {
+                    Some(CANCEL)
+                } else {
+                    received.request.body.extend_from_slice(data);
+                    None
+                }
+            }
+            None => Some(STREAM_CLOSED),
+        };
+        if let Some(error_code) = error_code {
+            self.release(length)?;
+            return self.reset(stream_id, error_code, receiving);
+        }
+        // Padding is never handed to the handler, so it's given straight back
+        self.release(length - data.len())?;
+
+        if !frame.has_flag(END_STREAM) {
+            // MAX_BODY_SIZE limits what a stream can buffer, so its window
+            // can be given back as soon as the data is
+            if length > 0 {
+                receiving.get_mut(&stream_id).unwrap().window += length as i64;
+                self.send(&window_update(stream_id, length))?;
+            }
+            return Ok(());
+        }
+
+        let request = receiving.remove(&stream_id).unwrap().request;
+        self.release(request.body.len())?;
+        let content_length = request
+            .header("content-length")
+            .map(|length| usize::from_str(length).ok());
+        if content_length.is_some_and(|length| length != Some(request.body.len())) {
+            return self.reset(stream_id, PROTOCOL_ERROR, receiving);
+        }
+
+        self.dispatch(stream_id, request, scope)
+    }
+
+    fn on_settings(&self, frame: &Frame) -> Result<(), Closed> {
+        if frame.stream_id != 0 {
+            return Err(Closed::GoAway(PROTOCOL_ERROR, "SETTINGS on a stream"));
+        }
+        if frame.has_flag(ACK) {
+            if !frame.payload.is_empty() {
+                return Err(Closed::GoAway(
+                    FRAME_SIZE_ERROR,
+                    "SETTINGS ACK with a payload",
+                ));
+            }
+            return Ok(());
+        }
+        self.apply_settings(&frame.payload)?;
+        self.send(&Frame::new(SETTINGS, ACK, 0, vec![]))?;
+        Ok(())
+    }
+
+    /** Applies a SETTINGS payload, from a frame or an HTTP2-Settings header */
+    fn apply_settings(&self, payload: &[u8]) -> Result<(), Closed> {
+        if !payload.len().is_multiple_of(6) {
+            return Err(Closed::GoAway(FRAME_SIZE_ERROR, "SETTINGS size"));
+        }
+
+        {
+            let mut state = self.state.lock().unwrap();
+            for setting in payload.chunks(6) {
+                let identifier = u16::from_be_bytes([setting[0], setting[1]]);
+                let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+
+                match identifier {
+                    SETTINGS_ENABLE_PUSH if value > 1 => {
+                        return Err(Closed::GoAway(PROTOCOL_ERROR, "Invalid ENABLE_PUSH"));
+                    }
+                    SETTINGS_INITIAL_WINDOW_SIZE => {
+                        let value = value as i64;
+                        if value > MAX_WINDOW_SIZE {
+                            return Err(Closed::GoAway(
+                                FLOW_CONTROL_ERROR,
+                                "INITIAL_WINDOW_SIZE is too large",
+                            ));
+                        }
+                        // Changes apply to the windows of streams already open
+                        let delta = value - state.initial_window_size;
+                        for stream in state.streams.values_mut() {
+                            stream.send_window += delta;
+                            if stream.send_window > MAX_WINDOW_SIZE {
+                                return Err(Closed::GoAway(
+                                    FLOW_CONTROL_ERROR,
+                                    "Stream window is too large",
+                                ));
+                            }
+                        }
+                        state.initial_window_size = value;
+                    }
+                    SETTINGS_MAX_FRAME_SIZE => {
+                        let value = value as usize;
+                        if !(DEFAULT_MAX_FRAME_SIZE..=MAX_FRAME_SIZE_LIMIT).contains(&value) {
+                            return Err(Closed::GoAway(PROTOCOL_ERROR, "Invalid MAX_FRAME_SIZE"));
+                        }
+                        state.max_frame_size = value;
+                    }
+                    // Tables larger than the default aren't worth the memory
+                    SETTINGS_HEADER_TABLE_SIZE => {
+                        let table_size = (value as usize).min(DEFAULT_TABLE_SIZE);
+                        self.writer
+                            .lock()
+                            .unwrap()
+                            .encoder
+                            .set_max_table_size(table_size);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        self.window_update.notify_all();
+        Ok(())
+    }
+
+    fn on_window_update(
+        &self,
+        frame: &Frame,
+        receiving: &mut HashMap<u32, Receiving>,
+    ) -> Result<(), Closed> {
+        if frame.payload.len() != 4 {
+            return Err(Closed::GoAway(FRAME_SIZE_ERROR, "WINDOW_UPDATE size"));
+        }
+        let increment = (u32::from_be_bytes([
+            frame.payload[0],
+            frame.payload[1],
+            frame.payload[2],
+            frame.payload[3],
+        ]) & 0x7fff_ffff) as i64;
+
+        if frame.stream_id == 0 {
+            if increment == 0 {
+                return Err(Closed::GoAway(PROTOCOL_ERROR, "WINDOW_UPDATE of 0"));
+            }
+            let mut state = self.state.lock().unwrap();
+            state.send_window += increment;
+            if state.send_window > MAX_WINDOW_SIZE {
+                return Err(Closed::GoAway(
+                    FLOW_CONTROL_ERROR,
+                    "Connection window is too large",
+                ));
+            }
+        } else {
+            if frame.stream_id > self.last_stream_id() {
+                return Err(Closed::GoAway(
+                    PROTOCOL_ERROR,
+                    "WINDOW_UPDATE on an idle stream",
+                ));
+            }
+            if increment == 0 {
+                return self.reset(frame.stream_id, PROTOCOL_ERROR, receiving);
+            }
+
+            let mut state = self.state.lock().unwrap();
+            // Updates can still arrive for streams that just closed
+            if let Some(stream) = state.streams.get_mut(&frame.stream_id) {
+                stream.send_window += increment;
+                if stream.send_window > MAX_WINDOW_SIZE {
+                    drop(state);
+                    return self.reset(frame.stream_id, FLOW_CONTROL_ERROR, receiving);
+                }
+            }
+        }
+
+        self.window_update.notify_all();
+        Ok(())
+    }
+
+    fn dispatch<'scope>(
+        &'scope self,
+        stream_id: u32,
+        request: Request,
+        scope: &'scope Scope<'scope, '_>,
+    ) -> Result<(), Closed> {
+        scope.spawn(move || {
+            if let Err(err) = self.respond(stream_id, &request) {
+                eprintln!("Failed to send response on stream {}: {}", stream_id, err);
+            }
+            self.end_stream(stream_id);
+        });
+        Ok(())
+    }
+
+    fn respond(&self, stream_id: u32, request: &Request) -> Result<(), Error> {
+        let mut response = self.handler.handle(request);
+        // HTTP/2 frames the body itself, so a chunked one has to be decoded first
+        if response.is_chunked() {
+            response.buffer_body()?;
+        }
+
+        let mut fields = vec![(":status".to_string(), response.status_code.0.to_string())];
+        for (header_name, values) in &response.headers {
+            let header_name = header_name.to_lowercase();
+            if !CONNECTION_HEADERS.contains(&header_name.as_str()) {
+                fields.push((header_name, values.join(";")));
+            }
+        }
+
+        let stream_body = response.stream_body.take();
+        let has_body =
+            request.method != Method::HEAD && (!response.body.is_empty() || stream_body.is_some());
+
+        self.send_headers(stream_id, &fields, !has_body)?;
+        if !has_body {
+            return Ok(());
+        }
+
+        self.send_data(stream_id, &response.body, stream_body.is_none())?;
+        if let Some(stream_body) = stream_body {
+            stream_body(&mut DataWriter {
+                connection: self,
+                stream_id,
+            })?;
+            self.send_data(stream_id, &[], true)?;
+        }
+
+        Ok(())
+    }
+
+    /** Sends a header block as HEADERS and as many CONTINUATION frames as it takes */
+    fn send_headers(
+        &self,
+        stream_id: u32,
+        fields: &[(String, String)],
+        end_stream: bool,
+    ) -> Result<(), Error> {
+        let max_frame_size = {
+            let state = self.state.lock().unwrap();
+            match state.streams.get(&stream_id) {
+                Some(stream) if !stream.reset => state.max_frame_size,
+                _ => return Err(Error::new(ErrorKind::ConnectionReset, "Stream was reset")),
+            }
+        };
+
+        let mut writer = self.writer.lock().unwrap();
+        let block = writer.encoder.encode(fields);
+        let mut fragments = block.chunks(max_frame_size).peekable();
+        let mut kind = HEADERS;
+        let end_stream_flag = if end_stream { END_STREAM } else { 0 };
+        let mut flags = end_stream_flag;
+
+        loop {
+            let fragment = fragments.next().unwrap_or_default();
+            let is_last = fragments.peek().is_none();
+            if is_last {
+                flags |= END_HEADERS;
+            }
+            writer.write_frame(&Frame::new(kind, flags, stream_id, fragment.to_vec()))?;
+            if is_last {
+                return Ok(());
+            }
+            kind = CONTINUATION;
+            flags = 0;
+        }
+    }
+
+    /** Sends DATA, waiting for WINDOW_UPDATEs whenever the peer's windows run out */
+    fn send_data(&self, stream_id: u32, mut data: &[u8], end_stream: bool) -> Result<(), Error> {
+        loop {
+            let length = {
+                let mut state = self.state.lock().unwrap();
+                loop {
+                    if state.closed {
+                        return Err(Error::new(
+                            ErrorKind::ConnectionAborted,
+                            "Connection closed",
+                        ));
+                    }
+                    let max_frame_size = state.max_frame_size as i64;
+                    let connection_window = state.send_window;
+                    let stream = match state.streams.get_mut(&stream_id) {
+                        Some(stream) if !stream.reset => stream,
+                        _ => {
+                            return Err(Error::new(ErrorKind::ConnectionReset, "Stream was reset"))
+                        }
+                    };
+                    let available = connection_window
+                        .min(stream.send_window)
+                        .min(max_frame_size);
+                    if data.is_empty() || available > 0 {
+                        let length = (available.max(0) as usize).min(data.len());
+                        stream.send_window -= length as i64;
+                        state.send_window -= length as i64;
+                        break length;
+                    }
+                    state = self.window_update.wait(state).unwrap();
+                }
+            };
+
+            let (chunk, rest) = data.split_at(length);
+            let is_last = rest.is_empty();
+            let flags = if is_last && end_stream { END_STREAM } else { 0 };
+            self.send(&Frame::new(DATA, flags, stream_id, chunk.to_vec()))?;
+
+            if is_last {
+                return Ok(());
+            }
+            data = rest;
+        }
+    }
+
+    fn send(&self, frame: &Frame) -> Result<(), Error> {
+        self.writer.lock().unwrap().write_frame(frame)
+    }
+
+    fn last_stream_id(&self) -> u32 {
+        self.state.lock().unwrap().last_stream_id
+    }
+
+    /** Sends RST_STREAM and stops whatever the stream was doing */
+    fn reset(
+        &self,
+        stream_id: u32,
+        error_code: u32,
+        receiving: &mut HashMap<u32, Receiving>,
+    ) -> Result<(), Closed> {
+        if let Some(received) = receiving.remove(&stream_id) {
+            self.release(received.request.body.len())?;
+            self.end_stream(stream_id);
+        } else if let Some(stream) = self.state.lock().unwrap().streams.get_mut(&stream_id) {
+            // The thread sending the response ends the stream once it notices
+            stream.reset = true;
+        }
+        self.window_update.notify_all();
+        self.send(&Frame::new(
+            RST_STREAM,
+            0,
+            stream_id,
+            error_code.to_be_bytes().to_vec(),
+        ))?;
+        Ok(())
+    }
+
+    /** Gives back connection window for DATA that's been handed over or thrown away */
+    fn release(&self, length: usize) -> Result<(), Error> {
+        if length == 0 {
+            return Ok(());
+        }
+        self.state.lock().unwrap().receive_window += length as i64;
+        self.send(&window_update(0, length))
+    }
+
+    fn end_stream(&self, stream_id: u32) {
+        self.state.lock().unwrap().streams.remove(&stream_id);
+        self.window_update.notify_all();
+    }
+
+    fn go_away(&self, error_code: u32, message: &str) -> Result<(), Error> {
+        let mut payload = self.last_stream_id().to_be_bytes().to_vec();
+        payload.extend_from_slice(&error_code.to_be_bytes());
+        payload.extend_from_slice(message.as_bytes());
+        self.send(&Frame::new(GOAWAY, 0, 0, payload))
+    }
+
+    /** Wakes any responses waiting on flow control so they give up */
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.window_update.notify_all();
+    }
+}
+
+/** Lets a stream_body write to an HTTP/2 stream as DATA frames */
+struct DataWriter<'a, 'b> {
+    connection: &'a Connection<'b>,
+    stream_id: u32,
+}
+
+impl Write for DataWriter<'_, '_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        if !buf.is_empty() {
+            self.connection.send_data(self.stream_id, buf, false)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/** Reads the rest of a header block from any CONTINUATION frames after `frame` */
+fn read_header_block(reader: &mut dyn Read, frame: &Frame) -> Result<Vec<u8>, Closed> {
+    if frame.stream_id == 0 {
+        return Err(Closed::GoAway(PROTOCOL_ERROR, "HEADERS on stream 0"));
+    }
+
+    let mut fragment = frame.unpadded()?;
+    if frame.has_flag(PRIORITY_FLAG) {
+        if fragment.len() < 5 {
+            return Err(Closed::GoAway(FRAME_SIZE_ERROR, "HEADERS priority size"));
+        }
+        fragment = &fragment[5..];
+    }
+
+    let mut block = fragment.to_vec();
+    let mut end_headers = frame.has_flag(END_HEADERS);
+    loop {
+        // Otherwise CONTINUATION frames could grow the block without end
+        if block.len() > MAX_HEADER_LIST_SIZE {
+            return Err(Closed::GoAway(
+                ENHANCE_YOUR_CALM,
+                "Header block is too large",
+            ));
+        }
+        if end_headers {
+            break;
+        }
+
+        let continuation = Frame::read_from(reader, DEFAULT_MAX_FRAME_SIZE)?;
+        if continuation.kind != CONTINUATION || continuation.stream_id != frame.stream_id {
+            return Err(Closed::GoAway(
+                PROTOCOL_ERROR,
+                "Header block interrupted by another frame",
+            ));
+        }
+        block.extend_from_slice(&continuation.payload);
+        end_headers = continuation.has_flag(END_HEADERS);
+    }
+
+    Ok(block)
+}
+
+/**
+ * Builds a Request from a stream's header fields, or None if they're
+ * malformed (RFC 9113 section 8.3). Values are split on ';' as they are for
+ * HTTP/1.1 requests, and :authority becomes Host. HTTP/3 has the same rules
+ * (RFC 9114 section 4.3), so it builds its requests here too.
+ */
+pub(super) fn request_from_headers(fields: Vec<(String, String)>) -> Option<Request> {
+    let mut pseudo_headers: HashMap<String, String> = HashMap::new();
+    let mut headers: HashMap<String, Vec<String>> = HashMap::new();
+
+    for (name, value) in fields {
+        if let Some(pseudo_header) = name.strip_prefix(':') {
+            let is_known = matches!(pseudo_header, "method" | "scheme" | "path" | "authority");
+            // Pseudo-headers come first, once each
+            if !is_known || !headers.is_empty() || pseudo_headers.contains_key(pseudo_header) {
+                return None;
+            }
+            pseudo_headers.insert(pseudo_header.to_string(), value);
+            continue;
+        }
+
+        let is_connection_header = CONNECTION_HEADERS.contains(&name.as_str());
+        if name.chars().any(|c| c.is_ascii_uppercase())
+            || is_connection_header
+            || (name == "te" && value != "trailers")
+        {
+            return None;
+        }
+
+        headers
+            .entry(name)
+            .or_default()
+            .extend(value.split(';').map(|value| value.trim().to_string()));
+    }
+
+    let method = Method::from_str(pseudo_headers.get("method")?).ok()?;
+    let raw_target = if method == Method::CONNECT {
+        pseudo_headers.get("authority")?.clone()
+    } else {
+        pseudo_headers.get("scheme")?;
+        pseudo_headers
+            .get("path")
+            .filter(|path| !path.is_empty())?
+            .clone()
+    };
+
+    if let Some(authority) = pseudo_headers.remove("authority") {
+        headers.entry("host".to_string()).or_insert(vec![authority]);
+    }
+
+    Some(Request {
+        method,
+        raw_target,
+        http_version: HttpVersion::Http2_0,
+        headers,
+        body: vec![],
+        peer_addr: None,
+        client_certificate: None,
+        peer_credentials: None,
+        tls: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{TcpListener, TcpStream},
+        sync::atomic::{AtomicUsize, Ordering},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::server::handle_connection;
+
+    /** The client end of an HTTP/2 connection to handle_connection */
+    struct Client {
+        stream: TcpStream,
+        encoder: Encoder,
+        decoder: Decoder,
+    }
+
+    impl Client {
+        fn connect<H: Handler>(handler: H, settings: &[(u16, u32)]) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            thread::spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                handle_connection(stream.into(), &handler, None);
+            });
+
+            let mut client = Client {
+                stream: TcpStream::connect(addr).unwrap(),
+                encoder: Encoder::new(),
+                decoder: Decoder::new(),
+            };
+            client
+                .stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            client.stream.write_all(PREFACE).unwrap();
+
+            let mut payload = vec![];
+            for (identifier, value) in settings {
+                payload.extend_from_slice(&identifier.to_be_bytes());
+                payload.extend_from_slice(&value.to_be_bytes());
+            }
+            client.send(Frame::new(SETTINGS, 0, 0, payload));
+
+            let server_settings = client.receive();
+            assert_eq!((SETTINGS, 0), (server_settings.kind, server_settings.flags));
+            assert_eq!(
+                window_update(0, CONNECTION_WINDOW_SIZE - DEFAULT_WINDOW_SIZE as usize),
+                client.receive()
+            );
+            let ack = client.receive();
+            assert_eq!((SETTINGS, ACK), (ack.kind, ack.flags));
+            client
+        }
+
+        fn send(&mut self, frame: Frame) {
+            frame.write_to(&mut self.stream).unwrap();
+        }
+
+        fn receive(&mut self) -> Frame {
+            match Frame::read_from(&mut self.stream, MAX_FRAME_SIZE_LIMIT) {
+                Ok(frame) => frame,
+                Err(_) => panic!("Failed to read a frame"),
+            }
+        }
+
+        /** Skips the SETTINGS exchange, for when it races with other frames */
+        fn receive_except_settings(&mut self) -> Frame {
+            loop {
+                let frame = self.receive();
+                if frame.kind != SETTINGS {
+                    return frame;
+                }
+            }
+        }
+
+        fn request(&mut self, stream_id: u32, method: &str, path: &str, end_stream: bool) {
+            let fields = [
+                (":method", method),
+                (":scheme", "http"),
+                (":path", path),
+                (":authority", "localhost"),
+            ]
+            .map(|(name, value)| (name.to_string(), value.to_string()));
+            let block = self.encoder.encode(&fields);
+            let flags = END_HEADERS | if end_stream { END_STREAM } else { 0 };
+            self.send(Frame::new(HEADERS, flags, stream_id, block));
+        }
+
+        /** Reads frames until a stream ends, returning its status and body */
+        fn response(&mut self, stream_id: u32) -> (String, Vec<u8>) {
+            let mut status = String::new();
+            let mut body = vec![];
+            loop {
+                let frame = self.receive();
+                assert_eq!(stream_id, frame.stream_id);
+                match frame.kind {
+                    HEADERS => {
+                        let headers = self.decoder.decode(&frame.payload).unwrap();
+                        status = headers[0].1.clone();
+                    }
+                    DATA => body.extend_from_slice(&frame.payload),
+                    kind => panic!("Unexpected frame type {}", kind),
+                }
+                if frame.has_flag(END_STREAM) {
+                    return (status, body);
+                }
+            }
+        }
+    }
+
+    fn echo(request: &Request) -> Response {
+        let mut response = Response::new();
+        response
+            .headers
+            .insert("Connection".to_string(), vec!["keep-alive".to_string()]);
+        response.body = match request.raw_target.as_str() {
+            "/host" => request
+                .header("host")
+                .unwrap_or_default()
+                .as_bytes()
+                .to_vec(),
+            _ => request.body.clone(),
+        };
+        response
+    }
+
+    #[test]
+    fn serves_requests_with_prior_knowledge() {
+        let mut client = Client::connect(echo, &[]);
+
+        client.request(1, "GET", "/host", true);
+        let frame = client.receive();
+        assert_eq!((HEADERS, END_HEADERS), (frame.kind, frame.flags));
+        let headers = client.decoder.decode(&frame.payload).unwrap();
+        assert_eq!((":status".to_string(), "200".to_string()), headers[0]);
+        assert!(headers.iter().all(|(name, _)| name != "connection"));
+
+        let frame = client.receive();
+        assert_eq!((DATA, END_STREAM), (frame.kind, frame.flags));
+        assert_eq!(b"localhost".to_vec(), frame.payload);
+
+        client.request(3, "POST", "/echo", false);
+        client.send(Frame::new(DATA, 0, 3, b"hello ".to_vec()));
+        client.send(Frame::new(DATA, END_STREAM, 3, b"world".to_vec()));
+        let mut updates = 0;
+        loop {
+            let frame = client.receive();
+            if frame.kind == WINDOW_UPDATE {
+                updates += 1;
+                continue;
+            }
+            assert_eq!(HEADERS, frame.kind);
+            break;
+        }
+        // The stream's window comes back as the body arrives, the
+        // connection's once the handler has it
+        assert_eq!(2, updates);
+        let frame = client.receive();
+        assert_eq!(b"hello world".to_vec(), frame.payload);
+
+        client.send(Frame::new(PING, 0, 0, b"12345678".to_vec()));
+        assert_eq!(
+            Frame::new(PING, ACK, 0, b"12345678".to_vec()),
+            client.receive()
+        );
+    }
+
+    #[test]
+    fn streams_are_multiplexed() {
+        let mut client = Client::connect(
+            |request: &Request| {
+                if request.raw_target == "/slow" {
+                    thread::sleep(Duration::from_millis(200));
+                }
+                let mut response = Response::new();
+                response.body = request.raw_target.as_bytes().to_vec();
+                response
+            },
+            &[],
+        );
+
+        client.request(1, "GET", "/slow", true);
+        client.request(3, "GET", "/fast", true);
+
+        assert_eq!(("200".to_string(), b"/fast".to_vec()), client.response(3));
+        assert_eq!(("200".to_string(), b"/slow".to_vec()), client.response(1));
+    }
+
+    #[test]
+    fn data_waits_for_flow_control_window() {
+        let mut client = Client::connect(
+            |_: &Request| {
+                let mut response = Response::new();
+                response.body = b"0123456789abcdefghij".to_vec();
+                response
+            },
+            &[(SETTINGS_INITIAL_WINDOW_SIZE, 8)],
+        );
+
+        client.request(1, "GET", "/", true);
+        assert_eq!(HEADERS, client.receive().kind);
+        assert_eq!(b"01234567".to_vec(), client.receive().payload);
+
+        client.send(window_update(1, 100));
+        let frame = client.receive();
+        assert_eq!(b"89abcdefghij".to_vec(), frame.payload);
+        assert!(frame.has_flag(END_STREAM));
+    }
+
+    #[test]
+    fn bodies_are_capped() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let mut client = Client::connect(
+            move |_: &Request| {
+                counted.fetch_add(1, Ordering::SeqCst);
+                Response::new()
+            },
+            &[],
+        );
+
+        client.request(1, "POST", "/", false);
+        let chunk = vec![0; DEFAULT_MAX_FRAME_SIZE];
+        for _ in 0..MAX_BODY_SIZE / DEFAULT_MAX_FRAME_SIZE {
+            client.send(Frame::new(DATA, 0, 1, chunk.clone()));
+        }
+        client.send(Frame::new(DATA, END_STREAM, 1, vec![0]));
+
+        let mut released = 0;
+        let reset = loop {
+            let frame = client.receive();
+            if frame.kind != WINDOW_UPDATE {
+                break frame;
+            }
+            if frame.stream_id == 0 {
+                released += u32::from_be_bytes(frame.payload[..].try_into().unwrap()) as usize;
+            }
+        };
+        assert_eq!(
+            Frame::new(RST_STREAM, 0, 1, CANCEL.to_be_bytes().to_vec()),
+            reset
+        );
+        // The whole body is given back to the connection
+        assert_eq!(MAX_BODY_SIZE + 1, released);
+        assert_eq!(0, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn connection_window_is_enforced() {
+        let mut client = Client::connect(echo, &[]);
+
+        // Bodies that are still arriving hold on to the connection window
+        let chunk = vec![0; DEFAULT_MAX_FRAME_SIZE];
+        for stream_id in [1, 3] {
+            client.request(stream_id, "POST", "/", false);
+            for _ in 0..MAX_BODY_SIZE / DEFAULT_MAX_FRAME_SIZE {
+                client.send(Frame::new(DATA, 0, stream_id, chunk.clone()));
+            }
+        }
+        client.request(5, "POST", "/", false);
+        client.send(Frame::new(DATA, END_STREAM, 5, vec![0]));
+
+        let go_away = loop {
+            let frame = client.receive();
+            if frame.kind != WINDOW_UPDATE {
+                break frame;
+            }
+            assert_ne!(0, frame.stream_id);
+        };
+        assert_eq!(GOAWAY, go_away.kind);
+        assert_eq!(FLOW_CONTROL_ERROR.to_be_bytes(), go_away.payload[4..8]);
+    }
+
+    #[test]
+    fn streamed_bodies_become_data_frames() {
+        let mut client = Client::connect(
+            |_: &Request| {
+                let mut response = Response::new();
+                response.stream_body = Some(Box::new(|stream: &mut dyn Write| {
+                    stream.write_all(b"first")?;
+                    stream.write_all(b"second")
+                }));
+                response
+            },
+            &[],
+        );
+
+        client.request(1, "GET", "/", true);
+        assert_eq!(
+            ("200".to_string(), b"firstsecond".to_vec()),
+            client.response(1)
+        );
+    }
+
+    #[test]
+    fn errors_reset_streams_or_close_the_connection() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let mut client = Client::connect(
+            move |_: &Request| {
+                counted.fetch_add(1, Ordering::SeqCst);
+                Response::new()
+            },
+            &[],
+        );
+
+        let block = client
+            .encoder
+            .encode(&[(":method".to_string(), "GET".to_string())]);
+        client.send(Frame::new(HEADERS, END_HEADERS | END_STREAM, 1, block));
+        let reset = client.receive();
+        assert_eq!((RST_STREAM, 1), (reset.kind, reset.stream_id));
+        assert_eq!(PROTOCOL_ERROR.to_be_bytes().to_vec(), reset.payload);
+        assert_eq!(0, calls.load(Ordering::SeqCst));
+
+        client.send(Frame::new(DATA, 0, 0, b"nope".to_vec()));
+        let go_away = client.receive();
+        assert_eq!(GOAWAY, go_away.kind);
+        assert_eq!(1u32.to_be_bytes(), go_away.payload[..4]);
+        assert_eq!(PROTOCOL_ERROR.to_be_bytes(), go_away.payload[4..8]);
+    }
+
+    #[test]
+    fn endless_header_blocks_are_cut_off() {
+        let mut client = Client::connect(echo, &[]);
+
+        let block = client
+            .encoder
+            .encode(&[(":method".to_string(), "GET".to_string())]);
+        client.send(Frame::new(HEADERS, 0, 1, block));
+        // Just past the limit, so the server has read everything before it hangs up
+        for _ in 0..MAX_HEADER_LIST_SIZE / DEFAULT_MAX_FRAME_SIZE {
+            client.send(Frame::new(
+                CONTINUATION,
+                0,
+                1,
+                vec![0; DEFAULT_MAX_FRAME_SIZE],
+            ));
+        }
+
+        let go_away = client.receive_except_settings();
+        assert_eq!(GOAWAY, go_away.kind);
+        assert_eq!(ENHANCE_YOUR_CALM.to_be_bytes(), go_away.payload[4..8]);
+    }
+
+    #[test]
+    fn header_lists_are_capped_once_decoded() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let mut client = Client::connect(
+            move |_: &Request| {
+                counted.fetch_add(1, Ordering::SeqCst);
+                Response::new()
+            },
+            &[],
+        );
+
+        let mut block = client
+            .encoder
+            .encode(&[("x-big".to_string(), "a".repeat(4000))]);
+        // A byte each, but every one decodes to the 4 KiB field just added
+        block.extend_from_slice(&[0xbe; 20]);
+        client.send(Frame::new(HEADERS, END_HEADERS | END_STREAM, 1, block));
+
+        let go_away = client.receive_except_settings();
+        assert_eq!(GOAWAY, go_away.kind);
+        assert_eq!(COMPRESSION_ERROR.to_be_bytes(), go_away.payload[4..8]);
+        assert_eq!(0, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn preface_can_arrive_in_pieces() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            has_preface(&stream.into())
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(&PREFACE[..2]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        client.write_all(&PREFACE[2..]).unwrap();
+        assert!(server.join().unwrap());
+    }
+
+    #[test]
+    fn h2c_upgrade_answers_on_stream_1() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(stream.into(), &echo, None);
+        });
+
+        let mut client = Client {
+            stream: TcpStream::connect(addr).unwrap(),
+            encoder: Encoder::new(),
+            decoder: Decoder::new(),
+        };
+        client
+            .stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        // HTTP2-Settings sets INITIAL_WINDOW_SIZE to 4
+        client
+            .stream
+            .write_all(
+                b"GET /host HTTP/1.1\r\n\
+                Host: localhost\r\n\
+                Connection: Upgrade, HTTP2-Settings\r\n\
+                Upgrade: h2c\r\n\
+                HTTP2-Settings: AAQAAAAE\r\n\
+                \r\n",
+            )
+            .unwrap();
+
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0];
+            client.stream.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(head.contains("Upgrade: h2c\r\n"));
+
+        client.stream.write_all(PREFACE).unwrap();
+        client.send(Frame::new(SETTINGS, 0, 0, vec![]));
+
+        assert_eq!(WINDOW_UPDATE, client.receive_except_settings().kind);
+        let headers = client.receive_except_settings();
+        assert_eq!((HEADERS, 1), (headers.kind, headers.stream_id));
+        assert_eq!(
+            Frame::new(DATA, 0, 1, b"loca".to_vec()),
+            client.receive_except_settings()
+        );
+
+        client.send(window_update(1, 10));
+        assert_eq!(
+            Frame::new(DATA, END_STREAM, 1, b"lhost".to_vec()),
+            client.receive_except_settings()
+        );
+
+        // Later streams start with the same window
+        client.request(3, "GET", "/host", true);
+        assert_eq!(HEADERS, client.receive_except_settings().kind);
+        assert_eq!(
+            Frame::new(DATA, 0, 3, b"loca".to_vec()),
+            client.receive_except_settings()
+        );
+    }
+
+    #[test]
+    fn h2c_upgrade_requests() {
+        let upgrade = |headers: &str| {
+            let raw_request = format!("GET / HTTP/1.1\r\n{}\r\n", headers);
+            h2c_upgrade_settings(&Request::from_stream(&mut raw_request.as_bytes()).unwrap())
+        };
+
+        assert_eq!(
+            Some(vec![0, 3, 0, 0, 0, 100, 0, 4, 0, 0, 255, 255]),
+            upgrade(
+                "Connection: Upgrade, HTTP2-Settings\r\n\
+                Upgrade: h2c\r\n\
+                HTTP2-Settings: AAMAAABkAAQAAP__\r\n"
+            )
+        );
+        assert_eq!(
+            Some(vec![]),
+            upgrade("Connection: upgrade\r\nUpgrade: H2C\r\nHTTP2-Settings: \r\n")
+        );
+        assert_eq!(
+            None,
+            upgrade("Connection: Upgrade\r\nUpgrade: h2c\r\n"),
+            "HTTP2-Settings is required"
+        );
+        assert_eq!(
+            None,
+            upgrade("Connection: Upgrade\r\nUpgrade: h2c\r\nHTTP2-Settings: AAM\r\n"),
+            "Settings are 6 bytes each"
+        );
+        assert_eq!(
+            None,
+            upgrade("Connection: Upgrade\r\nUpgrade: websocket\r\nHTTP2-Settings: \r\n")
+        );
+        assert_eq!(
+            None,
+            upgrade("Upgrade: h2c\r\nHTTP2-Settings: \r\n"),
+            "Upgrade has to be a connection option"
+        );
+    }
+
+    #[test]
+    fn pseudo_headers_become_the_request() {
+        let fields = |fields: &[(&str, &str)]| {
+            fields
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        let request = request_from_headers(fields(&[
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":authority", "example.com"),
+            (":path", "/search?q=1"),
+            ("cookie", "a=1; b=2"),
+        ]))
+        .unwrap();
+        assert_eq!(Method::GET, request.method);
+        assert_eq!("/search?q=1", request.raw_target);
+        assert_eq!(Some("example.com"), request.header("Host"));
+        assert_eq!(
+            Some(&vec!["a=1".to_string(), "b=2".to_string()]),
+            request.headers.get("cookie")
+        );
+
+        let connect =
+            request_from_headers(fields(&[(":method", "CONNECT"), (":authority", "db:5432")]));
+        assert_eq!("db:5432", connect.unwrap().raw_target);
+
+        assert!(request_from_headers(fields(&[
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", "/"),
+            ("Accept", "*/*"),
+        ]))
+        .is_none());
+        assert!(request_from_headers(fields(&[
+            (":method", "GET"),
+            ("accept", "*/*"),
+            (":scheme", "http"),
+            (":path", "/"),
+        ]))
+        .is_none());
+        assert!(request_from_headers(fields(&[
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", "/"),
+            ("connection", "keep-alive"),
+        ]))
+        .is_none());
+    }
+}
@@ -0,0 +1,997 @@
+use std::{
+    io::{self, BufReader, Error, Read, Write},
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, Scope},
+    time::Duration,
+};
+
+use quinn::{
+    crypto::rustls::QuicServerConfig, Endpoint, RecvStream, SendStream, ServerConfig, VarInt,
+};
+use rustls::pki_types::CertificateDer;
+use tokio::runtime::{self, Handle, Runtime};
+
+use super::{
+    client_certificate::ClientCertificate,
+    handler::Handler,
+    http2::{request_from_headers, CONNECTION_HEADERS},
+    http_version::HttpVersion,
+    method::Method,
+    qpack,
+    request::Request,
+    response::Response,
+    tls::{client_certificate, invalid_tls_config, TlsConfig},
+};
+
+/** The ALPN protocol ID that QUIC clients ask for to speak HTTP/3 */
+pub const ALPN: &[u8] = b"h3";
+
+const DATA: u64 = 0x0;
+const HEADERS: u64 = 0x1;
+const CANCEL_PUSH: u64 = 0x3;
+const SETTINGS: u64 = 0x4;
+const PUSH_PROMISE: u64 = 0x5;
+const GOAWAY: u64 = 0x7;
+const MAX_PUSH_ID: u64 = 0xd;
+/** HTTP/2 frame types that HTTP/3 reserves so they can't be sent by mistake */
+const HTTP2_FRAMES: [u64; 4] = [0x2, 0x6, 0x8, 0x9];
+
+const CONTROL_STREAM: u64 = 0x0;
+const PUSH_STREAM: u64 = 0x1;
+const QPACK_ENCODER_STREAM: u64 = 0x2;
+const QPACK_DECODER_STREAM: u64 = 0x3;
+
+const SETTINGS_QPACK_MAX_TABLE_CAPACITY: u64 = 0x1;
+const SETTINGS_MAX_FIELD_SECTION_SIZE: u64 = 0x6;
+const SETTINGS_QPACK_BLOCKED_STREAMS: u64 = 0x7;
+/** HTTP/2 settings that HTTP/3 reserves */
+const HTTP2_SETTINGS: [u64; 4] = [0x2, 0x3, 0x4, 0x5];
+
+/** Error codes for closing connections and resetting streams (RFC 9114 section 8.1) */
+pub const H3_NO_ERROR: u32 = 0x100;
+pub const H3_INTERNAL_ERROR: u32 = 0x102;
+pub const H3_STREAM_CREATION_ERROR: u32 = 0x103;
+pub const H3_CLOSED_CRITICAL_STREAM: u32 = 0x104;
+pub const H3_FRAME_UNEXPECTED: u32 = 0x105;
+pub const H3_FRAME_ERROR: u32 = 0x106;
+pub const H3_EXCESSIVE_LOAD: u32 = 0x107;
+pub const H3_SETTINGS_ERROR: u32 = 0x109;
+pub const H3_MISSING_SETTINGS: u32 = 0x10a;
+pub const H3_REQUEST_INCOMPLETE: u32 = 0x10d;
+pub const H3_MESSAGE_ERROR: u32 = 0x10e;
+pub const QPACK_DECOMPRESSION_FAILED: u32 = 0x200;
+
+/** The largest field section we accept, and the limit on other non-DATA frames */
+const MAX_FIELD_SECTION_SIZE: u64 = 64 * 1024;
+
+/** Why a stream or connection ended early */
+enum Failed {
+    Io(Error),
+    /** A stream error, with the code to reset the stream with */
+    Stream(u32),
+    /** A connection error, with the code and message to close the connection with */
+    Connection(u32, &'static str),
+}
+
+impl From<Error> for Failed {
+    fn from(err: Error) -> Self {
+        Failed::Io(err)
+    }
+}
+
+/** RFC 9000 section 16: an integer in 1, 2, 4 or 8 bytes, sized by its top two bits */
+fn read_varint(reader: &mut dyn Read) -> Result<Option<u64>, Error> {
+    let mut first = [0; 1];
+    if reader.read(&mut first)? == 0 {
+        return Ok(None);
+    }
+
+    let length = 1 << (first[0] >> 6);
+    let mut bytes = [0; 8];
+    bytes[8 - length] = first[0] & 0x3f;
+    reader.read_exact(&mut bytes[9 - length..])?;
+    Ok(Some(u64::from_be_bytes(bytes)))
+}
+
+fn write_varint(buf: &mut Vec<u8>, value: u64) {
+    match value {
+        0..=0x3f => buf.push(value as u8),
+        0x40..=0x3fff => buf.extend_from_slice(&(value as u16 | 0x4000).to_be_bytes()),
+        0x4000..=0x3fff_ffff => buf.extend_from_slice(&(value as u32 | 0x8000_0000).to_be_bytes()),
+        _ => buf.extend_from_slice(&(value | 0xc000_0000_0000_0000).to_be_bytes()),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Frame {
+    kind: u64,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    fn new(kind: u64, payload: Vec<u8>) -> Self {
+        Frame { kind, payload }
+    }
+
+    /** The next frame, or None where the stream ends between frames */
+    fn read_from(reader: &mut dyn Read) -> Result<Option<Self>, Failed> {
+        let kind = match read_varint(reader)? {
+            Some(kind) => kind,
+            None => return Ok(None),
+        };
+        let length = read_varint(reader)?.ok_or(Failed::Connection(
+            H3_FRAME_ERROR,
+            "Stream ends inside a frame",
+        ))?;
+        // DATA is the only frame that can reasonably be large
+        if kind != DATA && length > MAX_FIELD_SECTION_SIZE {
+            return Err(Failed::Connection(H3_EXCESSIVE_LOAD, "Frame is too large"));
+        }
+
+        let mut payload = vec![];
+        Read::take(reader, length).read_to_end(&mut payload)?;
+        if payload.len() as u64 != length {
+            return Err(Failed::Connection(
+                H3_FRAME_ERROR,
+                "Stream ends inside a frame",
+            ));
+        }
+
+        Ok(Some(Frame { kind, payload }))
+    }
+
+    fn write_to(&self, stream: &mut dyn Write) -> Result<(), Error> {
+        let mut buf = vec![];
+        write_varint(&mut buf, self.kind);
+        write_varint(&mut buf, self.payload.len() as u64);
+        buf.extend_from_slice(&self.payload);
+        stream.write_all(&buf)
+    }
+}
+
+/** Frames that only belong on the control stream, or that no client may send */
+fn is_unexpected_on_request_stream(kind: u64) -> bool {
+    matches!(
+        kind,
+        CANCEL_PUSH | SETTINGS | PUSH_PROMISE | GOAWAY | MAX_PUSH_ID
+    ) || HTTP2_FRAMES.contains(&kind)
+}
+
+/** Lets the blocking code below read a QUIC stream, driven by the runtime */
+struct StreamReader<'a> {
+    runtime: &'a Handle,
+    stream: RecvStream,
+}
+
+impl Read for StreamReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        match self.runtime.block_on(self.stream.read(buf)) {
+            Ok(Some(length)) => Ok(length),
+            Ok(None) => Ok(0),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+struct StreamWriter<'a> {
+    runtime: &'a Handle,
+    stream: SendStream,
+}
+
+impl Write for StreamWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        Ok(self.runtime.block_on(self.stream.write(buf))?)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/** Lets a stream_body write to an HTTP/3 stream as DATA frames */
+struct DataWriter<'a> {
+    stream: &'a mut dyn Write,
+}
+
+impl Write for DataWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        if !buf.is_empty() {
+            Frame::new(DATA, buf.to_vec()).write_to(self.stream)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/**
+ * An experimental HTTP/3 listener (RFC 9114): QUIC on a UDP socket, with
+ * TLS 1.3 from a PEM certificate chain and private key. Handlers see the
+ * same Request and Response as on any other connection, with an
+ * http_version of HTTP/3. QUIC needs an async runtime, so the listener owns
+ * a small one, but each connection and request still gets its own thread
+ * and blocks on the runtime where it would block on a socket.
+ */
+pub struct Http3Server {
+    runtime: Runtime,
+    endpoint: Endpoint,
+}
+
+impl Http3Server {
+    pub fn bind(addr: SocketAddr, cert_path: &Path, key_path: &Path) -> Result<Self, Error> {
+        Self::bind_with_config(addr, TlsConfig::new(cert_path, key_path))
+    }
+
+    /**
+     * Like bind(), with the certificates and client certificate settings of
+     * a TlsConfig, so a route that checks client certificates sees them over
+     * HTTP/3 just as it does over TlsServer
+     */
+    pub fn bind_with_config(addr: SocketAddr, config: TlsConfig) -> Result<Self, Error> {
+        let (mut tls, _) = config.server_config(&[&rustls::version::TLS13])?;
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+
+        let crypto = QuicServerConfig::try_from(tls).map_err(invalid_tls_config)?;
+        let config = ServerConfig::with_crypto(Arc::new(crypto));
+
+        let runtime = runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()?;
+        // The endpoint registers its socket with whichever runtime it's made in
+        let endpoint = {
+            let _runtime = runtime.enter();
+            Endpoint::server(config, addr)?
+        };
+
+        Ok(Http3Server { runtime, endpoint })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.endpoint.local_addr()
+    }
+
+    /** Accepts connections until the endpoint is closed */
+    pub fn serve<H: Handler>(self, handler: H) {
+        let handler = Arc::new(handler);
+
+        while let Some(incoming) = self.runtime.block_on(self.endpoint.accept()) {
+            let runtime = self.runtime.handle().clone();
+            let handler = Arc::clone(&handler);
+            thread::spawn(move || match runtime.block_on(async { incoming.await }) {
+                Ok(quic) => serve_connection(&runtime, quic, handler.as_ref()),
+                Err(err) => eprintln!("QUIC handshake failed: {}", err),
+            });
+        }
+    }
+}
+
+pub fn start_http3_server<H: Handler>(port: u16, cert_path: &Path, key_path: &Path, handler: H) {
+    let server = Http3Server::bind(
+        SocketAddr::from(([127, 0, 0, 1], port)),
+        cert_path,
+        key_path,
+    )
+    .unwrap_or_else(|err| panic!("Unable to listen for HTTP/3 on localhost:{port}: {err}"));
+    server.serve(handler);
+}
+
+/**
+ * Serves HTTP/3 on UDP `port` alongside start_server on TCP `port`, where
+ * responses carry an Alt-Svc header telling clients about the HTTP/3
+ * listener.
+ */
+pub fn start_server_with_http3<H: Handler>(
+    port: u16,
+    cert_path: &Path,
+    key_path: &Path,
+    handler: H,
+) {
+    let server = Http3Server::bind(
+        SocketAddr::from(([127, 0, 0, 1], port)),
+        cert_path,
+        key_path,
+    )
+    .unwrap_or_else(|err| panic!("Unable to listen for HTTP/3 on localhost:{port}: {err}"));
+
+    let handler = Arc::new(handler);
+    let http3_handler = Arc::clone(&handler);
+    thread::spawn(move || server.serve(move |request: &Request| http3_handler.handle(request)));
+
+    super::start_server(
+        port,
+        AltSvc::new(move |request: &Request| handler.handle(request), port),
+    );
+}
+
+fn serve_connection(runtime: &Handle, quic: quinn::Connection, handler: &dyn Handler) {
+    // Only there if the endpoint asked for one and the verifier accepted it
+    let client_certificate = quic
+        .peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<CertificateDer<'static>>>().ok())
+        .and_then(|certificates| client_certificate(Some(&certificates)));
+    let connection = Connection {
+        runtime,
+        quic,
+        handler,
+        client_certificate,
+        has_control_stream: AtomicBool::new(false),
+    };
+
+    thread::scope(|scope| {
+        if let Err(failed) = connection.run(scope) {
+            connection.fail(failed);
+        }
+    });
+}
+
+struct Connection<'a> {
+    runtime: &'a Handle,
+    quic: quinn::Connection,
+    handler: &'a dyn Handler,
+    client_certificate: Option<ClientCertificate>,
+    has_control_stream: AtomicBool,
+}
+
+impl Connection<'_> {
+    fn run<'scope>(&'scope self, scope: &'scope Scope<'scope, '_>) -> Result<(), Failed> {
+        // Closing our control stream would end the connection, so it's held until we're done
+        let mut control = StreamWriter {
+            runtime: self.runtime,
+            stream: self
+                .runtime
+                .block_on(self.quic.open_uni())
+                .map_err(Error::from)?,
+        };
+        let mut settings = vec![];
+        for (identifier, value) in [
+            (SETTINGS_QPACK_MAX_TABLE_CAPACITY, 0),
+            (SETTINGS_QPACK_BLOCKED_STREAMS, 0),
+            (SETTINGS_MAX_FIELD_SECTION_SIZE, MAX_FIELD_SECTION_SIZE),
+        ] {
+            write_varint(&mut settings, identifier);
+            write_varint(&mut settings, value);
+        }
+        control.write_all(&[CONTROL_STREAM as u8])?;
+        Frame::new(SETTINGS, settings).write_to(&mut control)?;
+
+        scope.spawn(move || {
+            while let Ok(stream) = self.runtime.block_on(self.quic.accept_uni()) {
+                scope.spawn(move || {
+                    if let Err(failed) = self.on_unidirectional_stream(stream) {
+                        self.fail(failed);
+                    }
+                });
+            }
+        });
+
+        // Requests keep coming until the client closes the connection
+        while let Ok((send, recv)) = self.runtime.block_on(self.quic.accept_bi()) {
+            scope.spawn(move || self.on_request_stream(send, recv));
+        }
+
+        Ok(())
+    }
+
+    fn on_unidirectional_stream(&self, stream: RecvStream) -> Result<(), Failed> {
+        let mut reader = BufReader::new(StreamReader {
+            runtime: self.runtime,
+            stream,
+        });
+
+        match read_varint(&mut reader)? {
+            Some(CONTROL_STREAM) => {
+                if self.has_control_stream.swap(true, Ordering::SeqCst) {
+                    return Err(Failed::Connection(
+                        H3_STREAM_CREATION_ERROR,
+                        "Second control stream",
+                    ));
+                }
+                self.on_control_stream(&mut reader)
+            }
+            Some(PUSH_STREAM) => Err(Failed::Connection(
+                H3_STREAM_CREATION_ERROR,
+                "Clients can't push",
+            )),
+            Some(QPACK_ENCODER_STREAM | QPACK_DECODER_STREAM) => {
+                // With no dynamic table there's nothing on these streams to act on
+                io::copy(&mut reader, &mut io::sink())?;
+                Err(Failed::Connection(
+                    H3_CLOSED_CRITICAL_STREAM,
+                    "QPACK stream closed",
+                ))
+            }
+            // Unknown stream types are for extensions we don't speak
+            Some(_) => {
+                let _ = reader
+                    .get_mut()
+                    .stream
+                    .stop(VarInt::from_u32(H3_STREAM_CREATION_ERROR));
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn on_control_stream(&self, reader: &mut dyn Read) -> Result<(), Failed> {
+        let mut first = true;
+
+        while let Some(frame) = Frame::read_from(reader)? {
+            match frame.kind {
+                SETTINGS if first => check_settings(&frame.payload)?,
+                _ if first => {
+                    return Err(Failed::Connection(
+                        H3_MISSING_SETTINGS,
+                        "Control stream must start with SETTINGS",
+                    ))
+                }
+                DATA | HEADERS | SETTINGS | PUSH_PROMISE => {
+                    return Err(Failed::Connection(
+                        H3_FRAME_UNEXPECTED,
+                        "Unexpected frame on the control stream",
+                    ))
+                }
+                kind if HTTP2_FRAMES.contains(&kind) => {
+                    return Err(Failed::Connection(
+                        H3_FRAME_UNEXPECTED,
+                        "HTTP/2 frame on the control stream",
+                    ))
+                }
+                // We never push, so GOAWAY, CANCEL_PUSH and MAX_PUSH_ID need nothing from us
+                _ => {}
+            }
+            first = false;
+        }
+
+        Err(Failed::Connection(
+            H3_CLOSED_CRITICAL_STREAM,
+            "Control stream closed",
+        ))
+    }
+
+    fn on_request_stream(&self, send: SendStream, recv: RecvStream) {
+        let mut reader = BufReader::new(StreamReader {
+            runtime: self.runtime,
+            stream: recv,
+        });
+        let mut writer = StreamWriter {
+            runtime: self.runtime,
+            stream: send,
+        };
+
+        let request = match self.read_request(&mut reader) {
+            Ok(request) => request,
+            Err(Failed::Stream(error_code)) => {
+                let _ = reader.get_mut().stream.stop(VarInt::from_u32(error_code));
+                let _ = writer.stream.reset(VarInt::from_u32(error_code));
+                return;
+            }
+            Err(Failed::Io(err)) => {
+                eprintln!("Failed to read request: {}", err);
+                return;
+            }
+            Err(failed) => return self.fail(failed),
+        };
+
+        if let Err(err) = self.respond(&mut writer, &request) {
+            eprintln!(
+                "Failed to send response on stream {}: {}",
+                writer.stream.id(),
+                err
+            );
+            let _ = writer.stream.reset(VarInt::from_u32(H3_INTERNAL_ERROR));
+        }
+    }
+
+    /** Reads HEADERS, any DATA after them, and ignores trailers (RFC 9114 section 4.1) */
+    fn read_request(&self, reader: &mut dyn Read) -> Result<Request, Failed> {
+        let headers = loop {
+            match Frame::read_from(reader)? {
+                Some(frame) if frame.kind == HEADERS => break frame,
+                Some(frame)
+                    if frame.kind == DATA || is_unexpected_on_request_stream(frame.kind) =>
+                {
+                    return Err(Failed::Connection(
+                        H3_FRAME_UNEXPECTED,
+                        "Request must start with HEADERS",
+                    ))
+                }
+                Some(_) => {}
+                None => return Err(Failed::Stream(H3_REQUEST_INCOMPLETE)),
+            }
+        };
+
+        let fields = qpack::decode(&headers.payload)
+            .map_err(|_| Failed::Connection(QPACK_DECOMPRESSION_FAILED, "Invalid field section"))?;
+        let mut request = request_from_headers(fields).ok_or(Failed::Stream(H3_MESSAGE_ERROR))?;
+        request.http_version = HttpVersion::Http3_0;
+        request.peer_addr = Some(self.quic.remote_address());
+        request.client_certificate = self.client_certificate.clone();
+        request.tls = true;
+
+        let mut has_trailers = false;
+        while let Some(frame) = Frame::read_from(reader)? {
+            match frame.kind {
+                DATA if !has_trailers => request.body.extend_from_slice(&frame.payload),
+                HEADERS if !has_trailers => has_trailers = true,
+                DATA | HEADERS => {
+                    return Err(Failed::Connection(
+                        H3_FRAME_UNEXPECTED,
+                        "Frame after trailers",
+                    ))
+                }
+                kind if is_unexpected_on_request_stream(kind) => {
+                    return Err(Failed::Connection(
+                        H3_FRAME_UNEXPECTED,
+                        "Unexpected frame on a request stream",
+                    ))
+                }
+                _ => {}
+            }
+        }
+
+        Ok(request)
+    }
+
+    fn respond(&self, writer: &mut StreamWriter, request: &Request) -> Result<(), Error> {
+        let mut response = self.handler.handle(request);
+        // HTTP/3 frames the body itself, so a chunked one has to be decoded first
+        if response.is_chunked() {
+            response.buffer_body()?;
+        }
+
+        let mut fields = vec![(":status".to_string(), response.status_code.0.to_string())];
+        for (header_name, values) in &response.headers {
+            let header_name = header_name.to_lowercase();
+            if !CONNECTION_HEADERS.contains(&header_name.as_str()) {
+                fields.push((header_name, values.join(";")));
+            }
+        }
+        Frame::new(HEADERS, qpack::encode(&fields)).write_to(writer)?;
+
+        if request.method != Method::HEAD {
+            if !response.body.is_empty() {
+                Frame::new(DATA, std::mem::take(&mut response.body)).write_to(writer)?;
+            }
+            if let Some(stream_body) = response.stream_body.take() {
+                stream_body(&mut DataWriter { stream: writer })?;
+            }
+        }
+
+        Ok(writer.stream.finish()?)
+    }
+
+    fn fail(&self, failed: Failed) {
+        // I/O errors mean the connection or stream is already gone
+        if let Failed::Connection(error_code, message) = failed {
+            self.quic
+                .close(VarInt::from_u32(error_code), message.as_bytes());
+        }
+    }
+}
+
+/** We don't use anything the client can set, but its SETTINGS must still be valid */
+fn check_settings(mut payload: &[u8]) -> Result<(), Failed> {
+    let invalid = Failed::Connection(H3_SETTINGS_ERROR, "Invalid SETTINGS");
+    while !payload.is_empty() {
+        let identifier = read_varint(&mut payload)
+            .ok()
+            .flatten()
+            .filter(|identifier| !HTTP2_SETTINGS.contains(identifier));
+        let value = read_varint(&mut payload).ok().flatten();
+        if identifier.is_none() || value.is_none() {
+            return Err(invalid);
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Advertises an HTTP/3 listener on `port` to clients on other protocols with
+ * an Alt-Svc header (RFC 7838), so they can switch to it for later
+ * requests. Responses that already have an Alt-Svc header are left alone.
+ */
+pub struct AltSvc<H: Handler> {
+    handler: H,
+    port: u16,
+    max_age: Duration,
+}
+
+impl<H: Handler> AltSvc<H> {
+    pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+    pub fn new(handler: H, port: u16) -> Self {
+        AltSvc {
+            handler,
+            port,
+            max_age: Self::DEFAULT_MAX_AGE,
+        }
+    }
+
+    /** How long clients may remember the advertisement */
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+}
+
+impl<H: Handler> Handler for AltSvc<H> {
+    fn handle(&self, request: &Request) -> Response {
+        let mut response = self.handler.handle(request);
+        if request.http_version != HttpVersion::Http3_0 && response.header("alt-svc").is_none() {
+            response.headers.insert(
+                "Alt-Svc".to_string(),
+                vec![format!(
+                    "h3=\":{}\"; ma={}",
+                    self.port,
+                    self.max_age.as_secs()
+                )],
+            );
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use quinn::{crypto::rustls::QuicClientConfig, ConnectionError, ReadError, ReadToEndError};
+    use rcgen::{
+        BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use rustls::pki_types::PrivateKeyDer;
+
+    use super::*;
+
+    /** Writes a fresh self-signed certificate for localhost, returning its paths and DER */
+    fn certificate() -> (PathBuf, PathBuf, CertificateDer<'static>) {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let name = format!(
+            "http_server_http3_{}_{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        );
+        let cert_path = std::env::temp_dir().join(format!("{}.crt", name));
+        let key_path = std::env::temp_dir().join(format!("{}.key", name));
+        std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+        std::fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+        (cert_path, key_path, certified.cert.der().clone())
+    }
+
+    /** The client end of an HTTP/3 connection to an Http3Server */
+    struct Client {
+        runtime: Runtime,
+        quic: quinn::Connection,
+    }
+
+    impl Client {
+        fn connect<H: Handler>(handler: H) -> Self {
+            let (cert_path, key_path, cert) = certificate();
+            Self::connect_as(TlsConfig::new(cert_path, key_path), cert, None, handler).unwrap()
+        }
+
+        /** Connects to a server with `config`, authenticating with `identity` if there is one */
+        fn connect_as<H: Handler>(
+            config: TlsConfig,
+            cert: CertificateDer<'static>,
+            identity: Option<ClientIdentity>,
+            handler: H,
+        ) -> Result<Self, ConnectionError> {
+            let server =
+                Http3Server::bind_with_config("127.0.0.1:0".parse().unwrap(), config).unwrap();
+            let addr = server.local_addr().unwrap();
+            thread::spawn(move || server.serve(handler));
+
+            let mut roots = rustls::RootCertStore::empty();
+            roots.add(cert).unwrap();
+            let provider = Arc::new(rustls::crypto::ring::default_provider());
+            let tls = rustls::ClientConfig::builder_with_provider(provider)
+                .with_protocol_versions(&[&rustls::version::TLS13])
+                .unwrap()
+                .with_root_certificates(roots);
+            let mut tls = match identity {
+                Some((cert, key)) => tls.with_client_auth_cert(vec![cert], key).unwrap(),
+                None => tls.with_no_client_auth(),
+            };
+            tls.alpn_protocols = vec![ALPN.to_vec()];
+            let crypto = QuicClientConfig::try_from(tls).unwrap();
+
+            let runtime = runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            let quic = runtime.block_on(async {
+                let mut endpoint = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+                endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+                endpoint.connect(addr, "localhost").unwrap().await
+            })?;
+
+            Ok(Client { runtime, quic })
+        }
+
+        /** Sends frames on a new request stream, returning the frames sent back or the reset code */
+        fn exchange(&self, frames: &[Frame]) -> Result<Vec<Frame>, u64> {
+            let mut recv = self.send(frames);
+            let received = self.runtime.block_on(recv.read_to_end(1 << 20));
+
+            match received {
+                Ok(received) => {
+                    let mut reader = &received[..];
+                    let mut frames = vec![];
+                    while let Ok(Some(frame)) = Frame::read_from(&mut reader) {
+                        frames.push(frame);
+                    }
+                    Ok(frames)
+                }
+                Err(ReadToEndError::Read(ReadError::Reset(error_code))) => {
+                    Err(error_code.into_inner())
+                }
+                Err(err) => panic!("Failed to read response: {}", err),
+            }
+        }
+
+        /** Sends frames on a new request stream, leaving the response to the caller */
+        fn send(&self, frames: &[Frame]) -> RecvStream {
+            let mut buf = vec![];
+            for frame in frames {
+                frame.write_to(&mut buf).unwrap();
+            }
+
+            self.runtime.block_on(async {
+                let (mut send, recv) = self.quic.open_bi().await.unwrap();
+                send.write_all(&buf).await.unwrap();
+                send.finish().unwrap();
+                recv
+            })
+        }
+
+        /** Sends a request, returning the response's fields and body */
+        fn request(
+            &self,
+            method: &str,
+            path: &str,
+            body: &[u8],
+        ) -> (Vec<(String, String)>, Vec<u8>) {
+            let fields = [
+                (":method", method),
+                (":scheme", "https"),
+                (":path", path),
+                (":authority", "localhost"),
+            ]
+            .map(|(name, value)| (name.to_string(), value.to_string()));
+            let mut frames = vec![Frame::new(HEADERS, qpack::encode(&fields))];
+            if !body.is_empty() {
+                frames.push(Frame::new(DATA, body.to_vec()));
+            }
+
+            let frames = self.exchange(&frames).unwrap();
+            let (headers, data) = frames.split_first().unwrap();
+            assert_eq!(HEADERS, headers.kind);
+            let body = data
+                .iter()
+                .inspect(|frame| assert_eq!(DATA, frame.kind))
+                .flat_map(|frame| frame.payload.clone())
+                .collect();
+            (qpack::decode(&headers.payload).unwrap(), body)
+        }
+
+        /** Opens a control stream and sends it `frames` */
+        fn control(&self, frames: &[Frame]) {
+            let mut buf = vec![CONTROL_STREAM as u8];
+            for frame in frames {
+                frame.write_to(&mut buf).unwrap();
+            }
+            self.runtime.block_on(async {
+                let mut send = self.quic.open_uni().await.unwrap();
+                send.write_all(&buf).await.unwrap();
+                // Dropping the stream would close it, which is an error of its own
+                std::mem::forget(send);
+            });
+        }
+
+        /** The error code the server closed the connection with */
+        fn closed(&self) -> u64 {
+            match self.runtime.block_on(self.quic.closed()) {
+                ConnectionError::ApplicationClosed(close) => close.error_code.into_inner(),
+                err => panic!("Connection closed unexpectedly: {}", err),
+            }
+        }
+    }
+
+    type ClientIdentity = (CertificateDer<'static>, PrivateKeyDer<'static>);
+
+    /** Writes a fresh CA certificate to a PEM file, returning an identity it issued */
+    fn client_ca(common_name: &str) -> (PathBuf, ClientIdentity) {
+        let mut params = CertificateParams::default();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = params.self_signed(&ca_key).unwrap();
+        let (ca_path, _, _) = certificate();
+        std::fs::write(&ca_path, ca.pem()).unwrap();
+
+        let mut params = CertificateParams::default();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        let key = KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+        let identity = (
+            cert.der().clone(),
+            PrivateKeyDer::Pkcs8(key.serialize_der().into()),
+        );
+        (ca_path, identity)
+    }
+
+    fn subject(request: &Request) -> Response {
+        let mut response = Response::new();
+        response.body = match &request.client_certificate {
+            Some(certificate) => certificate.subject.clone().into_bytes(),
+            None => b"anonymous".to_vec(),
+        };
+        response
+    }
+
+    fn echo(request: &Request) -> Response {
+        let mut response = Response::new();
+        response
+            .headers
+            .insert("Connection".to_string(), vec!["keep-alive".to_string()]);
+        response.body = format!(
+            "{} {} {}",
+            request.http_version.as_str(),
+            request.header("host").unwrap_or_default(),
+            String::from_utf8_lossy(&request.body)
+        )
+        .into_bytes();
+        response
+    }
+
+    #[test]
+    fn serves_requests_over_quic() {
+        let client = Client::connect(echo);
+
+        let (fields, body) = client.request("POST", "/echo", b"hello");
+        assert_eq!((":status".to_string(), "200".to_string()), fields[0]);
+        assert!(fields.iter().all(|(name, _)| name != "connection"));
+        assert_eq!(b"HTTP/3 localhost hello".to_vec(), body);
+
+        // Requests can share the connection, each on its own stream
+        let (_, body) = client.request("GET", "/", b"");
+        assert_eq!(b"HTTP/3 localhost ".to_vec(), body);
+    }
+
+    #[test]
+    fn streamed_bodies_become_data_frames() {
+        let client = Client::connect(|_: &Request| {
+            let mut response = Response::new();
+            response.body = b"first".to_vec();
+            response.stream_body = Some(Box::new(|stream: &mut dyn Write| {
+                stream.write_all(b" second")?;
+                stream.write_all(b" third")
+            }));
+            response
+        });
+
+        let (_, body) = client.request("GET", "/", b"");
+        assert_eq!(b"first second third".to_vec(), body);
+
+        let (fields, body) = client.request("HEAD", "/", b"");
+        assert_eq!("200", fields[0].1);
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn malformed_requests_reset_the_stream() {
+        let client = Client::connect(echo);
+
+        let without_scheme = [(":method", "GET"), (":path", "/")]
+            .map(|(name, value)| (name.to_string(), value.to_string()));
+        let headers = Frame::new(HEADERS, qpack::encode(&without_scheme));
+        assert_eq!(Err(H3_MESSAGE_ERROR as u64), client.exchange(&[headers]));
+        assert_eq!(Err(H3_REQUEST_INCOMPLETE as u64), client.exchange(&[]));
+
+        // The stream errors left the connection usable
+        let (fields, _) = client.request("GET", "/", b"");
+        assert_eq!("200", fields[0].1);
+
+        // Unknown frame types are skipped
+        let fields = [(":method", "GET"), (":scheme", "https"), (":path", "/")]
+            .map(|(name, value)| (name.to_string(), value.to_string()));
+        let frames = client
+            .exchange(&[
+                Frame::new(0x21, b"reserved".to_vec()),
+                Frame::new(HEADERS, qpack::encode(&fields)),
+            ])
+            .unwrap();
+        assert_eq!(2, frames.len());
+    }
+
+    #[test]
+    fn connection_errors_close_the_connection() {
+        let client = Client::connect(echo);
+        client.control(&[Frame::new(GOAWAY, vec![0])]);
+        assert_eq!(H3_MISSING_SETTINGS as u64, client.closed());
+
+        let client = Client::connect(echo);
+        client.control(&[Frame::new(SETTINGS, vec![]), Frame::new(DATA, vec![])]);
+        assert_eq!(H3_FRAME_UNEXPECTED as u64, client.closed());
+
+        let client = Client::connect(echo);
+        client.send(&[Frame::new(DATA, b"body first".to_vec())]);
+        assert_eq!(H3_FRAME_UNEXPECTED as u64, client.closed());
+
+        let client = Client::connect(echo);
+        client.send(&[Frame::new(HEADERS, vec![0x00, 0x00, 0x80])]);
+        assert_eq!(QPACK_DECOMPRESSION_FAILED as u64, client.closed());
+    }
+
+    #[test]
+    fn alt_svc_advertises_the_listener() {
+        let handler = AltSvc::new(echo, 8443).max_age(Duration::from_secs(3600));
+
+        let response = handler.handle(&Request::new(Method::GET, "/"));
+        assert_eq!(Some("h3=\":8443\"; ma=3600"), response.header("alt-svc"));
+
+        let mut request = Request::new(Method::GET, "/");
+        request.http_version = HttpVersion::Http3_0;
+        assert_eq!(None, handler.handle(&request).header("alt-svc"));
+    }
+
+    #[test]
+    fn client_certificates_reach_requests() {
+        let (cert_path, key_path, cert) = certificate();
+        let (ca_path, identity) = client_ca("billing");
+        let config = TlsConfig::new(cert_path, key_path).request_client_certificates(&ca_path);
+        let client = Client::connect_as(config.clone(), cert.clone(), Some(identity), subject);
+        let (_, body) = client.unwrap().request("GET", "/", b"");
+        assert_eq!(b"CN=billing".to_vec(), body);
+
+        let client = Client::connect_as(config, cert.clone(), None, subject).unwrap();
+        let (_, body) = client.request("GET", "/", b"");
+        assert_eq!(b"anonymous".to_vec(), body);
+
+        // Without one, a server that requires them closes the connection
+        let (cert_path, key_path, cert) = certificate();
+        let config = TlsConfig::new(cert_path, key_path).require_client_certificates(&ca_path);
+        if let Ok(client) = Client::connect_as(config, cert, None, subject) {
+            let closed = client.runtime.block_on(client.quic.closed());
+            assert!(
+                matches!(closed, ConnectionError::ConnectionClosed(_)),
+                "{}",
+                closed
+            );
+        }
+    }
+
+    #[test]
+    fn varints() {
+        for (value, encoded) in [
+            (37, &[0x25][..]),
+            (15_293, &[0x7b, 0xbd]),
+            (494_878_333, &[0x9d, 0x7f, 0x3e, 0x7d]),
+            (
+                151_288_809_941_952_652,
+                &[0xc2, 0x19, 0x7c, 0x5e, 0xff, 0x14, 0xe8, 0x8c],
+            ),
+        ] {
+            let mut buf = vec![];
+            write_varint(&mut buf, value);
+            assert_eq!(encoded, &buf[..]);
+            assert_eq!(Some(value), read_varint(&mut &buf[..]).unwrap());
+        }
+        assert_eq!(None, read_varint(&mut &[][..]).unwrap());
+    }
+}
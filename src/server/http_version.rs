@@ -1,10 +1,22 @@
 use std::str::FromStr;
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum HttpVersion {
     Http1_0,
     Http1_1,
     Http2_0,
+    Http3_0,
+}
+
+impl HttpVersion {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Http1_0 => "HTTP/1.0",
+            Self::Http1_1 => "HTTP/1.1",
+            Self::Http2_0 => "HTTP/2.0",
+            Self::Http3_0 => "HTTP/3",
+        }
+    }
 }
 
 impl FromStr for HttpVersion {
@@ -15,6 +27,7 @@ impl FromStr for HttpVersion {
             "HTTP/1.0" => Ok(Self::Http1_0),
             "HTTP/1.1" => Ok(Self::Http1_1),
             "HTTP/2.0" => Ok(Self::Http2_0),
+            "HTTP/3" => Ok(Self::Http3_0),
             _ => Err(()),
         }
     }
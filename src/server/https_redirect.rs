@@ -0,0 +1,198 @@
+use std::str::FromStr;
+
+use super::{
+    client::Url, client_certificate::normalized_path, handler::Handler, method::Method,
+    request::Request, response::Response, start_server, status_code::StatusCode,
+    tunnel::parse_authority,
+};
+
+/**
+ * Answers plain HTTP requests with a redirect to the same URL over https,
+ * for the port 80 listener that sits next to a TLS one. GET and HEAD get a
+ * 301; other methods get a 308 so clients repeat them with the same method
+ * and body. Paths under an exempt prefix, like ACME's `/.well-known/`, are
+ * served by their own handler instead.
+ */
+pub struct HttpsRedirect {
+    https_port: u16,
+    exempt: Vec<(String, Box<dyn Handler>)>,
+}
+
+impl Default for HttpsRedirect {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HttpsRedirect {
+    pub const DEFAULT_HTTPS_PORT: u16 = 443;
+
+    pub fn new() -> Self {
+        HttpsRedirect {
+            https_port: Self::DEFAULT_HTTPS_PORT,
+            exempt: vec![],
+        }
+    }
+
+    /** The port the TLS listener is on, added to redirects unless it's 443 */
+    pub fn https_port(mut self, https_port: u16) -> Self {
+        self.https_port = https_port;
+        self
+    }
+
+    /**
+     * Serves paths under `path_prefix` with `handler` rather than redirecting.
+     * The prefix matches whole segments of the normalized path, so
+     * `/.well-known` covers `/.well-known/x` but not `/.well-knownx`.
+     */
+    pub fn exempt<H: Handler>(mut self, path_prefix: &str, handler: H) -> Self {
+        let path_prefix = path_prefix.trim_end_matches('/').to_string();
+        self.exempt.push((path_prefix, Box::new(handler)));
+        self
+    }
+
+    /** The https URL for a request, or None if it has no usable host */
+    fn location(&self, request: &Request) -> Option<String> {
+        let (host, target) = match Url::from_str(&request.raw_target) {
+            // An absolute-form target's authority takes precedence over Host
+            Ok(url) => (url.host, url.path),
+            Err(_) if request.raw_target.starts_with('/') => {
+                let host = request.header("host")?;
+                let (host, _) =
+                    parse_authority(host).or_else(|| parse_authority(&format!("{}:80", host)))?;
+                (host, request.raw_target.clone())
+            }
+            Err(_) => return None,
+        };
+
+        let host = if host.contains(':') {
+            format!("[{}]", host)
+        } else {
+            host
+        };
+        Some(match self.https_port {
+            Self::DEFAULT_HTTPS_PORT => format!("https://{}{}", host, target),
+            port => format!("https://{}:{}{}", host, port, target),
+        })
+    }
+}
+
+impl Handler for HttpsRedirect {
+    fn handle(&self, request: &Request) -> Response {
+        let path = normalized_path(&request.raw_target);
+        if let Some((_, handler)) = self.exempt.iter().find(|(prefix, _)| {
+            path.strip_prefix(prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        }) {
+            return handler.handle(request);
+        }
+
+        let Some(location) = self.location(request) else {
+            return Response::error(StatusCode::BAD_REQUEST, "Missing or invalid Host header");
+        };
+
+        let status_code = match request.method {
+            Method::GET | Method::HEAD => StatusCode::MOVED_PERMANENTLY,
+            _ => StatusCode::PERMANENT_REDIRECT,
+        };
+        let mut response = Response::error(status_code, &format!("Moved to {}", location));
+        response
+            .headers
+            .insert("Location".to_string(), vec![location]);
+        response
+    }
+}
+
+/** Listens on localhost:`port` and redirects everything to https, besides exempt paths */
+pub fn start_https_redirect_server(port: u16, redirect: HttpsRedirect) {
+    start_server(port, redirect);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, target: &str, host: Option<&str>) -> Request {
+        let mut request = Request::new(method, target);
+        if let Some(host) = host {
+            request
+                .headers
+                .insert("host".to_string(), vec![host.to_string()]);
+        }
+        request
+    }
+
+    #[test]
+    fn requests_are_redirected_to_https() {
+        let redirect = HttpsRedirect::new();
+
+        let response = redirect.handle(&request(Method::GET, "/a/b?c=d", Some("Example.com:8080")));
+        assert_eq!(StatusCode::MOVED_PERMANENTLY, response.status_code);
+        assert_eq!(
+            Some("https://example.com/a/b?c=d"),
+            response.header("Location")
+        );
+
+        let response = redirect.handle(&request(Method::POST, "/form", Some("[::1]")));
+        assert_eq!(StatusCode::PERMANENT_REDIRECT, response.status_code);
+        assert_eq!(Some("https://[::1]/form"), response.header("Location"));
+
+        let response = redirect.handle(&request(
+            Method::HEAD,
+            "http://other.test/x",
+            Some("example.com"),
+        ));
+        assert_eq!(Some("https://other.test/x"), response.header("Location"));
+
+        let redirect = redirect.https_port(8443);
+        let response = redirect.handle(&request(Method::GET, "/", Some("example.com")));
+        assert_eq!(
+            Some("https://example.com:8443/"),
+            response.header("Location")
+        );
+    }
+
+    #[test]
+    fn bad_hosts_are_rejected() {
+        let redirect = HttpsRedirect::new();
+
+        for (target, host) in [
+            ("/", None),
+            ("/", Some("evil.test/path")),
+            ("/", Some("user@evil.test")),
+            ("*", Some("example.com")),
+        ] {
+            let response = redirect.handle(&request(Method::GET, target, host));
+            assert_eq!(StatusCode::BAD_REQUEST, response.status_code, "{:?}", host);
+            assert_eq!(None, response.header("Location"));
+        }
+    }
+
+    #[test]
+    fn exempt_paths_are_served() {
+        let redirect = HttpsRedirect::new().exempt("/.well-known/", |request: &Request| {
+            let mut response = Response::new();
+            response.body = request.raw_target.clone().into_bytes();
+            response
+        });
+
+        let response = redirect.handle(&request(
+            Method::GET,
+            "/.well-known/acme-challenge/token",
+            Some("example.com"),
+        ));
+        assert_eq!(StatusCode::OK, response.status_code);
+        assert_eq!(b"/.well-known/acme-challenge/token".to_vec(), response.body);
+
+        // Matched on the normalized path, a segment at a time
+        for (target, status_code) in [
+            ("/%2Ewell-known/acme-challenge/token", StatusCode::OK),
+            ("/.well-known", StatusCode::OK),
+            ("/.well-known/../private", StatusCode::MOVED_PERMANENTLY),
+            ("/.well-knownx", StatusCode::MOVED_PERMANENTLY),
+        ] {
+            let response = redirect.handle(&request(Method::GET, target, Some("example.com")));
+            assert_eq!(status_code, response.status_code, "{}", target);
+        }
+    }
+}
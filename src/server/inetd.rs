@@ -0,0 +1,143 @@
+use std::{
+    io::{self, Error, ErrorKind, Read, Write},
+    net::SocketAddr,
+};
+
+use super::{
+    bad_request, handler::Handler, request::Request, response::Response, status_code::StatusCode,
+};
+
+/**
+ * Serves the one connection a supervisor (inetd, socat, systemd with
+ * Accept=yes) has handed the process as its stdin and stdout, then
+ * returns. Nothing else may write to stdout, nor to stderr where the
+ * supervisor points that at the connection too, as classic inetd does.
+ * Requests are plain HTTP/1.1: there is no HTTP/2, and upgrades are
+ * refused with 501 since the connection can't be handed on.
+ */
+pub fn serve_stdio<H: Handler>(handler: H) -> Result<(), Error> {
+    let peer_addr = stdin_peer_addr();
+    serve_io(
+        &mut io::stdin().lock(),
+        &mut io::stdout().lock(),
+        peer_addr,
+        handler,
+    )
+}
+
+/** Serves one request read from `reader`, writing the response to `writer` */
+pub fn serve_io<H: Handler>(
+    reader: &mut dyn Read,
+    writer: &mut dyn Write,
+    peer_addr: Option<SocketAddr>,
+    handler: H,
+) -> Result<(), Error> {
+    let mut request = match Request::from_stream(reader) {
+        Ok(request) => request,
+        // The client went away without asking for anything
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+        Err(err) => {
+            if let Some(mut response) = bad_request(&err) {
+                response.write_to(writer)?;
+                writer.flush()?;
+            }
+            return Err(err);
+        }
+    };
+    request.peer_addr = peer_addr;
+
+    let mut response = handler.handle(&request);
+    // Whatever the 101 (or 200 for CONNECT) promised couldn't be delivered
+    if response.on_upgrade.is_some() {
+        response = Response::error(
+            StatusCode::NOT_IMPLEMENTED,
+            "Upgrades aren't supported over stdin and stdout",
+        );
+    }
+    response.write_to(writer)?;
+    writer.flush()
+}
+
+/** The client's address, when stdin is the TCP connection itself rather than a pipe */
+#[cfg(unix)]
+fn stdin_peer_addr() -> Option<SocketAddr> {
+    socket2::SockRef::from(&io::stdin())
+        .peer_addr()
+        .ok()?
+        .as_socket()
+}
+
+#[cfg(not(unix))]
+fn stdin_peer_addr() -> Option<SocketAddr> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::upgrade::switching_protocols, *};
+
+    fn echo(request: &Request) -> Response {
+        let mut response = Response::new();
+        response.body = format!("{} {:?}", request.raw_target, request.peer_addr).into_bytes();
+        response
+    }
+
+    #[test]
+    fn serves_a_request_from_a_reader() {
+        let peer = SocketAddr::from(([192, 0, 2, 1], 1234));
+        let mut written = vec![];
+        serve_io(
+            &mut &b"GET /hello HTTP/1.1\r\nHost: localhost\r\n\r\n"[..],
+            &mut written,
+            Some(peer),
+            echo,
+        )
+        .unwrap();
+
+        let response = String::from_utf8(written).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(
+            response.ends_with("/hello Some(192.0.2.1:1234)"),
+            "{}",
+            response
+        );
+    }
+
+    #[test]
+    fn nothing_is_written_for_an_empty_connection() {
+        let mut written = vec![];
+        serve_io(&mut &b""[..], &mut written, None, echo).unwrap();
+        assert!(written.is_empty());
+    }
+
+    #[test]
+    fn upgrades_are_refused() {
+        let mut written = vec![];
+        serve_io(
+            &mut &b"GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\r\n"[..],
+            &mut written,
+            None,
+            |_: &Request| switching_protocols("websocket", |_| {}),
+        )
+        .unwrap();
+
+        let response = String::from_utf8(written).unwrap();
+        assert!(response.starts_with("HTTP/1.1 501"), "{}", response);
+    }
+
+    #[test]
+    fn malformed_requests_get_a_400() {
+        let mut written = vec![];
+        let err = serve_io(
+            &mut &b"POST / HTTP/1.1\r\nContent-Length: x\r\n\r\n"[..],
+            &mut written,
+            None,
+            echo,
+        )
+        .unwrap_err();
+
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+        let response = String::from_utf8(written).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+    }
+}
@@ -0,0 +1,578 @@
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        io::{AsRawFd, RawFd},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+};
+use std::{
+    io::{Error, ErrorKind, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+};
+
+use super::{
+    handle_connection,
+    handler::Handler,
+    shutdown::{ServerHandle, Tracked},
+    socket_options::SocketOptions,
+};
+
+/**
+ * The process on the other end of a Unix socket, as the kernel recorded it
+ * when the connection was made (SO_PEERCRED)
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/** Where connections come from: a TCP port or a Unix domain socket */
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub fn accept(&self) -> Result<Stream, Error> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _)| Stream::Tcp(stream)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.accept().map(|(stream, _)| Stream::Unix(stream)),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<(), Error> {
+        match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn try_clone(&self) -> Result<Self, Error> {
+        match self {
+            Listener::Tcp(listener) => listener.try_clone().map(Listener::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.try_clone().map(Listener::Unix),
+        }
+    }
+
+    /** Serves connections forever, each on a thread of its own */
+    pub fn serve<H: Handler>(self, handler: H) {
+        self.serve_until_shutdown(handler, &ServerHandle::new());
+    }
+
+    /**
+     * Serves connections, each on a thread of its own, until `handle` is
+     * shut down. Returns once the shutdown has finished with the
+     * connections that were open. Other processes can accept from the same
+     * socket meanwhile, e.g. after a handoff.
+     */
+    pub fn serve_until_shutdown<H: Handler>(self, handler: H, handle: &ServerHandle) {
+        self.serve_with_options(handler, handle, &SocketOptions::default());
+    }
+
+    /**
+     * As serve_until_shutdown, setting `options` on each connection
+     * accepted. Those for the listening socket itself are set by
+     * BindAddress::bind.
+     */
+    pub fn serve_with_options<H: Handler>(
+        self,
+        handler: H,
+        handle: &ServerHandle,
+        options: &SocketOptions,
+    ) {
+        self.accept_until_shutdown(handle, options, move |stream, tracked| {
+            handle_connection(stream, &handler, tracked)
+        });
+    }
+
+    /**
+     * The accept loop behind serve_with_options, for listeners that serve
+     * their connections some other way, e.g. over TLS. Each connection is
+     * passed to `serve` on a thread of its own.
+     */
+    pub(super) fn accept_until_shutdown<F>(
+        self,
+        handle: &ServerHandle,
+        options: &SocketOptions,
+        serve: F,
+    ) where
+        F: Fn(Stream, Option<&Tracked>) + Send + Sync + 'static,
+    {
+        let serve = Arc::new(serve);
+        let acceptor = match handle.acceptor(&self) {
+            Ok(acceptor) => acceptor,
+            Err(err) => {
+                eprintln!("Unable to accept connections: {}", err);
+                return;
+            }
+        };
+
+        while let Some(accepted) = acceptor.accept() {
+            let stream = match accepted {
+                Ok(stream) => stream,
+                Err(err) => {
+                    eprintln!("Failed to accept connection: {}", err);
+                    continue;
+                }
+            };
+            if let Err(err) = options.accepted(&stream) {
+                eprintln!("Failed to set socket options: {}", err);
+            }
+            let tracked = handle.track(&stream);
+            let serve = Arc::clone(&serve);
+            // Each connection gets its own thread so a long-lived response (e.g. an
+            // event stream) doesn't hold up the accept loop
+            thread::spawn(move || serve(stream, tracked.as_ref()));
+        }
+
+        // Closes the socket, so new connections are refused rather than queued
+        drop(acceptor);
+        drop(self);
+        handle.wait();
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Self {
+        Listener::Unix(listener)
+    }
+}
+
+/** An accepted connection, from whichever kind of Listener */
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub fn try_clone(&self) -> Result<Self, Error> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<(), Error> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    /** Reads without consuming, so the bytes are read again by the next read() */
+    pub fn peek(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            Stream::Tcp(stream) => stream.peek(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => {
+                // SAFETY: buf is valid for writes of buf.len() bytes, and the fd
+                // stays open for the call since we hold a reference to the stream
+                let read = unsafe {
+                    libc::recv(
+                        stream.as_raw_fd(),
+                        buf.as_mut_ptr().cast(),
+                        buf.len(),
+                        libc::MSG_PEEK,
+                    )
+                };
+                if read < 0 {
+                    return Err(Error::last_os_error());
+                }
+                Ok(read as usize)
+            }
+        }
+    }
+
+    /**
+     * Like peek, but waits until `buf` is full, failing with UnexpectedEof if
+     * the connection ends first. Elsewhere than Unix it only waits for the
+     * first bytes, so a short read fails too.
+     */
+    pub fn peek_exact(&self, buf: &mut [u8]) -> Result<(), Error> {
+        #[cfg(unix)]
+        let peeked = {
+            let fd = match self {
+                Stream::Tcp(stream) => stream.as_raw_fd(),
+                Stream::Unix(stream) => stream.as_raw_fd(),
+            };
+            // SAFETY: buf is valid for writes of buf.len() bytes, and the fd
+            // stays open for the call since we hold a reference to the stream
+            let peeked = unsafe {
+                libc::recv(
+                    fd,
+                    buf.as_mut_ptr().cast(),
+                    buf.len(),
+                    libc::MSG_PEEK | libc::MSG_WAITALL,
+                )
+            };
+            if peeked < 0 {
+                return Err(Error::last_os_error());
+            }
+            peeked as usize
+        };
+        #[cfg(not(unix))]
+        let peeked = self.peek(buf)?;
+
+        if peeked < buf.len() {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Connection closed before enough was sent to peek at",
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), Error> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    /** The client's address, for TCP connections */
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Stream::Tcp(stream) => stream.peer_addr().ok(),
+            #[cfg(unix)]
+            Stream::Unix(_) => None,
+        }
+    }
+
+    /** The client process's credentials, for Unix socket connections on Linux */
+    pub fn peer_credentials(&self) -> Option<PeerCredentials> {
+        match self {
+            Stream::Tcp(_) => None,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            Stream::Unix(stream) => peer_credentials(stream)
+                .inspect_err(|err| eprintln!("Failed to read peer credentials: {}", err))
+                .ok(),
+            #[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+            Stream::Unix(_) => None,
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl From<TcpStream> for Stream {
+    fn from(stream: TcpStream) -> Self {
+        Stream::Tcp(stream)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixStream> for Stream {
+    fn from(stream: UnixStream) -> Self {
+        Stream::Unix(stream)
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_credentials(stream: &UnixStream) -> Result<PeerCredentials, Error> {
+    let mut ucred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut length = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: ucred and length describe a buffer of the size SO_PEERCRED fills
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut ucred as *mut libc::ucred).cast(),
+            &mut length,
+        )
+    };
+    if result != 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(PeerCredentials {
+        pid: ucred.pid,
+        uid: ucred.uid,
+        gid: ucred.gid,
+    })
+}
+
+#[cfg(unix)]
+#[derive(Clone, Debug)]
+enum UnixAddress {
+    Path(PathBuf),
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    Abstract(Vec<u8>),
+}
+
+/**
+ * A Unix domain socket to listen on, e.g. for a sidecar on the same host.
+ * Requests that come in on one have no peer_addr, but have the connecting
+ * process's peer_credentials on Linux.
+ */
+#[cfg(unix)]
+#[derive(Clone, Debug)]
+pub struct UnixSocket {
+    address: UnixAddress,
+    mode: Option<u32>,
+}
+
+#[cfg(unix)]
+impl UnixSocket {
+    /**
+     * A socket file at `path`. A socket already there that nothing is
+     * listening on is left from a server that didn't shut down cleanly, and
+     * is replaced; anything else there makes bind() fail.
+     */
+    pub fn path(path: impl Into<PathBuf>) -> Self {
+        UnixSocket {
+            address: UnixAddress::Path(path.into()),
+            mode: None,
+        }
+    }
+
+    /**
+     * A socket in Linux's abstract namespace, named without the leading NUL.
+     * It has no file, so there's nothing to clean up and no permissions;
+     * anything in the same network namespace can connect.
+     */
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn abstract_name(name: impl Into<Vec<u8>>) -> Self {
+        UnixSocket {
+            address: UnixAddress::Abstract(name.into()),
+            mode: None,
+        }
+    }
+
+    /**
+     * Sets the socket file's permission bits once it's bound, e.g. 0o660 to
+     * only let the owner and group connect. Until then the umask applies.
+     */
+    pub fn permissions(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    pub fn bind(&self) -> Result<Listener, Error> {
+        let listener = match &self.address {
+            UnixAddress::Path(path) => {
+                remove_stale_socket(path)?;
+                let listener = UnixListener::bind(path)?;
+                if let Some(mode) = self.mode {
+                    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+                }
+                listener
+            }
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            UnixAddress::Abstract(name) => {
+                #[cfg(target_os = "android")]
+                use std::os::android::net::SocketAddrExt;
+                #[cfg(target_os = "linux")]
+                use std::os::linux::net::SocketAddrExt;
+
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                UnixListener::bind_addr(&addr)?
+            }
+        };
+        Ok(Listener::Unix(listener))
+    }
+}
+
+/** Removes a socket file if it's one nothing is listening on any more */
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> Result<(), Error> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} exists and isn't a socket", path.display()),
+        ));
+    }
+
+    match UnixStream::connect(path) {
+        Ok(_) => Err(Error::new(
+            ErrorKind::AddrInUse,
+            format!("Something is already listening on {}", path.display()),
+        )),
+        Err(err) if err.kind() == ErrorKind::ConnectionRefused => fs::remove_file(path),
+        Err(err) => Err(err),
+    }
+}
+
+/** Listens on `socket` until SIGTERM or SIGINT, then shuts down gracefully */
+#[cfg(unix)]
+pub fn start_unix_server<H: Handler>(socket: UnixSocket, handler: H) {
+    let listener = socket
+        .bind()
+        .unwrap_or_else(|err| panic!("Unable to listen on {:?}: {err}", socket));
+
+    let handle = ServerHandle::new();
+    if let Err(err) = handle.shutdown_on_signals(super::shutdown::DEFAULT_GRACE_PERIOD) {
+        eprintln!("Failed to handle shutdown signals: {}", err);
+    }
+    listener.serve_until_shutdown(handler, &handle);
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{
+        os::unix::fs::MetadataExt,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::{
+        super::{http2, request::Request, response::Response},
+        *,
+    };
+
+    fn socket_path() -> PathBuf {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        std::env::temp_dir().join(format!(
+            "http_server_unix_{}_{}.sock",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        ))
+    }
+
+    fn credentials(request: &Request) -> Response {
+        let mut response = Response::new();
+        response.body = format!(
+            "{:?} {:?}",
+            request.peer_addr,
+            request.peer_credentials.map(|credentials| credentials.pid)
+        )
+        .into_bytes();
+        response
+    }
+
+    fn get(mut stream: UnixStream) -> String {
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn serves_a_socket_file() {
+        let path = socket_path();
+        let listener = UnixSocket::path(&path).permissions(0o600).bind().unwrap();
+        thread::spawn(move || listener.serve(credentials));
+
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(0o600, metadata.mode() & 0o777);
+
+        let response = get(UnixStream::connect(&path).unwrap());
+        assert!(
+            response.ends_with(&format!("None Some({})", std::process::id())),
+            "{}",
+            response
+        );
+
+        // HTTP/2 is detected by peeking, the same as over TCP
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream.write_all(http2::PREFACE).unwrap();
+        stream.write_all(&[0, 0, 0, 0x4, 0, 0, 0, 0, 0]).unwrap();
+        let mut frame_header = [0; 9];
+        stream.read_exact(&mut frame_header).unwrap();
+        assert_eq!(0x4, frame_header[3]);
+    }
+
+    #[test]
+    fn stale_sockets_are_replaced() {
+        let path = socket_path();
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let listener = UnixSocket::path(&path).bind().unwrap();
+
+        let err = UnixSocket::path(&path).bind().unwrap_err();
+        assert_eq!(ErrorKind::AddrInUse, err.kind());
+        drop(listener);
+
+        let file = socket_path();
+        fs::write(&file, "not a socket").unwrap();
+        let err = UnixSocket::path(&file).bind().unwrap_err();
+        assert_eq!(ErrorKind::AlreadyExists, err.kind());
+        assert_eq!("not a socket", fs::read_to_string(&file).unwrap());
+        fs::remove_file(file).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn serves_an_abstract_socket() {
+        use std::os::linux::net::SocketAddrExt;
+
+        let name = format!("http_server_test_{}", std::process::id());
+        let listener = UnixSocket::abstract_name(name.as_bytes()).bind().unwrap();
+        thread::spawn(move || listener.serve(credentials));
+
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let response = get(UnixStream::connect_addr(&addr).unwrap());
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+}
@@ -1,6 +1,7 @@
 use std::str::FromStr;
 
-#[derive(Debug, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Method {
     GET,
     HEAD,
@@ -13,6 +14,22 @@ pub enum Method {
     TRACE,
 }
 
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::GET => "GET",
+            Self::HEAD => "HEAD",
+            Self::OPTIONS => "OPTIONS",
+            Self::POST => "POST",
+            Self::PUT => "PUT",
+            Self::PATCH => "PATCH",
+            Self::DELETE => "DELETE",
+            Self::CONNECT => "CONNECT",
+            Self::TRACE => "TRACE",
+        }
+    }
+}
+
 impl FromStr for Method {
     type Err = ();
 
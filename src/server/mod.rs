@@ -1,50 +1,153 @@
+pub mod balancer;
+pub mod bind;
+pub mod cache;
+pub mod client;
+pub mod client_certificate;
+pub mod coalesce;
+pub mod date;
 mod fields;
-mod http_version;
-mod method;
-mod request;
-mod response;
-mod status_code;
+pub mod forward_proxy;
+pub mod handler;
+#[cfg(unix)]
+pub mod handoff;
+pub mod hpack;
+pub mod http2;
+#[cfg(feature = "http3")]
+pub mod http3;
+pub mod http_version;
+pub mod https_redirect;
+pub mod inetd;
+pub mod listener;
+pub mod method;
+#[cfg(unix)]
+pub mod prefork;
+pub mod proxy;
+pub mod qpack;
+pub mod request;
+pub mod response;
+pub mod shutdown;
+#[cfg(unix)]
+mod signal;
+pub mod socket_options;
+pub mod sse;
+pub mod status_code;
+#[cfg(unix)]
+pub mod systemd;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod tunnel;
+pub mod upgrade;
+pub mod websocket;
 
 use std::{
-    io::Write,
-    net::{SocketAddr, TcpListener, TcpStream},
+    io::{BufReader, Error, ErrorKind, Read, Write},
+    net::SocketAddr,
 };
 
-use self::{request::Request, response::Response};
+use self::{
+    bind::Server,
+    handler::Handler,
+    listener::Stream,
+    request::Request,
+    response::Response,
+    shutdown::{ServerHandle, Tracked, DEFAULT_GRACE_PERIOD},
+    status_code::StatusCode,
+    upgrade::Upgraded,
+};
+
+pub fn start_server<H: Handler>(port: u16, handler: H) {
+    start_server_on(&[SocketAddr::from(([127, 0, 0, 1], port))], handler);
+}
+
+/**
+ * Serves every one of `addresses`, e.g. `0.0.0.0:8080` and `[::]:8080` to
+ * be reachable from other hosts, until SIGTERM or SIGINT. Use bind::Server
+ * for IPV6_V6ONLY or a handler per address.
+ */
+pub fn start_server_on<H: Handler>(addresses: &[SocketAddr], handler: H) {
+    let handle = ServerHandle::new();
+    #[cfg(unix)]
+    if let Err(err) = handle.shutdown_on_signals(DEFAULT_GRACE_PERIOD) {
+        eprintln!("Failed to handle shutdown signals: {}", err);
+    }
+
+    let server = addresses
+        .iter()
+        .fold(Server::new(), |server, &addr| server.bind(addr));
+    if let Err(err) = server.serve_until_shutdown(handler, &handle) {
+        panic!("{err}");
+    }
+}
+
+/** The 400 for a request that couldn't be parsed, rather than one cut short */
+fn bad_request(err: &Error) -> Option<Response> {
+    matches!(err.kind(), ErrorKind::InvalidData | ErrorKind::InvalidInput)
+        .then(|| Response::error(StatusCode::BAD_REQUEST, &err.to_string()))
+}
 
-pub fn start_server(port: u16) {
-    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], port)))
-        .expect(&format!("Unable to listen on localhost:{port}"));
+fn handle_connection(stream: Stream, handler: &dyn Handler, tracked: Option<&Tracked>) {
+    if http2::has_preface(&stream) {
+        http2::serve(BufReader::new(stream), handler, tracked);
+        return;
+    }
+
+    let mut reader = BufReader::new(stream);
+    let Some(mut request) = read_request(&mut reader) else {
+        return;
+    };
+    request.peer_addr = reader.get_ref().peer_addr();
+    request.peer_credentials = reader.get_ref().peer_credentials();
+
+    if let Some(settings) = http2::h2c_upgrade_settings(&request) {
+        http2::serve_h2c_upgrade(reader, handler, request, &settings, tracked);
+        return;
+    }
+
+    let mut response = handler.handle(&request);
+    if let Err(err) = write_response(reader.get_mut(), &mut response, tracked) {
+        eprintln!("Failed to write response: {}", err);
+        return;
+    }
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        handle_connection(stream);
+    if let Some(on_upgrade) = response.on_upgrade.take() {
+        let buffered = reader.buffer().to_vec();
+        on_upgrade(Upgraded {
+            stream: reader.into_inner(),
+            buffered,
+        });
     }
 }
 
-fn handle_connection(mut stream: TcpStream) {
-    let request = Request::from_stream(&mut stream).unwrap();
-
-    println!(
-        "Raw body (length {}): {:?}",
-        request.body.len(),
-        request.body
-    );
-
-    let body_text = String::from_utf8(
-        request
-            .body
-            .into_iter()
-            .take_while(|b| *b != 0)
-            .collect::<Vec<_>>(),
-    )
-    .expect("Body is not valid UTF-8");
-
-    let mut response = Response::new();
-    response
-        .body
-        .write_all(&format!("You sent me: \"{}\"\n", body_text).as_bytes())
-        .unwrap();
-
-    response.write_to(&mut stream).unwrap();
+/**
+ * Reads the request on an HTTP/1.1 connection, answering 400 to one that
+ * can't be parsed. None means there's nothing left to do but close it.
+ */
+fn read_request<S: Read + Write>(reader: &mut BufReader<S>) -> Option<Request> {
+    match Request::from_reader(reader) {
+        Ok(request) => Some(request),
+        // Nothing was sent, e.g. a speculative connection a browser didn't use
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => None,
+        Err(err) => {
+            eprintln!("Failed to read request: {}", err);
+            if let Some(mut response) = bad_request(&err) {
+                let _ = response.write_to(reader.get_mut());
+            }
+            None
+        }
+    }
+}
+
+/** Writes the response on an HTTP/1.1 connection, saying so if the server is draining */
+fn write_response(
+    stream: &mut dyn Write,
+    response: &mut Response,
+    tracked: Option<&Tracked>,
+) -> Result<(), Error> {
+    // The connection is never reused, but this tells the client not to try
+    if response.on_upgrade.is_none() && tracked.is_some_and(Tracked::is_draining) {
+        response
+            .headers
+            .insert("Connection".to_string(), vec!["close".to_string()]);
+    }
+    response.write_to(stream)
 }
@@ -0,0 +1,370 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::Error,
+    net::SocketAddr,
+    os::unix::process::ExitStatusExt,
+    process::{self, ExitStatus},
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::{
+    bind::BindAddress,
+    handler::Handler,
+    shutdown::{ServerHandle, DEFAULT_GRACE_PERIOD},
+    signal::Signals,
+    socket_options::SocketOptions,
+};
+
+/** Workers that die sooner than this after starting are restarted after the same delay */
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/**
+ * Serves from several worker processes rather than threads of one, so a
+ * crash only takes down the connections of the worker it happened in.
+ * Each worker binds the same address with SO_REUSEPORT and the kernel
+ * spreads connections between them. The master process only supervises:
+ * it restarts workers that exit, passes SIGTERM and SIGINT on to them and
+ * waits for them to shut down gracefully, and on SIGHUP replaces them with
+ * new ones that build their handler afresh, e.g. to reread configuration.
+ */
+pub struct Prefork {
+    workers: usize,
+    grace_period: Duration,
+    options: SocketOptions,
+}
+
+impl Default for Prefork {
+    fn default() -> Self {
+        Self::new(thread::available_parallelism().map_or(1, |workers| workers.get()))
+    }
+}
+
+impl Prefork {
+    pub fn new(workers: usize) -> Self {
+        Prefork {
+            workers: workers.max(1),
+            grace_period: DEFAULT_GRACE_PERIOD,
+            options: SocketOptions::default(),
+        }
+    }
+
+    /** How long workers wait for open connections once told to shut down */
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /** Tunes each worker's listening socket and the connections it accepts */
+    pub fn options(mut self, options: SocketOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /**
+     * Starts the workers, each serving `addr` with a handler from
+     * `new_handler`, and supervises them until they've all shut down after
+     * SIGTERM or SIGINT. Workers are forked, so call this before starting
+     * any threads.
+     */
+    pub fn serve<H: Handler, F: Fn() -> H>(
+        &self,
+        addr: SocketAddr,
+        new_handler: F,
+    ) -> Result<(), Error> {
+        // Each worker binds the same address with SO_REUSEPORT
+        let address = BindAddress::new(addr).options(self.options).reuse_port();
+        // Fails here, rather than in every worker, if the address can't be used
+        drop(address.bind_socket()?);
+
+        let mut master = Master {
+            prefork: self,
+            address,
+            new_handler,
+            signals: Signals::new(&[libc::SIGCHLD, libc::SIGTERM, libc::SIGINT, libc::SIGHUP])?,
+            workers: HashMap::new(),
+            retiring: HashSet::new(),
+            stopping: false,
+        };
+        for _ in 0..self.workers {
+            master.spawn_worker()?;
+        }
+        master.supervise()
+    }
+}
+
+struct Master<'a, F> {
+    prefork: &'a Prefork,
+    address: BindAddress,
+    new_handler: F,
+    signals: Signals,
+    /** When each worker was started, by pid */
+    workers: HashMap<libc::pid_t, Instant>,
+    /** Workers that were told to shut down on SIGHUP, and aren't replaced when they exit */
+    retiring: HashSet<libc::pid_t>,
+    stopping: bool,
+}
+
+impl<H: Handler, F: Fn() -> H> Master<'_, F> {
+    fn supervise(&mut self) -> Result<(), Error> {
+        while !self.workers.is_empty() {
+            match self.signals.wait()? {
+                libc::SIGCHLD => self.reap()?,
+                libc::SIGHUP if !self.stopping => {
+                    eprintln!("Replacing {} workers", self.workers.len());
+                    let old = self.workers.keys().copied().collect::<Vec<_>>();
+                    for _ in 0..self.prefork.workers {
+                        self.spawn_worker()?;
+                    }
+                    for pid in old {
+                        self.retiring.insert(pid);
+                        kill(pid, libc::SIGTERM);
+                    }
+                }
+                libc::SIGHUP => {}
+                // A second one makes the workers close their connections right away
+                signal => {
+                    self.stopping = true;
+                    for &pid in self.workers.keys() {
+                        kill(pid, signal);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /** Collects workers that have exited, and replaces them unless they were told to stop */
+    fn reap(&mut self) -> Result<(), Error> {
+        // Only our workers, as whatever else started other children waits for them itself
+        let exited = self
+            .workers
+            .keys()
+            .filter_map(|&pid| try_wait(pid).map(|status| (pid, status)))
+            .collect::<Vec<_>>();
+
+        for (pid, status) in exited {
+            let started = self.workers.remove(&pid).unwrap_or_else(Instant::now);
+            if self.retiring.remove(&pid) || self.stopping {
+                continue;
+            }
+
+            eprintln!("Worker {} exited with {}, restarting it", pid, status);
+            // So a worker that can't start doesn't spin
+            if started.elapsed() < RESTART_DELAY {
+                thread::sleep(RESTART_DELAY);
+            }
+            self.spawn_worker()?;
+        }
+        Ok(())
+    }
+
+    fn spawn_worker(&mut self) -> Result<(), Error> {
+        let master = process::id();
+        // Until the child has restored the default actions, its signals would
+        // go to the master's socket, which it shares
+        let mask = block_signals();
+        // SAFETY: the master has no other threads, so the child is a whole copy of it
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            self.signals.restore();
+            unblock_signals(&mask);
+            process::exit(self.work(master));
+        }
+        unblock_signals(&mask);
+
+        if pid < 0 {
+            return Err(Error::last_os_error());
+        }
+        self.workers.insert(pid, Instant::now());
+        Ok(())
+    }
+
+    /** Runs in the worker process, returning its exit code */
+    fn work(&self, master: u32) -> i32 {
+        // Go down with the master, rather than serving on unsupervised
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            // SAFETY: PR_SET_PDEATHSIG takes a signal number, and nothing else is read
+            unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) };
+            // In case it died before that was set. SAFETY: getppid can't fail
+            if unsafe { libc::getppid() } as u32 != master {
+                return 1;
+            }
+        }
+
+        let listener = match self.address.bind() {
+            Ok(listener) => listener,
+            Err(err) => {
+                eprintln!(
+                    "Worker {} is unable to listen on {}: {}",
+                    process::id(),
+                    self.address.addr(),
+                    err
+                );
+                return 1;
+            }
+        };
+
+        let handle = ServerHandle::new();
+        if let Err(err) = handle.shutdown_on_signals(self.prefork.grace_period) {
+            eprintln!("Failed to handle shutdown signals: {}", err);
+            return 1;
+        }
+        listener.serve_with_options((self.new_handler)(), &handle, &self.prefork.options);
+        0
+    }
+}
+
+/** Blocks every signal for the calling thread, returning the mask to restore */
+fn block_signals() -> libc::sigset_t {
+    // SAFETY: sigset_t is plain data, initialized by sigfillset and pthread_sigmask
+    unsafe {
+        let mut all = std::mem::zeroed();
+        let mut mask = std::mem::zeroed();
+        libc::sigfillset(&mut all);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &all, &mut mask);
+        mask
+    }
+}
+
+fn unblock_signals(mask: &libc::sigset_t) {
+    // SAFETY: mask came from block_signals
+    unsafe { libc::pthread_sigmask(libc::SIG_SETMASK, mask, std::ptr::null_mut()) };
+}
+
+fn kill(pid: libc::pid_t, signal: libc::c_int) {
+    // SAFETY: kill only takes numbers; pid is a child we haven't reaped, so it's still ours
+    if unsafe { libc::kill(pid, signal) } != 0 {
+        eprintln!(
+            "Failed to signal worker {}: {}",
+            pid,
+            Error::last_os_error()
+        );
+    }
+}
+
+/** How `pid` exited, or None if it's still running */
+fn try_wait(pid: libc::pid_t) -> Option<ExitStatus> {
+    let mut status = 0;
+    // SAFETY: status is a valid place for waitpid to write to
+    match unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) } {
+        0 => None,
+        -1 => {
+            eprintln!(
+                "Failed to wait for worker {}: {}",
+                pid,
+                Error::last_os_error()
+            );
+            // Nothing to wait for any more, so it's as good as gone
+            Some(ExitStatus::from_raw(status))
+        }
+        _ => Some(ExitStatus::from_raw(status)),
+    }
+}
+
+/**
+ * Serves on localhost:`port` from a worker process per CPU, until SIGTERM
+ * or SIGINT. Each worker calls `new_handler` once for its handler.
+ */
+pub fn start_prefork_server<H: Handler, F: Fn() -> H>(port: u16, new_handler: F) {
+    Prefork::default()
+        .serve(SocketAddr::from(([127, 0, 0, 1], port)), new_handler)
+        .unwrap_or_else(|err| panic!("Unable to serve localhost:{port} from workers: {err}"));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        env,
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        process::Command,
+    };
+
+    use super::{
+        super::{request::Request, response::Response},
+        *,
+    };
+
+    const TEST_ADDR: &str = "PREFORK_TEST_ADDR";
+
+    /** Run by workers_are_supervised as the master; does nothing otherwise */
+    #[test]
+    fn serves_as_master() {
+        let Some(addr) = env::var_os(TEST_ADDR) else {
+            return;
+        };
+        let addr = addr.to_str().unwrap().parse().unwrap();
+
+        Prefork::new(2)
+            .grace_period(Duration::from_secs(1))
+            .serve(addr, || {
+                |_: &Request| {
+                    let mut response = Response::new();
+                    response.body = process::id().to_string().into_bytes();
+                    response
+                }
+            })
+            .unwrap();
+    }
+
+    /** The pid of the worker that answered, or None if the connection failed */
+    fn worker(addr: SocketAddr) -> Option<libc::pid_t> {
+        let mut stream = TcpStream::connect(addr).ok()?;
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .ok()?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).ok()?;
+        response.split("\r\n\r\n").nth(1)?.parse().ok()
+    }
+
+    /** Asks until a worker not in `seen` answers, and returns it */
+    fn new_worker(addr: SocketAddr, seen: &HashSet<libc::pid_t>) -> libc::pid_t {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            match worker(addr) {
+                Some(pid) if !seen.contains(&pid) => return pid,
+                _ => thread::sleep(Duration::from_millis(10)),
+            }
+        }
+        panic!("No new worker answered");
+    }
+
+    #[test]
+    fn workers_are_supervised() {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut master = Command::new(env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "--quiet",
+                "server::prefork::tests::serves_as_master",
+            ])
+            .env(TEST_ADDR, addr.to_string())
+            .spawn()
+            .unwrap();
+
+        let mut seen = HashSet::new();
+        let first = new_worker(addr, &seen);
+        seen.insert(first);
+        seen.insert(new_worker(addr, &seen));
+        assert!(!seen.contains(&(master.id() as libc::pid_t)));
+
+        // A crashed worker is replaced
+        kill(first, libc::SIGKILL);
+        seen.insert(new_worker(addr, &seen));
+
+        // As are all of them on SIGHUP
+        kill(master.id() as libc::pid_t, libc::SIGHUP);
+        seen.insert(new_worker(addr, &seen));
+
+        kill(master.id() as libc::pid_t, libc::SIGTERM);
+        assert!(master.wait().unwrap().success());
+        assert!(TcpStream::connect(addr).is_err());
+    }
+}
@@ -0,0 +1,433 @@
+use std::{
+    collections::HashMap,
+    io::{copy, BufReader, Error, ErrorKind, Write},
+    net::{IpAddr, TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use super::{
+    handler::Handler, http_version::HttpVersion, request::Request, response::Response,
+    status_code::StatusCode,
+};
+
+/** Headers that only describe a single connection and must not be forwarded */
+pub const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/**
+ * Forwards every request to a single upstream `host:port` over HTTP/1.1 and
+ * streams the upstream's response back to the client.
+ */
+pub struct ReverseProxy {
+    upstream: String,
+    host: Option<String>,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+}
+
+impl ReverseProxy {
+    pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+    pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+    pub fn new(upstream: &str) -> Self {
+        ReverseProxy {
+            upstream: upstream.to_string(),
+            host: None,
+            connect_timeout: Self::DEFAULT_CONNECT_TIMEOUT,
+            read_timeout: Self::DEFAULT_READ_TIMEOUT,
+        }
+    }
+
+    /** Sends `host` as the Host header instead of the one the client sent */
+    pub fn rewrite_host(mut self, host: &str) -> Self {
+        self.host = Some(host.to_string());
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /** How long to wait on the upstream for any read, including the response head */
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    pub fn upstream(&self) -> &str {
+        &self.upstream
+    }
+
+    fn connect(&self) -> Result<TcpStream, Error> {
+        let mut last_err = Error::new(
+            ErrorKind::NotFound,
+            format!("Upstream did not resolve: {}", self.upstream),
+        );
+
+        for addr in self.upstream.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, self.connect_timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = err,
+            }
+        }
+
+        Err(last_err)
+    }
+
+    /** Sends `request` upstream, returning the upstream's response or why it failed */
+    pub fn forward(&self, request: &Request) -> Result<Response, Error> {
+        let mut upstream = self.connect()?;
+        upstream.set_read_timeout(Some(self.read_timeout))?;
+
+        let mut upstream_request = request.clone();
+        upstream_request.http_version = HttpVersion::Http1_1;
+
+        let headers = &mut upstream_request.headers;
+        strip_hop_by_hop(headers);
+        add_forwarded_headers(request, headers);
+
+        if let Some(host) = &self.host {
+            headers.insert("host".to_string(), vec![host.clone()]);
+        } else if !headers.contains_key("host") {
+            headers.insert("host".to_string(), vec![self.upstream.clone()]);
+        }
+        headers.remove("content-length");
+        if !request.body.is_empty() {
+            headers.insert(
+                "content-length".to_string(),
+                vec![request.body.len().to_string()],
+            );
+        }
+        // Closing after one exchange lets the response body be relayed until EOF
+        headers.insert("connection".to_string(), vec!["close".to_string()]);
+
+        upstream_request.write_to(&mut upstream)?;
+        upstream.flush()?;
+
+        let mut reader = BufReader::new(upstream);
+        // Interim responses like 100 Continue or 103 Early Hints come before the final one
+        let mut response = loop {
+            let response = Response::head_from_reader(&mut reader)?;
+            if response.status_code.0 >= 200
+                || response.status_code == StatusCode::SWITCHING_PROTOCOLS
+            {
+                break response;
+            }
+        };
+        response.http_version = HttpVersion::Http1_1;
+
+        // The body is relayed byte for byte, so its framing stays as the upstream sent it
+        let transfer_encoding = response.headers.get("transfer-encoding").cloned();
+        strip_hop_by_hop(&mut response.headers);
+        if let Some(transfer_encoding) = transfer_encoding {
+            response
+                .headers
+                .insert("transfer-encoding".to_string(), transfer_encoding);
+        }
+
+        response.stream_body = Some(Box::new(move |client| {
+            copy(&mut reader, client)?;
+            Ok(())
+        }));
+
+        Ok(response)
+    }
+}
+
+impl Handler for ReverseProxy {
+    fn handle(&self, request: &Request) -> Response {
+        match self.forward(request) {
+            Ok(response) => response,
+            Err(err) => {
+                eprintln!("Proxying to {} failed: {}", self.upstream, err);
+                Response::error(upstream_error_status(&err), "Upstream request failed")
+            }
+        }
+    }
+}
+
+/** GATEWAY_TIMEOUT when the upstream was too slow, BAD_GATEWAY for anything else */
+pub fn upstream_error_status(err: &Error) -> StatusCode {
+    match err.kind() {
+        ErrorKind::TimedOut | ErrorKind::WouldBlock => StatusCode::GATEWAY_TIMEOUT,
+        _ => StatusCode::BAD_GATEWAY,
+    }
+}
+
+/** Removes hop-by-hop headers, including any the Connection header names */
+pub fn strip_hop_by_hop(headers: &mut HashMap<String, Vec<String>>) {
+    let listed = headers
+        .get("connection")
+        .map(|values| {
+            values
+                .iter()
+                .flat_map(|value| value.split(','))
+                .map(|name| name.trim().to_lowercase())
+                .filter(|name| !name.is_empty())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    for name in HOP_BY_HOP_HEADERS.iter().map(|name| name.to_string()) {
+        headers.remove(&name);
+    }
+    for name in listed {
+        headers.remove(&name);
+    }
+}
+
+/** Appends this hop to Forwarded, X-Forwarded-For, -Host and -Proto */
+fn add_forwarded_headers(request: &Request, headers: &mut HashMap<String, Vec<String>>) {
+    let host = request.header("host").map(|host| host.to_string());
+    let mut forwarded = vec![];
+
+    if let Some(peer_addr) = request.peer_addr {
+        let client = peer_addr.ip().to_string();
+
+        let mut forwarded_for = headers
+            .get("x-forwarded-for")
+            .map(|values| values.join(";"))
+            .unwrap_or_default();
+        if !forwarded_for.is_empty() {
+            forwarded_for.push_str(", ");
+        }
+        forwarded_for.push_str(&client);
+        headers.insert("x-forwarded-for".to_string(), vec![forwarded_for]);
+
+        match peer_addr.ip() {
+            IpAddr::V4(_) => forwarded.push(format!("for={}", client)),
+            IpAddr::V6(_) => forwarded.push(format!("for=\"[{}]\"", client)),
+        }
+    }
+
+    if let Some(host) = &host {
+        headers.insert("x-forwarded-host".to_string(), vec![host.clone()]);
+        forwarded.push(format!("host=\"{}\"", host));
+    }
+
+    let proto = if request.tls { "https" } else { "http" };
+    headers.insert("x-forwarded-proto".to_string(), vec![proto.to_string()]);
+    forwarded.push(format!("proto={}", proto));
+
+    let mut forwarded = forwarded.join(";");
+    if let Some(previous) = headers.get("forwarded") {
+        forwarded = format!("{}, {}", previous.join(";"), forwarded);
+    }
+    headers.insert("forwarded".to_string(), vec![forwarded]);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Read,
+        net::{SocketAddr, TcpListener},
+        thread,
+    };
+
+    use super::*;
+
+    fn read_head(stream: &mut TcpStream) -> String {
+        let mut head = vec![];
+        let mut byte = [0];
+        while !head.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    fn client_request(raw_request: &str) -> Request {
+        let mut request = Request::from_stream(&mut raw_request.as_bytes()).unwrap();
+        request.peer_addr = Some(SocketAddr::from(([192, 168, 0, 7], 51000)));
+        request
+    }
+
+    #[test]
+    fn forwards_request_and_streams_response() {
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let upstream_server = thread::spawn(move || {
+            let (mut stream, _) = upstream.accept().unwrap();
+            let head = read_head(&mut stream);
+            let mut body = [0; 4];
+            stream.read_exact(&mut body).unwrap();
+            stream
+                .write_all(
+                    "HTTP/1.1 201 Created\r\n\
+                    Connection: keep-alive, X-Internal\r\n\
+                    X-Internal: secret\r\n\
+                    X-Upstream: yes\r\n\
+                    Content-Length: 5\r\n\
+                    \r\n\
+                    hello"
+                        .as_bytes(),
+                )
+                .unwrap();
+            (head, body)
+        });
+
+        let proxy = ReverseProxy::new(&upstream_addr.to_string()).rewrite_host("backend.local");
+        let mut response = proxy.handle(&client_request(
+            "POST /items?id=1 HTTP/1.1\r\n\
+            Host: example.com:8080\r\n\
+            Connection: keep-alive, X-Trace\r\n\
+            X-Trace: abc\r\n\
+            Keep-Alive: timeout=5\r\n\
+            X-Forwarded-For: 10.0.0.1\r\n\
+            Content-Length: 4\r\n\
+            \r\n\
+            ping",
+        ));
+
+        let mut output = vec![];
+        response.write_to(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        let (head, body) = upstream_server.join().unwrap();
+        let head = head.to_lowercase();
+
+        assert!(head.starts_with("post /items?id=1 http/1.1\r\n"));
+        assert!(head.contains("host: backend.local\r\n"));
+        assert!(head.contains("x-forwarded-for: 10.0.0.1, 192.168.0.7\r\n"));
+        assert!(head.contains("x-forwarded-host: example.com:8080\r\n"));
+        assert!(head.contains("x-forwarded-proto: http\r\n"));
+        assert!(
+            head.contains("forwarded: for=192.168.0.7;host=\"example.com:8080\";proto=http\r\n")
+        );
+        assert!(head.contains("connection: close\r\n"));
+        assert!(head.contains("content-length: 4\r\n"));
+        assert!(!head.contains("x-trace"));
+        assert!(!head.contains("keep-alive"));
+        assert_eq!(b"ping", &body);
+
+        assert!(output.starts_with("HTTP/1.1 201 Created\r\n"));
+        assert!(output.contains("x-upstream: yes\r\n"));
+        assert!(!output.contains("x-internal"));
+        assert!(!output.contains("connection"));
+        assert!(output.ends_with("\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn chunked_request_body_is_forwarded() {
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let upstream_server = thread::spawn(move || {
+            let (mut stream, _) = upstream.accept().unwrap();
+            let head = read_head(&mut stream);
+            let mut body = [0; 12];
+            stream.read_exact(&mut body).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .unwrap();
+            (head, body)
+        });
+
+        let proxy = ReverseProxy::new(&upstream_addr.to_string());
+        let response = proxy.handle(&client_request(
+            "POST /upload HTTP/1.1\r\n\
+            Host: example.com\r\n\
+            Transfer-Encoding: chunked\r\n\
+            \r\n\
+            5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n",
+        ));
+
+        let (head, body) = upstream_server.join().unwrap();
+        let head = head.to_lowercase();
+        assert_eq!(StatusCode::NO_CONTENT, response.status_code);
+        assert!(head.contains("content-length: 12\r\n"), "{}", head);
+        assert!(!head.contains("transfer-encoding"), "{}", head);
+        assert_eq!(b"hello, world", &body);
+    }
+
+    #[test]
+    fn unreachable_upstream_is_bad_gateway() {
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let proxy = ReverseProxy::new(&format!("127.0.0.1:{}", port));
+
+        let response = proxy.handle(&client_request("GET / HTTP/1.1\r\n\r\n"));
+
+        assert_eq!(StatusCode::BAD_GATEWAY, response.status_code);
+    }
+
+    #[test]
+    fn slow_upstream_is_gateway_timeout() {
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let upstream_server = thread::spawn(move || {
+            let (stream, _) = upstream.accept().unwrap();
+            thread::sleep(Duration::from_millis(200));
+            drop(stream);
+        });
+
+        let proxy =
+            ReverseProxy::new(&upstream_addr.to_string()).read_timeout(Duration::from_millis(20));
+        let response = proxy.handle(&client_request("GET / HTTP/1.1\r\n\r\n"));
+
+        assert_eq!(StatusCode::GATEWAY_TIMEOUT, response.status_code);
+        upstream_server.join().unwrap();
+    }
+
+    /** An upstream that answers one request with `reply`, returning the request's head */
+    fn upstream(reply: &'static str) -> (SocketAddr, thread::JoinHandle<String>) {
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let upstream_server = thread::spawn(move || {
+            let (mut stream, _) = upstream.accept().unwrap();
+            let head = read_head(&mut stream);
+            stream.write_all(reply.as_bytes()).unwrap();
+            head
+        });
+        (upstream_addr, upstream_server)
+    }
+
+    #[test]
+    fn requests_over_tls_are_forwarded_as_https() {
+        let (upstream_addr, upstream_server) = upstream("HTTP/1.1 204 No Content\r\n\r\n");
+
+        let mut request = client_request("GET / HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        request.tls = true;
+        ReverseProxy::new(&upstream_addr.to_string()).handle(&request);
+
+        let head = upstream_server.join().unwrap().to_lowercase();
+        assert!(head.contains("x-forwarded-proto: https\r\n"), "{}", head);
+        assert!(head.contains(";proto=https\r\n"), "{}", head);
+    }
+
+    #[test]
+    fn interim_responses_are_skipped() {
+        let (upstream_addr, upstream_server) = upstream(
+            "HTTP/1.1 100 Continue\r\n\r\n\
+            HTTP/1.1 103 Early Hints\r\n\
+            Link: </style.css>; rel=preload\r\n\
+            \r\n\
+            HTTP/1.1 200 OK\r\n\
+            Content-Length: 4\r\n\
+            \r\n\
+            done",
+        );
+
+        let mut response = ReverseProxy::new(&upstream_addr.to_string())
+            .handle(&client_request("GET / HTTP/1.1\r\n\r\n"));
+        let mut output = vec![];
+        response.write_to(&mut output).unwrap();
+        upstream_server.join().unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"), "{}", output);
+        assert!(!output.contains("link"));
+        assert!(output.ends_with("\r\n\r\ndone"));
+    }
+}
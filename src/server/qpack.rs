@@ -0,0 +1,292 @@
+use std::io::{Error, ErrorKind};
+
+use super::hpack::{decode_integer, decode_string, encode_integer, encode_string};
+
+/** The static table from RFC 9204 Appendix A; index 0 is the first entry */
+const STATIC_TABLE: [(&str, &str); 99] = [
+    (":authority", ""),
+    (":path", "/"),
+    ("age", "0"),
+    ("content-disposition", ""),
+    ("content-length", "0"),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("referer", ""),
+    ("set-cookie", ""),
+    (":method", "CONNECT"),
+    (":method", "DELETE"),
+    (":method", "GET"),
+    (":method", "HEAD"),
+    (":method", "OPTIONS"),
+    (":method", "POST"),
+    (":method", "PUT"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "103"),
+    (":status", "200"),
+    (":status", "304"),
+    (":status", "404"),
+    (":status", "503"),
+    ("accept", "*/*"),
+    ("accept", "application/dns-message"),
+    ("accept-encoding", "gzip, deflate, br"),
+    ("accept-ranges", "bytes"),
+    ("access-control-allow-headers", "cache-control"),
+    ("access-control-allow-headers", "content-type"),
+    ("access-control-allow-origin", "*"),
+    ("cache-control", "max-age=0"),
+    ("cache-control", "max-age=2592000"),
+    ("cache-control", "max-age=604800"),
+    ("cache-control", "no-cache"),
+    ("cache-control", "no-store"),
+    ("cache-control", "public, max-age=31536000"),
+    ("content-encoding", "br"),
+    ("content-encoding", "gzip"),
+    ("content-type", "application/dns-message"),
+    ("content-type", "application/javascript"),
+    ("content-type", "application/json"),
+    ("content-type", "application/x-www-form-urlencoded"),
+    ("content-type", "image/gif"),
+    ("content-type", "image/jpeg"),
+    ("content-type", "image/png"),
+    ("content-type", "text/css"),
+    ("content-type", "text/html; charset=utf-8"),
+    ("content-type", "text/plain"),
+    ("content-type", "text/plain;charset=utf-8"),
+    ("range", "bytes=0-"),
+    ("strict-transport-security", "max-age=31536000"),
+    (
+        "strict-transport-security",
+        "max-age=31536000; includesubdomains",
+    ),
+    (
+        "strict-transport-security",
+        "max-age=31536000; includesubdomains; preload",
+    ),
+    ("vary", "accept-encoding"),
+    ("vary", "origin"),
+    ("x-content-type-options", "nosniff"),
+    ("x-xss-protection", "1; mode=block"),
+    (":status", "100"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "302"),
+    (":status", "400"),
+    (":status", "403"),
+    (":status", "421"),
+    (":status", "425"),
+    (":status", "500"),
+    ("accept-language", ""),
+    ("access-control-allow-credentials", "FALSE"),
+    ("access-control-allow-credentials", "TRUE"),
+    ("access-control-allow-headers", "*"),
+    ("access-control-allow-methods", "get"),
+    ("access-control-allow-methods", "get, post, options"),
+    ("access-control-allow-methods", "options"),
+    ("access-control-expose-headers", "content-length"),
+    ("access-control-request-headers", "content-type"),
+    ("access-control-request-method", "get"),
+    ("access-control-request-method", "post"),
+    ("alt-svc", "clear"),
+    ("authorization", ""),
+    (
+        "content-security-policy",
+        "script-src 'none'; object-src 'none'; base-uri 'none'",
+    ),
+    ("early-data", "1"),
+    ("expect-ct", ""),
+    ("forwarded", ""),
+    ("if-range", ""),
+    ("origin", ""),
+    ("purpose", "prefetch"),
+    ("server", ""),
+    ("timing-allow-origin", "*"),
+    ("upgrade-insecure-requests", "1"),
+    ("user-agent", ""),
+    ("x-forwarded-for", ""),
+    ("x-frame-options", "deny"),
+    ("x-frame-options", "sameorigin"),
+];
+
+/** Fields sent as never-indexed literals, so intermediaries that re-encode them don't index them */
+const NEVER_INDEXED: [&str; 2] = ["authorization", "proxy-authorization"];
+
+fn decompression_failed(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+fn static_entry(index: usize) -> Result<(&'static str, &'static str), Error> {
+    STATIC_TABLE
+        .get(index)
+        .copied()
+        .ok_or_else(|| decompression_failed("Static table index out of range"))
+}
+
+/**
+ * Decodes a QPACK field section (RFC 9204). We advertise a dynamic table
+ * capacity of 0, so a field section that refers to the dynamic table in any
+ * way is an error rather than something to wait for.
+ */
+pub fn decode(mut block: &[u8]) -> Result<Vec<(String, String)>, Error> {
+    // Section prefix: Required Insert Count, then the sign bit and Delta Base
+    if decode_integer(&mut block, 8)? != 0 {
+        return Err(decompression_failed(
+            "Field section refers to the dynamic table",
+        ));
+    }
+    decode_integer(&mut block, 7)?;
+
+    let mut fields = vec![];
+
+    while let Some(&first) = block.first() {
+        if first & 0x80 != 0 {
+            // Indexed field line
+            if first & 0x40 == 0 {
+                return Err(decompression_failed("Dynamic table reference"));
+            }
+            let (name, value) = static_entry(decode_integer(&mut block, 6)?)?;
+            fields.push((name.to_string(), value.to_string()));
+        } else if first & 0xc0 == 0x40 {
+            // Literal field line with name reference
+            if first & 0x10 == 0 {
+                return Err(decompression_failed("Dynamic table reference"));
+            }
+            let (name, _) = static_entry(decode_integer(&mut block, 4)?)?;
+            let value = decode_string(&mut block, 7)?;
+            fields.push((name.to_string(), value));
+        } else if first & 0xe0 == 0x20 {
+            // Literal field line with literal name
+            let name = decode_string(&mut block, 3)?;
+            let value = decode_string(&mut block, 7)?;
+            fields.push((name, value));
+        } else {
+            // Post-base indexed (0001) and post-base name reference (0000)
+            return Err(decompression_failed("Dynamic table reference"));
+        }
+    }
+
+    Ok(fields)
+}
+
+/**
+ * Encodes a QPACK field section using only the static table, so it never
+ * needs the encoder stream. Fields in the static table are sent as an
+ * index, others as literals, with Huffman coding wherever it's shorter.
+ */
+pub fn encode(fields: &[(String, String)]) -> Vec<u8> {
+    // Required Insert Count and Delta Base are both 0
+    let mut block = vec![0x00, 0x00];
+
+    for (name, value) in fields {
+        let never_indexed = NEVER_INDEXED.contains(&name.as_str());
+        let (full_index, name_index) = find(name, value);
+
+        if let Some(index) = full_index.filter(|_| !never_indexed) {
+            encode_integer(&mut block, 0xc0, 6, index);
+            continue;
+        }
+
+        match name_index {
+            Some(index) => {
+                let flags = if never_indexed { 0x70 } else { 0x50 };
+                encode_integer(&mut block, flags, 4, index);
+            }
+            None => {
+                let flags = if never_indexed { 0x30 } else { 0x20 };
+                encode_string(&mut block, flags, 3, name, true);
+            }
+        }
+        encode_string(&mut block, 0x00, 7, value, true);
+    }
+
+    block
+}
+
+/** The index of a static entry matching the whole field, and of one matching its name */
+fn find(name: &str, value: &str) -> (Option<usize>, Option<usize>) {
+    let mut name_index = None;
+    for (index, &(entry_name, entry_value)) in STATIC_TABLE.iter().enumerate() {
+        if entry_name != name {
+            continue;
+        }
+        if entry_value == value {
+            return (Some(index), Some(index));
+        }
+        name_index = name_index.or(Some(index));
+    }
+    (None, name_index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let request = fields(&[
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":path", "/index.html"),
+            (":authority", "example.com"),
+            ("accept", "*/*"),
+            ("x-custom", "some value"),
+            ("authorization", "Bearer token"),
+        ]);
+
+        assert_eq!(request, decode(&encode(&request)).unwrap());
+    }
+
+    #[test]
+    fn field_line_representations() {
+        // Static entries are a single byte, and names from the table are referenced
+        assert_eq!(
+            vec![0x00, 0x00, 0xd1, 0xd7],
+            encode(&fields(&[(":method", "GET"), (":scheme", "https")]))
+        );
+        assert_eq!(vec![0x00, 0x00, 0xc1], encode(&fields(&[(":path", "/")])));
+        let block = encode(&fields(&[(":path", "/index.html")]));
+        assert_eq!((0x51, 0x80), (block[2], block[3] & 0x80));
+        // Sensitive fields never use the table, even when they'd match an entry
+        assert_eq!(0x70 | 0x0f, encode(&fields(&[("authorization", "")]))[2]);
+
+        // Literal names, Huffman coded or not
+        let block = [0x00, 0x00, 0x23, b'a', b'b', b'c', 0x01, b'd'];
+        assert_eq!(fields(&[("abc", "d")]), decode(&block).unwrap());
+        let huffman = encode(&fields(&[("custom-key", "custom-value")]));
+        assert_eq!(0x28, huffman[2] & 0xf8);
+        assert_eq!(
+            fields(&[("custom-key", "custom-value")]),
+            decode(&huffman).unwrap()
+        );
+    }
+
+    #[test]
+    fn dynamic_table_references_are_rejected() {
+        // Required Insert Count, indexed dynamic, name reference dynamic, post-base
+        for block in [
+            &[0x01, 0x00][..],
+            &[0x00, 0x00, 0x80],
+            &[0x00, 0x00, 0x40, 0x00],
+            &[0x00, 0x00, 0x10],
+            &[0x00, 0x00, 0x00],
+        ] {
+            assert!(decode(block).is_err(), "{:?}", block);
+        }
+
+        assert!(decode(&[0x00, 0x00, 0xff, 0x24]).is_err());
+        assert!(decode(&[0x00, 0x00, 0x23, b'a']).is_err());
+    }
+}
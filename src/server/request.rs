@@ -1,33 +1,87 @@
 use std::{
     collections::HashMap,
-    io::{BufRead, BufReader, Error, ErrorKind, Read},
+    io::{BufRead, BufReader, Error, ErrorKind, Read, Write},
+    net::SocketAddr,
     str::FromStr,
 };
 
-use super::{http_version::HttpVersion, method::Method};
+use super::{
+    client_certificate::ClientCertificate,
+    http_version::HttpVersion,
+    listener::PeerCredentials,
+    method::Method,
+    response::{read_body, read_chunked_body},
+};
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Request {
     pub method: Method,
     pub raw_target: String,
     pub http_version: HttpVersion,
     pub headers: HashMap<String, Vec<String>>,
     pub body: Vec<u8>,
+    /** Address of the client that sent the request, when read off a socket */
+    pub peer_addr: Option<SocketAddr>,
+    /** The certificate the client authenticated with, when it came over TLS with one */
+    pub client_certificate: Option<ClientCertificate>,
+    /** The process that connected, when the request came over a Unix socket */
+    pub peer_credentials: Option<PeerCredentials>,
+    /** Whether the request came over TLS, i.e. for an https URL */
+    pub tls: bool,
 }
 
 impl Request {
+    /** An HTTP/1.1 request with no headers or body, e.g. for sending with a Client */
+    pub fn new(method: Method, raw_target: &str) -> Self {
+        Request {
+            method,
+            raw_target: raw_target.to_string(),
+            http_version: HttpVersion::Http1_1,
+            headers: HashMap::new(),
+            body: vec![],
+            peer_addr: None,
+            client_certificate: None,
+            peer_credentials: None,
+            tls: false,
+        }
+    }
+
     pub fn from_stream(stream: &mut dyn Read) -> Result<Self, Error> {
-        let mut buf_reader = BufReader::new(stream);
+        Self::from_reader(&mut BufReader::new(stream))
+    }
 
+    /**
+     * Reads exactly one request from an already-buffered reader. Anything
+     * after the body is left in the reader, so callers that keep using the
+     * connection (e.g. after an upgrade) don't lose those bytes.
+     */
+    pub fn from_reader(buf_reader: &mut dyn BufRead) -> Result<Self, Error> {
         let mut request_line = String::new();
-        buf_reader.read_line(&mut request_line)?;
+        if buf_reader.read_line(&mut request_line)? == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Connection closed before a request was received",
+            ));
+        }
         request_line = request_line.trim().to_string();
 
         let mut request_line_parts = request_line.split(' ');
 
-        let method_str = request_line_parts.next().unwrap();
-        let target_str = request_line_parts.next().unwrap();
-        let http_version_str = request_line_parts.next().unwrap();
+        let (method_str, target_str, http_version_str) = match (
+            request_line_parts.next(),
+            request_line_parts.next(),
+            request_line_parts.next(),
+        ) {
+            (Some(method_str), Some(target_str), Some(http_version_str)) => {
+                (method_str, target_str, http_version_str)
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Malformed request line: {}", request_line),
+                ))
+            }
+        };
         if let Some(word) = request_line_parts.next() {
             return Err(Error::new(
                 ErrorKind::InvalidInput,
@@ -64,16 +118,18 @@ impl Request {
             if header_line.is_empty() {
                 break;
             }
-            println!("Reading line: '{}'", header_line);
-            if header_line.is_empty() {
-                println!("Empty line; breaking");
-                break;
-            }
 
-            let mut line_parts = header_line.split(':');
-            let header_name = line_parts.next().unwrap().trim().to_string().to_lowercase();
-            let header_values = line_parts.collect::<Vec<_>>().join("");
-            let header_values = header_values.trim().to_string();
+            let (header_name, header_values) = match header_line.split_once(':') {
+                Some(parts) => parts,
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Header line is missing a colon: {}", header_line),
+                    ))
+                }
+            };
+            let header_name = header_name.trim().to_lowercase();
+            let header_values = header_values.trim();
 
             let header_values = header_values.split(';');
 
@@ -87,32 +143,80 @@ impl Request {
             }
         }
 
-        println!("Collecting body");
-
-        let mut content_length: usize = 0;
-
-        if let Some(length_str) = headers.get("content-length") {
-            content_length = usize::from_str(length_str.first().unwrap().as_str()).unwrap();
-        }
-
         let mut body = vec![];
-        let mut body_buf: [u8; 128] = [0; 128];
-
-        while body.len() < content_length {
-            buf_reader.read(&mut body_buf)?;
-            body.extend_from_slice(&body_buf);
+        // Transfer-Encoding overrides Content-Length (RFC 9112 section 6.3)
+        if let Some(encoding) = headers
+            .get("transfer-encoding")
+            .and_then(|values| values.last())
+        {
+            if !encoding.to_lowercase().ends_with("chunked") {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Unsupported Transfer-Encoding: {}", encoding),
+                ));
+            }
+            // Decoded here so handlers see the body with a plain Content-Length
+            body = read_chunked_body(buf_reader)?;
+            headers.remove("transfer-encoding");
+            headers.insert("content-length".to_string(), vec![body.len().to_string()]);
+        } else if let Some(length_str) = headers
+            .get("content-length")
+            .and_then(|values| values.first())
+        {
+            let content_length = usize::from_str(length_str).map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid Content-Length: {}", length_str),
+                )
+            })?;
+            read_body(buf_reader, content_length, &mut body)?;
         }
 
-        println!("Body: {}", String::from_utf8(body.clone()).unwrap());
-
         Ok(Request {
             method,
             raw_target,
             http_version,
             headers,
             body,
+            peer_addr: None,
+            client_certificate: None,
+            peer_credentials: None,
+            tls: false,
         })
     }
+
+    /** Returns the first value of the named header, if present */
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_lowercase())
+            .and_then(|values| values.first())
+            .map(|value| value.as_str())
+    }
+
+    /** Writes the request line, headers and body exactly as they are set */
+    pub fn write_to(&self, stream: &mut dyn Write) -> Result<(), Error> {
+        let request_line = format!(
+            "{} {} {}\r\n",
+            self.method.as_str(),
+            self.raw_target,
+            self.http_version.as_str()
+        );
+
+        let headers_lines = self
+            .headers
+            .iter()
+            .map(|(header_name, header_values)| {
+                format!("{}: {}\r\n", header_name, header_values.join(";"))
+            })
+            .collect::<String>();
+
+        stream.write_all(request_line.as_bytes())?;
+        stream.write_all(headers_lines.as_bytes())?;
+        stream.write_all("\r\n".as_bytes())?;
+        stream.write_all(&self.body)?;
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -126,7 +230,7 @@ mod tests {
             Host: localhost\r\n\
             x-my-header: foo; bar\r\n\
             x-my-header: baz\r\n\
-            Content-Length: 44\r\n\
+            Content-Length: 45\r\n\
             \r\n\
             The quick brown fox jumped over the lazy dog\n\
         "
@@ -159,4 +263,85 @@ mod tests {
                 .collect::<Vec<_>>()
         );
     }
+
+    #[test]
+    fn header_values_keep_their_colons() {
+        let mut raw_request = "GET / HTTP/1.1\r\n\
+            Host: localhost:8080\r\n\
+            Referer: http://localhost:8080/\r\n\
+            \r\n"
+            .as_bytes();
+        let request = Request::from_stream(&mut raw_request).unwrap();
+        assert_eq!(Some("localhost:8080"), request.header("Host"));
+        assert_eq!(Some("http://localhost:8080/"), request.header("Referer"));
+
+        let mut raw_request = "GET / HTTP/1.1\r\nHost localhost\r\n\r\n".as_bytes();
+        let err = Request::from_stream(&mut raw_request).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn chunked_body_is_decoded() {
+        let mut raw_request = "POST / HTTP/1.1\r\n\
+            Transfer-Encoding: chunked\r\n\
+            Content-Length: 100\r\n\
+            \r\n\
+            5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\n\r\n\
+            GET /next HTTP/1.1\r\n\r\n"
+            .as_bytes();
+        let mut reader = BufReader::new(&mut raw_request);
+        let request = Request::from_reader(&mut reader).unwrap();
+        assert_eq!(b"hello, world", request.body.as_slice());
+        assert_eq!(None, request.header("Transfer-Encoding"));
+        assert_eq!(Some("12"), request.header("Content-Length"));
+
+        let next = Request::from_reader(&mut reader).unwrap();
+        assert_eq!("/next", next.raw_target);
+
+        let mut raw_request = "POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n".as_bytes();
+        let err = Request::from_stream(&mut raw_request).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn write_to_round_trip() {
+        let mut request = Request::new(Method::PUT, "/items/7?force=true");
+        request
+            .headers
+            .insert("host".to_string(), vec!["localhost:8080".to_string()]);
+        request.headers.insert(
+            "accept".to_string(),
+            vec!["text/html".to_string(), "q=0.9".to_string()],
+        );
+        request
+            .headers
+            .insert("content-length".to_string(), vec!["5".to_string()]);
+        request.body = "hello".as_bytes().to_vec();
+
+        let mut output = vec![];
+        request.write_to(&mut output).unwrap();
+
+        assert!(output.starts_with(b"PUT /items/7?force=true HTTP/1.1\r\n"));
+        assert!(output.ends_with(b"\r\n\r\nhello"));
+        assert_eq!(
+            request,
+            Request::from_stream(&mut output.as_slice()).unwrap()
+        );
+    }
+
+    #[test]
+    fn invalid_content_length_is_an_error() {
+        let mut raw_request = "POST / HTTP/1.1\r\nContent-Length: x\r\n\r\n".as_bytes();
+        let err = Request::from_stream(&mut raw_request).unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn body_isnt_allocated_from_the_content_length() {
+        // Allocating this up front would abort the process
+        let mut raw_request =
+            "POST / HTTP/1.1\r\nContent-Length: 18446744073709551615\r\n\r\nshort".as_bytes();
+        let err = Request::from_stream(&mut raw_request).unwrap_err();
+        assert_eq!(ErrorKind::UnexpectedEof, err.kind());
+    }
 }
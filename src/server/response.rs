@@ -1,33 +1,196 @@
 use std::{
     collections::HashMap,
-    io::{Error, Write},
+    fmt,
+    io::{BufRead, BufReader, Error, ErrorKind, Read, Write},
+    str::FromStr,
 };
 
-use super::{http_version::HttpVersion, status_code::StatusCode};
+use super::{http_version::HttpVersion, status_code::StatusCode, upgrade::OnUpgrade};
+
+/** Writes the remainder of a body after the head and `body` have been sent */
+pub type StreamBody = Box<dyn FnOnce(&mut dyn Write) -> Result<(), Error> + Send>;
 
 pub struct Response {
     pub http_version: HttpVersion,
     pub status_code: StatusCode,
     pub headers: HashMap<String, Vec<String>>,
     pub body: Vec<u8>,
+    pub stream_body: Option<StreamBody>,
+    pub on_upgrade: Option<OnUpgrade>,
+}
+
+impl fmt::Debug for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Response")
+            .field("http_version", &self.http_version)
+            .field("status_code", &self.status_code)
+            .field("headers", &self.headers)
+            .field("body", &self.body)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for Response {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Response {
     pub fn new() -> Self {
-        return Response {
+        Response {
             http_version: HttpVersion::Http1_1,
             status_code: StatusCode::OK,
             headers: HashMap::new(),
             body: vec![],
-        };
+            stream_body: None,
+            on_upgrade: None,
+        }
     }
 
-    pub fn write_to(&self, stream: &mut dyn Write) -> Result<(), Error> {
-        let http_version_str = match self.http_version {
-            HttpVersion::Http1_0 => "HTTP/1.0",
-            HttpVersion::Http1_1 => "HTTP/1.1",
-            HttpVersion::Http2_0 => "HTTP/2.0",
-        };
+    /** A plain-text response for a request that couldn't be served */
+    pub fn error(status_code: StatusCode, message: &str) -> Self {
+        let mut response = Response::new();
+        response.status_code = status_code;
+        response.body = format!("{}\n", message).into_bytes();
+        response
+    }
+
+    pub fn from_stream(stream: &mut dyn Read) -> Result<Self, Error> {
+        Self::from_reader(&mut BufReader::new(stream))
+    }
+
+    /**
+     * Reads one response, including a body framed by Content-Length, chunked
+     * transfer coding or the end of the stream. Chunked bodies are decoded and
+     * any trailer fields dropped. Responses to HEAD requests carry no body, so
+     * read those with head_from_reader instead.
+     */
+    pub fn from_reader(reader: &mut dyn BufRead) -> Result<Self, Error> {
+        let mut response = Self::head_from_reader(reader)?;
+
+        if response.status_code.0 < 200
+            || response.status_code == StatusCode::NO_CONTENT
+            || response.status_code == StatusCode::NOT_MODIFIED
+        {
+            return Ok(response);
+        }
+
+        if response.is_chunked() {
+            response.body = read_chunked_body(reader)?;
+        } else if let Some(content_length) = response.content_length()? {
+            read_body(reader, content_length, &mut response.body)?;
+        } else {
+            reader.read_to_end(&mut response.body)?;
+        }
+
+        Ok(response)
+    }
+
+    /** Reads the status line and headers, leaving the body in `reader` */
+    pub fn head_from_reader(reader: &mut dyn BufRead) -> Result<Self, Error> {
+        let invalid = |message: String| Error::new(ErrorKind::InvalidData, message);
+
+        let mut status_line = String::new();
+        if reader.read_line(&mut status_line)? == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Connection closed before a response was received",
+            ));
+        }
+
+        let mut status_line_parts = status_line.trim_end().splitn(3, ' ');
+        let http_version_str = status_line_parts.next().unwrap_or_default();
+        let status_code_str = status_line_parts.next().unwrap_or_default();
+
+        let http_version = HttpVersion::from_str(http_version_str)
+            .map_err(|_| invalid(format!("Invalid http_version: {}", http_version_str)))?;
+        let status_code = u16::from_str(status_code_str)
+            .map_err(|_| invalid(format!("Invalid status code: {}", status_code_str)))?;
+
+        let mut response = Response::new();
+        response.http_version = http_version;
+        // Codes we don't know are kept, just without a reason phrase
+        response.status_code =
+            StatusCode::from_int(status_code as usize).unwrap_or(StatusCode(status_code, ""));
+
+        loop {
+            let mut header_line = String::new();
+            reader.read_line(&mut header_line)?;
+            let header_line = header_line.trim();
+            if header_line.is_empty() {
+                break;
+            }
+
+            let (header_name, header_values) = header_line.split_once(':').ok_or_else(|| {
+                invalid(format!("Header line is missing a colon: {}", header_line))
+            })?;
+
+            let values = response
+                .headers
+                .entry(header_name.trim().to_lowercase())
+                .or_default();
+            for value in header_values.split(';') {
+                values.push(value.trim().to_string());
+            }
+        }
+
+        Ok(response)
+    }
+
+    /** Returns the first value of the named header, if present */
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .and_then(|(_, values)| values.first())
+            .map(|value| value.as_str())
+    }
+
+    pub fn is_chunked(&self) -> bool {
+        self.header("Transfer-Encoding")
+            .map(|encoding| encoding.to_lowercase().ends_with("chunked"))
+            .unwrap_or(false)
+    }
+
+    pub fn content_length(&self) -> Result<Option<usize>, Error> {
+        match self.header("Content-Length") {
+            Some(length_str) => usize::from_str(length_str).map(Some).map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid Content-Length: {}", length_str),
+                )
+            }),
+            None => Ok(None),
+        }
+    }
+
+    /**
+     * Runs any stream_body into `body` so the whole response is in memory.
+     * A chunked body is decoded and given a Content-Length instead.
+     */
+    pub fn buffer_body(&mut self) -> Result<(), Error> {
+        if let Some(stream_body) = self.stream_body.take() {
+            stream_body(&mut self.body)?;
+        }
+
+        if self.is_chunked() {
+            self.body = read_chunked_body(&mut self.body.as_slice())?;
+            self.headers.retain(|header_name, _| {
+                !header_name.eq_ignore_ascii_case("transfer-encoding")
+                    && !header_name.eq_ignore_ascii_case("content-length")
+            });
+            self.headers.insert(
+                "content-length".to_string(),
+                vec![self.body.len().to_string()],
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn write_to(&mut self, stream: &mut dyn Write) -> Result<(), Error> {
+        let http_version_str = self.http_version.as_str();
 
         let status_code = self.status_code.0;
         let reason_phrase = self.status_code.1;
@@ -38,21 +201,85 @@ impl Response {
             .headers
             .iter()
             .map(|(header_name, header_values)| {
-                format!("{}: {}", header_name, header_values.join(";"))
+                format!("{}: {}\r\n", header_name, header_values.join(";"))
             })
-            .collect::<Vec<_>>()
-            .join("\r\n")
-            + "\r\n";
+            .collect::<String>();
 
         stream.write_all(status_line.as_bytes())?;
         stream.write_all(headers_lines.as_bytes())?;
         stream.write_all("\r\n".as_bytes())?;
         stream.write_all(&self.body)?;
 
+        if let Some(stream_body) = self.stream_body.take() {
+            stream_body(stream)?;
+        }
+
         Ok(())
     }
 }
 
+/**
+ * Appends a body of `length` bytes, as the peer says it is, to `body`. The
+ * buffer grows with what actually arrives rather than being allocated up
+ * front, so a made-up length can't exhaust memory by itself.
+ */
+pub(super) fn read_body(
+    reader: &mut dyn Read,
+    length: usize,
+    body: &mut Vec<u8>,
+) -> Result<(), Error> {
+    let read = reader.take(length as u64).read_to_end(body)?;
+    if read < length {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "Connection closed in the middle of a body",
+        ));
+    }
+    Ok(())
+}
+
+pub(super) fn read_chunked_body(reader: &mut dyn BufRead) -> Result<Vec<u8>, Error> {
+    let mut body = vec![];
+
+    loop {
+        let mut size_line = String::new();
+        if reader.read_line(&mut size_line)? == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Connection closed in the middle of a chunked body",
+            ));
+        }
+
+        // Chunk extensions after ';' carry nothing we use
+        let size_str = size_line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size_str, 16).map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid chunk size: {}", size_str),
+            )
+        })?;
+
+        if size == 0 {
+            break;
+        }
+
+        read_body(reader, size, &mut body)?;
+
+        let mut crlf = String::new();
+        reader.read_line(&mut crlf)?;
+    }
+
+    // Skip trailer fields up to the blank line ending the body
+    loop {
+        let mut trailer_line = String::new();
+        if reader.read_line(&mut trailer_line)? == 0 || trailer_line.trim().is_empty() {
+            break;
+        }
+    }
+
+    Ok(body)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -102,15 +329,10 @@ mod tests {
         parse_header_line(&header_line1.to_string(), &mut fields);
         parse_header_line(&header_line2.to_string(), &mut fields);
 
-        assert_eq!(
-            &vec!["*/*".to_string()],
-            fields.get(&"Accept".to_string()).unwrap()
-        );
+        assert_eq!(&vec!["*/*".to_string()], fields.get("Accept").unwrap());
         assert_eq!(
             &vec!["localhost", "*.example.com"],
-            fields
-                .get(&"Access-Control-Allow-Origins".to_string())
-                .unwrap()
+            fields.get("Access-Control-Allow-Origins").unwrap()
         );
 
         let blank_line = lines.next().unwrap();
@@ -125,4 +347,92 @@ mod tests {
             body
         );
     }
+
+    #[test]
+    fn from_stream_with_content_length() {
+        let mut raw_response = "HTTP/1.1 201 Created\r\n\
+            Location: http://localhost:8080/items/7\r\n\
+            Content-Length: 5\r\n\
+            \r\n\
+            hello, and then some"
+            .as_bytes();
+
+        let response = Response::from_stream(&mut raw_response).unwrap();
+
+        assert_eq!(StatusCode::CREATED, response.status_code);
+        assert_eq!(
+            Some("http://localhost:8080/items/7"),
+            response.header("Location")
+        );
+        assert_eq!(b"hello".to_vec(), response.body);
+    }
+
+    #[test]
+    fn from_stream_with_chunked_body() {
+        let mut raw_response = "HTTP/1.1 200 OK\r\n\
+            Transfer-Encoding: chunked\r\n\
+            \r\n\
+            5\r\n\
+            hello\r\n\
+            7;ext=1\r\n\
+            , world\r\n\
+            0\r\n\
+            Expires: never\r\n\
+            \r\n"
+            .as_bytes();
+
+        let response = Response::from_stream(&mut raw_response).unwrap();
+
+        assert_eq!(b"hello, world".to_vec(), response.body);
+    }
+
+    #[test]
+    fn from_stream_until_close() {
+        let mut raw_response = "HTTP/1.0 418 I'm a teapot\r\n\
+            \r\n\
+            short and stout"
+            .as_bytes();
+
+        let response = Response::from_stream(&mut raw_response).unwrap();
+
+        assert_eq!(HttpVersion::Http1_0, response.http_version);
+        assert_eq!(StatusCode(418, ""), response.status_code);
+        assert_eq!(b"short and stout".to_vec(), response.body);
+    }
+
+    #[test]
+    fn write_to_round_trip() {
+        let mut response = Response::error(StatusCode::NOT_FOUND, "Nothing here");
+
+        let mut output = vec![];
+        response.write_to(&mut output).unwrap();
+
+        assert_eq!(
+            "HTTP/1.1 404 Not Found\r\n\r\nNothing here\n",
+            String::from_utf8(output.clone()).unwrap()
+        );
+        assert_eq!(
+            b"Nothing here\n".to_vec(),
+            Response::from_stream(&mut output.as_slice()).unwrap().body
+        );
+    }
+
+    #[test]
+    fn buffer_body_decodes_streamed_chunks() {
+        let mut response = Response::new();
+        response
+            .headers
+            .insert("Transfer-Encoding".to_string(), vec!["chunked".to_string()]);
+        response.stream_body = Some(Box::new(|stream| {
+            stream.write_all(b"3\r\nabc\r\n")?;
+            stream.write_all(b"2\r\nde\r\n0\r\n\r\n")
+        }));
+
+        response.buffer_body().unwrap();
+
+        assert!(response.stream_body.is_none());
+        assert!(!response.is_chunked());
+        assert_eq!(b"abcde".to_vec(), response.body);
+        assert_eq!(Some("5"), response.header("Content-Length"));
+    }
 }
@@ -0,0 +1,510 @@
+#[cfg(not(unix))]
+use std::net::{SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::{fd::AsRawFd, unix::net::UnixStream};
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind},
+    net::Shutdown,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::listener::{Listener, Stream};
+#[cfg(unix)]
+use super::signal::Signals;
+
+/** How long start_server() waits for open connections after SIGTERM or SIGINT */
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/** Wakes an accept loop once the server starts shutting down */
+enum Waker {
+    /** Shut down to make the other end, which is polled along with the listener, readable */
+    #[cfg(unix)]
+    Socket(UnixStream),
+    /** Connected to, to wake a listener that's blocked in accept() */
+    #[cfg(not(unix))]
+    Connect(SocketAddr),
+}
+
+impl Waker {
+    fn wake(&self) {
+        let _ = match self {
+            #[cfg(unix)]
+            Waker::Socket(socket) => socket.shutdown(Shutdown::Write),
+            #[cfg(not(unix))]
+            Waker::Connect(addr) => {
+                TcpStream::connect_timeout(addr, Duration::from_secs(1)).map(drop)
+            }
+        };
+    }
+}
+
+struct Open {
+    stream: Stream,
+    on_drain: Option<Box<dyn FnOnce() + Send>>,
+}
+
+#[derive(Default)]
+struct Connections {
+    next_id: u64,
+    open: HashMap<u64, Open>,
+    deadline: Option<Instant>,
+    finished: bool,
+}
+
+#[derive(Default)]
+struct State {
+    draining: AtomicBool,
+    wakers: Mutex<Vec<Waker>>,
+    on_shutdown: Mutex<Vec<Box<dyn FnOnce() + Send>>>,
+    connections: Mutex<Connections>,
+    /** Notified when a connection closes, the deadline moves, or shutdown finishes */
+    changed: Condvar,
+}
+
+/**
+ * Shuts down the listeners serving with it, gracefully: they stop
+ * accepting, HTTP/1.1 responses still being written say `Connection:
+ * close`, HTTP/2 clients get a GOAWAY, and open connections get until the
+ * grace period is up to finish before they're closed.
+ */
+#[derive(Clone, Default)]
+pub struct ServerHandle {
+    state: Arc<State>,
+}
+
+impl ServerHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.state.draining.load(Ordering::SeqCst)
+    }
+
+    /**
+     * Stops accepting connections, then waits up to `grace_period` for open
+     * ones to finish before closing whatever's left. Returns once they're
+     * all closed. Calling it again while a shutdown is underway brings the
+     * deadline forward if `grace_period` is shorter, and waits for it too.
+     */
+    pub fn shutdown(&self, grace_period: Duration) {
+        let first = !self.state.draining.swap(true, Ordering::SeqCst);
+
+        let on_drain = {
+            let mut connections = self.state.connections.lock().unwrap();
+            let deadline = Instant::now() + grace_period;
+            connections.deadline = Some(connections.deadline.map_or(deadline, |d| d.min(deadline)));
+            self.state.changed.notify_all();
+            if !first {
+                drop(connections);
+                self.wait();
+                return;
+            }
+            connections
+                .open
+                .values_mut()
+                .filter_map(|open| open.on_drain.take())
+                .collect::<Vec<_>>()
+        };
+
+        for waker in self.state.wakers.lock().unwrap().iter() {
+            waker.wake();
+        }
+        for on_shutdown in std::mem::take(&mut *self.state.on_shutdown.lock().unwrap()) {
+            on_shutdown();
+        }
+        // Each on its own thread, as one can block on a slow client
+        for on_drain in on_drain {
+            thread::spawn(on_drain);
+        }
+
+        let mut connections = self.state.connections.lock().unwrap();
+        while !connections.open.is_empty() {
+            let now = Instant::now();
+            let deadline = connections.deadline.unwrap_or(now);
+            if now >= deadline {
+                eprintln!(
+                    "Closing {} connections that didn't finish in time",
+                    connections.open.len()
+                );
+                for open in connections.open.values() {
+                    let _ = open.stream.shutdown(Shutdown::Both);
+                }
+                break;
+            }
+            connections = self
+                .state
+                .changed
+                .wait_timeout(connections, deadline - now)
+                .unwrap()
+                .0;
+        }
+        connections.finished = true;
+        self.state.changed.notify_all();
+    }
+
+    /**
+     * Runs `on_shutdown` once a shutdown starts, before the open connections
+     * are waited for, or now if one already has
+     */
+    pub fn on_shutdown(&self, on_shutdown: Box<dyn FnOnce() + Send>) {
+        let mut on_shutdowns = self.state.on_shutdown.lock().unwrap();
+        // shutdown() sets this before it takes the callbacks, so it can't miss this one
+        if self.is_shutting_down() {
+            drop(on_shutdowns);
+            on_shutdown();
+        } else {
+            on_shutdowns.push(on_shutdown);
+        }
+    }
+
+    /** Blocks until a shutdown has finished */
+    pub fn wait(&self) {
+        let mut connections = self.state.connections.lock().unwrap();
+        while !connections.finished {
+            connections = self.state.changed.wait(connections).unwrap();
+        }
+    }
+
+    /**
+     * Shuts down on SIGTERM or SIGINT, waiting up to `grace_period`. A
+     * second signal closes the remaining connections straight away.
+     */
+    #[cfg(unix)]
+    pub fn shutdown_on_signals(&self, grace_period: Duration) -> Result<(), std::io::Error> {
+        let mut signals = Signals::new(&[libc::SIGTERM, libc::SIGINT])?;
+        let handle = self.clone();
+
+        thread::spawn(move || {
+            let mut grace_period = grace_period;
+            loop {
+                match signals.wait() {
+                    Ok(signal) => {
+                        eprintln!("Received signal {}, shutting down", signal);
+                        let handle = handle.clone();
+                        thread::spawn(move || handle.shutdown(grace_period));
+                        grace_period = Duration::ZERO;
+                    }
+                    Err(err) => {
+                        eprintln!("Failed to wait for signals: {}", err);
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /** Accepts from `listener` until shutdown() is called */
+    pub(super) fn acceptor<'a>(&'a self, listener: &'a Listener) -> Result<Acceptor<'a>, Error> {
+        #[cfg(unix)]
+        let (acceptor, waker) = {
+            let (woken, waker) = UnixStream::pair()?;
+            // Polled rather than blocked on, and after a handoff another
+            // process can take a connection between the two
+            listener.set_nonblocking(true)?;
+            let acceptor = Acceptor {
+                listener,
+                handle: self,
+                woken,
+            };
+            (acceptor, Waker::Socket(waker))
+        };
+        #[cfg(not(unix))]
+        let (acceptor, waker) = {
+            let Listener::Tcp(tcp) = listener;
+            let mut addr = tcp.local_addr()?;
+            // A wildcard address can't be connected to, but loopback reaches it
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr {
+                    SocketAddr::V4(_) => [127, 0, 0, 1].into(),
+                    SocketAddr::V6(_) => [0, 0, 0, 0, 0, 0, 0, 1].into(),
+                });
+            }
+            let acceptor = Acceptor {
+                listener,
+                handle: self,
+            };
+            (acceptor, Waker::Connect(addr))
+        };
+
+        let mut wakers = self.state.wakers.lock().unwrap();
+        // shutdown() sets this before it takes the wakers, so it can't miss this one too
+        if self.is_shutting_down() {
+            waker.wake();
+        }
+        wakers.push(waker);
+        Ok(acceptor)
+    }
+
+    /** Has shutdown() wait for `stream` to close, or close it once the grace period is up */
+    pub(super) fn track(&self, stream: &Stream) -> Option<Tracked> {
+        let stream = stream
+            .try_clone()
+            .inspect_err(|err| eprintln!("Failed to track connection: {}", err))
+            .ok()?;
+
+        let mut connections = self.state.connections.lock().unwrap();
+        let id = connections.next_id;
+        connections.next_id += 1;
+        connections.open.insert(
+            id,
+            Open {
+                stream,
+                on_drain: None,
+            },
+        );
+        Some(Tracked {
+            state: Arc::clone(&self.state),
+            id,
+        })
+    }
+}
+
+/** Accepts connections from a listener until its ServerHandle is shut down */
+pub(super) struct Acceptor<'a> {
+    listener: &'a Listener,
+    handle: &'a ServerHandle,
+    #[cfg(unix)]
+    woken: UnixStream,
+}
+
+impl Acceptor<'_> {
+    /** The next connection, or None once the server is shutting down */
+    #[cfg(unix)]
+    pub(super) fn accept(&self) -> Option<Result<Stream, Error>> {
+        loop {
+            let mut fds =
+                [self.listener.as_raw_fd(), self.woken.as_raw_fd()].map(|fd| libc::pollfd {
+                    fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                });
+            // SAFETY: fds is an array of as many pollfds as poll is told
+            if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+                let err = Error::last_os_error();
+                if err.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return Some(Err(err));
+            }
+            if fds[1].revents != 0 || self.handle.is_shutting_down() {
+                return None;
+            }
+
+            match self.listener.accept() {
+                // Accepted sockets inherit non-blocking mode on some systems
+                Ok(stream) => return Some(stream.set_nonblocking(false).map(|()| stream)),
+                // Another process sharing the socket took it
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+
+    /** The next connection, or None once the server is shutting down */
+    #[cfg(not(unix))]
+    pub(super) fn accept(&self) -> Option<Result<Stream, Error>> {
+        if self.handle.is_shutting_down() {
+            return None;
+        }
+        let accepted = self.listener.accept();
+        // Most likely the connection shutdown() made to wake us
+        if self.handle.is_shutting_down() {
+            return None;
+        }
+        Some(accepted)
+    }
+}
+
+/** An open connection a ServerHandle is waiting on, until this is dropped */
+pub struct Tracked {
+    state: Arc<State>,
+    id: u64,
+}
+
+impl Tracked {
+    /** Whether the server is shutting down, so the connection shouldn't be kept open */
+    pub fn is_draining(&self) -> bool {
+        self.state.draining.load(Ordering::SeqCst)
+    }
+
+    /** Runs `on_drain` once the server starts shutting down, or now if it already has */
+    pub fn on_drain(&self, on_drain: Box<dyn FnOnce() + Send>) {
+        let mut connections = self.state.connections.lock().unwrap();
+        // Checked with the lock held, as shutdown() takes the callbacks with it held
+        if self.is_draining() {
+            drop(connections);
+            on_drain();
+        } else if let Some(open) = connections.open.get_mut(&self.id) {
+            open.on_drain = Some(on_drain);
+        }
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.state.connections.lock().unwrap().open.remove(&self.id);
+        self.state.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::{SocketAddr, TcpListener, TcpStream},
+        sync::mpsc,
+    };
+
+    use super::{
+        super::{http2, request::Request, response::Response},
+        *,
+    };
+
+    /** Serves `handler` until the returned handle is shut down */
+    fn start<H: super::super::handler::Handler>(handler: H) -> (SocketAddr, ServerHandle) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = ServerHandle::new();
+        let serving = handle.clone();
+        thread::spawn(move || Listener::from(listener).serve_until_shutdown(handler, &serving));
+        (addr, handle)
+    }
+
+    #[test]
+    fn requests_finish_before_shutdown_returns() {
+        let (started, handling) = mpsc::channel();
+        let (finish, finishing) = mpsc::channel::<()>();
+        let finishing = Mutex::new(finishing);
+        let (addr, handle) = start(move |_: &Request| {
+            started.send(()).unwrap();
+            finishing.lock().unwrap().recv().unwrap();
+            let mut response = Response::new();
+            response.body = b"done".to_vec();
+            response
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        handling.recv().unwrap();
+
+        let shutting_down = handle.clone();
+        let shutdown = thread::spawn(move || shutting_down.shutdown(Duration::from_secs(10)));
+        while !handle.is_shutting_down() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        // The listener is closed once the accept loop has been woken
+        let mut refused = false;
+        for _ in 0..100 {
+            if TcpStream::connect(addr).is_err() {
+                refused = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(refused);
+        assert!(!shutdown.is_finished());
+
+        finish.send(()).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.contains("Connection: close\r\n"), "{}", response);
+        assert!(response.ends_with("done"));
+        shutdown.join().unwrap();
+    }
+
+    #[test]
+    fn connections_are_closed_after_the_grace_period() {
+        let (addr, handle) = start(|_: &Request| Response::new());
+
+        // Never sends a request, so the connection is never done
+        let mut idle = TcpStream::connect(addr).unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        let started = Instant::now();
+        handle.shutdown(Duration::from_millis(100));
+        assert!(started.elapsed() >= Duration::from_millis(100));
+
+        let mut rest = vec![];
+        assert_eq!(0, idle.read_to_end(&mut rest).unwrap_or(0));
+    }
+
+    #[test]
+    fn http2_clients_get_goaway() {
+        let (addr, handle) = start(|_: &Request| Response::new());
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(http2::PREFACE).unwrap();
+        client.write_all(&[0, 0, 0, 0x4, 0, 0, 0, 0, 0]).unwrap();
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        // Wait for the server's SETTINGS, so the connection is being served
+        reader.fill_buf().unwrap();
+
+        let shutting_down = handle.clone();
+        let shutdown = thread::spawn(move || shutting_down.shutdown(Duration::from_secs(10)));
+
+        let go_away = loop {
+            let mut header = [0; 9];
+            reader.read_exact(&mut header).unwrap();
+            let mut payload =
+                vec![0; u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize];
+            reader.read_exact(&mut payload).unwrap();
+            if header[3] == 0x7 {
+                break payload;
+            }
+        };
+        assert_eq!(
+            (1u32 << 31) - 1,
+            u32::from_be_bytes(go_away[..4].try_into().unwrap())
+        );
+        assert_eq!(http2::NO_ERROR.to_be_bytes(), go_away[4..8]);
+
+        // The client closes once it has nothing left in flight
+        drop(reader);
+        client.shutdown(Shutdown::Both).unwrap();
+        shutdown.join().unwrap();
+    }
+
+    #[test]
+    fn shutting_down_again_brings_the_deadline_forward() {
+        let (addr, handle) = start(|_: &Request| Response::new());
+        let _idle = TcpStream::connect(addr).unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        let shutting_down = handle.clone();
+        let shutdown = thread::spawn(move || shutting_down.shutdown(Duration::from_secs(60)));
+        while !handle.is_shutting_down() {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let started = Instant::now();
+        handle.shutdown(Duration::ZERO);
+        shutdown.join().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn on_shutdown_runs_once_when_shutdown_starts() {
+        let handle = ServerHandle::new();
+        let (ran, runs) = mpsc::channel();
+        let before = ran.clone();
+        handle.on_shutdown(Box::new(move || before.send("before").unwrap()));
+        assert!(runs.try_recv().is_err());
+
+        handle.shutdown(Duration::ZERO);
+        handle.shutdown(Duration::ZERO);
+        assert_eq!(vec!["before"], runs.try_iter().collect::<Vec<_>>());
+
+        handle.on_shutdown(Box::new(move || ran.send("after").unwrap()));
+        assert_eq!(vec!["after"], runs.try_iter().collect::<Vec<_>>());
+    }
+}
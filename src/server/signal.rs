@@ -0,0 +1,109 @@
+use std::{
+    io::{Error, Read},
+    os::{fd::AsRawFd, unix::net::UnixStream},
+    ptr,
+    sync::atomic::{AtomicI32, Ordering},
+};
+
+/** Where the handler writes each signal, by signal number; -1 when nothing's waiting for it */
+static WRITERS: [AtomicI32; 65] = [const { AtomicI32::new(-1) }; 65];
+
+extern "C" fn forward(signal: libc::c_int) {
+    let fd = WRITERS[signal as usize].load(Ordering::SeqCst);
+    if fd >= 0 {
+        let byte = signal as u8;
+        // SAFETY: write() is async-signal-safe, and the byte outlives the call.
+        // If the socket's buffer is full the signal is dropped rather than blocking.
+        unsafe { libc::write(fd, (&byte as *const u8).cast(), 1) };
+    }
+}
+
+/**
+ * Turns signals into bytes on a socket, so a thread can wait for them with
+ * an ordinary blocking read rather than doing its work in a signal handler.
+ * Each signal goes to the most recently created Signals that asked for it.
+ * Dropping a Signals restores the default action for its signals.
+ */
+pub struct Signals {
+    signals: Vec<libc::c_int>,
+    reader: UnixStream,
+    writer: UnixStream,
+}
+
+impl Signals {
+    pub fn new(signals: &[libc::c_int]) -> Result<Self, Error> {
+        let (reader, writer) = UnixStream::pair()?;
+        writer.set_nonblocking(true)?;
+        let installed = Signals {
+            signals: signals.to_vec(),
+            reader,
+            writer,
+        };
+
+        for &signal in signals {
+            WRITERS[signal as usize].store(installed.writer.as_raw_fd(), Ordering::SeqCst);
+            set_action(
+                signal,
+                forward as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            )?;
+        }
+        Ok(installed)
+    }
+
+    /** Blocks until one of the signals arrives, and returns it */
+    pub fn wait(&mut self) -> Result<libc::c_int, Error> {
+        let mut signal = [0];
+        self.reader.read_exact(&mut signal)?;
+        Ok(signal[0] as libc::c_int)
+    }
+
+    /**
+     * Restores the default action for the signals, as dropping does. A
+     * forked child calls this so its signals don't go to its parent's socket.
+     */
+    pub fn restore(&self) {
+        let fd = self.writer.as_raw_fd();
+        for &signal in &self.signals {
+            // Unless a newer Signals has taken the signal over
+            if WRITERS[signal as usize]
+                .compare_exchange(fd, -1, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                let _ = set_action(signal, libc::SIG_DFL);
+            }
+        }
+    }
+}
+
+impl Drop for Signals {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
+
+fn set_action(signal: libc::c_int, handler: libc::sighandler_t) -> Result<(), Error> {
+    // SAFETY: sigaction is plain data, for which all zeroes is a valid value
+    let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+    action.sa_sigaction = handler;
+    // Blocking calls interrupted by the signal carry on rather than failing with EINTR
+    action.sa_flags = libc::SA_RESTART;
+    // SAFETY: action is initialized, and the old action isn't asked for
+    if unsafe { libc::sigaction(signal, &action, ptr::null_mut()) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signals_are_read_from_the_socket() {
+        let mut signals = Signals::new(&[libc::SIGUSR1]).unwrap();
+
+        // SAFETY: SIGUSR1 is handled, so this doesn't kill the test process
+        unsafe { libc::raise(libc::SIGUSR1) };
+        assert_eq!(libc::SIGUSR1, signals.wait().unwrap());
+    }
+}
@@ -0,0 +1,336 @@
+use std::{io::Error, time::Duration};
+
+use socket2::{SockRef, Socket, TcpKeepalive};
+
+use super::listener::Stream;
+
+/** The listen backlog when none is set, as TcpListener::bind uses */
+pub const DEFAULT_BACKLOG: i32 = 128;
+
+/**
+ * TCP keepalive probes for idle connections, so ones whose client has
+ * vanished without closing them are noticed and dropped
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Keepalive {
+    idle: Duration,
+    interval: Option<Duration>,
+    count: Option<u32>,
+}
+
+impl Keepalive {
+    /** Starts probing once a connection has been idle for `idle` */
+    pub fn new(idle: Duration) -> Self {
+        Keepalive {
+            idle,
+            interval: None,
+            count: None,
+        }
+    }
+
+    /**
+     * The time between probes that go unanswered; unset, the system's
+     * default. Like `count`, only set where socket2 supports it: Linux,
+     * Android, macOS, FreeBSD and NetBSD.
+     */
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    /** How many unanswered probes drop the connection; unset, the system's default */
+    pub fn count(mut self, count: u32) -> Self {
+        self.count = Some(count);
+        self
+    }
+
+    fn params(&self) -> TcpKeepalive {
+        #[cfg_attr(
+            not(any(
+                target_os = "linux",
+                target_os = "android",
+                target_os = "macos",
+                target_os = "freebsd",
+                target_os = "netbsd"
+            )),
+            allow(unused_mut)
+        )]
+        let mut params = TcpKeepalive::new().with_time(self.idle);
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "macos",
+            target_os = "freebsd",
+            target_os = "netbsd"
+        ))]
+        {
+            if let Some(interval) = self.interval {
+                params = params.with_interval(interval);
+            }
+            if let Some(count) = self.count {
+                params = params.with_retries(count);
+            }
+        }
+        params
+    }
+}
+
+/**
+ * Options for a TCP listener's socket and the connections accepted from
+ * it. Anything unset is left as the system has it. The backlog, buffer
+ * sizes, TCP_FASTOPEN and TCP_DEFER_ACCEPT are set on the listening
+ * socket, the buffer sizes before listening so the window scale offered in
+ * the handshake suits them, and accepted sockets inherit them. TCP_NODELAY
+ * and keepalive are set on every accepted connection.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    backlog: Option<i32>,
+    nodelay: Option<bool>,
+    keepalive: Option<Keepalive>,
+    send_buffer_size: Option<usize>,
+    recv_buffer_size: Option<usize>,
+    fastopen: Option<u32>,
+    defer_accept: Option<Duration>,
+}
+
+impl SocketOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /** How many connections can wait to be accepted; 128 unless set */
+    pub fn backlog(mut self, backlog: i32) -> Self {
+        self.backlog = Some(backlog);
+        self
+    }
+
+    /**
+     * Whether writes go out right away rather than being held back to
+     * coalesce small ones (Nagle's algorithm), which saves a round trip's
+     * delay for responses written in several pieces
+     */
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = Some(nodelay);
+        self
+    }
+
+    /** Turns on SO_KEEPALIVE with these probes */
+    pub fn keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /** SO_SNDBUF in bytes, which the system may round or double */
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /** SO_RCVBUF in bytes, which the system may round or double */
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /**
+     * Lets clients that have connected before send their request in the
+     * SYN (TCP_FASTOPEN), with up to `queue` such connections pending at
+     * once. Linux only; binding fails elsewhere.
+     */
+    pub fn fastopen(mut self, queue: u32) -> Self {
+        self.fastopen = Some(queue);
+        self
+    }
+
+    /**
+     * Only wakes the server for a connection once the client has sent
+     * something, or `timeout` has passed since the handshake
+     * (TCP_DEFER_ACCEPT), which the system rounds to whole seconds. Linux
+     * only; binding fails elsewhere.
+     */
+    pub fn defer_accept(mut self, timeout: Duration) -> Self {
+        self.defer_accept = Some(timeout);
+        self
+    }
+
+    /** Sets the options for a listening socket that has to be set before it's bound */
+    pub(super) fn before_bind(&self, socket: &Socket) -> Result<(), Error> {
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+
+    /** Sets the rest of the listening socket's options, and starts listening */
+    pub(super) fn listen(&self, socket: &Socket) -> Result<(), Error> {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            if let Some(timeout) = self.defer_accept {
+                let seconds = timeout.as_secs_f64().ceil() as i32;
+                set_tcp_option(socket, libc::TCP_DEFER_ACCEPT, seconds)?;
+            }
+            if let Some(queue) = self.fastopen {
+                set_tcp_option(socket, libc::TCP_FASTOPEN, queue as i32)?;
+            }
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        if self.defer_accept.is_some() || self.fastopen.is_some() {
+            return Err(Error::new(
+                std::io::ErrorKind::Unsupported,
+                "TCP_FASTOPEN and TCP_DEFER_ACCEPT are only supported on Linux",
+            ));
+        }
+        socket.listen(self.backlog.unwrap_or(DEFAULT_BACKLOG))
+    }
+
+    /** Sets the options for a connection just accepted; Unix socket ones have none */
+    pub(super) fn accepted(&self, stream: &Stream) -> Result<(), Error> {
+        let stream = match stream {
+            Stream::Tcp(stream) => stream,
+            #[cfg(unix)]
+            Stream::Unix(_) => return Ok(()),
+        };
+        let socket = SockRef::from(stream);
+        if let Some(nodelay) = self.nodelay {
+            socket.set_tcp_nodelay(nodelay)?;
+        }
+        if let Some(keepalive) = self.keepalive {
+            socket.set_tcp_keepalive(&keepalive.params())?;
+        }
+        Ok(())
+    }
+}
+
+/** setsockopt at IPPROTO_TCP, for options socket2 doesn't have */
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_tcp_option(socket: &Socket, option: libc::c_int, value: libc::c_int) -> Result<(), Error> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: value is a valid c_int for the call, and its size is passed with it
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            option,
+            (&value as *const libc::c_int).cast(),
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpStream};
+
+    use super::{
+        super::{bind::BindAddress, listener::Listener},
+        *,
+    };
+
+    fn localhost() -> BindAddress {
+        BindAddress::new(SocketAddr::from(([127, 0, 0, 1], 0)))
+    }
+
+    #[test]
+    fn accepted_connections_get_the_options() {
+        let options = SocketOptions::new().nodelay(true).keepalive(
+            Keepalive::new(Duration::from_secs(60))
+                .interval(Duration::from_secs(5))
+                .count(3),
+        );
+        let listener = localhost().options(options).bind().unwrap();
+        let Listener::Tcp(tcp) = &listener else {
+            unreachable!()
+        };
+        let _client = TcpStream::connect(tcp.local_addr().unwrap()).unwrap();
+
+        let stream = listener.accept().unwrap();
+        options.accepted(&stream).unwrap();
+        let socket = match &stream {
+            Stream::Tcp(stream) => SockRef::from(stream),
+            #[cfg(unix)]
+            Stream::Unix(_) => unreachable!(),
+        };
+        assert!(socket.tcp_nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(
+            Duration::from_secs(60),
+            socket.tcp_keepalive_time().unwrap()
+        );
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            assert_eq!(
+                Duration::from_secs(5),
+                socket.tcp_keepalive_interval().unwrap()
+            );
+            assert_eq!(3, socket.tcp_keepalive_retries().unwrap());
+        }
+    }
+
+    #[test]
+    fn unset_options_are_left_alone() {
+        let listener = localhost().bind().unwrap();
+        let Listener::Tcp(tcp) = &listener else {
+            unreachable!()
+        };
+        let _client = TcpStream::connect(tcp.local_addr().unwrap()).unwrap();
+
+        let stream = listener.accept().unwrap();
+        SocketOptions::new().accepted(&stream).unwrap();
+        let Stream::Tcp(stream) = &stream else {
+            unreachable!()
+        };
+        assert!(!SockRef::from(stream).tcp_nodelay().unwrap());
+        assert!(!SockRef::from(stream).keepalive().unwrap());
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn listening_sockets_get_the_options() {
+        use std::os::fd::AsRawFd;
+
+        let listener = localhost()
+            .options(
+                SocketOptions::new()
+                    .backlog(16)
+                    .recv_buffer_size(64 * 1024)
+                    .fastopen(8)
+                    .defer_accept(Duration::from_secs(5)),
+            )
+            .bind()
+            .unwrap();
+        let Listener::Tcp(tcp) = &listener else {
+            unreachable!()
+        };
+        // Linux doubles it, for its bookkeeping
+        assert!(SockRef::from(tcp).recv_buffer_size().unwrap() >= 64 * 1024);
+
+        let tcp_option = |option| {
+            let mut value: libc::c_int = 0;
+            let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+            // SAFETY: value and len are valid for writes, and len is value's size
+            let result = unsafe {
+                libc::getsockopt(
+                    tcp.as_raw_fd(),
+                    libc::IPPROTO_TCP,
+                    option,
+                    (&mut value as *mut libc::c_int).cast(),
+                    &mut len,
+                )
+            };
+            assert_eq!(0, result, "{}", Error::last_os_error());
+            value
+        };
+        assert_eq!(8, tcp_option(libc::TCP_FASTOPEN));
+        // Kept as a number of SYN-ACK retransmissions, so read back roughly
+        assert!(tcp_option(libc::TCP_DEFER_ACCEPT) >= 5);
+    }
+}
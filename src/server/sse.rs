@@ -0,0 +1,228 @@
+use std::{
+    io::{Error, Write},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    time::Duration,
+};
+
+use super::{request::Request, response::Response};
+
+/** A single Server-Sent Event, built up field by field */
+#[derive(Debug, Default, PartialEq)]
+pub struct Event {
+    id: Option<String>,
+    event: Option<String>,
+    data: Option<String>,
+    retry: Option<Duration>,
+}
+
+impl Event {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn id(mut self, id: &str) -> Self {
+        self.id = Some(id.to_string());
+        self
+    }
+
+    pub fn event(mut self, event: &str) -> Self {
+        self.event = Some(event.to_string());
+        self
+    }
+
+    pub fn data(mut self, data: &str) -> Self {
+        self.data = Some(data.to_string());
+        self
+    }
+
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    pub fn write_to(&self, stream: &mut dyn Write) -> Result<(), Error> {
+        let mut lines = vec![];
+
+        if let Some(id) = &self.id {
+            lines.push(format!("id: {}", single_line(id)));
+        }
+        if let Some(event) = &self.event {
+            lines.push(format!("event: {}", single_line(event)));
+        }
+        if let Some(retry) = &self.retry {
+            lines.push(format!("retry: {}", retry.as_millis()));
+        }
+        if let Some(data) = &self.data {
+            // The client joins consecutive data fields back together with '\n'
+            let data = data.replace("\r\n", "\n").replace('\r', "\n");
+            for line in data.split('\n') {
+                lines.push(format!("data: {}", line));
+            }
+        }
+
+        let mut event = lines.join("\n");
+        event.push_str("\n\n");
+
+        stream.write_all(event.as_bytes())
+    }
+}
+
+/** Field values other than data can't span lines, so any line breaks are dropped */
+fn single_line(value: &str) -> String {
+    value.chars().filter(|c| *c != '\r' && *c != '\n').collect()
+}
+
+/**
+ * The receiving half of an event stream. Events sent on the paired Sender are
+ * written to the client as they arrive; once the client goes away the stream
+ * stops and further sends fail, which is how a producer notices disconnects.
+ */
+pub struct EventStream {
+    receiver: Receiver<Event>,
+    keep_alive: Duration,
+}
+
+impl EventStream {
+    pub const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+    pub fn new() -> (Sender<Event>, Self) {
+        let (sender, receiver) = mpsc::channel();
+        (
+            sender,
+            EventStream {
+                receiver,
+                keep_alive: Self::DEFAULT_KEEP_ALIVE,
+            },
+        )
+    }
+
+    /** How long the stream may sit idle before a keep-alive comment is sent */
+    pub fn keep_alive(mut self, interval: Duration) -> Self {
+        self.keep_alive = interval;
+        self
+    }
+
+    pub fn into_response(self) -> Response {
+        let mut response = Response::new();
+        response.headers.insert(
+            "Content-Type".to_string(),
+            vec!["text/event-stream".to_string()],
+        );
+        response
+            .headers
+            .insert("Cache-Control".to_string(), vec!["no-cache".to_string()]);
+        response.stream_body = Some(Box::new(move |stream| self.write_to(stream)));
+        response
+    }
+
+    /** Writes events until every Sender is dropped or the client disconnects */
+    pub fn write_to(self, stream: &mut dyn Write) -> Result<(), Error> {
+        loop {
+            match self.receiver.recv_timeout(self.keep_alive) {
+                Ok(event) => event.write_to(stream)?,
+                Err(RecvTimeoutError::Timeout) => stream.write_all(b": keep-alive\n\n")?,
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+            stream.flush()?;
+        }
+    }
+}
+
+/** The id of the last event a reconnecting client saw, for resuming a stream */
+pub fn last_event_id(request: &Request) -> Option<&str> {
+    request.header("Last-Event-ID")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::ErrorKind, thread};
+
+    use super::*;
+
+    #[test]
+    fn event_fields() {
+        let event = Event::new()
+            .id("42")
+            .event("log")
+            .retry(Duration::from_secs(3))
+            .data("first line\nsecond line\r\nthird line");
+
+        let mut output = vec![];
+        event.write_to(&mut output).unwrap();
+
+        assert_eq!(
+            "id: 42\n\
+            event: log\n\
+            retry: 3000\n\
+            data: first line\n\
+            data: second line\n\
+            data: third line\n\
+            \n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn line_breaks_are_dropped_from_id_and_event() {
+        let event = Event::new().id("4\n2").event("lo\r\ng");
+
+        let mut output = vec![];
+        event.write_to(&mut output).unwrap();
+
+        assert_eq!("id: 42\nevent: log\n\n", String::from_utf8(output).unwrap());
+    }
+
+    #[test]
+    fn stream_sends_keep_alives_until_senders_are_dropped() {
+        let (sender, stream) = EventStream::new();
+        let stream = stream.keep_alive(Duration::from_millis(10));
+
+        let producer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            sender.send(Event::new().data("done")).unwrap();
+        });
+
+        let mut output = vec![];
+        stream.write_to(&mut output).unwrap();
+        producer.join().unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with(": keep-alive\n\n"));
+        assert!(output.ends_with("data: done\n\n"));
+    }
+
+    struct DisconnectedClient;
+
+    impl Write for DisconnectedClient {
+        fn write(&mut self, _: &[u8]) -> Result<usize, Error> {
+            Err(Error::new(ErrorKind::BrokenPipe, "client went away"))
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn sends_fail_once_client_disconnects() {
+        let (sender, stream) = EventStream::new();
+        sender.send(Event::new().data("hello")).unwrap();
+
+        let err = stream.write_to(&mut DisconnectedClient).unwrap_err();
+
+        assert_eq!(ErrorKind::BrokenPipe, err.kind());
+        assert!(sender.send(Event::new().data("anyone there?")).is_err());
+    }
+
+    #[test]
+    fn last_event_id_header() {
+        let mut raw_request = "GET /logs HTTP/1.1\r\n\
+            Last-Event-ID: 17\r\n\
+            \r\n"
+            .as_bytes();
+
+        let request = Request::from_stream(&mut raw_request).unwrap();
+
+        assert_eq!(Some("17"), last_event_id(&request));
+    }
+}
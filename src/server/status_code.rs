@@ -1,5 +1,5 @@
 /** StatusCode (u16 (HTTP Status Code), String (Reason Phrase, e.g. 'OK')) */
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct StatusCode(pub u16, pub &'static str);
 
 impl StatusCode {
@@ -55,6 +55,7 @@ impl StatusCode {
     pub const HTTP_VERSION_NOT_SUPPORTED: StatusCode =
         StatusCode(505, "HTTP Version Not Supported");
 
+    #[allow(clippy::result_unit_err)]
     pub fn from_int(code: usize) -> Result<Self, ()> {
         match code {
             100 => Ok(StatusCode::CONTINUE),
@@ -0,0 +1,424 @@
+use std::{
+    env,
+    io::{Error, ErrorKind},
+    net::TcpListener,
+    os::{
+        fd::{FromRawFd, RawFd},
+        unix::net::{SocketAddr, UnixDatagram, UnixListener},
+    },
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use super::{
+    handler::Handler,
+    listener::Listener,
+    request::Request,
+    shutdown::{ServerHandle, DEFAULT_GRACE_PERIOD},
+};
+
+/** The first descriptor systemd passes, after stdin, stdout and stderr */
+const LISTEN_FDS_START: RawFd = 3;
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, message)
+}
+
+/**
+ * Takes the listening sockets systemd passed in for socket activation
+ * (sd_listen_fds(3)), each named by its FileDescriptorName= or "unknown".
+ * Returns none if the LISTEN_* variables aren't meant for this process.
+ * With `unset_environment` the variables are removed either way, which is
+ * only sound before any other threads start, as they may be reading the
+ * environment. Left set, LISTEN_PID still keeps child processes from
+ * taking the sockets too.
+ */
+pub fn listen_fds(unset_environment: bool) -> Result<Vec<(String, Listener)>, Error> {
+    let passed = passed_fds(
+        env::var("LISTEN_PID").ok().as_deref(),
+        env::var("LISTEN_FDS").ok().as_deref(),
+        env::var("LISTEN_FDNAMES").ok().as_deref(),
+        std::process::id(),
+    );
+    if unset_environment {
+        for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            env::remove_var(name);
+        }
+    }
+
+    passed?
+        .into_iter()
+        // SAFETY: systemd hands these descriptors to us and nothing else uses them
+        .map(|(name, fd)| Ok((name, unsafe { listener_from_fd(fd) }?)))
+        .collect()
+}
+
+/** The descriptors the LISTEN_* variables describe, with their names */
+fn passed_fds(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    listen_fdnames: Option<&str>,
+    pid: u32,
+) -> Result<Vec<(String, RawFd)>, Error> {
+    let (Some(listen_pid), Some(listen_fds)) = (listen_pid, listen_fds) else {
+        return Ok(vec![]);
+    };
+    // Inherited from a parent that was activated, not meant for us
+    if listen_pid.parse::<u32>().ok() != Some(pid) {
+        return Ok(vec![]);
+    }
+
+    let count = listen_fds
+        .parse::<RawFd>()
+        .map_err(|_| invalid(format!("LISTEN_FDS isn't a number: {}", listen_fds)))?;
+    let names = listen_fdnames
+        .map(|names| names.split(':').collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    Ok((0..count)
+        .map(|index| {
+            let name = names.get(index as usize).copied().unwrap_or("unknown");
+            (name.to_string(), LISTEN_FDS_START + index)
+        })
+        .collect())
+}
+
+fn socket_option(fd: RawFd, option: libc::c_int) -> Result<libc::c_int, Error> {
+    let mut value: libc::c_int = 0;
+    let mut length = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: value and length describe a buffer the size of the int the option is
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            option,
+            (&mut value as *mut libc::c_int).cast(),
+            &mut length,
+        )
+    };
+    if result != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(value)
+}
+
+fn socket_family(fd: RawFd) -> Result<libc::c_int, Error> {
+    // SAFETY: sockaddr_storage is plain data, and big enough for any address
+    let mut address: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut length = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    // SAFETY: address and length describe that buffer
+    let result = unsafe {
+        libc::getsockname(
+            fd,
+            (&mut address as *mut libc::sockaddr_storage).cast(),
+            &mut length,
+        )
+    };
+    if result != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(address.ss_family as libc::c_int)
+}
+
+/**
+ * Wraps a descriptor for a listening TCP or Unix stream socket. Anything
+ * else is an error, and the descriptor is left open.
+ *
+ * # Safety
+ *
+ * `fd` must be open, and owned by nothing else: the Listener closes it.
+ */
+pub(super) unsafe fn listener_from_fd(fd: RawFd) -> Result<Listener, Error> {
+    if socket_option(fd, libc::SO_TYPE)? != libc::SOCK_STREAM
+        || socket_option(fd, libc::SO_ACCEPTCONN)? == 0
+    {
+        return Err(invalid(format!(
+            "File descriptor {} isn't a listening stream socket",
+            fd
+        )));
+    }
+
+    let listener = match socket_family(fd)? {
+        libc::AF_INET | libc::AF_INET6 => Listener::Tcp(TcpListener::from_raw_fd(fd)),
+        libc::AF_UNIX => Listener::Unix(UnixListener::from_raw_fd(fd)),
+        family => {
+            return Err(invalid(format!(
+                "File descriptor {} has unsupported address family {}",
+                fd, family
+            )))
+        }
+    };
+
+    // Like any socket we open ourselves, it shouldn't leak into child processes
+    if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(listener)
+}
+
+/**
+ * Tells systemd about the service's state over its notification socket
+ * (sd_notify(3)): READY=1 for Type=notify units, STATUS= for systemctl
+ * status, STOPPING=1, and WATCHDOG=1 pings for units with WatchdogSec=.
+ */
+#[derive(Debug)]
+pub struct Notifier {
+    socket: UnixDatagram,
+    address: SocketAddr,
+}
+
+impl Notifier {
+    /** Sends to NOTIFY_SOCKET, or None when systemd didn't set it */
+    pub fn from_env() -> Result<Option<Self>, Error> {
+        match env::var("NOTIFY_SOCKET") {
+            Ok(socket) => Self::new(&socket).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /** Sends to the datagram socket at `socket`, or `@name` in the abstract namespace */
+    pub fn new(socket: &str) -> Result<Self, Error> {
+        let address = match socket.strip_prefix('@') {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            Some(name) => {
+                #[cfg(target_os = "android")]
+                use std::os::android::net::SocketAddrExt;
+                #[cfg(target_os = "linux")]
+                use std::os::linux::net::SocketAddrExt;
+
+                SocketAddr::from_abstract_name(name)?
+            }
+            _ => SocketAddr::from_pathname(socket)?,
+        };
+
+        Ok(Notifier {
+            socket: UnixDatagram::unbound()?,
+            address,
+        })
+    }
+
+    fn send(&self, state: &str) -> Result<(), Error> {
+        self.socket.send_to_addr(state.as_bytes(), &self.address)?;
+        Ok(())
+    }
+
+    pub fn ready(&self) -> Result<(), Error> {
+        self.send("READY=1")
+    }
+
+    /** A one-line description shown by `systemctl status` */
+    pub fn status(&self, status: &str) -> Result<(), Error> {
+        // Each line of a notification is its own assignment
+        self.send(&format!("STATUS={}", status.replace('\n', " ")))
+    }
+
+    /** Hands the service over to another process, e.g. one it started to replace itself */
+    pub fn main_pid(&self, pid: u32) -> Result<(), Error> {
+        self.send(&format!("MAINPID={}", pid))
+    }
+
+    pub fn stopping(&self) -> Result<(), Error> {
+        self.send("STOPPING=1")
+    }
+
+    pub fn watchdog(&self) -> Result<(), Error> {
+        self.send("WATCHDOG=1")
+    }
+
+    /** Pings the watchdog every `interval` for as long as the process runs */
+    pub fn start_watchdog(self: Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        thread::spawn(move || loop {
+            if let Err(err) = self.watchdog() {
+                eprintln!("Failed to ping the systemd watchdog: {}", err);
+            }
+            thread::sleep(interval);
+        })
+    }
+}
+
+/**
+ * How often to ping the watchdog: half of WATCHDOG_USEC, as sd_watchdog_enabled(3)
+ * suggests, or None if the unit has no watchdog or it's for another process
+ */
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+/**
+ * Serves on every socket systemd passed in, then reports READY=1 and pings
+ * the watchdog if the unit has one. SIGTERM, which systemd stops the unit
+ * with, shuts down gracefully after reporting STOPPING=1. Panics if there
+ * are no sockets, e.g. when not started by a .socket unit.
+ */
+pub fn start_socket_activated_server<H: Handler>(handler: H) {
+    let listeners = listen_fds(false)
+        .unwrap_or_else(|err| panic!("Unable to use the sockets systemd passed in: {err}"));
+    if listeners.is_empty() {
+        panic!("No sockets were passed in by systemd");
+    }
+
+    let handle = ServerHandle::new();
+    if let Err(err) = handle.shutdown_on_signals(DEFAULT_GRACE_PERIOD) {
+        eprintln!("Failed to handle shutdown signals: {}", err);
+    }
+
+    let handler = Arc::new(handler);
+    let count = listeners.len();
+    let servers = listeners
+        .into_iter()
+        .map(|(_, listener)| {
+            let handler = Arc::clone(&handler);
+            let handle = handle.clone();
+            thread::spawn(move || {
+                listener
+                    .serve_until_shutdown(move |request: &Request| handler.handle(request), &handle)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    match Notifier::from_env() {
+        Ok(Some(notifier)) => {
+            let notifier = Arc::new(notifier);
+            let notified = notifier
+                .ready()
+                .and_then(|_| notifier.status(&format!("Serving on {} sockets", count)));
+            if let Err(err) = notified {
+                eprintln!("Failed to notify systemd: {}", err);
+            }
+            let stopping = Arc::clone(&notifier);
+            handle.on_shutdown(Box::new(move || {
+                if let Err(err) = stopping.stopping() {
+                    eprintln!("Failed to notify systemd: {}", err);
+                }
+            }));
+            if let Some(interval) = watchdog_interval() {
+                notifier.start_watchdog(interval);
+            }
+        }
+        Ok(None) => {}
+        Err(err) => eprintln!("Unable to use NOTIFY_SOCKET: {}", err),
+    }
+
+    for server in servers {
+        let _ = server.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{TcpStream, UdpSocket},
+        os::fd::IntoRawFd,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::{super::response::Response, *};
+
+    fn socket_path() -> std::path::PathBuf {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        std::env::temp_dir().join(format!(
+            "http_server_systemd_{}_{}.sock",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        ))
+    }
+
+    fn received(socket: &UnixDatagram) -> String {
+        let mut buf = [0; 256];
+        let length = socket.recv(&mut buf).unwrap();
+        String::from_utf8(buf[..length].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn passed_fds_are_numbered_from_3() {
+        assert_eq!(
+            vec![
+                ("http".to_string(), 3),
+                ("admin".to_string(), 4),
+                ("unknown".to_string(), 5)
+            ],
+            passed_fds(Some("42"), Some("3"), Some("http:admin"), 42).unwrap()
+        );
+        assert_eq!(
+            vec![("unknown".to_string(), 3)],
+            passed_fds(Some("42"), Some("1"), None, 42).unwrap()
+        );
+
+        assert!(passed_fds(Some("41"), Some("1"), None, 42)
+            .unwrap()
+            .is_empty());
+        assert!(passed_fds(None, None, None, 42).unwrap().is_empty());
+        assert!(passed_fds(Some("42"), Some("many"), None, 42).is_err());
+    }
+
+    #[test]
+    fn listeners_are_taken_over() {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let listener = unsafe { listener_from_fd(tcp.into_raw_fd()) }.unwrap();
+        assert!(matches!(listener, Listener::Tcp(_)));
+        thread::spawn(move || listener.serve(|_: &Request| Response::new()));
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+
+        let path = socket_path();
+        let unix = UnixListener::bind(&path).unwrap();
+        let listener = unsafe { listener_from_fd(unix.into_raw_fd()) }.unwrap();
+        assert!(matches!(listener, Listener::Unix(_)));
+        std::fs::remove_file(path).unwrap();
+
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap().into_raw_fd();
+        assert!(unsafe { listener_from_fd(udp) }.is_err());
+        drop(unsafe { UdpSocket::from_raw_fd(udp) });
+    }
+
+    #[test]
+    fn notifications_are_sent() {
+        let path = socket_path();
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        let notifier = Notifier::new(path.to_str().unwrap()).unwrap();
+
+        notifier.ready().unwrap();
+        assert_eq!("READY=1", received(&systemd));
+        notifier.status("Serving\nREADY=1").unwrap();
+        assert_eq!("STATUS=Serving READY=1", received(&systemd));
+        notifier.stopping().unwrap();
+        assert_eq!("STOPPING=1", received(&systemd));
+        notifier.main_pid(42).unwrap();
+        assert_eq!("MAINPID=42", received(&systemd));
+
+        Arc::new(notifier).start_watchdog(Duration::from_millis(10));
+        assert_eq!("WATCHDOG=1", received(&systemd));
+        assert_eq!("WATCHDOG=1", received(&systemd));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn abstract_notify_sockets() {
+        use std::os::linux::net::SocketAddrExt;
+
+        let name = format!("http_server_notify_{}", std::process::id());
+        let address = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let systemd = UnixDatagram::bind_addr(&address).unwrap();
+
+        Notifier::new(&format!("@{}", name))
+            .unwrap()
+            .ready()
+            .unwrap();
+        assert_eq!("READY=1", received(&systemd));
+    }
+}
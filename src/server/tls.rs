@@ -0,0 +1,986 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Error, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use rustls::{
+    crypto::CryptoProvider,
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::{danger::ClientCertVerifier, ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+    sign::CertifiedKey,
+    RootCertStore, ServerConfig, ServerConnection, SupportedProtocolVersion, DEFAULT_VERSIONS,
+};
+
+use super::{
+    bind::BindAddress,
+    client_certificate::ClientCertificate,
+    handler::Handler,
+    http2,
+    listener::{Listener, Stream},
+    read_request,
+    response::Response,
+    shutdown::{ServerHandle, Tracked, DEFAULT_GRACE_PERIOD},
+    socket_options::SocketOptions,
+    status_code::StatusCode,
+    write_response,
+};
+
+/** The ALPN protocol IDs we offer, most preferred first */
+pub const ALPN_PROTOCOLS: [&[u8]; 2] = [b"h2", b"http/1.1"];
+
+/** Where one certificate chain and its private key are loaded from */
+#[derive(Clone, Debug)]
+struct CertificateFiles {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+}
+
+impl CertificateFiles {
+    fn new(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        CertificateFiles {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+        }
+    }
+
+    fn load(&self, provider: &CryptoProvider) -> Result<Arc<CertifiedKey>, Error> {
+        let key = provider
+            .key_provider
+            .load_private_key(load_private_key(&self.key_path)?)
+            .map_err(invalid_tls_config)?;
+        let certified = CertifiedKey::new(load_certs(&self.cert_path)?, key);
+        certified.keys_match().map_err(invalid_tls_config)?;
+        Ok(Arc::new(certified))
+    }
+
+    fn modified(&self) -> [Option<SystemTime>; 2] {
+        [&self.cert_path, &self.key_path].map(|path| {
+            path.metadata()
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        })
+    }
+}
+
+/** Which CAs client certificates have to be issued by, and whether clients must send one */
+#[derive(Clone, Debug)]
+struct ClientAuth {
+    ca_path: PathBuf,
+    required: bool,
+}
+
+impl ClientAuth {
+    fn verifier(
+        &self,
+        provider: Arc<CryptoProvider>,
+    ) -> Result<Arc<dyn ClientCertVerifier>, Error> {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(&self.ca_path)? {
+            roots.add(cert).map_err(invalid_tls_config)?;
+        }
+        let mut builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+        if !self.required {
+            builder = builder.allow_unauthenticated();
+        }
+        builder.build().map_err(invalid_tls_config)
+    }
+}
+
+/**
+ * Configures a TLS listener: a default certificate chain and key in PEM
+ * files, optionally more for particular hostnames, picked by the SNI
+ * hostname the client asks for.
+ */
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    default: CertificateFiles,
+    hostnames: Vec<(String, CertificateFiles)>,
+    reload_interval: Option<Duration>,
+    client_auth: Option<ClientAuth>,
+}
+
+impl TlsConfig {
+    pub fn new(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        TlsConfig {
+            default: CertificateFiles::new(cert_path, key_path),
+            hostnames: vec![],
+            reload_interval: None,
+            client_auth: None,
+        }
+    }
+
+    /**
+     * Serves a different certificate to clients asking for `hostname`. A
+     * hostname like `*.example.com` matches any one label in its place.
+     */
+    pub fn hostname(
+        mut self,
+        hostname: &str,
+        cert_path: impl Into<PathBuf>,
+        key_path: impl Into<PathBuf>,
+    ) -> Self {
+        self.hostnames.push((
+            hostname.to_ascii_lowercase(),
+            CertificateFiles::new(cert_path, key_path),
+        ));
+        self
+    }
+
+    /** Checks the PEM files this often, and reloads them once any have changed */
+    pub fn reload_interval(mut self, reload_interval: Duration) -> Self {
+        self.reload_interval = Some(reload_interval);
+        self
+    }
+
+    /**
+     * Asks clients for a certificate issued by one of the CAs in the PEM
+     * bundle at `ca_path`. Clients that don't send one still connect, with
+     * no client_certificate on their requests; ones that send a certificate
+     * the CAs didn't issue fail the handshake. The bundle is read once, by
+     * TlsServer::bind() or Http3Server::bind_with_config().
+     */
+    pub fn request_client_certificates(mut self, ca_path: impl Into<PathBuf>) -> Self {
+        self.client_auth = Some(ClientAuth {
+            ca_path: ca_path.into(),
+            required: false,
+        });
+        self
+    }
+
+    /** Like request_client_certificates(), but clients without a certificate fail the handshake */
+    pub fn require_client_certificates(mut self, ca_path: impl Into<PathBuf>) -> Self {
+        self.client_auth = Some(ClientAuth {
+            ca_path: ca_path.into(),
+            required: true,
+        });
+        self
+    }
+
+    /**
+     * The rustls config for these certificates and client certificate
+     * settings, shared by the TCP and QUIC listeners. Starts watching the
+     * files if a reload_interval was set.
+     */
+    pub(super) fn server_config(
+        self,
+        protocol_versions: &[&'static SupportedProtocolVersion],
+    ) -> Result<(ServerConfig, Arc<Certificates>), Error> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let reload_interval = self.reload_interval;
+        let client_verifier = match &self.client_auth {
+            Some(client_auth) => client_auth.verifier(Arc::clone(&provider))?,
+            None => WebPkiClientVerifier::no_client_auth(),
+        };
+        let certificates = Arc::new(Certificates::new(self, Arc::clone(&provider))?);
+
+        let server_config = ServerConfig::builder_with_provider(provider)
+            .with_protocol_versions(protocol_versions)
+            .map_err(invalid_tls_config)?
+            .with_client_cert_verifier(client_verifier)
+            .with_cert_resolver(certificates.clone());
+
+        if let Some(reload_interval) = reload_interval {
+            let certificates = Arc::clone(&certificates);
+            thread::spawn(move || certificates.watch(reload_interval));
+        }
+        Ok((server_config, certificates))
+    }
+}
+
+/** The certificates currently being served */
+#[derive(Debug)]
+struct Loaded {
+    default: Arc<CertifiedKey>,
+    hostnames: HashMap<String, Arc<CertifiedKey>>,
+}
+
+/**
+ * The certificates a TLS listener serves, chosen per handshake by SNI.
+ * reload() rereads every PEM file, so renewed certificates take effect on
+ * the next handshake without a restart; connections already open keep the
+ * certificate they started with.
+ */
+#[derive(Debug)]
+pub struct Certificates {
+    config: TlsConfig,
+    provider: Arc<CryptoProvider>,
+    loaded: RwLock<Loaded>,
+}
+
+impl Certificates {
+    fn new(config: TlsConfig, provider: Arc<CryptoProvider>) -> Result<Self, Error> {
+        let loaded = Self::load(&config, &provider)?;
+        Ok(Certificates {
+            config,
+            provider,
+            loaded: RwLock::new(loaded),
+        })
+    }
+
+    fn load(config: &TlsConfig, provider: &CryptoProvider) -> Result<Loaded, Error> {
+        let mut hostnames = HashMap::new();
+        for (hostname, files) in &config.hostnames {
+            hostnames.insert(hostname.clone(), files.load(provider)?);
+        }
+        Ok(Loaded {
+            default: config.default.load(provider)?,
+            hostnames,
+        })
+    }
+
+    /**
+     * Rereads every certificate and key. If any can't be loaded, the ones
+     * already being served are kept and the error is returned.
+     */
+    pub fn reload(&self) -> Result<(), Error> {
+        let loaded = Self::load(&self.config, &self.provider)?;
+        *self.loaded.write().unwrap() = loaded;
+        Ok(())
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        let hostnames = self.config.hostnames.iter().map(|(_, files)| files);
+        std::iter::once(&self.config.default)
+            .chain(hostnames)
+            .flat_map(CertificateFiles::modified)
+            .collect()
+    }
+
+    /** Reloads whenever the files' modification times change */
+    fn watch(self: Arc<Self>, interval: Duration) {
+        let mut modified = self.modified();
+        loop {
+            thread::sleep(interval);
+            let now_modified = self.modified();
+            if now_modified == modified {
+                continue;
+            }
+            match self.reload() {
+                Ok(()) => modified = now_modified,
+                Err(err) => eprintln!("Failed to reload certificates: {}", err),
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let loaded = self.loaded.read().unwrap();
+        let Some(server_name) = client_hello.server_name() else {
+            return Some(Arc::clone(&loaded.default));
+        };
+
+        let server_name = server_name.to_ascii_lowercase();
+        let wildcard = server_name
+            .split_once('.')
+            .map(|(_, parent)| format!("*.{}", parent));
+        let certified = loaded
+            .hostnames
+            .get(&server_name)
+            .or_else(|| loaded.hostnames.get(wildcard.as_deref()?))
+            .unwrap_or(&loaded.default);
+        Some(Arc::clone(certified))
+    }
+}
+
+/**
+ * A listener that terminates TLS, then serves HTTP/2 or HTTP/1.1 depending
+ * on what ALPN settled on. Clients that don't use ALPN get HTTP/1.1.
+ */
+pub struct TlsServer {
+    listener: TcpListener,
+    options: SocketOptions,
+    config: Arc<ServerConfig>,
+    certificates: Arc<Certificates>,
+}
+
+impl TlsServer {
+    /** Listens on `address`, with its SocketOptions, as BindAddress::bind does */
+    pub fn bind(address: impl Into<BindAddress>, config: TlsConfig) -> Result<Self, Error> {
+        let address = address.into();
+        let (mut server_config, certificates) = config.server_config(DEFAULT_VERSIONS)?;
+        server_config.alpn_protocols = ALPN_PROTOCOLS.map(<[u8]>::to_vec).to_vec();
+
+        let listener = match address.bind()? {
+            Listener::Tcp(listener) => listener,
+            #[cfg(unix)]
+            Listener::Unix(_) => unreachable!("Bind addresses are TCP"),
+        };
+        Ok(TlsServer {
+            listener,
+            options: address.socket_options(),
+            config: Arc::new(server_config),
+            certificates,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.listener.local_addr()
+    }
+
+    /** The certificates being served, e.g. to reload() them on a signal */
+    pub fn certificates(&self) -> Arc<Certificates> {
+        Arc::clone(&self.certificates)
+    }
+
+    pub fn serve<H: Handler>(self, handler: H) {
+        self.serve_until_shutdown(handler, &ServerHandle::new());
+    }
+
+    /**
+     * Serves until `handle` is shut down, then waits for open connections as
+     * Listener::serve_until_shutdown does
+     */
+    pub fn serve_until_shutdown<H: Handler>(self, handler: H, handle: &ServerHandle) {
+        let config = self.config;
+        Listener::from(self.listener).accept_until_shutdown(
+            handle,
+            &self.options,
+            move |stream, tracked| {
+                let stream = match stream {
+                    Stream::Tcp(stream) => stream,
+                    #[cfg(unix)]
+                    Stream::Unix(_) => unreachable!("TCP listeners accept TCP connections"),
+                };
+                handle_connection(stream, Arc::clone(&config), &handler, tracked)
+            },
+        );
+    }
+}
+
+pub fn start_tls_server<H: Handler>(port: u16, config: TlsConfig, handler: H) {
+    let handle = ServerHandle::new();
+    #[cfg(unix)]
+    if let Err(err) = handle.shutdown_on_signals(DEFAULT_GRACE_PERIOD) {
+        eprintln!("Failed to handle shutdown signals: {}", err);
+    }
+
+    let server = TlsServer::bind(SocketAddr::from(([127, 0, 0, 1], port)), config)
+        .unwrap_or_else(|err| panic!("Unable to listen for TLS on localhost:{port}: {err}"));
+    server.serve_until_shutdown(handler, &handle);
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    config: Arc<ServerConfig>,
+    handler: &dyn Handler,
+    tracked: Option<&Tracked>,
+) {
+    let peer_addr = stream.peer_addr().ok();
+    let (reader, writer) = match accept(stream, config) {
+        Ok(halves) => halves,
+        Err(err) => {
+            eprintln!("TLS handshake failed: {}", err);
+            return;
+        }
+    };
+
+    let (is_http2, client_certificate) = {
+        let connection = reader.shared.connection.lock().unwrap();
+        (
+            connection.alpn_protocol() == Some(b"h2"),
+            client_certificate(connection.peer_certificates()),
+        )
+    };
+    if is_http2 {
+        http2::serve_stream(
+            &mut BufReader::new(reader),
+            Box::new(writer),
+            peer_addr,
+            client_certificate,
+            handler,
+            tracked,
+        );
+        return;
+    }
+
+    // HTTP/1.1 reads and writes on the one thread, so the halves go back together
+    let mut reader = BufReader::new(TlsStream { reader, writer });
+    let Some(mut request) = read_request(&mut reader) else {
+        // After a 400, so the client knows it has all of it
+        reader.get_mut().writer.close();
+        return;
+    };
+    request.peer_addr = peer_addr;
+    request.client_certificate = client_certificate;
+    request.tls = true;
+
+    let mut response = handler.handle(&request);
+    // Upgraded connections are handed a TcpStream, which would skip the TLS layer
+    if response.on_upgrade.is_some() {
+        response = Response::error(
+            StatusCode::NOT_IMPLEMENTED,
+            "Upgrades aren't supported over TLS",
+        );
+    }
+
+    let stream = reader.get_mut();
+    if let Err(err) = write_response(stream, &mut response, tracked) {
+        eprintln!("Failed to write response: {}", err);
+        return;
+    }
+    stream.writer.close();
+}
+
+/** The verified certificate the client sent, if it sent one */
+pub(super) fn client_certificate(
+    peer_certificates: Option<&[CertificateDer<'_>]>,
+) -> Option<ClientCertificate> {
+    let der = peer_certificates?.first()?;
+    ClientCertificate::from_der(der)
+        .inspect_err(|err| eprintln!("Unable to read client certificate: {}", err))
+        .ok()
+}
+
+/** Completes the handshake, then splits the connection into its two directions */
+fn accept(
+    mut stream: TcpStream,
+    config: Arc<ServerConfig>,
+) -> Result<(TlsReader, TlsWriter), Error> {
+    let mut connection = ServerConnection::new(config).map_err(invalid_tls_config)?;
+    while connection.is_handshaking() {
+        connection.complete_io(&mut stream)?;
+    }
+
+    let shared = Arc::new(Shared {
+        connection: Mutex::new(connection),
+        socket: stream,
+    });
+    Ok((
+        TlsReader {
+            shared: Arc::clone(&shared),
+        },
+        TlsWriter { shared },
+    ))
+}
+
+/**
+ * A TLS connection whose reading and writing happen on different threads,
+ * as HTTP/2's do. The socket is only read without the lock held, so a
+ * blocked read doesn't stop responses from being written.
+ */
+struct Shared {
+    connection: Mutex<ServerConnection>,
+    socket: TcpStream,
+}
+
+impl Shared {
+    /** Sends whatever TLS records the connection has ready, with the lock held */
+    fn write_tls(&self, connection: &mut ServerConnection) -> Result<(), Error> {
+        while connection.wants_write() {
+            connection.write_tls(&mut &self.socket)?;
+        }
+        Ok(())
+    }
+}
+
+struct TlsReader {
+    shared: Arc<Shared>,
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut incoming = [0; 16 * 1024];
+        loop {
+            match self.shared.connection.lock().unwrap().reader().read(buf) {
+                // Either data, or 0 once the peer's close_notify has arrived
+                Ok(length) => return Ok(length),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+
+            let length = (&self.shared.socket).read(&mut incoming)?;
+            if length == 0 {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "Connection closed without close_notify",
+                ));
+            }
+
+            let mut connection = self.shared.connection.lock().unwrap();
+            let mut received = &incoming[..length];
+            while !received.is_empty() {
+                connection.read_tls(&mut received)?;
+                let processed = connection.process_new_packets();
+                // Either an alert for the error, or a reply such as a key update
+                self.shared.write_tls(&mut connection)?;
+                processed.map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+            }
+        }
+    }
+}
+
+struct TlsWriter {
+    shared: Arc<Shared>,
+}
+
+/** Both halves of a connection, for HTTP/1.1 */
+struct TlsStream {
+    reader: TlsReader,
+    writer: TlsWriter,
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.reader.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush()
+    }
+}
+
+impl TlsWriter {
+    /** Sends close_notify, so the client knows the response wasn't truncated */
+    fn close(&mut self) {
+        let mut connection = self.shared.connection.lock().unwrap();
+        connection.send_close_notify();
+        let _ = self.shared.write_tls(&mut connection);
+    }
+}
+
+impl Write for TlsWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let mut connection = self.shared.connection.lock().unwrap();
+        loop {
+            let length = connection.writer().write(buf)?;
+            self.shared.write_tls(&mut connection)?;
+            // Nothing is written only while the outgoing buffer is full, which it no longer is
+            if length > 0 || buf.is_empty() {
+                return Ok(length);
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        let mut connection = self.shared.connection.lock().unwrap();
+        connection.writer().flush()?;
+        self.shared.write_tls(&mut connection)
+    }
+}
+
+pub(super) fn invalid_tls_config(err: impl std::error::Error + Send + Sync + 'static) -> Error {
+    Error::new(ErrorKind::InvalidInput, err)
+}
+
+pub(super) fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("No certificates in {}", path.display()),
+        ));
+    }
+    Ok(certs)
+}
+
+pub(super) fn load_private_key(path: &Path) -> Result<PrivateKeyDer<'static>, Error> {
+    rustls_pemfile::private_key(&mut BufReader::new(File::open(path)?))?.ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("No private key in {}", path.display()),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use rcgen::{
+        BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, StreamOwned};
+
+    use super::{
+        super::{hpack::Encoder, request::Request},
+        *,
+    };
+
+    /** Writes a fresh self-signed certificate for `hostnames`, returning its paths and DER */
+    fn certificate(hostnames: &[&str]) -> (PathBuf, PathBuf, CertificateDer<'static>) {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(
+            "http_server_tls_{}_{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        );
+        let cert_path = std::env::temp_dir().join(format!("{}.crt", name));
+        let key_path = std::env::temp_dir().join(format!("{}.key", name));
+        let der = write_certificate(hostnames, &cert_path, &key_path);
+        (cert_path, key_path, der)
+    }
+
+    fn write_certificate(
+        hostnames: &[&str],
+        cert_path: &Path,
+        key_path: &Path,
+    ) -> CertificateDer<'static> {
+        let hostnames = hostnames
+            .iter()
+            .map(|hostname| hostname.to_string())
+            .collect::<Vec<_>>();
+        let certified = rcgen::generate_simple_self_signed(hostnames).unwrap();
+        std::fs::write(cert_path, certified.cert.pem()).unwrap();
+        std::fs::write(key_path, certified.key_pair.serialize_pem()).unwrap();
+        certified.cert.der().clone()
+    }
+
+    fn start<H: Handler>(config: TlsConfig, handler: H) -> (SocketAddr, Arc<Certificates>) {
+        let server = TlsServer::bind(SocketAddr::from(([127, 0, 0, 1], 0)), config).unwrap();
+        let addr = server.local_addr().unwrap();
+        let certificates = server.certificates();
+        thread::spawn(move || server.serve(handler));
+        (addr, certificates)
+    }
+
+    /** Completes a handshake, trusting only `roots` */
+    fn connect(
+        addr: SocketAddr,
+        server_name: &str,
+        roots: &[&CertificateDer<'static>],
+        alpn_protocols: &[&[u8]],
+    ) -> StreamOwned<ClientConnection, TcpStream> {
+        connect_as(addr, server_name, roots, alpn_protocols, None)
+    }
+
+    /** Like connect(), authenticating with `identity` if there is one */
+    fn connect_as(
+        addr: SocketAddr,
+        server_name: &str,
+        roots: &[&CertificateDer<'static>],
+        alpn_protocols: &[&[u8]],
+        identity: Option<ClientIdentity>,
+    ) -> StreamOwned<ClientConnection, TcpStream> {
+        let mut root_store = RootCertStore::empty();
+        for root in roots {
+            root_store.add((*root).clone()).unwrap();
+        }
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(root_store);
+        let mut config = match identity {
+            Some((cert, key)) => config.with_client_auth_cert(vec![cert], key).unwrap(),
+            None => config.with_no_client_auth(),
+        };
+        config.alpn_protocols = alpn_protocols
+            .iter()
+            .map(|protocol| protocol.to_vec())
+            .collect();
+
+        let server_name = ServerName::try_from(server_name.to_string()).unwrap();
+        let connection = ClientConnection::new(Arc::new(config), server_name).unwrap();
+        let mut stream = StreamOwned::new(connection, TcpStream::connect(addr).unwrap());
+        while stream.conn.is_handshaking() {
+            stream.conn.complete_io(&mut stream.sock).unwrap();
+        }
+        stream
+    }
+
+    type ClientIdentity = (CertificateDer<'static>, PrivateKeyDer<'static>);
+
+    /** Writes a fresh CA certificate to a PEM file, for client certificates to be issued by */
+    fn client_ca() -> (PathBuf, rcgen::Certificate, KeyPair) {
+        let mut params = CertificateParams::default();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let key = KeyPair::generate().unwrap();
+        let cert = params.self_signed(&key).unwrap();
+
+        let (ca_path, _, _) = certificate(&[]);
+        std::fs::write(&ca_path, cert.pem()).unwrap();
+        (ca_path, cert, key)
+    }
+
+    fn client_identity(
+        common_name: &str,
+        ca: &rcgen::Certificate,
+        ca_key: &KeyPair,
+    ) -> ClientIdentity {
+        let mut params = CertificateParams::default();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        let key = KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, ca, ca_key).unwrap();
+        (
+            cert.der().clone(),
+            PrivateKeyDer::Pkcs8(key.serialize_der().into()),
+        )
+    }
+
+    /** Sends a GET over HTTP/1.1 and returns the response */
+    fn get(mut stream: StreamOwned<ClientConnection, TcpStream>) -> Result<String, Error> {
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    fn subject(request: &Request) -> Response {
+        let mut response = Response::new();
+        response.body = match &request.client_certificate {
+            Some(certificate) => certificate.subject.clone().into_bytes(),
+            None => b"anonymous".to_vec(),
+        };
+        response
+    }
+
+    fn served_certificate(
+        stream: &StreamOwned<ClientConnection, TcpStream>,
+    ) -> CertificateDer<'static> {
+        stream.conn.peer_certificates().unwrap()[0].clone()
+    }
+
+    fn hello(request: &Request) -> Response {
+        let mut response = Response::new();
+        response.body = format!("Hello over {}", request.http_version.as_str()).into_bytes();
+        response
+    }
+
+    #[test]
+    fn alpn_picks_http2_or_http1() {
+        let (cert_path, key_path, cert) = certificate(&["localhost"]);
+        let (addr, _) = start(TlsConfig::new(cert_path, key_path), hello);
+
+        for alpn_protocols in [&[&b"http/1.1"[..]][..], &[]] {
+            let mut stream = connect(addr, "localhost", &[&cert], alpn_protocols);
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            let mut response = String::new();
+            // Ends cleanly because the server sends close_notify
+            stream.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+            assert!(response.ends_with("Hello over HTTP/1.1"));
+        }
+
+        let mut stream = connect(addr, "localhost", &[&cert], &ALPN_PROTOCOLS);
+        assert_eq!(Some(&b"h2"[..]), stream.conn.alpn_protocol());
+        stream.write_all(http2::PREFACE).unwrap();
+        stream.write_all(&[0, 0, 0, 0x4, 0, 0, 0, 0, 0]).unwrap();
+        let mut frame_header = [0; 9];
+        stream.read_exact(&mut frame_header).unwrap();
+        // The server's SETTINGS
+        assert_eq!(0x4, frame_header[3]);
+    }
+
+    #[test]
+    fn sni_picks_the_certificate() {
+        let (default_cert_path, default_key_path, default) =
+            certificate(&["localhost", "other.test"]);
+        let (example_cert_path, example_key_path, example) = certificate(&["example.test"]);
+        let (wildcard_cert_path, wildcard_key_path, wildcard) = certificate(&["*.wild.test"]);
+        let config = TlsConfig::new(default_cert_path, default_key_path)
+            .hostname("Example.test", example_cert_path, example_key_path)
+            .hostname("*.wild.test", wildcard_cert_path, wildcard_key_path);
+        let (addr, _) = start(config, hello);
+
+        let roots = [&default, &example, &wildcard];
+        for (server_name, expected) in [
+            ("example.test", &example),
+            ("sub.wild.test", &wildcard),
+            ("other.test", &default),
+            ("localhost", &default),
+        ] {
+            let stream = connect(addr, server_name, &roots, &[]);
+            assert_eq!(expected, &served_certificate(&stream), "{}", server_name);
+        }
+    }
+
+    #[test]
+    fn certificates_reload_without_a_restart() {
+        let (cert_path, key_path, first) = certificate(&["localhost"]);
+        let (addr, certificates) = start(TlsConfig::new(&cert_path, &key_path), hello);
+
+        let second = write_certificate(&["localhost"], &cert_path, &key_path);
+        let stream = connect(addr, "localhost", &[&first, &second], &[]);
+        assert_eq!(first, served_certificate(&stream));
+
+        certificates.reload().unwrap();
+        let stream = connect(addr, "localhost", &[&first, &second], &[]);
+        assert_eq!(second, served_certificate(&stream));
+
+        // A broken file leaves the last good certificate in place
+        std::fs::write(&key_path, "not a key").unwrap();
+        assert!(certificates.reload().is_err());
+        let stream = connect(addr, "localhost", &[&first, &second], &[]);
+        assert_eq!(second, served_certificate(&stream));
+    }
+
+    #[test]
+    fn client_certificates_are_required() {
+        let (cert_path, key_path, cert) = certificate(&["localhost"]);
+        let (ca_path, ca, ca_key) = client_ca();
+        let config = TlsConfig::new(cert_path, key_path).require_client_certificates(ca_path);
+        let (addr, _) = start(config, subject);
+
+        let identity = client_identity("billing", &ca, &ca_key);
+        let stream = connect_as(addr, "localhost", &[&cert], &[], Some(identity));
+        assert!(get(stream).unwrap().ends_with("\r\n\r\nCN=billing"));
+
+        // The server's alert only arrives after the client thinks it's done
+        let stream = connect(addr, "localhost", &[&cert], &[]);
+        assert!(get(stream).is_err());
+        let (other_ca_path, other_ca, other_ca_key) = client_ca();
+        std::fs::remove_file(other_ca_path).unwrap();
+        let identity = client_identity("billing", &other_ca, &other_ca_key);
+        let stream = connect_as(addr, "localhost", &[&cert], &[], Some(identity));
+        assert!(get(stream).is_err());
+    }
+
+    #[test]
+    fn client_certificates_can_be_optional() {
+        let (cert_path, key_path, cert) = certificate(&["localhost"]);
+        let (ca_path, ca, ca_key) = client_ca();
+        let config = TlsConfig::new(cert_path, key_path).request_client_certificates(ca_path);
+        let (addr, _) = start(config, subject);
+
+        let stream = connect(addr, "localhost", &[&cert], &[]);
+        assert!(get(stream).unwrap().ends_with("\r\n\r\nanonymous"));
+
+        // Every request on an HTTP/2 connection gets the certificate
+        let identity = client_identity("reports", &ca, &ca_key);
+        let mut stream = connect_as(addr, "localhost", &[&cert], &ALPN_PROTOCOLS, Some(identity));
+        stream.write_all(http2::PREFACE).unwrap();
+        stream.write_all(&[0, 0, 0, 0x4, 0, 0, 0, 0, 0]).unwrap();
+        let mut encoder = Encoder::new();
+        for stream_id in [1u32, 3] {
+            let block = encoder.encode(&[
+                (":method".to_string(), "GET".to_string()),
+                (":scheme".to_string(), "https".to_string()),
+                (":path".to_string(), "/".to_string()),
+                (":authority".to_string(), "localhost".to_string()),
+            ]);
+            let mut frame = (block.len() as u32).to_be_bytes()[1..].to_vec();
+            frame.extend([0x1, 0x4 | 0x1]);
+            frame.extend(stream_id.to_be_bytes());
+            frame.extend(block);
+            stream.write_all(&frame).unwrap();
+        }
+
+        let mut bodies = vec![];
+        while bodies.len() < 2 {
+            let mut header = [0; 9];
+            stream.read_exact(&mut header).unwrap();
+            let mut payload =
+                vec![0; u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize];
+            stream.read_exact(&mut payload).unwrap();
+            if header[3] == 0x0 && !payload.is_empty() {
+                bodies.push(String::from_utf8(payload).unwrap());
+            }
+        }
+        assert_eq!(vec!["CN=reports", "CN=reports"], bodies);
+    }
+
+    #[test]
+    fn changed_files_are_reloaded() {
+        let (cert_path, key_path, first) = certificate(&["localhost"]);
+        let config =
+            TlsConfig::new(&cert_path, &key_path).reload_interval(Duration::from_millis(10));
+        let (addr, _) = start(config, hello);
+
+        thread::sleep(Duration::from_millis(50));
+        let second = write_certificate(&["localhost"], &cert_path, &key_path);
+
+        let mut served = first.clone();
+        for _ in 0..100 {
+            served = served_certificate(&connect(addr, "localhost", &[&first, &second], &[]));
+            if served == second {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(second, served);
+    }
+
+    #[test]
+    fn malformed_requests_get_400() {
+        let (cert_path, key_path, cert) = certificate(&["localhost"]);
+        let (addr, _) = start(TlsConfig::new(cert_path, key_path), hello);
+
+        let mut stream = connect(addr, "localhost", &[&cert], &[]);
+        stream.write_all(b"GET /\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[test]
+    fn listening_socket_gets_the_options() {
+        let (cert_path, key_path, _) = certificate(&["localhost"]);
+        let address = BindAddress::new(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .options(SocketOptions::new().recv_buffer_size(256 * 1024));
+        let server = TlsServer::bind(address, TlsConfig::new(cert_path, key_path)).unwrap();
+        let socket = socket2::SockRef::from(&server.listener);
+        assert!(socket.recv_buffer_size().unwrap() >= 256 * 1024);
+    }
+
+    #[test]
+    fn shutdown_waits_for_requests_in_flight() {
+        let (cert_path, key_path, cert) = certificate(&["localhost"]);
+        let server = TlsServer::bind(
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            TlsConfig::new(cert_path, key_path),
+        )
+        .unwrap();
+        let addr = server.local_addr().unwrap();
+        let (started, handling) = std::sync::mpsc::channel();
+        let (finish, finishing) = std::sync::mpsc::channel::<()>();
+        let finishing = Mutex::new(finishing);
+        let handle = ServerHandle::new();
+        let serving = handle.clone();
+        let served = thread::spawn(move || {
+            server.serve_until_shutdown(
+                move |request: &Request| {
+                    started.send(()).unwrap();
+                    finishing.lock().unwrap().recv().unwrap();
+                    hello(request)
+                },
+                &serving,
+            )
+        });
+
+        let mut stream = connect(addr, "localhost", &[&cert], &[]);
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        handling.recv().unwrap();
+
+        let shutting_down = handle.clone();
+        let shutdown = thread::spawn(move || shutting_down.shutdown(Duration::from_secs(10)));
+        while !handle.is_shutting_down() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        thread::sleep(Duration::from_millis(50));
+        assert!(!shutdown.is_finished());
+
+        finish.send(()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("Hello over HTTP/1.1"), "{}", response);
+        assert!(response.contains("Connection: close\r\n"), "{}", response);
+        shutdown.join().unwrap();
+        served.join().unwrap();
+        assert!(TcpStream::connect(addr).is_err());
+    }
+}
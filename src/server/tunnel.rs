@@ -0,0 +1,232 @@
+use std::{
+    io::{copy, Write},
+    net::{Shutdown, TcpStream, ToSocketAddrs},
+    thread,
+    time::Duration,
+};
+
+use super::{
+    handler::Handler,
+    method::Method,
+    request::Request,
+    response::Response,
+    status_code::StatusCode,
+    upgrade::{self, Upgraded},
+};
+
+/**
+ * Handles CONNECT requests by opening a TCP connection to the requested
+ * host:port and relaying bytes both ways, so the server can act as an HTTPS
+ * forward proxy. Only destinations on the allowlist may be reached.
+ */
+pub struct Tunnel {
+    allowlist: Vec<(String, u16)>,
+    connect_timeout: Duration,
+}
+
+impl Default for Tunnel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tunnel {
+    pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+    pub fn new() -> Self {
+        Tunnel {
+            allowlist: vec![],
+            connect_timeout: Self::DEFAULT_CONNECT_TIMEOUT,
+        }
+    }
+
+    pub fn allow(mut self, host: &str, port: u16) -> Self {
+        self.allowlist.push((host.to_lowercase(), port));
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    fn is_allowed(&self, host: &str, port: u16) -> bool {
+        self.allowlist
+            .iter()
+            .any(|(allowed_host, allowed_port)| allowed_host == host && *allowed_port == port)
+    }
+
+    fn connect(&self, host: &str, port: u16) -> Option<TcpStream> {
+        let addrs = (host, port).to_socket_addrs().ok()?;
+        addrs
+            .into_iter()
+            .find_map(|addr| TcpStream::connect_timeout(&addr, self.connect_timeout).ok())
+    }
+}
+
+impl Handler for Tunnel {
+    fn handle(&self, request: &Request) -> Response {
+        if request.method != Method::CONNECT {
+            return Response::error(StatusCode::METHOD_NOT_ALLOWED, "Only CONNECT is supported");
+        }
+
+        let (host, port) = match parse_authority(&request.raw_target) {
+            Some(authority) => authority,
+            None => {
+                return Response::error(
+                    StatusCode::BAD_REQUEST,
+                    "CONNECT target must be in host:port form",
+                )
+            }
+        };
+
+        if !self.is_allowed(&host, port) {
+            return Response::error(StatusCode::FORBIDDEN, "Destination is not allowed");
+        }
+
+        let upstream = match self.connect(&host, port) {
+            Some(upstream) => upstream,
+            None => return Response::error(StatusCode::BAD_GATEWAY, "Unable to reach destination"),
+        };
+
+        upgrade::hijack(Response::new(), move |client| relay(client, upstream))
+    }
+}
+
+/** Splits an authority-form target (`host:port` or `[v6addr]:port`) */
+pub fn parse_authority(target: &str) -> Option<(String, u16)> {
+    let (host, port) = target.rsplit_once(':')?;
+    let port = port.parse().ok()?;
+
+    let host = match host.strip_prefix('[') {
+        Some(bracketed) => bracketed.strip_suffix(']')?,
+        None if host.contains(':') => return None,
+        None => host,
+    };
+
+    if host.is_empty() || host.contains(['/', '@', '[', ']']) {
+        return None;
+    }
+
+    Some((host.to_lowercase(), port))
+}
+
+/** Copies bytes in both directions until each side has finished sending */
+fn relay(mut client: Upgraded, mut upstream: TcpStream) {
+    let (mut client_writer, mut upstream_reader) =
+        match (client.stream.try_clone(), upstream.try_clone()) {
+            (Ok(client_writer), Ok(upstream_reader)) => (client_writer, upstream_reader),
+            _ => return,
+        };
+
+    let downstream = thread::spawn(move || {
+        let _ = copy(&mut upstream_reader, &mut client_writer);
+        let _ = client_writer.flush();
+        let _ = client_writer.shutdown(Shutdown::Write);
+    });
+
+    let _ = copy(&mut client, &mut upstream);
+    let _ = upstream.shutdown(Shutdown::Write);
+
+    let _ = downstream.join();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::server::handle_connection;
+
+    fn connect_request(target: &str) -> Request {
+        let raw_request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target);
+        Request::from_stream(&mut raw_request.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn authority_form() {
+        assert_eq!(
+            Some(("example.com".to_string(), 443)),
+            parse_authority("Example.com:443")
+        );
+        assert_eq!(
+            Some(("::1".to_string(), 8443)),
+            parse_authority("[::1]:8443")
+        );
+        assert_eq!(None, parse_authority("example.com"));
+        assert_eq!(None, parse_authority("example.com:https"));
+        assert_eq!(None, parse_authority("::1:443"));
+        assert_eq!(None, parse_authority("/index.html"));
+        assert_eq!(None, parse_authority("user@example.com:443"));
+    }
+
+    #[test]
+    fn destinations_off_the_allowlist_are_forbidden() {
+        let tunnel = Tunnel::new().allow("localhost", 443);
+
+        let response = tunnel.handle(&connect_request("localhost:8443"));
+
+        assert_eq!(StatusCode::FORBIDDEN, response.status_code);
+        assert!(response.on_upgrade.is_none());
+    }
+
+    #[test]
+    fn unreachable_destination_is_bad_gateway() {
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let tunnel = Tunnel::new().allow("127.0.0.1", port);
+
+        let response = tunnel.handle(&connect_request(&format!("127.0.0.1:{}", port)));
+
+        assert_eq!(StatusCode::BAD_GATEWAY, response.status_code);
+    }
+
+    #[test]
+    fn relays_bytes_both_ways() {
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_port = upstream.local_addr().unwrap().port();
+        let upstream_server = thread::spawn(move || {
+            let (mut stream, _) = upstream.accept().unwrap();
+            let mut received = vec![];
+            stream.read_to_end(&mut received).unwrap();
+            stream.write_all(b"pong:").unwrap();
+            stream.write_all(&received).unwrap();
+        });
+
+        let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        let proxy_server = thread::spawn(move || {
+            let (stream, _) = proxy.accept().unwrap();
+            handle_connection(
+                stream.into(),
+                &Tunnel::new().allow("127.0.0.1", upstream_port),
+                None,
+            );
+        });
+
+        let mut client = TcpStream::connect(proxy_addr).unwrap();
+        client
+            .write_all(
+                format!("CONNECT 127.0.0.1:{} HTTP/1.1\r\n\r\nping", upstream_port).as_bytes(),
+            )
+            .unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+
+        let mut reader = BufReader::new(client);
+        let mut status_line = String::new();
+        reader.read_line(&mut status_line).unwrap();
+        assert_eq!("HTTP/1.1 200 OK\r\n", status_line);
+
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!("\r\npong:ping", rest);
+
+        upstream_server.join().unwrap();
+        proxy_server.join().unwrap();
+    }
+}
@@ -0,0 +1,142 @@
+use std::io::{Error, Read, Write};
+
+use super::{listener::Stream, response::Response, status_code::StatusCode};
+
+/** Takes over a connection once the response head has been written */
+pub type OnUpgrade = Box<dyn FnOnce(Upgraded) + Send>;
+
+/**
+ * A connection handed over after an upgrade or hijack. `buffered` holds bytes
+ * the request reader had already pulled off the socket past the end of the
+ * request; reading from an Upgraded yields those before reading the socket.
+ */
+pub struct Upgraded {
+    pub stream: Stream,
+    pub buffered: Vec<u8>,
+}
+
+impl Read for Upgraded {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if self.buffered.is_empty() {
+            return self.stream.read(buf);
+        }
+
+        let read = self.buffered.as_slice().read(buf)?;
+        self.buffered.drain(..read);
+        Ok(read)
+    }
+}
+
+impl Write for Upgraded {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.stream.flush()
+    }
+}
+
+/**
+ * A 101 Switching Protocols response to an `Upgrade:` request. After it is
+ * written the connection is passed to `on_upgrade` to speak `protocol`.
+ */
+pub fn switching_protocols<F>(protocol: &str, on_upgrade: F) -> Response
+where
+    F: FnOnce(Upgraded) + Send + 'static,
+{
+    let mut response = Response::new();
+    response.status_code = StatusCode::SWITCHING_PROTOCOLS;
+    response
+        .headers
+        .insert("Connection".to_string(), vec!["Upgrade".to_string()]);
+    response
+        .headers
+        .insert("Upgrade".to_string(), vec![protocol.to_string()]);
+    response.on_upgrade = Some(Box::new(on_upgrade));
+    response
+}
+
+/** Takes over the connection after `response` is written, whatever its status */
+pub fn hijack<F>(mut response: Response, on_upgrade: F) -> Response
+where
+    F: FnOnce(Upgraded) + Send + 'static,
+{
+    response.on_upgrade = Some(Box::new(on_upgrade));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    use super::*;
+    use crate::server::{handle_connection, request::Request};
+
+    #[test]
+    fn upgraded_connection_sees_bytes_buffered_with_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(
+                stream.into(),
+                &|_: &Request| {
+                    switching_protocols("echo", |mut upgraded| {
+                        let mut buf = [0; 64];
+                        loop {
+                            let read = upgraded.read(&mut buf).unwrap();
+                            if read == 0 {
+                                break;
+                            }
+                            upgraded.write_all(&buf[..read]).unwrap();
+                        }
+                    })
+                },
+                None,
+            );
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        // Bytes for the new protocol arrive in the same write as the request
+        client
+            .write_all(
+                "GET /chat HTTP/1.1\r\n\
+                Connection: Upgrade\r\n\
+                Upgrade: echo\r\n\
+                \r\n\
+                early"
+                    .as_bytes(),
+            )
+            .unwrap();
+
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        let mut status_line = String::new();
+        reader.read_line(&mut status_line).unwrap();
+        assert_eq!("HTTP/1.1 101 Switching Protocols\r\n", status_line);
+
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+        }
+
+        let mut echoed = [0; 5];
+        reader.read_exact(&mut echoed).unwrap();
+        assert_eq!(b"early", &echoed);
+
+        client.write_all(b"later").unwrap();
+        reader.read_exact(&mut echoed).unwrap();
+        assert_eq!(b"later", &echoed);
+
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        server.join().unwrap();
+    }
+}
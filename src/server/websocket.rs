@@ -0,0 +1,1048 @@
+use std::io::{BufReader, Error, ErrorKind, Read, Write};
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+use super::{
+    method::Method,
+    request::Request,
+    response::{read_body, Response},
+    status_code::StatusCode,
+    upgrade::{switching_protocols, Upgraded},
+};
+
+/**
+ * Appended to the client's key before hashing it for Sec-WebSocket-Accept
+ * (RFC 6455 section 1.3)
+ */
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+pub const NORMAL_CLOSURE: u16 = 1000;
+pub const PROTOCOL_ERROR: u16 = 1002;
+pub const INVALID_PAYLOAD: u16 = 1007;
+pub const MESSAGE_TOO_BIG: u16 = 1009;
+
+/** The largest message received, after decompression, unless set otherwise */
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/**
+ * What every compressed message ends with once flushed, which is left off
+ * on the wire and put back to decompress it (RFC 7692 section 7.2.1)
+ */
+const DEFLATE_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/**
+ * The server's side of the permessage-deflate extension (RFC 7692), which
+ * compresses each message with DEFLATE. Only clients that offer it use it,
+ * and the parameters they offer are honored alongside these.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Deflate {
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+    server_max_window_bits: u8,
+    client_max_window_bits: Option<u8>,
+}
+
+impl Default for Deflate {
+    fn default() -> Self {
+        Deflate {
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+            server_max_window_bits: 15,
+            client_max_window_bits: None,
+        }
+    }
+}
+
+impl Deflate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /**
+     * Compresses each message on its own rather than referring back to the
+     * ones before, which costs compression but means no window is kept
+     * between messages. Clients can ask for this themselves too.
+     */
+    pub fn server_no_context_takeover(mut self, no_context_takeover: bool) -> Self {
+        self.server_no_context_takeover = no_context_takeover;
+        self
+    }
+
+    /** Asks the client to compress each message on its own */
+    pub fn client_no_context_takeover(mut self, no_context_takeover: bool) -> Self {
+        self.client_no_context_takeover = no_context_takeover;
+        self
+    }
+
+    /**
+     * The base-2 logarithm of the largest window outgoing messages are
+     * compressed with, from 9 to 15. Clients can ask for a smaller one.
+     */
+    pub fn server_max_window_bits(mut self, bits: u8) -> Self {
+        assert!((9..=15).contains(&bits), "Window bits must be from 9 to 15");
+        self.server_max_window_bits = bits;
+        self
+    }
+
+    /**
+     * Asks clients that can limit their window to compress with at most
+     * 2^`bits` bytes, from 8 to 15
+     */
+    pub fn client_max_window_bits(mut self, bits: u8) -> Self {
+        assert!((8..=15).contains(&bits), "Window bits must be from 8 to 15");
+        self.client_max_window_bits = Some(bits);
+        self
+    }
+
+    /** The parameters agreed for the first of the client's offers that can be accepted */
+    fn negotiate(&self, offers: &[Extension]) -> Option<Agreed> {
+        offers
+            .iter()
+            .filter(|offer| offer.name == "permessage-deflate")
+            .find_map(|offer| self.accept(offer))
+    }
+
+    fn accept(&self, offer: &Extension) -> Option<Agreed> {
+        let mut server_no_context_takeover = false;
+        let mut client_no_context_takeover = false;
+        let mut server_max_window_bits = None;
+        let mut client_max_window_bits = None;
+
+        for (i, (name, value)) in offer.params.iter().enumerate() {
+            // Each parameter can be given only once
+            if offer.params[..i].iter().any(|(other, _)| other == name) {
+                return None;
+            }
+            match (name.as_str(), value) {
+                ("server_no_context_takeover", None) => server_no_context_takeover = true,
+                ("client_no_context_takeover", None) => client_no_context_takeover = true,
+                ("server_max_window_bits", Some(value)) => {
+                    server_max_window_bits = Some(window_bits(value)?);
+                }
+                ("client_max_window_bits", None) => client_max_window_bits = Some(15),
+                ("client_max_window_bits", Some(value)) => {
+                    client_max_window_bits = Some(window_bits(value)?);
+                }
+                _ => return None,
+            }
+        }
+
+        let server_bits = self
+            .server_max_window_bits
+            .min(server_max_window_bits.unwrap_or(15));
+        // zlib can't compress with a 256-byte window, so a client that needs one is declined
+        if server_bits < 9 {
+            return None;
+        }
+        Some(Agreed {
+            server_no_context_takeover: self.server_no_context_takeover
+                || server_no_context_takeover,
+            client_no_context_takeover: self.client_no_context_takeover
+                || client_no_context_takeover,
+            // Once asked for, it has to be answered, with the same size or smaller
+            server_max_window_bits: (server_max_window_bits.is_some() || server_bits < 15)
+                .then_some(server_bits),
+            // Only clients that say they can limit their window may be asked to
+            client_max_window_bits: client_max_window_bits
+                .zip(self.client_max_window_bits)
+                .map(|(offered, wanted)| offered.min(wanted)),
+        })
+    }
+}
+
+/** A window size parameter's value: digits from 8 to 15, optionally quoted */
+fn window_bits(value: &str) -> Option<u8> {
+    let value = value.trim_matches('"');
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    value
+        .parse::<u8>()
+        .ok()
+        .filter(|bits| (8..=15).contains(bits))
+}
+
+/** The permessage-deflate parameters the server replied with */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Agreed {
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+    server_max_window_bits: Option<u8>,
+    client_max_window_bits: Option<u8>,
+}
+
+impl Agreed {
+    /** The Sec-WebSocket-Extensions value accepting the offer */
+    fn header(&self) -> String {
+        let mut header = "permessage-deflate".to_string();
+        if self.server_no_context_takeover {
+            header.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            header.push_str("; client_no_context_takeover");
+        }
+        if let Some(bits) = self.server_max_window_bits {
+            header.push_str(&format!("; server_max_window_bits={}", bits));
+        }
+        if let Some(bits) = self.client_max_window_bits {
+            header.push_str(&format!("; client_max_window_bits={}", bits));
+        }
+        header
+    }
+}
+
+/** One extension a client offered in Sec-WebSocket-Extensions, with its parameters */
+#[derive(Debug, PartialEq)]
+struct Extension {
+    name: String,
+    params: Vec<(String, Option<String>)>,
+}
+
+/** Every extension offered, in the client's order of preference */
+fn offered_extensions(request: &Request) -> Vec<Extension> {
+    // Header values come split at semicolons, which separate the parameters here
+    let Some(offers) = request
+        .headers
+        .get("sec-websocket-extensions")
+        .map(|values| values.join(";"))
+    else {
+        return vec![];
+    };
+
+    offers
+        .split(',')
+        .filter_map(|extension| {
+            let mut parts = extension.split(';').map(str::trim);
+            let name = parts.next().filter(|name| !name.is_empty())?;
+            let params = parts
+                .map(|param| match param.split_once('=') {
+                    Some((name, value)) => {
+                        (name.trim().to_string(), Some(value.trim().to_string()))
+                    }
+                    None => (param.to_string(), None),
+                })
+                .collect();
+            Some(Extension {
+                name: name.to_string(),
+                params,
+            })
+        })
+        .collect()
+}
+
+/**
+ * Completes a WebSocket handshake (RFC 6455), handing the connection to
+ * `on_open` once the 101 has been written. If `deflate` is set and the
+ * client offers permessage-deflate, messages are compressed and
+ * decompressed without the WebSocket's user seeing it. Requests that
+ * aren't a valid handshake get a 400, or a 426 for another version.
+ */
+pub fn accept<F>(request: &Request, deflate: Option<Deflate>, on_open: F) -> Response
+where
+    F: FnOnce(WebSocket) + Send + 'static,
+{
+    if request.header("sec-websocket-version") != Some("13") {
+        let mut response = Response::error(
+            StatusCode::UPGRADE_REQUIRED,
+            "Only WebSocket version 13 is supported",
+        );
+        response
+            .headers
+            .insert("Sec-WebSocket-Version".to_string(), vec!["13".to_string()]);
+        return response;
+    }
+    let key = match request.header("sec-websocket-key") {
+        Some(key)
+            if request.method == Method::GET
+                && has_token(request, "upgrade", "websocket")
+                && has_token(request, "connection", "upgrade") =>
+        {
+            key.trim()
+        }
+        _ => {
+            return Response::error(StatusCode::BAD_REQUEST, "Not a WebSocket handshake");
+        }
+    };
+
+    let agreed = deflate.and_then(|deflate| deflate.negotiate(&offered_extensions(request)));
+    let mut response = switching_protocols("websocket", move |upgraded| {
+        on_open(WebSocket::new(upgraded, agreed))
+    });
+    response
+        .headers
+        .insert("Sec-WebSocket-Accept".to_string(), vec![accept_key(key)]);
+    if let Some(agreed) = agreed {
+        response.headers.insert(
+            "Sec-WebSocket-Extensions".to_string(),
+            vec![agreed.header()],
+        );
+    }
+    response
+}
+
+fn has_token(request: &Request, header_name: &str, token: &str) -> bool {
+    request
+        .headers
+        .get(header_name)
+        .into_iter()
+        .flatten()
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+/** The Sec-WebSocket-Accept value proving the server read the client's key */
+fn accept_key(key: &str) -> String {
+    encode_base64(&sha1(format!("{}{}", key, ACCEPT_GUID).as_bytes()))
+}
+
+/** SHA-1 (RFC 3174), which the handshake needs for nothing security-related */
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, value) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(value);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (bytes, word) in digest.chunks_exact_mut(4).zip(h) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+/** Padded base64, as Sec-WebSocket-Accept uses */
+fn encode_base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &byte)| {
+            bits | ((byte as u32) << (16 - 8 * i))
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[((bits >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/** A complete message, put back together from however many frames it came in */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+struct Frame {
+    fin: bool,
+    rsv1: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/** The compressor and decompressor for a connection using permessage-deflate */
+struct PerMessageDeflate {
+    compress: Compress,
+    decompress: Decompress,
+    agreed: Agreed,
+}
+
+impl PerMessageDeflate {
+    fn new(agreed: Agreed) -> Self {
+        let window_bits = agreed.server_max_window_bits.unwrap_or(15);
+        PerMessageDeflate {
+            compress: Compress::new_with_window_bits(Compression::default(), false, window_bits),
+            // A window as large as any the client can use decompresses them all
+            decompress: Decompress::new_with_window_bits(false, 15),
+            agreed,
+        }
+    }
+
+    fn compress(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut compressed = Vec::with_capacity(data.len() / 2 + 64);
+        let mut input = data;
+        loop {
+            if compressed.len() == compressed.capacity() {
+                compressed.reserve(compressed.capacity());
+            }
+            let consumed = self.compress.total_in();
+            self.compress
+                .compress_vec(input, &mut compressed, FlushCompress::Sync)
+                .map_err(|err| Error::other(err.to_string()))?;
+            input = &input[(self.compress.total_in() - consumed) as usize..];
+            // With room left over, the flush is complete
+            if input.is_empty() && compressed.len() < compressed.capacity() {
+                break;
+            }
+        }
+
+        if compressed.ends_with(&DEFLATE_TAIL) {
+            compressed.truncate(compressed.len() - DEFLATE_TAIL.len());
+        }
+        if self.agreed.server_no_context_takeover {
+            self.compress.reset();
+        }
+        Ok(compressed)
+    }
+
+    /** Decompresses a message, or None if it would be larger than `max_size` */
+    fn decompress(&mut self, payload: &[u8], max_size: usize) -> Result<Option<Vec<u8>>, Error> {
+        let input = [payload, &DEFLATE_TAIL].concat();
+        let mut offset = 0;
+        let mut decompressed = Vec::with_capacity((payload.len() * 4).min(max_size) + 64);
+        loop {
+            if decompressed.len() == decompressed.capacity() {
+                // One byte past the limit is enough to tell it's been passed
+                let room = decompressed
+                    .capacity()
+                    .min(max_size.saturating_add(1) - decompressed.len());
+                decompressed.reserve(room.max(1));
+            }
+            let (consumed, produced) = (self.decompress.total_in(), self.decompress.total_out());
+            let status = self
+                .decompress
+                .decompress_vec(&input[offset..], &mut decompressed, FlushDecompress::Sync)
+                .map_err(|err| Error::new(ErrorKind::InvalidData, err.to_string()))?;
+            let consumed = (self.decompress.total_in() - consumed) as usize;
+            let produced = (self.decompress.total_out() - produced) as usize;
+            offset += consumed;
+
+            if decompressed.len() > max_size {
+                self.decompress.reset(false);
+                return Ok(None);
+            }
+            // The client ended the DEFLATE stream, so the next message starts a new one
+            if status == Status::StreamEnd {
+                self.decompress.reset(false);
+                break;
+            }
+            let has_room = decompressed.len() < decompressed.capacity();
+            if has_room && (offset == input.len() || consumed + produced == 0) {
+                break;
+            }
+        }
+
+        if self.agreed.client_no_context_takeover {
+            self.decompress.reset(false);
+        }
+        Ok(Some(decompressed))
+    }
+}
+
+/**
+ * An open WebSocket connection. Pings are answered and closes echoed while
+ * receiving, so all the user sees are whole messages.
+ */
+pub struct WebSocket {
+    connection: BufReader<Upgraded>,
+    deflate: Option<PerMessageDeflate>,
+    max_message_size: usize,
+    /** Whether a close frame has been sent, after which only a close is waited for */
+    close_sent: bool,
+    /** Whether the client's close frame has been received */
+    closed: bool,
+}
+
+impl WebSocket {
+    fn new(upgraded: Upgraded, agreed: Option<Agreed>) -> Self {
+        WebSocket {
+            connection: BufReader::new(upgraded),
+            deflate: agreed.map(PerMessageDeflate::new),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            close_sent: false,
+            closed: false,
+        }
+    }
+
+    /** The largest message to accept; larger ones close the connection with 1009 */
+    pub fn max_message_size(mut self, size: usize) -> Self {
+        self.max_message_size = size;
+        self
+    }
+
+    /** Whether permessage-deflate was negotiated */
+    pub fn is_compressed(&self) -> bool {
+        self.deflate.is_some()
+    }
+
+    /**
+     * The next message, or None once the connection has been closed. A client
+     * that breaks the protocol is sent a close frame saying why, and an
+     * InvalidData error is returned.
+     */
+    pub fn receive(&mut self) -> Result<Option<Message>, Error> {
+        let mut message: Option<(u8, bool, Vec<u8>)> = None;
+
+        while !self.closed {
+            let frame = self.read_frame()?;
+            match frame.opcode {
+                PING => {
+                    if !self.close_sent {
+                        self.write_frame(PONG, false, &frame.payload)?;
+                    }
+                    continue;
+                }
+                PONG => continue,
+                CLOSE => {
+                    self.closed = true;
+                    if frame.payload.len() == 1 {
+                        return Err(self.fail(PROTOCOL_ERROR, "Close frame with a 1-byte body"));
+                    }
+                    if !self.close_sent {
+                        let code = frame.payload.get(..2).unwrap_or(&[]).to_vec();
+                        self.write_frame(CLOSE, false, &code)?;
+                        self.close_sent = true;
+                    }
+                    return Ok(None);
+                }
+                CONTINUATION => match message.as_mut() {
+                    Some((_, _, data)) => data.extend_from_slice(&frame.payload),
+                    None => {
+                        return Err(self.fail(PROTOCOL_ERROR, "Continuation of no message"));
+                    }
+                },
+                TEXT | BINARY => {
+                    if message.is_some() {
+                        return Err(self.fail(PROTOCOL_ERROR, "Message interrupted by another"));
+                    }
+                    message = Some((frame.opcode, frame.rsv1, frame.payload));
+                }
+                _ => return Err(self.fail(PROTOCOL_ERROR, "Unknown opcode")),
+            }
+
+            if message
+                .as_ref()
+                .is_some_and(|(_, _, data)| data.len() > self.max_message_size)
+            {
+                return Err(self.fail(MESSAGE_TOO_BIG, "Message too big"));
+            }
+            if !frame.fin {
+                continue;
+            }
+            let Some((opcode, compressed, data)) = message.take() else {
+                continue;
+            };
+            // Messages that arrive after we've closed are dropped
+            if self.close_sent {
+                continue;
+            }
+
+            let data = match (compressed, self.deflate.as_mut()) {
+                (true, Some(deflate)) => match deflate.decompress(&data, self.max_message_size) {
+                    Ok(Some(data)) => data,
+                    Ok(None) => return Err(self.fail(MESSAGE_TOO_BIG, "Message too big")),
+                    Err(_) => return Err(self.fail(INVALID_PAYLOAD, "Invalid compressed data")),
+                },
+                _ => data,
+            };
+            return match opcode {
+                TEXT => match String::from_utf8(data) {
+                    Ok(text) => Ok(Some(Message::Text(text))),
+                    Err(_) => Err(self.fail(INVALID_PAYLOAD, "Text that isn't UTF-8")),
+                },
+                _ => Ok(Some(Message::Binary(data))),
+            };
+        }
+        Ok(None)
+    }
+
+    /** Sends a whole message in one frame, compressed if permessage-deflate was negotiated */
+    pub fn send(&mut self, message: &Message) -> Result<(), Error> {
+        if self.close_sent {
+            return Err(Error::new(
+                ErrorKind::NotConnected,
+                "The WebSocket has been closed",
+            ));
+        }
+        let (opcode, data) = match message {
+            Message::Text(text) => (TEXT, text.as_bytes()),
+            Message::Binary(data) => (BINARY, data.as_slice()),
+        };
+        match self.deflate.as_mut() {
+            Some(deflate) => {
+                let compressed = deflate.compress(data)?;
+                self.write_frame(opcode, true, &compressed)
+            }
+            None => self.write_frame(opcode, false, data),
+        }
+    }
+
+    /**
+     * Starts closing the connection with `code` and `reason`. receive()
+     * returns None once the client has closed its end too.
+     */
+    pub fn close(&mut self, code: u16, reason: &str) -> Result<(), Error> {
+        if self.close_sent {
+            return Ok(());
+        }
+        self.close_sent = true;
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        self.write_frame(CLOSE, false, &payload)
+    }
+
+    /** Closes the connection over a protocol error, returning the error to pass on */
+    fn fail(&mut self, code: u16, reason: &str) -> Error {
+        let _ = self.close(code, reason);
+        self.closed = true;
+        Error::new(ErrorKind::InvalidData, reason)
+    }
+
+    fn read_frame(&mut self) -> Result<Frame, Error> {
+        let mut head = [0; 2];
+        self.connection.read_exact(&mut head)?;
+        let fin = head[0] & 0x80 != 0;
+        let rsv1 = head[0] & 0x40 != 0;
+        let opcode = head[0] & 0x0f;
+        let is_control = opcode & 0x8 != 0;
+        let length = head[1] & 0x7f;
+
+        if head[0] & 0x30 != 0 {
+            return Err(self.fail(PROTOCOL_ERROR, "Reserved bits set"));
+        }
+        // Only the first frame of a data message says it's compressed
+        if rsv1 && (self.deflate.is_none() || is_control || opcode == CONTINUATION) {
+            return Err(self.fail(PROTOCOL_ERROR, "Reserved bits set"));
+        }
+        if head[1] & 0x80 == 0 {
+            return Err(self.fail(PROTOCOL_ERROR, "Client frames must be masked"));
+        }
+        if is_control && (!fin || length > 125) {
+            return Err(self.fail(PROTOCOL_ERROR, "Control frames can't be fragmented"));
+        }
+
+        let length = match length {
+            126 => {
+                let mut length = [0; 2];
+                self.connection.read_exact(&mut length)?;
+                u16::from_be_bytes(length) as u64
+            }
+            127 => {
+                let mut length = [0; 8];
+                self.connection.read_exact(&mut length)?;
+                u64::from_be_bytes(length)
+            }
+            length => length as u64,
+        };
+        if length > self.max_message_size as u64 {
+            return Err(self.fail(MESSAGE_TOO_BIG, "Message too big"));
+        }
+
+        let mut mask = [0; 4];
+        self.connection.read_exact(&mut mask)?;
+        let mut payload = vec![];
+        read_body(&mut self.connection, length as usize, &mut payload)?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+
+        Ok(Frame {
+            fin,
+            rsv1,
+            opcode,
+            payload,
+        })
+    }
+
+    fn write_frame(&mut self, opcode: u8, rsv1: bool, payload: &[u8]) -> Result<(), Error> {
+        let mut frame = vec![0x80 | if rsv1 { 0x40 } else { 0 } | opcode];
+        match payload.len() {
+            length @ 0..=125 => frame.push(length as u8),
+            length @ 126..=0xffff => {
+                frame.push(126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+
+        let stream = self.connection.get_mut();
+        stream.write_all(&frame)?;
+        stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    use super::*;
+    use crate::server::handle_connection;
+
+    fn handshake_text(extensions: Option<&str>) -> String {
+        let mut text = "GET /chat HTTP/1.1\r\n\
+            Host: localhost\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Version: 13\r\n"
+            .to_string();
+        if let Some(extensions) = extensions {
+            text.push_str(&format!("Sec-WebSocket-Extensions: {}\r\n", extensions));
+        }
+        text.push_str("\r\n");
+        text
+    }
+
+    fn handshake(extensions: Option<&str>) -> Request {
+        Request::from_reader(&mut handshake_text(extensions).as_bytes()).unwrap()
+    }
+
+    fn header<'a>(response: &'a Response, name: &str) -> Option<&'a str> {
+        response
+            .headers
+            .get(name)
+            .and_then(|values| values.first())
+            .map(String::as_str)
+    }
+
+    /** Connects to a server that echoes every message, returning the response head */
+    fn start(deflate: Option<Deflate>, extensions: Option<&str>) -> (TcpStream, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(
+                stream.into(),
+                &move |request: &Request| {
+                    accept(request, deflate, |websocket| {
+                        let mut websocket = websocket.max_message_size(64 * 1024);
+                        while let Ok(Some(message)) = websocket.receive() {
+                            websocket.send(&message).unwrap();
+                        }
+                    })
+                },
+                None,
+            );
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(handshake_text(extensions).as_bytes())
+            .unwrap();
+        // A byte at a time, so none of the first frame is read with the head
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0];
+            client.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        (client, String::from_utf8(head).unwrap())
+    }
+
+    fn send_frame(client: &mut TcpStream, first_byte: u8, payload: &[u8]) {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![first_byte];
+        match payload.len() {
+            length @ 0..=125 => frame.push(0x80 | length as u8),
+            length => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        client.write_all(&frame).unwrap();
+    }
+
+    /** Reads one of the server's frames, returning its first byte and its payload */
+    fn read_frame(client: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut head = [0; 2];
+        client.read_exact(&mut head).unwrap();
+        assert_eq!(0, head[1] & 0x80, "Server frames aren't masked");
+        let length = match head[1] {
+            126 => {
+                let mut length = [0; 2];
+                client.read_exact(&mut length).unwrap();
+                u16::from_be_bytes(length) as usize
+            }
+            length => length as usize,
+        };
+        let mut payload = vec![0; length];
+        client.read_exact(&mut payload).unwrap();
+        (head[0], payload)
+    }
+
+    /** The client's end of permessage-deflate with the default parameters */
+    fn client_deflate() -> PerMessageDeflate {
+        PerMessageDeflate::new(Agreed {
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+            server_max_window_bits: None,
+            client_max_window_bits: None,
+        })
+    }
+
+    #[test]
+    fn accept_key_matches_the_rfc_example() {
+        let response = accept(&handshake(None), None, |_| {});
+        assert_eq!(101, response.status_code.0);
+        assert_eq!(
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="),
+            header(&response, "Sec-WebSocket-Accept")
+        );
+        assert_eq!(None, header(&response, "Sec-WebSocket-Extensions"));
+    }
+
+    #[test]
+    fn bad_handshakes_are_refused() {
+        let mut request = handshake(None);
+        request.headers.remove("sec-websocket-key");
+        assert_eq!(400, accept(&request, None, |_| {}).status_code.0);
+
+        let mut request = handshake(None);
+        request
+            .headers
+            .insert("sec-websocket-version".to_string(), vec!["8".to_string()]);
+        let response = accept(&request, None, |_| {});
+        assert_eq!(426, response.status_code.0);
+        assert_eq!(Some("13"), header(&response, "Sec-WebSocket-Version"));
+    }
+
+    #[test]
+    fn offers_are_negotiated() {
+        for (deflate, offer, expected) in [
+            (
+                Deflate::new(),
+                "permessage-deflate",
+                Some("permessage-deflate"),
+            ),
+            (
+                Deflate::new(),
+                "permessage-deflate; client_max_window_bits",
+                Some("permessage-deflate"),
+            ),
+            (
+                Deflate::new().client_max_window_bits(10),
+                "permessage-deflate; client_max_window_bits",
+                Some("permessage-deflate; client_max_window_bits=10"),
+            ),
+            (
+                Deflate::new().client_max_window_bits(12),
+                "permessage-deflate; client_max_window_bits=9",
+                Some("permessage-deflate; client_max_window_bits=9"),
+            ),
+            (
+                Deflate::new(),
+                "permessage-deflate; server_no_context_takeover; server_max_window_bits=\"12\"",
+                Some("permessage-deflate; server_no_context_takeover; server_max_window_bits=12"),
+            ),
+            (
+                Deflate::new()
+                    .server_no_context_takeover(true)
+                    .client_no_context_takeover(true)
+                    .server_max_window_bits(10),
+                "permessage-deflate",
+                Some(
+                    "permessage-deflate; server_no_context_takeover; \
+                    client_no_context_takeover; server_max_window_bits=10",
+                ),
+            ),
+            // zlib can't do 8, so the next offer is taken
+            (
+                Deflate::new(),
+                "permessage-deflate; server_max_window_bits=8, permessage-deflate",
+                Some("permessage-deflate"),
+            ),
+            (Deflate::new(), "permessage-deflate; unknown", None),
+            (
+                Deflate::new(),
+                "permessage-deflate; server_max_window_bits",
+                None,
+            ),
+            (
+                Deflate::new(),
+                "permessage-deflate; server_max_window_bits=16",
+                None,
+            ),
+            (
+                Deflate::new(),
+                "permessage-deflate; server_no_context_takeover; server_no_context_takeover",
+                None,
+            ),
+            (Deflate::new(), "x-webkit-deflate-frame", None),
+        ] {
+            let response = accept(&handshake(Some(offer)), Some(deflate), |_| {});
+            assert_eq!(
+                expected,
+                header(&response, "Sec-WebSocket-Extensions"),
+                "{}",
+                offer
+            );
+        }
+    }
+
+    #[test]
+    fn messages_are_compressed_both_ways() {
+        let (mut client, head) = start(Some(Deflate::new()), Some("permessage-deflate"));
+        assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+        assert!(
+            head.contains("Sec-WebSocket-Extensions: permessage-deflate\r\n"),
+            "{}",
+            head
+        );
+
+        let mut inflater = client_deflate();
+        let mut sizes = vec![];
+        for _ in 0..2 {
+            // "Hello" compressed, from RFC 7692 section 7.2.3.1
+            send_frame(
+                &mut client,
+                0xc1,
+                &[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00],
+            );
+            let (first_byte, payload) = read_frame(&mut client);
+            assert_eq!(0xc1, first_byte);
+            sizes.push(payload.len());
+            let message = inflater.decompress(&payload, 1024).unwrap().unwrap();
+            assert_eq!(b"Hello", message.as_slice());
+        }
+        // The second refers back to the first
+        assert!(sizes[1] < sizes[0], "{:?}", sizes);
+    }
+
+    #[test]
+    fn server_no_context_takeover_compresses_each_message_alone() {
+        let (mut client, head) = start(
+            Some(Deflate::new()),
+            Some("permessage-deflate; server_no_context_takeover"),
+        );
+        assert!(head.contains("server_no_context_takeover"), "{}", head);
+
+        let json = br#"{"cpu": 0.25, "memory": 0.5, "disk": 0.75}"#.repeat(20);
+        let mut deflater = client_deflate();
+        let mut payloads = vec![];
+        for _ in 0..2 {
+            send_frame(&mut client, 0xc2, &deflater.compress(&json).unwrap());
+            let (first_byte, payload) = read_frame(&mut client);
+            assert_eq!(0xc2, first_byte);
+            assert!(payload.len() < json.len() / 4);
+            // Each one decompresses without the one before
+            let message = client_deflate().decompress(&payload, 64 * 1024).unwrap();
+            assert_eq!(Some(&json), message.as_ref());
+            payloads.push(payload);
+        }
+        assert_eq!(payloads[0], payloads[1]);
+    }
+
+    #[test]
+    fn messages_can_be_unlimited() {
+        let zeros = vec![0; 64 * 1024];
+        let compressed = client_deflate().compress(&zeros).unwrap();
+        let message = client_deflate()
+            .decompress(&compressed, usize::MAX)
+            .unwrap();
+        assert_eq!(Some(zeros), message);
+    }
+
+    #[test]
+    fn fragments_pings_and_uncompressed_messages_are_handled() {
+        let (mut client, _) = start(Some(Deflate::new()), Some("permessage-deflate"));
+        let compressed = client_deflate().compress(b"Hello, world").unwrap();
+        let (first, rest) = compressed.split_at(3);
+
+        // Only the first fragment says it's compressed, and a ping can come between
+        send_frame(&mut client, 0x41, first);
+        send_frame(&mut client, 0x89, b"ping");
+        send_frame(&mut client, 0x80, rest);
+        assert_eq!((0x8a, b"ping".to_vec()), read_frame(&mut client));
+        let (_, payload) = read_frame(&mut client);
+        let mut inflater = client_deflate();
+        let message = inflater.decompress(&payload, 1024).unwrap().unwrap();
+        assert_eq!(b"Hello, world", message.as_slice());
+
+        send_frame(&mut client, 0x81, b"plain");
+        let (_, payload) = read_frame(&mut client);
+        let message = inflater.decompress(&payload, 1024).unwrap().unwrap();
+        assert_eq!(b"plain", message.as_slice());
+
+        send_frame(&mut client, 0x88, &NORMAL_CLOSURE.to_be_bytes());
+        assert_eq!(
+            (0x88, NORMAL_CLOSURE.to_be_bytes().to_vec()),
+            read_frame(&mut client)
+        );
+    }
+
+    #[test]
+    fn compressed_frames_need_the_extension() {
+        let (mut client, head) = start(None, Some("permessage-deflate"));
+        assert!(!head.contains("Sec-WebSocket-Extensions"), "{}", head);
+
+        send_frame(
+            &mut client,
+            0xc1,
+            &[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00],
+        );
+        let (first_byte, payload) = read_frame(&mut client);
+        assert_eq!(0x88, first_byte);
+        assert_eq!(PROTOCOL_ERROR.to_be_bytes(), payload[..2]);
+    }
+
+    #[test]
+    fn decompressed_messages_are_limited() {
+        let (mut client, _) = start(Some(Deflate::new()), Some("permessage-deflate"));
+        // A few hundred bytes on the wire, a megabyte once decompressed
+        let bomb = client_deflate().compress(&vec![0; 1024 * 1024]).unwrap();
+        assert!(bomb.len() < 125 * 16);
+
+        send_frame(&mut client, 0xc2, &bomb);
+        let (first_byte, payload) = read_frame(&mut client);
+        assert_eq!(0x88, first_byte);
+        assert_eq!(MESSAGE_TOO_BIG.to_be_bytes(), payload[..2]);
+    }
+}